@@ -1,13 +1,118 @@
-use crate::messages::RoomId;
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use crate::messages::{RoomId, UserId};
+use jsonwebtoken::errors::{self as jwt_errors, ErrorKind};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, TokenData, Validation};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The reasons that a validly signed token may still be unacceptable, which clients may want to tell apart from a
+/// bogus token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    /// The token's expiration time has passed.
+    Expired,
+    /// The token was issued by someone other than the expected issuer.
+    WrongIssuer,
+    /// The token was meant for some audience other than this server.
+    WrongAudience,
+    /// The token's not-before time hasn't arrived yet.
+    Premature,
+    /// The token's ID has been revoked.
+    Revoked,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Expired => f.write_str("Token has expired."),
+            TokenError::WrongIssuer => f.write_str("Token was issued by an unexpected issuer."),
+            TokenError::WrongAudience => f.write_str("Token is intended for a different audience."),
+            TokenError::Premature => f.write_str("Token is not valid yet."),
+            TokenError::Revoked => f.write_str("Token has been revoked."),
+        }
+    }
+}
+
+impl Error for TokenError {}
+
+/// The reasons that a client may be refused authorization, each carrying a human-readable explanation. Clients get a
+/// machine-readable code for these, so that they can tell what went wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The token has expired.
+    Expired(&'static str),
+    /// The token is valid, but doesn't permit what was asked (or isn't acceptable to this server.)
+    Forbidden(&'static str),
+    /// The token couldn't be decoded or verified.
+    Malformed(&'static str),
+    /// The token is valid, but only for other rooms.
+    WrongRoom(&'static str),
+}
+
+impl AuthError {
+    /// The machine-readable code identifying this kind of error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::Expired(_) => "expired",
+            AuthError::Forbidden(_) => "forbidden",
+            AuthError::Malformed(_) => "malformed",
+            AuthError::WrongRoom(_) => "wrong_room",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Expired(msg) | AuthError::Forbidden(msg) | AuthError::Malformed(msg) | AuthError::WrongRoom(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+/// How tokens should be validated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthConfig {
+    /// The keys which tokens may be signed with; more than one may be active while rotating keys. For HMAC algorithms,
+    /// these are shared secrets; otherwise, they're DER-encoded public keys.
+    pub keys: Vec<Vec<u8>>,
+    /// The algorithm which tokens must be signed with.
+    pub algorithm: Algorithm,
+    /// If present, tokens must have this `iss` claim.
+    pub expected_issuer: Option<String>,
+    /// If present, tokens must have this `aud` claim.
+    pub expected_audience: Option<String>,
+    /// How many seconds of clock skew to tolerate when checking tokens' timestamps.
+    pub leeway_secs: u64,
+}
+
+impl AuthConfig {
+    fn decoding_key<'a>(&self, key: &'a [u8]) -> DecodingKey<'a> {
+        match self.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => DecodingKey::from_secret(key),
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_der(key),
+            _ => DecodingKey::from_rsa_der(key),
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ValidatedToken {
     pub join_hub: bool,
     pub kick_users: bool,
     pub room_ids: Option<Vec<RoomId>>,
+    /// Whether the bearer may be the first publisher in a room, opening it up.
+    pub create_room: bool,
+    /// Whether the bearer may start and stop recording rooms.
+    pub record: bool,
+    /// The token's unique ID, if it has one, so that it can be revoked.
+    pub id: Option<String>,
+    /// The user whose identity the bearer may take on, if the token names one.
+    pub user_id: Option<UserId>,
+    /// When the token stops being valid, in seconds since the Unix epoch.
+    pub expires_at: u64,
 }
 
 impl ValidatedToken {
@@ -29,6 +134,38 @@ impl ValidatedToken {
             false // this token disallows joining entirely
         }
     }
+
+    /// Whether this token grants moderator powers (e.g. kicking users or managing rosters) in the given room.
+    pub fn may_moderate(&self, room_id: &RoomId) -> bool {
+        match &self.room_ids {
+            Some(allowed_rooms) => self.kick_users && allowed_rooms.contains(room_id),
+            None => self.kick_users,
+        }
+    }
+
+    /// Whether this token lets the bearer record the given room.
+    pub fn may_record(&self, room_id: &RoomId) -> bool {
+        match &self.room_ids {
+            Some(allowed_rooms) => self.record && allowed_rooms.contains(room_id),
+            None => self.record,
+        }
+    }
+
+    /// Whether this token vouches that the bearer is the given user.
+    pub fn may_act_as(&self, user_id: &UserId) -> bool {
+        self.user_id.as_ref() == Some(user_id)
+    }
+
+    /// Whether this token grants server-wide administrative powers (e.g. revoking other tokens.)
+    pub fn may_administer(&self) -> bool {
+        self.kick_users && self.room_ids.is_none()
+    }
+
+    /// Whether this token had expired by the given time, in seconds since the Unix epoch, tolerating the given leeway
+    /// the same way that validating it does.
+    pub fn is_expired_at(&self, now: u64, leeway_secs: u64) -> bool {
+        self.expires_at.saturating_add(leeway_secs) < now
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,17 +176,375 @@ struct UserClaims {
     kick_users: bool,
     #[serde(default)]
     room_ids: Option<Vec<RoomId>>,
+    #[serde(default)]
+    create_room: bool,
+    #[serde(default)]
+    record: bool,
+    /// When this token stops being valid, in seconds since the Unix epoch.
+    exp: u64,
+    /// If present, when this token starts being valid, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nbf: Option<u64>,
+    /// If present, a unique ID for this token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+    /// If present, the user whose identity the bearer may take on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_id: Option<UserId>,
 }
 
 impl ValidatedToken {
-    pub fn from_str(value: &str, key: &[u8]) -> Result<ValidatedToken, Box<dyn Error>> {
-        let validation = Validation::new(Algorithm::RS512);
-        let dk = DecodingKey::from_rsa_der(key);
-        let token_data = decode::<UserClaims>(value, &dk, &validation)?;
+    pub fn from_str(value: &str, auth: &AuthConfig) -> Result<ValidatedToken, Box<dyn Error>> {
+        let mut validation = Validation::new(auth.algorithm);
+        validation.validate_exp = true;
+        validation.leeway = auth.leeway_secs;
+        validation.iss = auth.expected_issuer.clone();
+        if let Some(ref audience) = auth.expected_audience {
+            validation.set_audience(&[audience]);
+        }
+        let token_data = decode_with_any_key(value, auth, &validation).map_err(|e| -> Box<dyn Error> {
+            match e.kind() {
+                ErrorKind::ExpiredSignature => Box::new(TokenError::Expired),
+                ErrorKind::InvalidIssuer => Box::new(TokenError::WrongIssuer),
+                ErrorKind::InvalidAudience => Box::new(TokenError::WrongAudience),
+                _ => Box::new(e),
+            }
+        })?;
+        // the library insists on an nbf claim if it checks it at all, but ours is optional, so check it ourselves
+        if let Some(nbf) = token_data.claims.nbf {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            if nbf > now + auth.leeway_secs {
+                return Err(Box::new(TokenError::Premature));
+            }
+        }
         Ok(ValidatedToken {
             join_hub: token_data.claims.join_hub,
             kick_users: token_data.claims.kick_users,
             room_ids: token_data.claims.room_ids,
+            create_room: token_data.claims.create_room,
+            record: token_data.claims.record,
+            id: token_data.claims.jti,
+            user_id: token_data.claims.user_id,
+            expires_at: token_data.claims.exp,
         })
     }
 }
+
+/// Decodes the token with the first configured key which it was signed with. The signature is checked before any claims,
+/// so if a key's signature matches, its result is the most specific one there is, whether or not the claims are good.
+fn decode_with_any_key(value: &str, auth: &AuthConfig, validation: &Validation) -> jwt_errors::Result<TokenData<UserClaims>> {
+    let mut result = Err(ErrorKind::InvalidSignature.into());
+    for key in &auth.keys {
+        result = decode::<UserClaims>(value, &auth.decoding_key(key), validation);
+        match result {
+            Err(ref e) if matches!(e.kind(), ErrorKind::InvalidSignature) => continue,
+            _ => break,
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    static PRIVATE_KEY: &[u8] = include_bytes!("../testdata/test_key.pem");
+    static PUBLIC_KEY: &[u8] = include_bytes!("../testdata/test_key.pub.der");
+    static SECRET: &[u8] = b"correct horse battery staple";
+
+    fn rs512_config() -> AuthConfig {
+        AuthConfig {
+            keys: vec![PUBLIC_KEY.to_vec()],
+            algorithm: Algorithm::RS512,
+            expected_issuer: None,
+            expected_audience: None,
+            leeway_secs: 0,
+        }
+    }
+
+    fn hs256_config() -> AuthConfig {
+        AuthConfig {
+            keys: vec![SECRET.to_vec()],
+            algorithm: Algorithm::HS256,
+            expected_issuer: None,
+            expected_audience: None,
+            leeway_secs: 0,
+        }
+    }
+
+    /// Makes claims letting the bearer join any room, which expire the given number of seconds from now.
+    fn claims(expires_in: i64) -> UserClaims {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        UserClaims {
+            join_hub: true,
+            kick_users: false,
+            room_ids: None,
+            create_room: false,
+            record: false,
+            exp: (now + expires_in) as u64,
+            nbf: None,
+            jti: None,
+            user_id: None,
+        }
+    }
+
+    fn sign_rs512(claims: &impl Serialize) -> String {
+        let key = EncodingKey::from_rsa_pem(PRIVATE_KEY).unwrap();
+        encode(&Header::new(Algorithm::RS512), claims, &key).unwrap()
+    }
+
+    fn sign_hs256(claims: &impl Serialize) -> String {
+        encode(&Header::new(Algorithm::HS256), claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    #[test]
+    fn unexpired_token_is_valid() {
+        let token = ValidatedToken::from_str(&sign_rs512(&claims(3600)), &rs512_config()).unwrap();
+        assert!(token.may_join(&"alpha".into()));
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let err = ValidatedToken::from_str(&sign_rs512(&claims(-3600)), &rs512_config()).unwrap_err();
+        assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::Expired));
+    }
+
+    #[test]
+    fn garbage_token_is_invalid_but_not_expired() {
+        let err = ValidatedToken::from_str("garbage", &rs512_config()).unwrap_err();
+        assert!(!err.is::<TokenError>());
+    }
+
+    #[test]
+    fn hs256_token_is_valid_with_shared_secret() {
+        let token = ValidatedToken::from_str(&sign_hs256(&claims(3600)), &hs256_config()).unwrap();
+        assert!(token.may_join(&"alpha".into()));
+    }
+
+    #[test]
+    fn tokens_must_use_configured_algorithm() {
+        assert!(ValidatedToken::from_str(&sign_hs256(&claims(3600)), &rs512_config()).is_err());
+        assert!(ValidatedToken::from_str(&sign_rs512(&claims(3600)), &hs256_config()).is_err());
+    }
+
+    #[test]
+    fn any_active_key_validates() {
+        let config = AuthConfig {
+            keys: vec![b"old secret".to_vec(), SECRET.to_vec()],
+            ..hs256_config()
+        };
+        assert!(ValidatedToken::from_str(&sign_hs256(&claims(3600)), &config).is_ok());
+    }
+
+    #[test]
+    fn key_rotation_preserves_specific_errors() {
+        let config = AuthConfig {
+            keys: vec![b"old secret".to_vec(), SECRET.to_vec()],
+            ..hs256_config()
+        };
+        let err = ValidatedToken::from_str(&sign_hs256(&claims(-3600)), &config).unwrap_err();
+        assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::Expired));
+    }
+
+    #[test]
+    fn token_signed_with_unknown_key_is_rejected() {
+        let config = AuthConfig {
+            keys: vec![b"old secret".to_vec(), b"new secret".to_vec()],
+            ..hs256_config()
+        };
+        let err = ValidatedToken::from_str(&sign_hs256(&claims(3600)), &config).unwrap_err();
+        assert!(err.downcast_ref::<TokenError>().is_none());
+    }
+
+    #[test]
+    fn create_room_defaults_to_false() {
+        let token = ValidatedToken::from_str(&sign_hs256(&claims(3600)), &hs256_config()).unwrap();
+        assert!(!token.create_room);
+        let claims = UserClaims {
+            create_room: true,
+            ..claims(3600)
+        };
+        let token = ValidatedToken::from_str(&sign_hs256(&claims), &hs256_config()).unwrap();
+        assert!(token.create_room);
+    }
+
+    #[test]
+    fn token_id_is_exposed() {
+        let claims = UserClaims {
+            jti: Some("abc".into()),
+            ..claims(3600)
+        };
+        let token = ValidatedToken::from_str(&sign_hs256(&claims), &hs256_config()).unwrap();
+        assert_eq!(token.id, Some("abc".into()));
+    }
+
+    #[test]
+    fn tokens_may_vouch_for_a_user() {
+        let token = ValidatedToken::from_str(&sign_hs256(&claims(3600)), &hs256_config()).unwrap();
+        assert!(!token.may_act_as(&"alice".into()));
+        let claims = UserClaims {
+            user_id: Some("alice".into()),
+            ..claims(3600)
+        };
+        let token = ValidatedToken::from_str(&sign_hs256(&claims), &hs256_config()).unwrap();
+        assert!(token.may_act_as(&"alice".into()));
+        assert!(!token.may_act_as(&"bob".into()));
+    }
+
+    #[test]
+    fn leeway_tolerates_clock_skew() {
+        let token = sign_hs256(&claims(-2));
+        let lenient = AuthConfig {
+            leeway_secs: 5,
+            ..hs256_config()
+        };
+        assert!(ValidatedToken::from_str(&token, &lenient).is_ok());
+        let err = ValidatedToken::from_str(&token, &hs256_config()).unwrap_err();
+        assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::Expired));
+    }
+
+    /// Makes unexpiring claims which become valid the given number of seconds from now.
+    fn claims_not_before(starts_in: i64) -> UserClaims {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        UserClaims {
+            nbf: Some((now + starts_in) as u64),
+            ..claims(3600)
+        }
+    }
+
+    #[test]
+    fn token_is_rejected_before_nbf() {
+        let err = ValidatedToken::from_str(&sign_hs256(&claims_not_before(3600)), &hs256_config()).unwrap_err();
+        assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::Premature));
+    }
+
+    #[test]
+    fn token_is_valid_after_nbf() {
+        assert!(ValidatedToken::from_str(&sign_hs256(&claims_not_before(-60)), &hs256_config()).is_ok());
+    }
+
+    #[test]
+    fn leeway_applies_to_nbf() {
+        let token = sign_hs256(&claims_not_before(2));
+        let lenient = AuthConfig {
+            leeway_secs: 5,
+            ..hs256_config()
+        };
+        assert!(ValidatedToken::from_str(&token, &lenient).is_ok());
+        assert!(ValidatedToken::from_str(&token, &hs256_config()).is_err());
+    }
+
+    /// Makes unexpiring claims letting the bearer join any room, with the given extra claims added.
+    fn claims_with(extra: serde_json::Value) -> serde_json::Value {
+        let mut claims = serde_json::to_value(claims(3600)).unwrap();
+        claims.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        claims
+    }
+
+    fn restricted_config() -> AuthConfig {
+        AuthConfig {
+            expected_issuer: Some("accounts".into()),
+            expected_audience: Some("sfu".into()),
+            ..hs256_config()
+        }
+    }
+
+    #[test]
+    fn matching_issuer_and_audience_are_valid() {
+        let token = sign_hs256(&claims_with(json!({ "iss": "accounts", "aud": "sfu" })));
+        assert!(ValidatedToken::from_str(&token, &restricted_config()).is_ok());
+        let token = sign_hs256(&claims_with(json!({ "iss": "accounts", "aud": ["chat", "sfu"] })));
+        assert!(ValidatedToken::from_str(&token, &restricted_config()).is_ok());
+    }
+
+    #[test]
+    fn mismatched_issuer_or_audience_is_rejected() {
+        let token = sign_hs256(&claims_with(json!({ "iss": "impostor", "aud": "sfu" })));
+        let err = ValidatedToken::from_str(&token, &restricted_config()).unwrap_err();
+        assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::WrongIssuer));
+        let token = sign_hs256(&claims_with(json!({ "iss": "accounts", "aud": "chat" })));
+        let err = ValidatedToken::from_str(&token, &restricted_config()).unwrap_err();
+        assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::WrongAudience));
+    }
+
+    #[test]
+    fn absent_audience_is_rejected_only_when_expected() {
+        let token = sign_hs256(&claims_with(json!({ "iss": "accounts" })));
+        let err = ValidatedToken::from_str(&token, &restricted_config()).unwrap_err();
+        assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::WrongAudience));
+        assert!(ValidatedToken::from_str(&token, &hs256_config()).is_ok());
+    }
+
+    mod kick {
+
+        use super::*;
+
+        fn moderator(room_ids: Option<Vec<RoomId>>) -> ValidatedToken {
+            ValidatedToken {
+                join_hub: true,
+                kick_users: true,
+                room_ids,
+                create_room: true,
+                record: false,
+                id: None,
+                user_id: None,
+                expires_at: u64::MAX,
+            }
+        }
+
+        #[test]
+        fn scoped_token_may_kick_in_its_rooms() {
+            let token = moderator(Some(vec!["alpha".into(), "beta".into()]));
+            assert!(token.may_moderate(&"alpha".into()));
+            assert!(token.may_moderate(&"beta".into()));
+        }
+
+        #[test]
+        fn scoped_token_may_not_kick_in_other_rooms() {
+            let token = moderator(Some(vec!["alpha".into()]));
+            assert!(!token.may_moderate(&"gamma".into()));
+        }
+
+        #[test]
+        fn global_token_may_kick_anywhere() {
+            let token = moderator(None);
+            assert!(token.may_moderate(&"alpha".into()));
+            assert!(token.may_moderate(&"gamma".into()));
+        }
+
+        #[test]
+        fn token_without_kick_permission_may_not_kick() {
+            let token = ValidatedToken {
+                kick_users: false,
+                ..moderator(None)
+            };
+            assert!(!token.may_moderate(&"alpha".into()));
+        }
+
+        #[test]
+        fn tokens_expire_after_their_leeway() {
+            let token = ValidatedToken {
+                expires_at: 1000,
+                ..moderator(None)
+            };
+            assert!(!token.is_expired_at(1000, 0));
+            assert!(token.is_expired_at(1001, 0));
+            assert!(!token.is_expired_at(1010, 10));
+            assert!(token.is_expired_at(1011, 10));
+        }
+
+        #[test]
+        fn only_tokens_with_record_permission_may_record() {
+            assert!(!moderator(None).may_record(&"alpha".into()));
+            let token = ValidatedToken {
+                record: true,
+                ..moderator(Some(vec!["alpha".into()]))
+            };
+            assert!(token.may_record(&"alpha".into()));
+            assert!(!token.may_record(&"beta".into()));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+/// A token bucket for rate limiting.
+use std::time::Instant;
+
+/// Permits bursts of up to `capacity` events, refilling at `rate` events per second.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(capacity: u32, rate: f64, now: Instant) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            rate,
+            tokens: f64::from(capacity),
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token out of the bucket if one is available, returning whether one was.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn allows_bursts_up_to_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(3, 1.0, now);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, 2.0, now);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+        assert!(bucket.try_take(now + Duration::from_millis(500)));
+        assert!(!bucket.try_take(now + Duration::from_millis(500)));
+        assert!(bucket.try_take(now + Duration::from_secs(10)));
+        assert!(bucket.try_take(now + Duration::from_secs(10)));
+        assert!(!bucket.try_take(now + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,353 @@
+/// A channel over Unix datagram sockets for exchanging events with some other process on the same machine.
+use crate::bucket::TokenBucket;
+use crate::messages::{RoomId, UserId};
+use janus_plugin::{janus_err, janus_verb, janus_warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the incoming thread checks whether the channel has been closed.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Configuration for the event channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelConfig {
+    /// The path of the socket which we send outgoing events to. Some other process is expected to bind it.
+    pub outgoing_path: PathBuf,
+    /// The path of the socket which we bind to receive incoming events.
+    pub incoming_path: PathBuf,
+    /// The maximum size in bytes of an incoming datagram, and the maximum number of outgoing datagrams to queue.
+    pub buffer_size: usize,
+    /// The maximum number of incoming datagrams per second to process. Any more are discarded.
+    pub max_rate: u32,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            outgoing_path: PathBuf::from("/tmp/janus-sfu-out.sock"),
+            incoming_path: PathBuf::from("/tmp/janus-sfu-in.sock"),
+            buffer_size: 4096,
+            max_rate: 100,
+        }
+    }
+}
+
+/// Something that the datagram is about.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// Everything happening in a room.
+    Room(RoomId),
+    /// Data addressed to a particular user.
+    UserData(UserId),
+}
+
+/// The enumeration of all events which can be sent or received over the channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DatagramKind {
+    /// Sent when a user joins a room.
+    Join { room_id: RoomId, user_id: UserId },
+    /// Sent when a user leaves a room.
+    Leave { room_id: RoomId, user_id: UserId },
+    /// Received when the other process wants to be sent events about a topic.
+    Subscribe(Topic),
+    /// Received when the other process no longer wants to be sent events about a topic.
+    Unsubscribe(Topic),
+    /// Received when the other process wants to send data to a topic's users.
+    Message(Topic, Vec<u8>),
+}
+
+impl DatagramKind {
+    /// The topic which this datagram is about, if any.
+    pub fn topic(&self) -> Option<Topic> {
+        match self {
+            DatagramKind::Join { room_id, .. } | DatagramKind::Leave { room_id, .. } => Some(Topic::Room(room_id.clone())),
+            DatagramKind::Message(topic, _) => Some(topic.clone()),
+            DatagramKind::Subscribe(_) | DatagramKind::Unsubscribe(_) => None,
+        }
+    }
+
+    /// Encodes this datagram as MessagePack, with named fields, so that the other process needn't know their order.
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(rmp_serde::to_vec_named(self)?)
+    }
+
+    /// Decodes a datagram from MessagePack.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// A running event channel. Closes itself when dropped.
+///
+/// The other process must subscribe to topics in order to be sent events about them. Subscriptions are handled by the
+/// channel itself; all other incoming datagrams are passed along to the channel's handler.
+#[derive(Debug)]
+pub struct Channel {
+    outgoing: SyncSender<DatagramKind>,
+    incoming_path: PathBuf,
+    closed: Arc<AtomicBool>,
+    subscriptions: Arc<Mutex<HashSet<Topic>>>,
+}
+
+impl Channel {
+    /// Binds the incoming socket and spawns the threads servicing the channel. Incoming datagrams will be passed to
+    /// the given handler.
+    pub fn start<F>(config: &ChannelConfig, handler: F) -> io::Result<Self>
+    where
+        F: Fn(DatagramKind) + Send + 'static,
+    {
+        // clean up after any previous instance that didn't get to close its socket
+        if let Err(e) = fs::remove_file(&config.incoming_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        let incoming = UnixDatagram::bind(&config.incoming_path)?;
+        incoming.set_read_timeout(Some(POLL_INTERVAL))?;
+        let outgoing = UnixDatagram::unbound()?;
+        let (sender, receiver) = mpsc::sync_channel(config.buffer_size);
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let outgoing_path = config.outgoing_path.clone();
+        thread::Builder::new()
+            .name("sfu channel out".into())
+            .spawn(move || service_outgoing(&outgoing, &outgoing_path, receiver.iter()))?;
+
+        let incoming_config = config.clone();
+        let incoming_closed = Arc::clone(&closed);
+        let subscriptions = Arc::new(Mutex::new(HashSet::new()));
+        let incoming_subscriptions = Arc::clone(&subscriptions);
+        thread::Builder::new().name("sfu channel in".into()).spawn(move || {
+            service_incoming(&incoming, &incoming_config, &incoming_closed, |datagram| match datagram {
+                DatagramKind::Subscribe(topic) => {
+                    incoming_subscriptions.lock().unwrap().insert(topic);
+                }
+                DatagramKind::Unsubscribe(topic) => {
+                    incoming_subscriptions.lock().unwrap().remove(&topic);
+                }
+                other => handler(other),
+            })
+        })?;
+
+        Ok(Self {
+            outgoing: sender,
+            incoming_path: config.incoming_path.clone(),
+            closed,
+            subscriptions,
+        })
+    }
+
+    /// Whether the other process has subscribed to the given topic.
+    pub fn is_subscribed(&self, topic: &Topic) -> bool {
+        self.subscriptions.lock().unwrap().contains(topic)
+    }
+
+    /// Queues a datagram to be sent to the outgoing socket, if the other process is subscribed to its topic. If the
+    /// queue is full, the datagram is dropped.
+    pub fn send(&self, datagram: DatagramKind) {
+        match datagram.topic() {
+            Some(ref topic) if self.is_subscribed(topic) => (),
+            _ => return,
+        }
+        match self.outgoing.try_send(datagram) {
+            Ok(()) => (),
+            Err(TrySendError::Full(d)) => janus_warn!("Event channel queue is full; dropping {:?}.", d),
+            Err(TrySendError::Disconnected(d)) => janus_warn!("Event channel is closed; dropping {:?}.", d),
+        }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Err(e) = fs::remove_file(&self.incoming_path) {
+            janus_warn!("Failed to remove event channel socket {:?}: {}", self.incoming_path, e);
+        }
+    }
+}
+
+fn service_outgoing(socket: &UnixDatagram, path: &Path, datagrams: impl Iterator<Item = DatagramKind>) {
+    for datagram in datagrams {
+        let result = datagram.encode().and_then(|bytes| Ok(socket.send_to(&bytes, path)?));
+        if let Err(e) = result {
+            // the other end may well not be listening right now, so this isn't a big deal
+            janus_verb!("Failed to send {:?} over event channel: {}", datagram, e);
+        }
+    }
+}
+
+fn service_incoming<F: Fn(DatagramKind)>(socket: &UnixDatagram, config: &ChannelConfig, closed: &AtomicBool, handler: F) {
+    // the other process is trusted, but it could still be buggy, so don't let it monopolize us
+    let mut bucket = TokenBucket::new(config.max_rate, f64::from(config.max_rate), Instant::now());
+    let mut buffer = vec![0; config.buffer_size];
+    while !closed.load(Ordering::Relaxed) {
+        match socket.recv(&mut buffer) {
+            Ok(_) if !bucket.try_take(Instant::now()) => janus_warn!("Discarding datagram from event channel due to rate limit."),
+            Ok(len) => match DatagramKind::decode(&buffer[..len]) {
+                Ok(datagram) => handler(datagram),
+                Err(e) => janus_warn!("Discarding malformed datagram from event channel: {}", e),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+            Err(e) => {
+                janus_err!("Error reading from event channel: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn temp_config(name: &str) -> ChannelConfig {
+        let dir = env::temp_dir();
+        ChannelConfig {
+            outgoing_path: dir.join(format!("janus-sfu-{}-{}-out.sock", process::id(), name)),
+            incoming_path: dir.join(format!("janus-sfu-{}-{}-in.sock", process::id(), name)),
+            buffer_size: 1024,
+            max_rate: 100,
+        }
+    }
+
+    fn send_to_channel(config: &ChannelConfig, datagram: &DatagramKind) {
+        let client = UnixDatagram::unbound().unwrap();
+        client.send_to(&datagram.encode().unwrap(), &config.incoming_path).unwrap();
+    }
+
+    #[test]
+    fn datagrams_round_trip_through_msgpack() {
+        let datagrams = vec![
+            DatagramKind::Join {
+                room_id: "alpha".into(),
+                user_id: "10".into(),
+            },
+            DatagramKind::Leave {
+                room_id: "alpha".into(),
+                user_id: "10".into(),
+            },
+            DatagramKind::Subscribe(Topic::Room("alpha".into())),
+            DatagramKind::Unsubscribe(Topic::UserData("10".into())),
+            DatagramKind::Message(Topic::UserData("10".into()), vec![0, 1, 255]),
+        ];
+        for datagram in datagrams {
+            assert_eq!(DatagramKind::decode(&datagram.encode().unwrap()).unwrap(), datagram);
+        }
+    }
+
+    #[test]
+    fn join_datagrams_have_named_fields() {
+        let datagram = DatagramKind::Join {
+            room_id: "alpha".into(),
+            user_id: "10".into(),
+        };
+        let value: serde_json::Value = rmp_serde::from_slice(&datagram.encode().unwrap()).unwrap();
+        assert_eq!(value, serde_json::json!({ "join": { "room_id": "alpha", "user_id": "10" } }));
+    }
+
+    #[test]
+    fn malformed_datagrams_are_rejected() {
+        assert!(DatagramKind::decode(&[0xC1]).is_err());
+        assert!(DatagramKind::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn incoming_datagrams_reach_handler() {
+        let config = temp_config("incoming");
+        let (sender, receiver) = mpsc::channel();
+        let channel = Channel::start(&config, move |d| sender.send(d).unwrap()).unwrap();
+        let datagram = DatagramKind::Join {
+            room_id: "alpha".into(),
+            user_id: "10".into(),
+        };
+        send_to_channel(&config, &datagram);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), datagram);
+        drop(channel);
+    }
+
+    #[test]
+    fn incoming_datagrams_are_rate_limited() {
+        let config = ChannelConfig {
+            max_rate: 2,
+            ..temp_config("rate_limited")
+        };
+        let (sender, receiver) = mpsc::channel();
+        let _channel = Channel::start(&config, move |d| sender.send(d).unwrap()).unwrap();
+        let datagram = DatagramKind::Message(Topic::UserData("10".into()), vec![1, 2, 3]);
+        for _ in 0..5 {
+            send_to_channel(&config, &datagram);
+        }
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), datagram);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), datagram);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn subscriptions_are_handled_by_channel() {
+        let config = temp_config("subscriptions");
+        let (sender, receiver) = mpsc::channel();
+        let channel = Channel::start(&config, move |d| sender.send(d).unwrap()).unwrap();
+        let topic = Topic::Room("alpha".into());
+        send_to_channel(&config, &DatagramKind::Subscribe(topic.clone()));
+        send_to_channel(&config, &DatagramKind::Message(topic.clone(), vec![]));
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            DatagramKind::Message(topic.clone(), vec![])
+        );
+        assert!(channel.is_subscribed(&topic));
+        send_to_channel(&config, &DatagramKind::Unsubscribe(topic.clone()));
+        send_to_channel(&config, &DatagramKind::Message(topic.clone(), vec![]));
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            DatagramKind::Message(topic.clone(), vec![])
+        );
+        assert!(!channel.is_subscribed(&topic));
+    }
+
+    #[test]
+    fn outgoing_datagrams_reach_socket() {
+        let config = temp_config("outgoing");
+        let _ = fs::remove_file(&config.outgoing_path);
+        let listener = UnixDatagram::bind(&config.outgoing_path).unwrap();
+        let channel = Channel::start(&config, |_| ()).unwrap();
+        let ignored = DatagramKind::Join {
+            room_id: "beta".into(),
+            user_id: "10".into(),
+        };
+        let datagram = DatagramKind::Join {
+            room_id: "alpha".into(),
+            user_id: "10".into(),
+        };
+        channel.subscriptions.lock().unwrap().insert(Topic::Room("alpha".into()));
+        channel.send(ignored);
+        channel.send(datagram.clone());
+        let mut buffer = vec![0; 1024];
+        let len = listener.recv(&mut buffer).unwrap();
+        assert_eq!(DatagramKind::decode(&buffer[..len]).unwrap(), datagram);
+        fs::remove_file(&config.outgoing_path).unwrap();
+    }
+
+    #[test]
+    fn closing_removes_socket() {
+        let config = temp_config("closing");
+        let channel = Channel::start(&config, |_| ()).unwrap();
+        assert!(config.incoming_path.exists());
+        drop(channel);
+        assert!(!config.incoming_path.exists());
+    }
+}
@@ -1,31 +1,200 @@
 /// Code for reading the event handler config file into memory.
+use crate::auth::AuthConfig;
+use crate::channel::ChannelConfig;
+use crate::messages::{RoomId, Subscription};
+use crate::sessions::JoinKind;
+use crate::txid::DEFAULT_MAX_DISPLAY_LENGTH;
 use ini::Ini;
+use janus_plugin::janus_warn;
+use janus_plugin::sdp::{AudioCodec, VideoCodec};
+use jsonwebtoken::Algorithm;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Which kinds of joins a limit applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitScope {
+    Publishers,
+    Subscribers,
+    Everyone,
+    Nobody,
+}
+
+impl LimitScope {
+    pub fn applies_to(self, kind: JoinKind) -> bool {
+        match self {
+            LimitScope::Publishers => kind == JoinKind::Publisher,
+            LimitScope::Subscribers => kind == JoinKind::Subscriber,
+            LimitScope::Everyone => true,
+            LimitScope::Nobody => false,
+        }
+    }
+}
+
+impl FromStr for LimitScope {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "publishers" => Ok(LimitScope::Publishers),
+            "subscribers" => Ok(LimitScope::Subscribers),
+            "everyone" => Ok(LimitScope::Everyone),
+            "nobody" => Ok(LimitScope::Nobody),
+            other => Err(From::from(format!("Unknown limit scope: {}", other))),
+        }
+    }
+}
+
+/// The most message processing threads we'll run, however many are asked for.
+pub const MAX_MESSAGE_THREADS: usize = 256;
 
 /// All of the runtime configuration for the plugin.
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub auth_key: Option<Vec<u8>>,
+    pub auth: Option<AuthConfig>,
+    pub revoked_token_ids: HashSet<String>,
     pub max_room_size: usize,
+    pub max_subscribers_per_room: usize,
+    pub max_subscribers_per_publisher: usize,
     pub max_ccu: usize,
+    pub max_rooms: usize,
+    pub max_room_size_scope: LimitScope,
+    pub max_ccu_scope: LimitScope,
+    pub count_subscribers_in_ccu: bool,
+    pub reserved_moderator_slots: usize,
     pub message_threads: usize,
+    pub message_queue_depth: usize,
+    pub idle_timeout_ms: u64,
+    pub slow_message_threshold_ms: u64,
+    pub max_logged_txid_length: usize,
+    pub max_subscription_rate: u32,
+    pub max_data_message_bytes: usize,
+    pub max_id_length: usize,
+    pub protect_user_ids: bool,
+    pub offer_grace_period_ms: u64,
+    pub block_grace_period_ms: u64,
+    pub keyframe_min_interval_ms: u64,
+    pub remb_relay_enabled: bool,
+    pub nack_buffer_size: usize,
+    pub active_speaker_interval_ms: u64,
+    pub active_speaker_threshold: f32,
+    pub subscriber_count_interval_ms: u64,
+    pub audio_codec: AudioCodec,
+    pub opus_stereo: bool,
+    pub opus_sprop_stereo: bool,
+    pub opus_usedtx: bool,
+    pub video_codec: VideoCodec,
+    pub publisher_sendrecv: bool,
+    pub sequence_reliable_data: bool,
+    pub default_subscription: Option<Subscription>,
+    pub event_channel: Option<ChannelConfig>,
+    pub webhook_url: Option<String>,
+    pub status_http_addr: Option<SocketAddr>,
+    pub recording_dir: Option<PathBuf>,
+    pub rooms: HashMap<RoomId, RoomConfig>,
+}
+
+/// Settings which are overridden for a particular room, in a `[room.<room_id>]` section of the config file.
+#[derive(Debug, Clone, Default)]
+pub struct RoomConfig {
+    pub max_room_size: Option<usize>,
+    pub audio_codec: Option<AudioCodec>,
+    pub video_codec: Option<VideoCodec>,
+    pub publisher_sendrecv: Option<bool>,
+}
+
+/// The subset of the configuration which describes limits that clients may want to know about. Nothing sensitive
+/// belongs in here.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Limits {
+    pub max_room_size: usize,
+    pub max_ccu: usize,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            auth_key: None,
+            auth: None,
+            revoked_token_ids: HashSet::new(),
             max_room_size: 0,
+            max_subscribers_per_room: 0,
+            max_subscribers_per_publisher: 0,
             max_ccu: 0,
+            max_rooms: 0,
+            max_room_size_scope: LimitScope::Publishers,
+            max_ccu_scope: LimitScope::Publishers,
+            count_subscribers_in_ccu: false,
+            reserved_moderator_slots: 0,
             message_threads: 0,
+            message_queue_depth: 64,
+            idle_timeout_ms: 0,
+            slow_message_threshold_ms: 0,
+            max_logged_txid_length: DEFAULT_MAX_DISPLAY_LENGTH,
+            max_subscription_rate: 0,
+            max_data_message_bytes: 0,
+            max_id_length: 256,
+            protect_user_ids: false,
+            offer_grace_period_ms: 0,
+            block_grace_period_ms: 30000,
+            keyframe_min_interval_ms: 0,
+            remb_relay_enabled: false,
+            nack_buffer_size: 0,
+            active_speaker_interval_ms: 0,
+            // loudness runs from 0 for silence to 127 for as loud as possible
+            active_speaker_threshold: 50.0,
+            subscriber_count_interval_ms: 0,
+            // Opus is cross-compatible with everything we care about.
+            audio_codec: AudioCodec::Opus,
+            // mono, with discontinuous transmission, saves plenty of bandwidth for voice chat
+            opus_stereo: false,
+            opus_sprop_stereo: false,
+            opus_usedtx: true,
+            // H.264 is cross-compatible with modern Firefox, Chrome, Safari, and Edge; VP8/9 unfortunately isn't
+            // compatible with Safari.
+            video_codec: VideoCodec::H264,
+            publisher_sendrecv: false,
+            sequence_reliable_data: false,
+            default_subscription: None,
+            event_channel: None,
+            webhook_url: None,
+            status_http_addr: None,
+            recording_dir: None,
+            rooms: HashMap::new(),
         }
     }
 }
 
 impl Config {
+    /// Returns the configuration which applies in the given room, taking into account any overrides for it.
+    pub fn for_room(&self, room: &RoomId) -> Cow<'_, Config> {
+        match self.rooms.get(room) {
+            None => Cow::Borrowed(self),
+            Some(overrides) => {
+                let mut config = self.clone();
+                config.max_room_size = overrides.max_room_size.unwrap_or(self.max_room_size);
+                config.audio_codec = overrides.audio_codec.unwrap_or(self.audio_codec);
+                config.video_codec = overrides.video_codec.unwrap_or(self.video_codec);
+                config.publisher_sendrecv = overrides.publisher_sendrecv.unwrap_or(self.publisher_sendrecv);
+                Cow::Owned(config)
+            }
+        }
+    }
+
+    /// Returns the limits in this configuration which are safe to share with clients.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_room_size: self.max_room_size,
+            max_ccu: self.max_ccu,
+        }
+    }
+
     /// Reads the runtime configuration from an INI config file at the given path, applying defaults for individual
     /// configuration values that aren't present, or returning an error if no readable configuration is present at all.
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
@@ -33,21 +202,471 @@ impl Config {
         let section = conf.section(Some("general")).ok_or("No 'general' section present in the config file.")?;
         let defaults: Config = Default::default();
 
-        let auth_key = match section.get("auth_key") {
-            Some(keyfile) => {
-                let mut buffer = Vec::<u8>::new();
-                let mut file = File::open(keyfile)?;
-                file.read_to_end(&mut buffer)?;
-                Some(buffer)
+        let auth = match section.get("auth_key") {
+            Some(keyfiles) => {
+                let mut keys = Vec::new();
+                for keyfile in keyfiles.split(',').map(str::trim) {
+                    let mut buffer = Vec::<u8>::new();
+                    let mut file = File::open(keyfile)?;
+                    file.read_to_end(&mut buffer)?;
+                    keys.push(buffer);
+                }
+                Some(AuthConfig {
+                    keys,
+                    algorithm: section.get("auth_algorithm").and_then(|x| x.parse().ok()).unwrap_or(Algorithm::RS512),
+                    expected_issuer: section.get("expected_issuer").map(String::from),
+                    expected_audience: section.get("expected_audience").map(String::from),
+                    leeway_secs: section.get("token_leeway_secs").and_then(|x| x.parse().ok()).unwrap_or(0),
+                })
             }
             None => None,
         };
 
+        let revoked_token_ids = match section.get("revoked_tokens") {
+            Some(path) => {
+                let mut contents = String::new();
+                File::open(path)?.read_to_string(&mut contents)?;
+                contents.lines().map(str::trim).filter(|x| !x.is_empty()).map(String::from).collect()
+            }
+            None => defaults.revoked_token_ids,
+        };
+
+        let event_channel = match section.get("event_channel").map(|x| x.parse()) {
+            Some(Ok(true)) => {
+                let channel_defaults: ChannelConfig = Default::default();
+                Some(ChannelConfig {
+                    outgoing_path: section
+                        .get("event_channel_outgoing_path")
+                        .map(PathBuf::from)
+                        .unwrap_or(channel_defaults.outgoing_path),
+                    incoming_path: section
+                        .get("event_channel_incoming_path")
+                        .map(PathBuf::from)
+                        .unwrap_or(channel_defaults.incoming_path),
+                    buffer_size: section
+                        .get("event_channel_buffer_size")
+                        .and_then(|x| x.parse().ok())
+                        .unwrap_or(channel_defaults.buffer_size),
+                    max_rate: section
+                        .get("event_channel_max_rate")
+                        .and_then(|x| x.parse().ok())
+                        .unwrap_or(channel_defaults.max_rate),
+                })
+            }
+            _ => defaults.event_channel,
+        };
+
+        let default_subscription = match (section.get("default_subscription_notifications"), section.get("default_subscription_data")) {
+            (None, None) => defaults.default_subscription,
+            (notifications, data) => Some(Subscription {
+                notifications: notifications.and_then(|x| x.parse().ok()).unwrap_or(false),
+                data: data.and_then(|x| x.parse().ok()).unwrap_or(false),
+                media: None,
+            }),
+        };
+
+        let mut rooms = HashMap::new();
+        for (name, section) in conf.iter() {
+            if let Some(room_id) = name.as_ref().and_then(|x| x.strip_prefix("room.")) {
+                let overrides = RoomConfig {
+                    max_room_size: section.get("max_room_size").and_then(|x| x.parse().ok()),
+                    audio_codec: section.get("audio_codec").and_then(|x| parse_audio_codec(x)),
+                    video_codec: section.get("video_codec").and_then(|x| parse_video_codec(x)),
+                    publisher_sendrecv: section.get("publisher_sendrecv").and_then(|x| x.parse().ok()),
+                };
+                rooms.insert(room_id.to_owned(), overrides);
+            }
+        }
+
         Ok(Self {
-            auth_key: auth_key,
+            auth,
+            revoked_token_ids,
             max_room_size: section.get("max_room_size").and_then(|x| x.parse().ok()).unwrap_or(defaults.max_room_size),
+            max_subscribers_per_room: section
+                .get("max_subscribers_per_room")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.max_subscribers_per_room),
+            max_subscribers_per_publisher: section
+                .get("max_subscribers_per_publisher")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.max_subscribers_per_publisher),
             max_ccu: section.get("max_ccu").and_then(|x| x.parse().ok()).unwrap_or(defaults.max_ccu),
-            message_threads: section.get("message_threads").and_then(|x| x.parse().ok()).unwrap_or(defaults.message_threads),
+            max_rooms: section.get("max_rooms").and_then(|x| x.parse().ok()).unwrap_or(defaults.max_rooms),
+            max_room_size_scope: section
+                .get("max_room_size_scope")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.max_room_size_scope),
+            max_ccu_scope: section.get("max_ccu_scope").and_then(|x| x.parse().ok()).unwrap_or(defaults.max_ccu_scope),
+            count_subscribers_in_ccu: section
+                .get("count_subscribers_in_ccu")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.count_subscribers_in_ccu),
+            reserved_moderator_slots: section
+                .get("reserved_moderator_slots")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.reserved_moderator_slots),
+            message_threads: section
+                .get("message_threads")
+                .and_then(|x| parse_message_threads(x))
+                .unwrap_or(defaults.message_threads),
+            message_queue_depth: section
+                .get("message_queue_depth")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.message_queue_depth),
+            idle_timeout_ms: section.get("idle_timeout_ms").and_then(|x| x.parse().ok()).unwrap_or(defaults.idle_timeout_ms),
+            slow_message_threshold_ms: section
+                .get("slow_message_threshold_ms")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.slow_message_threshold_ms),
+            max_logged_txid_length: section
+                .get("max_logged_txid_length")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.max_logged_txid_length),
+            max_subscription_rate: section
+                .get("max_subscription_rate")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.max_subscription_rate),
+            max_data_message_bytes: section
+                .get("max_data_message_bytes")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.max_data_message_bytes),
+            max_id_length: section.get("max_id_length").and_then(|x| x.parse().ok()).unwrap_or(defaults.max_id_length),
+            protect_user_ids: section
+                .get("protect_user_ids")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.protect_user_ids),
+            offer_grace_period_ms: section
+                .get("offer_grace_period_ms")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.offer_grace_period_ms),
+            block_grace_period_ms: section
+                .get("block_grace_period_ms")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.block_grace_period_ms),
+            keyframe_min_interval_ms: section
+                .get("keyframe_min_interval_ms")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.keyframe_min_interval_ms),
+            remb_relay_enabled: section
+                .get("remb_relay_enabled")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.remb_relay_enabled),
+            nack_buffer_size: section
+                .get("nack_buffer_size")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.nack_buffer_size),
+            active_speaker_interval_ms: section
+                .get("active_speaker_interval_ms")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.active_speaker_interval_ms),
+            subscriber_count_interval_ms: section
+                .get("subscriber_count_interval_ms")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.subscriber_count_interval_ms),
+            active_speaker_threshold: section
+                .get("active_speaker_threshold")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.active_speaker_threshold),
+            audio_codec: section.get("audio_codec").and_then(|x| parse_audio_codec(x)).unwrap_or(defaults.audio_codec),
+            opus_stereo: section.get("opus_stereo").and_then(|x| x.parse().ok()).unwrap_or(defaults.opus_stereo),
+            opus_sprop_stereo: section
+                .get("opus_sprop_stereo")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.opus_sprop_stereo),
+            opus_usedtx: section.get("opus_usedtx").and_then(|x| x.parse().ok()).unwrap_or(defaults.opus_usedtx),
+            video_codec: section.get("video_codec").and_then(|x| parse_video_codec(x)).unwrap_or(defaults.video_codec),
+            publisher_sendrecv: section
+                .get("publisher_sendrecv")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.publisher_sendrecv),
+            sequence_reliable_data: section
+                .get("sequence_reliable_data")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(defaults.sequence_reliable_data),
+            default_subscription,
+            event_channel,
+            webhook_url: section.get("webhook_url").filter(|x| !x.is_empty()).map(String::from),
+            status_http_addr: section.get("status_http_addr").filter(|x| !x.is_empty()).and_then(|x| x.parse().ok()),
+            recording_dir: section.get("recording_dir").filter(|x| !x.is_empty()).map(PathBuf::from),
+            rooms,
         })
     }
 }
+
+/// Parses the number of message processing threads to run, where zero means one per CPU, clamping it to a sane maximum.
+fn parse_message_threads(value: &str) -> Option<usize> {
+    match value.trim().parse::<usize>() {
+        Ok(n) if n > MAX_MESSAGE_THREADS => {
+            janus_warn!("message_threads is set to {}; clamping it to {}.", n, MAX_MESSAGE_THREADS);
+            Some(MAX_MESSAGE_THREADS)
+        }
+        Ok(n) => Some(n),
+        Err(_) => {
+            janus_warn!("Ignoring invalid message_threads value: {}", value);
+            None
+        }
+    }
+}
+
+/// Parses the name of an audio codec that the plugin knows how to negotiate.
+fn parse_audio_codec(name: &str) -> Option<AudioCodec> {
+    match name.to_lowercase().as_str() {
+        "opus" => Some(AudioCodec::Opus),
+        "pcmu" => Some(AudioCodec::Pcmu),
+        "pcma" => Some(AudioCodec::Pcma),
+        "g722" => Some(AudioCodec::G722),
+        _ => None,
+    }
+}
+
+/// Parses the name of a video codec that the plugin knows how to negotiate.
+fn parse_video_codec(name: &str) -> Option<VideoCodec> {
+    match name.to_lowercase().as_str() {
+        "h264" => Some(VideoCodec::H264),
+        "vp8" => Some(VideoCodec::Vp8),
+        "vp9" => Some(VideoCodec::Vp9),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Loads a config from a temporary file containing the given contents.
+    fn load(name: &str, contents: &str) -> Config {
+        let path = env::temp_dir().join(format!("janus-sfu-{}-{}.cfg", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let config = Config::from_path(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn event_channel_disabled_by_default() {
+        let config = load("channel_default", "[general]\nevent_channel_incoming_path = /tmp/foo.sock\n");
+        assert_eq!(config.event_channel, None);
+        let config = load("channel_disabled", "[general]\nevent_channel = false\n");
+        assert_eq!(config.event_channel, None);
+    }
+
+    #[test]
+    fn event_channel_enabled() {
+        let config = load(
+            "channel_enabled",
+            "[general]\nevent_channel = true\nevent_channel_incoming_path = /tmp/foo.sock\n",
+        );
+        let channel_config = config.event_channel.unwrap();
+        assert_eq!(channel_config.incoming_path, PathBuf::from("/tmp/foo.sock"));
+        assert_eq!(channel_config.outgoing_path, ChannelConfig::default().outgoing_path);
+        assert_eq!(channel_config.buffer_size, ChannelConfig::default().buffer_size);
+    }
+
+    #[test]
+    fn blocks_are_kept_for_a_while_by_default() {
+        assert_eq!(load("block_grace_default", "[general]\n").block_grace_period_ms, 30000);
+        let config = load("block_grace_zero", "[general]\nblock_grace_period_ms = 0\n");
+        assert_eq!(config.block_grace_period_ms, 0);
+    }
+
+    #[test]
+    fn reliable_data_is_unsequenced_by_default() {
+        assert!(!load("sequence_default", "[general]\n").sequence_reliable_data);
+        assert!(load("sequence_enabled", "[general]\nsequence_reliable_data = true\n").sequence_reliable_data);
+    }
+
+    #[test]
+    fn joins_have_no_default_subscription_by_default() {
+        assert_eq!(load("default_subscription_default", "[general]\n").default_subscription, None);
+        let config = load("default_subscription_set", "[general]\ndefault_subscription_notifications = true\n");
+        let expected = Subscription {
+            notifications: true,
+            data: false,
+            media: None,
+        };
+        assert_eq!(config.default_subscription, Some(expected));
+    }
+
+    #[test]
+    fn logged_transaction_ids_are_limited_by_default() {
+        assert_eq!(load("txid_default", "[general]\n").max_logged_txid_length, DEFAULT_MAX_DISPLAY_LENGTH);
+        assert_eq!(load("txid_unlimited", "[general]\nmax_logged_txid_length = 0\n").max_logged_txid_length, 0);
+    }
+
+    #[test]
+    fn webhook_disabled_unless_url_given() {
+        assert_eq!(load("webhook_default", "[general]\n").webhook_url, None);
+        assert_eq!(load("webhook_empty", "[general]\nwebhook_url =\n").webhook_url, None);
+        let config = load("webhook_enabled", "[general]\nwebhook_url = http://localhost:8080/events\n");
+        assert_eq!(config.webhook_url.as_deref(), Some("http://localhost:8080/events"));
+    }
+
+    #[test]
+    fn status_server_disabled_unless_address_given() {
+        assert_eq!(load("status_default", "[general]\n").status_http_addr, None);
+        assert_eq!(load("status_invalid", "[general]\nstatus_http_addr = localhost\n").status_http_addr, None);
+        let config = load("status_enabled", "[general]\nstatus_http_addr = 127.0.0.1:8189\n");
+        assert_eq!(config.status_http_addr, Some("127.0.0.1:8189".parse().unwrap()));
+    }
+
+    #[test]
+    fn auth_algorithm_defaults_to_rs512() {
+        let keyfile = env::temp_dir().join(format!("janus-sfu-{}-auth.key", process::id()));
+        fs::write(&keyfile, b"secret").unwrap();
+        let config = load("auth_default", &format!("[general]\nauth_key = {}\n", keyfile.display()));
+        let auth = config.auth.unwrap();
+        assert_eq!(auth.algorithm, Algorithm::RS512);
+        assert_eq!(auth.leeway_secs, 0);
+        let config = load("auth_hs256", &format!("[general]\nauth_key = {}\nauth_algorithm = HS256\n", keyfile.display()));
+        let auth = config.auth.unwrap();
+        assert_eq!(auth.algorithm, Algorithm::HS256);
+        assert_eq!(auth.keys, vec![b"secret".to_vec()]);
+        fs::remove_file(&keyfile).unwrap();
+    }
+
+    #[test]
+    fn multiple_auth_keys_are_loaded() {
+        let old_keyfile = env::temp_dir().join(format!("janus-sfu-{}-old.key", process::id()));
+        let new_keyfile = env::temp_dir().join(format!("janus-sfu-{}-new.key", process::id()));
+        fs::write(&old_keyfile, b"old").unwrap();
+        fs::write(&new_keyfile, b"new").unwrap();
+        let contents = format!("[general]\nauth_key = {}, {}\n", old_keyfile.display(), new_keyfile.display());
+        let auth = load("auth_multiple", &contents).auth.unwrap();
+        assert_eq!(auth.keys, vec![b"old".to_vec(), b"new".to_vec()]);
+        fs::remove_file(&old_keyfile).unwrap();
+        fs::remove_file(&new_keyfile).unwrap();
+    }
+
+    #[test]
+    fn message_threads_are_clamped() {
+        assert_eq!(load("threads_default", "[general]\n").message_threads, 0);
+        assert_eq!(load("threads_auto", "[general]\nmessage_threads = 0\n").message_threads, 0);
+        assert_eq!(load("threads_some", "[general]\nmessage_threads = 8\n").message_threads, 8);
+        assert_eq!(
+            load("threads_many", "[general]\nmessage_threads = 100000\n").message_threads,
+            MAX_MESSAGE_THREADS
+        );
+        assert_eq!(load("threads_negative", "[general]\nmessage_threads = -4\n").message_threads, 0);
+    }
+
+    #[test]
+    fn idle_sessions_are_kept_by_default() {
+        assert_eq!(load("idle_default", "[general]\n").idle_timeout_ms, 0);
+        assert_eq!(load("idle_on", "[general]\nidle_timeout_ms = 60000\n").idle_timeout_ms, 60000);
+    }
+
+    #[test]
+    fn user_ids_are_unprotected_by_default() {
+        assert!(!load("protect_default", "[general]\n").protect_user_ids);
+        assert!(load("protect_on", "[general]\nprotect_user_ids = true\n").protect_user_ids);
+    }
+
+    #[test]
+    fn id_length_is_configurable() {
+        assert_eq!(load("ids_default", "[general]\n").max_id_length, 256);
+        assert_eq!(load("ids_short", "[general]\nmax_id_length = 16\n").max_id_length, 16);
+    }
+
+    #[test]
+    fn message_queues_are_bounded_by_default() {
+        assert_eq!(load("queue_default", "[general]\n").message_queue_depth, 64);
+        assert_eq!(load("queue_some", "[general]\nmessage_queue_depth = 8\n").message_queue_depth, 8);
+        assert_eq!(load("queue_invalid", "[general]\nmessage_queue_depth = lots\n").message_queue_depth, 64);
+    }
+
+    #[test]
+    fn rooms_are_unlimited_by_default() {
+        assert_eq!(load("max_rooms_default", "[general]\n").max_rooms, 0);
+        assert_eq!(load("max_rooms_set", "[general]\nmax_rooms = 500\n").max_rooms, 500);
+    }
+
+    #[test]
+    fn ccu_counts_publishers_by_default() {
+        assert!(!load("ccu_default", "[general]\n").count_subscribers_in_ccu);
+        assert!(load("ccu_subscribers", "[general]\ncount_subscribers_in_ccu = true\n").count_subscribers_in_ccu);
+    }
+
+    #[test]
+    fn subscriber_counts_are_off_by_default() {
+        assert_eq!(load("subscriber_count_default", "[general]\n").subscriber_count_interval_ms, 0);
+        let config = load("subscriber_count_on", "[general]\nsubscriber_count_interval_ms = 1000\n");
+        assert_eq!(config.subscriber_count_interval_ms, 1000);
+    }
+
+    #[test]
+    fn active_speaker_detection_is_off_by_default() {
+        let config = load("speaker_default", "[general]\n");
+        assert_eq!(config.active_speaker_interval_ms, 0);
+        let config = load("speaker_on", "[general]\nactive_speaker_interval_ms = 500\nactive_speaker_threshold = 30.5\n");
+        assert_eq!(config.active_speaker_interval_ms, 500);
+        assert!((config.active_speaker_threshold - 30.5).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn audio_codec_is_configurable() {
+        let codec = |name, contents| load(name, contents).audio_codec.to_cstr();
+        assert_eq!(codec("audio_default", "[general]\n"), AudioCodec::Opus.to_cstr());
+        assert_eq!(codec("audio_pcmu", "[general]\naudio_codec = pcmu\n"), AudioCodec::Pcmu.to_cstr());
+        assert_eq!(codec("audio_pcma", "[general]\naudio_codec = PCMA\n"), AudioCodec::Pcma.to_cstr());
+        assert_eq!(codec("audio_bogus", "[general]\naudio_codec = aac\n"), AudioCodec::Opus.to_cstr());
+    }
+
+    #[test]
+    fn video_codec_is_configurable() {
+        let codec = |name, contents| load(name, contents).video_codec.to_cstr();
+        assert_eq!(codec("video_default", "[general]\n"), VideoCodec::H264.to_cstr());
+        assert_eq!(codec("video_vp8", "[general]\nvideo_codec = vp8\n"), VideoCodec::Vp8.to_cstr());
+        assert_eq!(codec("video_vp9", "[general]\nvideo_codec = VP9\n"), VideoCodec::Vp9.to_cstr());
+        assert_eq!(codec("video_bogus", "[general]\nvideo_codec = av1\n"), VideoCodec::H264.to_cstr());
+    }
+
+    #[test]
+    fn rooms_may_override_settings() {
+        let contents = "[general]\nmax_room_size = 10\n[room.premium]\nmax_room_size = 50\nvideo_codec = vp8\npublisher_sendrecv = true\n";
+        let config = load("room_overrides", contents);
+        let premium = config.for_room(&"premium".into());
+        assert_eq!(premium.max_room_size, 50);
+        assert_eq!(premium.video_codec.to_cstr(), VideoCodec::Vp8.to_cstr());
+        assert_eq!(premium.audio_codec.to_cstr(), AudioCodec::Opus.to_cstr());
+        assert!(premium.publisher_sendrecv);
+        let free = config.for_room(&"free".into());
+        assert_eq!(free.max_room_size, 10);
+        assert_eq!(free.video_codec.to_cstr(), VideoCodec::H264.to_cstr());
+        assert!(!free.publisher_sendrecv);
+    }
+
+    #[test]
+    fn revoked_tokens_are_loaded() {
+        let path = env::temp_dir().join(format!("janus-sfu-{}-revoked.txt", process::id()));
+        fs::write(&path, "abc\n\n  def  \n").unwrap();
+        let config = load("revoked", &format!("[general]\nrevoked_tokens = {}\n", path.display()));
+        let expected = vec!["abc".to_owned(), "def".to_owned()].into_iter().collect::<HashSet<_>>();
+        assert_eq!(config.revoked_token_ids, expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parse_limit_scopes() {
+        assert_eq!("publishers".parse::<LimitScope>().unwrap(), LimitScope::Publishers);
+        assert_eq!("subscribers".parse::<LimitScope>().unwrap(), LimitScope::Subscribers);
+        assert_eq!("everyone".parse::<LimitScope>().unwrap(), LimitScope::Everyone);
+        assert_eq!("nobody".parse::<LimitScope>().unwrap(), LimitScope::Nobody);
+        assert!("listeners".parse::<LimitScope>().is_err());
+    }
+
+    #[test]
+    fn limit_scopes_apply_to_kinds() {
+        assert!(LimitScope::Publishers.applies_to(JoinKind::Publisher));
+        assert!(!LimitScope::Publishers.applies_to(JoinKind::Subscriber));
+        assert!(!LimitScope::Subscribers.applies_to(JoinKind::Publisher));
+        assert!(LimitScope::Subscribers.applies_to(JoinKind::Subscriber));
+        assert!(LimitScope::Everyone.applies_to(JoinKind::Publisher));
+        assert!(LimitScope::Everyone.applies_to(JoinKind::Subscriber));
+        assert!(!LimitScope::Nobody.applies_to(JoinKind::Publisher));
+        assert!(!LimitScope::Nobody.applies_to(JoinKind::Subscriber));
+    }
+}
@@ -0,0 +1,172 @@
+/// The ways that processing a client's message can fail.
+use crate::auth::AuthError;
+use std::error::Error;
+use std::ffi::NulError;
+use std::fmt;
+use std::sync::PoisonError;
+
+/// A reason that a client's message couldn't be processed. Clients get a machine-readable code for each kind, alongside
+/// a human-readable message, so that they can tell what went wrong without parsing the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SfuError {
+    /// The client's token doesn't permit what it asked for.
+    Unauthorized(AuthError),
+    /// The room can't take any more users.
+    RoomFull,
+    /// The server can't take any more users.
+    ServerFull,
+    /// The server can't take any more rooms.
+    RoomLimit,
+    /// A publisher or room can't take any more subscribers.
+    SubscriberLimit(&'static str),
+    /// The client needs to join a room first.
+    NotJoined(&'static str),
+    /// The client's handle is already in a room.
+    AlreadyJoined(&'static str),
+    /// Another connection is already publishing as the user.
+    AlreadyPublishing,
+    /// The client has already subscribed.
+    AlreadySubscribed(&'static str),
+    /// The client isn't subscribed to the media it's asking about.
+    NotSubscribed(&'static str),
+    /// The room has a roster, and the user isn't on it.
+    NotOnRoster,
+    /// The room is locked, so nobody new may publish in it.
+    RoomLocked,
+    /// The client is in a waiting room, and can't do this until it's admitted.
+    Waiting(&'static str),
+    /// The user in question isn't publishing in the room.
+    PublisherNotFound(&'static str),
+    /// The publisher hasn't finished negotiating its media yet, so the client should try again later.
+    PublisherNotReady,
+    /// The user in question isn't in the room's waiting room.
+    NotWaiting,
+    /// The client is doing this too often.
+    RateLimited,
+    /// The client's data message is bigger than the configured limit, in bytes.
+    TooLarge(usize),
+    /// The publisher's offer doesn't include the codec that the room requires.
+    CodecMismatch(String),
+    /// The server isn't configured to allow this.
+    NotConfigured(&'static str),
+    /// The message itself doesn't make sense.
+    Invalid(String),
+    /// Something went wrong on our end.
+    Internal(String),
+}
+
+impl SfuError {
+    /// The machine-readable code identifying this kind of error. Authorization failures use the code of their
+    /// underlying `AuthError`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SfuError::Unauthorized(e) => e.code(),
+            SfuError::RoomFull => "room_full",
+            SfuError::ServerFull => "server_full",
+            SfuError::RoomLimit => "room_limit",
+            SfuError::SubscriberLimit(_) => "subscriber_limit",
+            SfuError::NotJoined(_) => "not_joined",
+            SfuError::AlreadyJoined(_) => "already_joined",
+            SfuError::AlreadyPublishing => "already_publishing",
+            SfuError::AlreadySubscribed(_) => "already_subscribed",
+            SfuError::NotSubscribed(_) => "not_subscribed",
+            SfuError::NotOnRoster => "not_on_roster",
+            SfuError::RoomLocked => "room_locked",
+            SfuError::Waiting(_) => "waiting",
+            SfuError::PublisherNotFound(_) => "publisher_not_found",
+            SfuError::PublisherNotReady => "publisher_not_ready",
+            SfuError::NotWaiting => "not_waiting",
+            SfuError::RateLimited => "rate_limited",
+            SfuError::TooLarge(_) => "too_large",
+            SfuError::CodecMismatch(_) => "codec_mismatch",
+            SfuError::NotConfigured(_) => "not_configured",
+            SfuError::Invalid(_) => "invalid",
+            SfuError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for SfuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SfuError::Unauthorized(e) => fmt::Display::fmt(e, f),
+            SfuError::RoomFull => f.write_str("Room is full."),
+            SfuError::ServerFull => f.write_str("Server is full."),
+            SfuError::RoomLimit => f.write_str("Server room limit reached."),
+            SfuError::AlreadyPublishing => f.write_str("User is already publishing."),
+            SfuError::NotOnRoster => f.write_str("User is not on this room's roster."),
+            SfuError::RoomLocked => f.write_str("Room is locked."),
+            SfuError::PublisherNotReady => f.write_str("Publisher not ready; retry."),
+            SfuError::NotWaiting => f.write_str("That user isn't waiting to join this room."),
+            SfuError::RateLimited => f.write_str("Subscription changes are too frequent; try again later."),
+            SfuError::TooLarge(limit) => write!(f, "Data message is too large; the limit is {} bytes.", limit),
+            SfuError::SubscriberLimit(msg)
+            | SfuError::NotJoined(msg)
+            | SfuError::AlreadyJoined(msg)
+            | SfuError::AlreadySubscribed(msg)
+            | SfuError::NotSubscribed(msg)
+            | SfuError::Waiting(msg)
+            | SfuError::PublisherNotFound(msg)
+            | SfuError::NotConfigured(msg) => f.write_str(msg),
+            SfuError::CodecMismatch(msg) | SfuError::Invalid(msg) | SfuError::Internal(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Error for SfuError {}
+
+impl From<AuthError> for SfuError {
+    fn from(err: AuthError) -> Self {
+        SfuError::Unauthorized(err)
+    }
+}
+
+impl From<&str> for SfuError {
+    fn from(msg: &str) -> Self {
+        SfuError::Invalid(msg.to_owned())
+    }
+}
+
+impl From<String> for SfuError {
+    fn from(msg: String) -> Self {
+        SfuError::Invalid(msg)
+    }
+}
+
+impl<T> From<PoisonError<T>> for SfuError {
+    fn from(err: PoisonError<T>) -> Self {
+        SfuError::Internal(err.to_string())
+    }
+}
+
+impl From<NulError> for SfuError {
+    fn from(err: NulError) -> Self {
+        SfuError::Internal(err.to_string())
+    }
+}
+
+impl From<Box<dyn Error>> for SfuError {
+    fn from(err: Box<dyn Error>) -> Self {
+        SfuError::Internal(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn auth_errors_keep_their_code() {
+        let err = SfuError::from(AuthError::WrongRoom("wrong room!"));
+        assert_eq!(err.code(), "wrong_room");
+        assert_eq!(err.to_string(), "wrong room!");
+    }
+
+    #[test]
+    fn messages_are_invalid_requests() {
+        let err = SfuError::from("Data messages must have a body or body_bytes.");
+        assert_eq!(err, SfuError::Invalid("Data messages must have a body or body_bytes.".into()));
+        assert_eq!(err.code(), "invalid");
+    }
+}
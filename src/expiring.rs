@@ -0,0 +1,70 @@
+/// A map whose entries are forgotten after a deadline.
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+/// Stores values until their individual deadlines pass. Expired entries are swept out whenever the map is touched.
+#[derive(Debug)]
+pub struct ExpiringMap<K: Eq + Hash, V> {
+    entries: HashMap<K, (V, Instant)>,
+}
+
+impl<K: Eq + Hash, V> ExpiringMap<K, V> {
+    pub fn new() -> Self {
+        Self { entries: HashMap::new() }
+    }
+
+    /// Stores the given value until the given deadline, replacing any value already stored for the key.
+    pub fn insert(&mut self, k: K, v: V, expires_at: Instant, now: Instant) {
+        self.purge(now);
+        self.entries.insert(k, (v, expires_at));
+    }
+
+    /// Removes and returns the value stored for the given key, if it hasn't expired.
+    pub fn take<T>(&mut self, k: &T, now: Instant) -> Option<V>
+    where
+        K: Borrow<T>,
+        T: Hash + Eq + ?Sized,
+    {
+        self.purge(now);
+        self.entries.remove(k).map(|(v, _)| v)
+    }
+
+    /// Forgets every value whose deadline has passed.
+    pub fn purge(&mut self, now: Instant) {
+        self.entries.retain(|_, (_, expires_at)| *expires_at > now);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn values_are_retained_until_taken() {
+        let now = Instant::now();
+        let mut map = ExpiringMap::new();
+        map.insert("10".to_owned(), 1, now + Duration::from_secs(5), now);
+        assert_eq!(map.take("10", now + Duration::from_secs(1)), Some(1));
+        assert_eq!(map.take("10", now + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn values_expire_after_deadline() {
+        let now = Instant::now();
+        let mut map = ExpiringMap::new();
+        map.insert("10".to_owned(), 1, now + Duration::from_secs(5), now);
+        map.insert("11".to_owned(), 2, now + Duration::from_secs(1), now);
+        map.purge(now + Duration::from_secs(2));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.take("10", now + Duration::from_secs(5)), None);
+    }
+}
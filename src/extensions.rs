@@ -0,0 +1,46 @@
+//! Tools for reading the RTP header extensions which publishers use to describe their media.
+
+/// Returns the ID which the given SDP assigns to the header extension with the given URI, if any.
+pub fn extension_id(sdp: &str, uri: &str) -> Option<u8> {
+    sdp.lines().find_map(|line| {
+        let mut parts = line.trim().strip_prefix("a=extmap:")?.split_whitespace();
+        let id = parts.next()?.split('/').next()?.parse().ok()?;
+        match parts.next() {
+            Some(u) if u == uri => Some(id),
+            _ => None,
+        }
+    })
+}
+
+/// Returns the data of the given RTP packet's one-byte header extension with the given ID, if it has one.
+pub fn find_extension(packet: &[u8], extension_id: u8) -> Option<&[u8]> {
+    let first = *packet.first()?;
+    let has_extension = first & 0x10 != 0;
+    if !has_extension {
+        return None;
+    }
+    let csrc_count = (first & 0x0F) as usize;
+    let start = 12 + 4 * csrc_count;
+    let header = packet.get(start..start + 4)?;
+    if header[0..2] != [0xBE, 0xDE] {
+        return None; // not a one-byte header extension
+    }
+    let length = 4 * u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut elements = packet.get(start + 4..start + 4 + length)?;
+    while let Some((&element, rest)) = elements.split_first() {
+        let id = element >> 4;
+        match id {
+            0 => elements = rest, // padding
+            15 => return None,
+            _ => {
+                let len = (element & 0x0F) as usize + 1;
+                let data = rest.get(..len)?;
+                if id == extension_id {
+                    return Some(data);
+                }
+                elements = &rest[len..];
+            }
+        }
+    }
+    None
+}
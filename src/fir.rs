@@ -0,0 +1,46 @@
+/// Tools for writing FIR (full intra request) RTCP feedback, which is how we ask publishers for a keyframe.
+use crate::remb::PSFB;
+
+/// The feedback message type of a FIR.
+const FIR: u8 = 4;
+
+/// The length of a FIR asking a single source for a keyframe.
+pub const FIR_LEN: usize = 20;
+
+/// Builds a FIR with the sequence number following the given one, the same way Janus's `janus_rtcp_fir` does. The SSRCs
+/// are left blank, since Janus fills them in when relaying it to a peer. It's built on the stack, since we send one
+/// for every keyframe request that a subscriber makes.
+pub fn gen_fir(seq: isize) -> [u8; FIR_LEN] {
+    // Janus takes the sequence number as a C int, and wraps it into a single byte after incrementing it
+    let next = (seq as i32).wrapping_add(1);
+    let next = if (0..256).contains(&next) { next as u8 } else { 0 };
+    let mut packet = [0; FIR_LEN];
+    packet[..4].copy_from_slice(&[0x80 | FIR, PSFB, 0, (FIR_LEN / 4 - 1) as u8]);
+    // the sender and media source SSRCs, and the SSRC in the request, are all blank
+    packet[16] = next;
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn firs_match_janus() {
+        let expected = [0x84, 0xCE, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        assert_eq!(gen_fir(0), expected);
+        assert_eq!(gen_fir(41)[16], 42);
+        assert_eq!(gen_fir(41)[..16], expected[..16]);
+    }
+
+    #[test]
+    fn fir_sequence_numbers_wrap_around() {
+        assert_eq!(gen_fir(254)[16], 255);
+        assert_eq!(gen_fir(255)[16], 0);
+        assert_eq!(gen_fir(300)[16], 0);
+        assert_eq!(gen_fir(-5)[16], 0);
+        assert_eq!(gen_fir(-1)[16], 0);
+        assert_eq!(gen_fir(isize::MAX)[16], 0);
+    }
+}
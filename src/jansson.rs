@@ -0,0 +1,95 @@
+/// Conversion from serde JSON values to the Jansson values which Janus deals in.
+use janus_plugin::ffi::jansson as ffi;
+use janus_plugin::{JanssonDecodingFlags, JanssonValue, RawJanssonValue};
+use serde_json::Value as JsonValue;
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+/// Converts a serde JSON value to a Jansson JSON value. Every signalling message goes through here, so this builds
+/// the Jansson value directly, rather than printing the value out and having Jansson parse it back in.
+pub fn serde_to_jansson(input: &JsonValue) -> JanssonValue {
+    build(input).unwrap_or_else(|| reparse(input))
+}
+
+/// Inefficiently converts a serde JSON value to a Jansson JSON value, by way of a string.
+fn reparse(input: &JsonValue) -> JanssonValue {
+    JanssonValue::from_str(&input.to_string(), JanssonDecodingFlags::empty()).unwrap()
+}
+
+/// Builds the Jansson equivalent of the given value, or returns None if it contains something that can't be built
+/// directly, i.e. an integer too big for Jansson or an object key containing a NUL.
+fn build(input: &JsonValue) -> Option<JanssonValue> {
+    unsafe {
+        match input {
+            JsonValue::Null => JanssonValue::from_raw(ffi::json_null()),
+            JsonValue::Bool(true) => JanssonValue::from_raw(ffi::json_true()),
+            JsonValue::Bool(false) => JanssonValue::from_raw(ffi::json_false()),
+            JsonValue::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    JanssonValue::from_raw(ffi::json_integer(i as ffi::json_int_t))
+                } else if n.is_u64() {
+                    None
+                } else {
+                    JanssonValue::from_raw(ffi::json_real(n.as_f64()?))
+                }
+            }
+            JsonValue::String(s) => JanssonValue::from_raw(ffi::json_stringn(s.as_ptr() as *const c_char, s.len())),
+            JsonValue::Array(items) => {
+                let mut array = JanssonValue::from_raw(ffi::json_array())?;
+                for item in items {
+                    // appending takes ownership of the item, even if it fails
+                    let item = build(item)?;
+                    if ffi::json_array_append_new(array.as_mut_ref() as *mut RawJanssonValue, item.into_raw()) != 0 {
+                        return None;
+                    }
+                }
+                Some(array)
+            }
+            JsonValue::Object(fields) => {
+                let mut object = JanssonValue::from_raw(ffi::json_object())?;
+                for (key, val) in fields {
+                    let key = CString::new(key.as_str()).ok()?;
+                    let val = build(val)?;
+                    if ffi::json_object_set_new(object.as_mut_ref() as *mut RawJanssonValue, key.as_ptr(), val.into_raw()) != 0 {
+                        return None;
+                    }
+                }
+                Some(object)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use janus_plugin::JanssonEncodingFlags;
+    use serde_json::json;
+
+    fn encode(value: &JanssonValue) -> JsonValue {
+        let text = value.to_libcstring(JanssonEncodingFlags::empty());
+        serde_json::from_str(&text.to_string_lossy()).unwrap()
+    }
+
+    #[test]
+    fn built_values_match_reparsed_values() {
+        let events = [
+            json!({ "event": "join", "user_id": "alice", "room_id": "lobby" }),
+            json!({ "event": "leave", "user_id": "alice", "room_id": "lobby" }),
+            json!({ "event": "data", "body": "a string body \u{1F600} with \"quotes\"" }),
+            json!({ "event": "data", "body": { "x": [1, -2.5, true, null, { "y": [] }], "z": 9_007_199_254_740_993i64 } }),
+        ];
+        for event in &events {
+            let built = build(event).unwrap();
+            assert_eq!(encode(&built), *event);
+            assert_eq!(encode(&built), encode(&reparse(event)));
+        }
+    }
+
+    #[test]
+    fn values_which_cant_be_built_are_left_to_jansson() {
+        assert!(build(&json!({ "n": u64::MAX })).is_none());
+        assert!(build(&json!({ "a\u{0}b": 1 })).is_none());
+    }
+}
@@ -1,39 +1,88 @@
 mod auth;
+mod bucket;
+mod channel;
 mod config;
+mod errors;
+mod expiring;
+mod extensions;
+mod fir;
+mod jansson;
+mod locks;
 mod messages;
+mod metrics;
+mod nack;
+mod offers;
+mod recorder;
+mod remb;
+mod rooms;
+mod routes;
+mod sequencing;
 mod sessions;
+mod simulcast;
+mod speakers;
+mod status;
 mod switchboard;
+mod switching;
 mod txid;
+mod webhook;
+mod workers;
 
-use auth::ValidatedToken;
-use config::Config;
-use janus_plugin::rtcp::{gen_fir, has_fir, has_pli};
+use auth::{AuthConfig, AuthError, TokenError, ValidatedToken};
+use bucket::TokenBucket;
+use channel::{Channel, DatagramKind, Topic};
+use config::{Config, MAX_MESSAGE_THREADS};
+use errors::SfuError;
+use fir::gen_fir;
+use jansson::serde_to_jansson;
+use janus_plugin::rtcp::{has_fir, has_pli};
 use janus_plugin::sdp::{AudioCodec, MediaDirection, OfferAnswerParameters, Sdp, VideoCodec};
 use janus_plugin::utils::LibcString;
 use janus_plugin::{
-    answer_sdp, build_plugin, export_plugin, janus_err, janus_huge, janus_info, janus_verb, janus_warn, offer_sdp, JanssonDecodingFlags, JanssonEncodingFlags,
-    JanssonValue, JanusError, JanusResult, LibraryMetadata, Plugin, PluginCallbacks, PluginDataPacket, PluginResult, PluginRtcpPacket, PluginRtpPacket,
-    PluginSession, RawJanssonValue, RawPluginResult,
+    answer_sdp, build_plugin, export_plugin, janus_err, janus_huge, janus_info, janus_verb, janus_warn, offer_sdp, JanssonEncodingFlags, JanssonValue,
+    JanusError, JanusResult, LibraryMetadata, Plugin, PluginCallbacks, PluginDataPacket, PluginResult, PluginRtcpPacket, PluginRtpPacket, PluginSession,
+    RawJanssonValue, RawPluginResult,
 };
-use messages::{JsepKind, MessageKind, OptionalField, Subscription};
-use messages::{RoomId, UserId};
+use locks::{read_or_recover, write_or_recover};
+use messages::{validate_id, RoomId, UserId};
+use messages::{AdminCommand, Binary, JsepKind, MediaKind, MessageKind, OptionalField, Subscription};
+use metrics::{bump, Gauges, COUNTERS};
+use nack::{nacked_sequence_numbers, RtpBuffer};
+use offers::{codecs, combine, deactivate_sdp, has_media, renegotiate_sdp, sdp_text, MediaKinds};
 use once_cell::sync::{Lazy, OnceCell};
+use recorder::{now_micros, PublisherRecording};
+use remb::{gen_remb, lowest_estimate, remb_bitrate};
+use routes::RouteTable;
+use sequencing::{DataSequencer, RELIABLE_LABEL};
 use serde::de::DeserializeOwned;
 use serde_json::json;
 use serde_json::Value as JsonValue;
-use sessions::{JoinKind, JoinState, Session, SessionState};
-use std::collections::HashSet;
+use sessions::{CachedClaims, JoinCell, JoinKind, JoinState, KeyframeThrottle, LastSeen, MediaMutes, NegotiatedCodecs, Session, SessionState, TrafficCounters};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use simulcast::{Layer, LayerChoice, SimulcastLayers};
+use speakers::{audio_level_extension_id, dominant_speaker, AudioLevel};
+use status::StatusServer;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::ffi::{CStr, CString};
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::mem;
 use std::os::raw::{c_char, c_int};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::slice;
-use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex, RwLock, Weak};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use switchboard::Switchboard;
+use switching::SwitchingContext;
 use txid::TransactionId;
+use webhook::{Backoff, Webhook, WebhookEvent};
+use workers::WorkerPool;
 
 // courtesy of c_string crate, which also has some other stuff we aren't interested in
 // taking in as a dependency here.
@@ -62,16 +111,12 @@ struct RawMessage {
     pub jsep: Option<JanssonValue>,
 }
 
-/// Inefficiently converts a serde JSON value to a Jansson JSON value.
-fn serde_to_jansson(input: &JsonValue) -> JanssonValue {
-    JanssonValue::from_str(&input.to_string(), JanssonDecodingFlags::empty()).unwrap()
-}
-
 fn jansson_to_str(json: &JanssonValue) -> Result<LibcString, Box<dyn Error>> {
     Ok(json.to_libcstring(JanssonEncodingFlags::empty()))
 }
 
 /// A response to a signalling message. May carry either a response body, a JSEP, or both.
+#[derive(Debug)]
 struct MessageResponse {
     pub body: Option<JsonValue>,
     pub jsep: Option<JsonValue>, // todo: make this an Option<JsepKind>?
@@ -90,17 +135,10 @@ impl MessageResponse {
 }
 
 /// A result which carries a signalling message response to send to a client.
-type MessageResult = Result<MessageResponse, Box<dyn Error>>;
+type MessageResult = Result<MessageResponse, SfuError>;
 
 /// A result which carries a JSEP to send to a client.
-type JsepResult = Result<JsonValue, Box<dyn Error>>;
-
-/// The audio codec Janus will negotiate with all participants. Opus is cross-compatible with everything we care about.
-static AUDIO_CODEC: AudioCodec = AudioCodec::Opus;
-
-/// The video codec Janus will negotiate with all participants. H.264 is cross-compatible with modern Firefox, Chrome,
-/// Safari, and Edge; VP8/9 unfortunately isn't compatible with Safari.
-static VIDEO_CODEC: VideoCodec = VideoCodec::H264;
+type JsepResult = Result<JsonValue, SfuError>;
 
 /// Function pointers to the Janus core functionality made available to our plugin.
 static mut CALLBACKS: Option<&PluginCallbacks> = None;
@@ -114,58 +152,136 @@ fn gateway_callbacks() -> &'static PluginCallbacks {
 /// (people in the same room who are supposed to hear the audio.)
 static SWITCHBOARD: Lazy<RwLock<Switchboard>> = Lazy::new(|| RwLock::new(Switchboard::new()));
 
-/// The producer/consumer queue storing incoming plugin messages to be processed.
-static MESSAGE_SENDERS: OnceCell<Vec<mpsc::SyncSender<RawMessage>>> = OnceCell::new();
+/// Who should receive each publisher's media, by room, as kept up to date by the switchboard. Relaying media only
+/// needs to lock the sender's room in here, rather than the whole switchboard.
+static MEDIA_ROUTES: Lazy<Arc<RouteTable>> = Lazy::new(|| {
+    let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
+    Arc::clone(switchboard.routes())
+});
+
+/// The threads processing incoming plugin messages, and their queues.
+static MESSAGE_THREADS: OnceCell<WorkerPool<RawMessage>> = OnceCell::new();
+
+/// Counts the number of signalling message handlers which exceeded the configured slow message threshold.
+static SLOW_MESSAGE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The channel for exchanging events with another local process, if it's enabled.
+static EVENT_CHANNEL: OnceCell<Channel> = OnceCell::new();
 
-/// Counts the number of messages handled. Used for round-robin dispatching to handler threads.
-static MESSAGE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+/// The webhook which is told about significant room events, if it's enabled.
+static WEBHOOK: OnceCell<Webhook> = OnceCell::new();
 
-/// The plugin configuration, read from disk.
-static CONFIG: OnceCell<Config> = OnceCell::new();
+/// The HTTP server which reports on the plugin's health, if it's enabled.
+static STATUS_SERVER: OnceCell<StatusServer> = OnceCell::new();
+
+/// The plugin configuration, read from disk. It can be reloaded while running, so don't hold onto it for long.
+static CONFIG: Lazy<RwLock<Arc<Config>>> = Lazy::new(|| RwLock::new(Arc::new(Config::default())));
+
+/// The IDs of tokens which may no longer be used, whether revoked in the config or at runtime.
+static REVOKED_TOKEN_IDS: Lazy<RwLock<HashSet<String>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+
+/// How many sessions something was sent to successfully, and how many it couldn't be sent to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Delivery {
+    pub reached: usize,
+    pub failed: usize,
+}
 
 // todo: clean up duplication here
 
-fn send_data_user<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, target: &UserId, everyone: T) {
-    let receivers = everyone.into_iter().filter(|s| {
-        let subscription_state = s.as_ref().subscription.get();
-        let join_state = s.as_ref().join_state.get();
-        match (subscription_state, join_state) {
-            (Some(subscription), Some(joined)) => subscription.data && &joined.user_id == target,
-            _ => false,
-        }
-    });
+/// Whether the given session should get data addressed directly to the given user. Since it's explicitly addressed,
+/// the user's publisher session gets it whatever it's subscribed to, and their other sessions get it if they're
+/// subscribed to data.
+fn receives_whisper(session: &Session, target: &UserId) -> bool {
+    let subscription_state = session.subscription.lock().unwrap();
+    match (session.join_state.get(), &*subscription_state) {
+        (Some(joined), _) if &joined.user_id != target => false,
+        (Some(joined), _) if joined.kind == JoinKind::Publisher => true,
+        (Some(_), Some(subscription)) => subscription.data,
+        _ => false,
+    }
+}
+
+/// Whether the given session should get data which the given user sent to everyone in their room.
+fn receives_broadcast(session: &Session, myself: &UserId) -> bool {
+    let subscription_state = session.subscription.lock().unwrap();
+    match (&*subscription_state, session.join_state.get()) {
+        (Some(subscription), Some(joined)) => subscription.data && &joined.user_id != myself,
+        _ => false,
+    }
+}
+
+fn send_data_user<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, target: &UserId, everyone: T) -> Result<Delivery, SfuError> {
+    let receivers = everyone.into_iter().filter(|s| receives_whisper(s.as_ref(), target)).collect::<Vec<_>>();
+    if receivers.is_empty() {
+        return Err(From::from("No session in this room is receiving data for that user."));
+    }
+    Ok(send_message(json, receivers))
+}
+
+fn send_data_except<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, myself: &UserId, everyone: T) -> Delivery {
+    let receivers = everyone.into_iter().filter(|s| receives_broadcast(s.as_ref(), myself));
     send_message(json, receivers)
 }
 
-fn send_data_except<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, myself: &UserId, everyone: T) {
-    let receivers = everyone.into_iter().filter(|s| {
-        let subscription_state = s.as_ref().subscription.get();
+fn notify_user<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, target: &UserId, everyone: T) -> Delivery {
+    let notifiees = everyone.into_iter().filter(|s| {
+        let subscription_state = s.as_ref().subscription.lock().unwrap();
         let join_state = s.as_ref().join_state.get();
-        match (subscription_state, join_state) {
-            (Some(subscription), Some(joined)) => subscription.data && &joined.user_id != myself,
+        match (&*subscription_state, join_state) {
+            (Some(subscription), Some(joined)) => subscription.notifications && &joined.user_id == target,
             _ => false,
         }
     });
-    send_message(json, receivers)
+    send_message(json, notifiees)
 }
 
-fn notify_user<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, target: &UserId, everyone: T) {
+fn notify_everyone<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, everyone: T) -> Delivery {
     let notifiees = everyone.into_iter().filter(|s| {
-        let subscription_state = s.as_ref().subscription.get();
-        let join_state = s.as_ref().join_state.get();
-        match (subscription_state, join_state) {
-            (Some(subscription), Some(joined)) => subscription.notifications && &joined.user_id == target,
-            _ => false,
+        let subscription_state = s.as_ref().subscription.lock().unwrap();
+        match &*subscription_state {
+            Some(subscription) => subscription.notifications,
+            None => false,
         }
     });
     send_message(json, notifiees)
 }
 
-fn notify_except<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, myself: &UserId, everyone: T) {
+/// An event which some sessions should get. Functions which change rooms return these rather than sending them, so
+/// that callers decide when they go out.
+#[derive(Debug)]
+struct Notification {
+    event: JsonValue,
+    recipients: Vec<Arc<Session>>,
+}
+
+/// Sends each of the given notifications to its recipients.
+fn send_notifications<T: IntoIterator<Item = Notification>>(notifications: T) {
+    for notification in notifications {
+        send_message(&notification.event, &notification.recipients);
+    }
+}
+
+/// Posts the given room being created or destroyed to the webhook, returning the notification that everyone
+/// subscribed to notifications, in any room, should get about it.
+fn room_event(switchboard: &Switchboard, event: &'static str, room_id: &RoomId) -> Notification {
+    let notifiees = switchboard.connected_sessions().filter(|s| match &*s.subscription.lock().unwrap() {
+        Some(subscription) => subscription.notifications,
+        None => false,
+    });
+    let notification = Notification {
+        event: json!({ "event": event, "room_id": room_id }),
+        recipients: notifiees.cloned().collect(),
+    };
+    send_webhook_event(event, None, room_id);
+    notification
+}
+
+fn notify_except<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue, myself: &UserId, everyone: T) -> Delivery {
     let notifiees = everyone.into_iter().filter(|s| {
-        let subscription_state = s.as_ref().subscription.get();
+        let subscription_state = s.as_ref().subscription.lock().unwrap();
         let join_state = s.as_ref().join_state.get();
-        match (subscription_state, join_state) {
+        match (&*subscription_state, join_state) {
             (Some(subscription), Some(joined)) => subscription.notifications && &joined.user_id != myself,
             _ => false,
         }
@@ -173,11 +289,33 @@ fn notify_except<T: IntoIterator<Item = U>, U: AsRef<Session>>(json: &JsonValue,
     send_message(json, notifiees)
 }
 
-fn send_message<T: IntoIterator<Item = U>, U: AsRef<Session>>(body: &JsonValue, sessions: T) {
+/// Pushes something to each of the given sessions which hasn't been destroyed, tallying up the results.
+fn deliver<T, U, F>(sessions: T, mut push: F) -> Delivery
+where
+    T: IntoIterator<Item = U>,
+    U: AsRef<Session>,
+    F: FnMut(&Session) -> JanusResult,
+{
+    let mut delivery = Delivery::default();
+    for session in sessions {
+        let session = session.as_ref();
+        if session.destroyed.load(Ordering::Relaxed) {
+            janus_verb!("Not sending signalling message to destroyed session {:p}.", session.handle);
+            delivery.failed += 1;
+        } else if push(session).is_ok() {
+            delivery.reached += 1;
+        } else {
+            delivery.failed += 1;
+        }
+    }
+    delivery
+}
+
+fn send_message<T: IntoIterator<Item = U>, U: AsRef<Session>>(body: &JsonValue, sessions: T) -> Delivery {
     let mut msg = serde_to_jansson(body);
     let push_event = gateway_callbacks().push_event;
-    for session in sessions {
-        let handle = session.as_ref().handle;
+    deliver(sessions, |session| {
+        let handle = session.handle;
         janus_huge!("Signalling message going to {:p}: {}.", handle, body);
         let result = JanusError::from(push_event(
             handle,
@@ -192,17 +330,18 @@ fn send_message<T: IntoIterator<Item = U>, U: AsRef<Session>>(body: &JsonValue,
                 // session not found -- should be unusual but not problematic
                 janus_warn!("Attempted to send signalling message to missing session {:p}: {}", handle, body);
             }
-            Err(e) => janus_err!("Error sending signalling message to {:p}: {}", handle, e),
+            Err(ref e) => janus_err!("Error sending signalling message to {:p}: {}", handle, e),
         }
-    }
+        result
+    })
 }
 
-fn send_offer<T: IntoIterator<Item = U>, U: AsRef<Session>>(offer: &JsonValue, sessions: T) {
+fn send_offer<T: IntoIterator<Item = U>, U: AsRef<Session>>(offer: &JsonValue, sessions: T) -> Delivery {
     let mut msg = serde_to_jansson(&json!({}));
     let mut jsep = serde_to_jansson(offer);
     let push_event = gateway_callbacks().push_event;
-    for session in sessions {
-        let handle = session.as_ref().handle;
+    deliver(sessions, |session| {
+        let handle = session.handle;
         janus_huge!("Offer going to {:p}: {}.", handle, offer);
         let result = JanusError::from(push_event(
             handle,
@@ -217,33 +356,218 @@ fn send_offer<T: IntoIterator<Item = U>, U: AsRef<Session>>(offer: &JsonValue, s
                 // session not found -- should be unusual but not problematic
                 janus_warn!("Attempted to send signalling message to missing session {:p}: {}", handle, offer);
             }
-            Err(e) => janus_err!("Error sending signalling message to {:p}: {}", handle, e),
+            Err(ref e) => janus_err!("Error sending signalling message to {:p}: {}", handle, e),
         }
-    }
+        result
+    })
+}
+
+fn send_remb(publisher: &Session, bitrate: u32) {
+    let relay_rtcp = gateway_callbacks().relay_rtcp;
+    let mut remb = gen_remb(bitrate);
+    let mut packet = PluginRtcpPacket {
+        video: 1,
+        buffer: remb.as_mut_ptr(),
+        length: remb.len() as i16,
+    };
+    relay_rtcp(publisher.as_ptr(), &mut packet);
+}
+
+/// Sends the given subscriber the given video packet again, as it saw it the first time.
+fn retransmit_rtp(subscriber: &Session, mut data: Vec<u8>) {
+    let relay_rtp = gateway_callbacks().relay_rtp;
+    // we don't know the header extensions of a retransmitted packet, so we leave them blank
+    let mut packet: PluginRtpPacket = unsafe { mem::zeroed() };
+    packet.video = 1;
+    packet.buffer = data.as_mut_ptr() as *mut _;
+    packet.length = data.len() as _;
+    subscriber.switching.relay(&mut data, true, || relay_rtp(subscriber.as_ptr(), &mut packet));
+    subscriber.traffic.sent(data.len() as u64);
+    bump(&COUNTERS.rtp_retransmitted, 1);
 }
 
 fn send_fir<T: IntoIterator<Item = U>, U: AsRef<Session>>(publishers: T) {
     let relay_rtcp = gateway_callbacks().relay_rtcp;
     for publisher in publishers {
-        let mut seq = publisher.as_ref().fir_seq.fetch_add(1, Ordering::Relaxed) as i32;
-        let mut fir = gen_fir(&mut seq);
+        let mut fir = gen_fir(publisher.as_ref().fir_seq.fetch_add(1, Ordering::Relaxed));
         let mut packet = PluginRtcpPacket {
             video: 1,
             buffer: fir.as_mut_ptr(),
             length: fir.len() as i16,
         };
         relay_rtcp(publisher.as_ref().as_ptr(), &mut packet);
+        bump(&COUNTERS.firs_sent, 1);
+    }
+}
+
+/// Returns the current plugin configuration.
+fn current_config() -> Arc<Config> {
+    Arc::clone(&read_or_recover(&CONFIG, "Config"))
+}
+
+/// Replaces the plugin configuration. Anything which reads the configuration from now on will see the new one.
+fn set_config(config: Config) {
+    txid::set_max_display_length(config.max_logged_txid_length);
+    REVOKED_TOKEN_IDS.write().unwrap().extend(config.revoked_token_ids.iter().cloned());
+    let config = Arc::new(config);
+    *write_or_recover(&CONFIG, "Config") = Arc::clone(&config);
+    for sess in read_or_recover(&SWITCHBOARD, "Switchboard").connected_sessions() {
+        cache_packet_config(sess, &config);
     }
 }
 
-fn get_config(config_root: *const c_char) -> Result<Config, Box<dyn Error>> {
-    let config_path = unsafe { Path::new(CStr::from_ptr(config_root).to_str()?) };
-    let config_file = config_path.join("janus.plugin.sfu.cfg");
-    Config::from_path(config_file)
+/// Copies the parts of the configuration which are needed for every packet onto the given session, so that relaying
+/// packets needn't read the configuration.
+fn cache_packet_config(sess: &Session, config: &Config) {
+    sess.nack_buffer_size.store(config.nack_buffer_size, Ordering::Relaxed);
+    sess.sequence_reliable_data.store(config.sequence_reliable_data, Ordering::Relaxed);
+}
+
+fn get_config_file(config_root: *const c_char) -> PathBuf {
+    let config_path = unsafe { CStr::from_ptr(config_root).to_string_lossy() };
+    Path::new(config_path.as_ref()).join("janus.plugin.sfu.cfg")
+}
+
+/// Reloads the configuration from the given file whenever we get a SIGHUP, so that it can be changed without dropping
+/// everyone's calls. If the new configuration can't be loaded, the old one stays in effect.
+fn watch_for_reloads(config_file: PathBuf) -> Result<(), Box<dyn Error>> {
+    let mut signals = Signals::new(&[SIGHUP])?;
+    thread::Builder::new().name("sfu config reload".into()).spawn(move || {
+        for _ in signals.forever() {
+            match Config::from_path(&config_file) {
+                Ok(c) => {
+                    janus_info!("Reloaded SFU plugin configuration: {:?}", c);
+                    set_config(c);
+                }
+                Err(e) => janus_warn!("Error reloading configuration for SFU plugin; keeping the old one: {}", e),
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Picks out the dominant speaker in each room, and lets everyone in the room know whenever it changes.
+fn announce_speakers(switchboard: &Switchboard, speakers: &mut HashMap<RoomId, UserId>, threshold: f32) {
+    speakers.retain(|room, _| !switchboard.publishers_occupying(room).is_empty());
+    for (room, _) in switchboard.rooms() {
+        let publishers = switchboard.publishers_occupying(room);
+        let levels: Vec<_> = publishers
+            .iter()
+            .filter_map(|p| p.join_state.get().map(|joined| (joined.user_id.clone(), p.audio_level.check())))
+            .collect();
+        if let Some(speaker) = dominant_speaker(levels.iter().map(|(user, loudness)| (user, *loudness)), threshold) {
+            if speakers.get(room) != Some(speaker) {
+                let notification = json!({ "event": "active_speaker", "room_id": room, "user_id": speaker });
+                notify_everyone(&notification, publishers);
+                speakers.insert(room.clone(), speaker.clone());
+            }
+        }
+    }
+}
+
+/// Checks who's speaking in every room at the given interval, announcing changes of dominant speaker.
+fn watch_for_speakers(interval: Duration) -> Result<(), Box<dyn Error>> {
+    thread::Builder::new().name("sfu active speaker".into()).spawn(move || {
+        let mut speakers = HashMap::new();
+        loop {
+            thread::sleep(interval);
+            let threshold = current_config().active_speaker_threshold;
+            let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
+            announce_speakers(&switchboard, &mut speakers, threshold);
+        }
+    })?;
+    Ok(())
+}
+
+/// Returns each publisher whose number of subscribers has changed since the given counts were taken, along with its new
+/// count, and updates the counts to match. Publishers who have left are forgotten.
+fn changed_subscriber_counts(switchboard: &Switchboard, counts: &mut HashMap<UserId, usize>) -> Vec<(Arc<Session>, usize)> {
+    counts.retain(|user_id, _| switchboard.get_publisher(user_id).is_some());
+    let mut changed = Vec::new();
+    for user_id in switchboard.get_all_users() {
+        if let Some(publisher) = switchboard.get_publisher(user_id) {
+            let count = switchboard.subscribers_to(publisher).len();
+            if counts.get(user_id).copied().unwrap_or(0) != count {
+                counts.insert(user_id.clone(), count);
+                changed.push((Arc::clone(publisher), count));
+            }
+        }
+    }
+    changed
+}
+
+/// Tells publishers how many subscribers they have, at most once per the given interval, so that a crowd subscribing
+/// at once only makes for one event.
+fn watch_subscriber_counts(interval: Duration) -> Result<(), Box<dyn Error>> {
+    thread::Builder::new().name("sfu subscriber counts".into()).spawn(move || {
+        let mut counts = HashMap::new();
+        loop {
+            thread::sleep(interval);
+            let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
+            for (publisher, count) in changed_subscriber_counts(&switchboard, &mut counts) {
+                send_message(&json!({ "event": "subscriber_count", "count": count }), iter::once(&publisher));
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Returns the connected sessions which, at the given time, we haven't heard from for longer than the given timeout.
+fn idle_sessions(switchboard: &Switchboard, timeout: Duration, now: Instant) -> Vec<Arc<Session>> {
+    let sessions = switchboard.connected_sessions();
+    sessions.filter(|s| s.last_seen.is_idle(timeout, now)).cloned().collect()
+}
+
+/// Starts a thread which ends sessions that we haven't heard from for longer than the given timeout.
+fn reap_idle_sessions(timeout: Duration) -> Result<(), Box<dyn Error>> {
+    thread::Builder::new().name("sfu idle reaper".into()).spawn(move || loop {
+        thread::sleep(timeout / 2);
+        // don't hold the switchboard while ending sessions, since ending them takes it to clean up after them
+        let idle = idle_sessions(&read_or_recover(&SWITCHBOARD, "Switchboard"), timeout, Instant::now());
+        for session in idle {
+            janus_info!("Ending {:p}, which has been idle for longer than {:?}.", session.handle, timeout);
+            end_session(&session);
+        }
+    })?;
+    Ok(())
+}
+
+/// Which of the given number of message processing threads should handle messages from the given handle. Every message
+/// from a handle goes to the same thread, so that they're processed in the order they were sent, e.g. a join before
+/// the subscribe which follows it.
+fn message_thread_for(handle: *mut PluginSession, thread_count: usize) -> usize {
+    // handles are aligned, so hash them rather than using their low bits directly
+    let mut hasher = DefaultHasher::new();
+    (handle as usize).hash(&mut hasher);
+    (hasher.finish() % thread_count as u64) as usize
+}
+
+/// Puts the given message on the given message processing thread's queue. If the queue is full, the message is turned
+/// away instead of waited on, so that the client hears that the server is busy and can retry, rather than hanging.
+fn queue_message(sender: &mpsc::SyncSender<RawMessage>, msg: RawMessage) -> Result<(), &'static CStr> {
+    sender.try_send(msg).map_err(|e| match e {
+        mpsc::TrySendError::Full(_) => {
+            bump(&COUNTERS.messages_rejected, 1);
+            c_str!("Server is busy; try again.")
+        }
+        mpsc::TrySendError::Disconnected(_) => c_str!("Message processing has stopped!"),
+    })
+}
+
+/// How many message processing threads to run for the given setting, where zero means one per CPU.
+fn message_thread_count(message_threads: usize) -> usize {
+    if message_threads == 0 {
+        let cpus = num_cpus::get().min(MAX_MESSAGE_THREADS);
+        janus_info!("message_threads is set to 0, setting it to {} (number of cpus)", cpus);
+        cpus
+    } else {
+        message_threads
+    }
 }
 
 extern "C" fn init(callbacks: *mut PluginCallbacks, config_path: *const c_char) -> c_int {
-    let config = match get_config(config_path) {
+    let config_file = get_config_file(config_path);
+    let config = match Config::from_path(&config_file) {
         Ok(c) => {
             janus_info!("Loaded SFU plugin configuration: {:?}", c);
             c
@@ -254,38 +578,75 @@ extern "C" fn init(callbacks: *mut PluginCallbacks, config_path: *const c_char)
         }
     };
     let message_threads = config.message_threads;
-    CONFIG.set(config).expect("Big problem: config already initialized!");
+    let message_queue_depth = config.message_queue_depth;
+    let active_speaker_interval = Duration::from_millis(config.active_speaker_interval_ms);
+    let subscriber_count_interval = Duration::from_millis(config.subscriber_count_interval_ms);
+    let idle_timeout = Duration::from_millis(config.idle_timeout_ms);
+    set_config(config);
+    if let Err(e) = watch_for_reloads(config_file) {
+        janus_warn!("Failed to watch for SFU plugin configuration reloads: {}", e);
+    }
     match unsafe { callbacks.as_ref() } {
         Some(c) => {
             unsafe { CALLBACKS = Some(c) };
-            let mut senders = Vec::new();
-            let num_threads = if message_threads == 0 {
-                let cpus = num_cpus::get();
-                janus_info!("message_threads is set to 0, setting it to {} (number of cpus)", cpus);
-                cpus
-            } else {
-                message_threads
-            };
+            let num_threads = message_thread_count(message_threads);
+            let message_threads = WorkerPool::start("sfu msg", num_threads, message_queue_depth, |msg| {
+                if let Err(e) = handle_message_async(msg) {
+                    janus_err!("Error processing message: {}", e);
+                }
+            })
+            .expect("Failed to spawn message threads.");
+            janus_verb!("{} message processing threads are alive.", num_threads);
+            let _ = MESSAGE_THREADS.set(message_threads);
+
+            if active_speaker_interval > Duration::from_millis(0) {
+                if let Err(e) = watch_for_speakers(active_speaker_interval) {
+                    janus_err!("Failed to start active speaker detection: {}", e);
+                }
+            }
 
-            for i in 0..num_threads {
-                let (messages_tx, messages_rx) = mpsc::sync_channel(0);
-                senders.push(messages_tx.clone());
+            if subscriber_count_interval > Duration::from_millis(0) {
+                if let Err(e) = watch_subscriber_counts(subscriber_count_interval) {
+                    janus_err!("Failed to start subscriber counts: {}", e);
+                }
+            }
+
+            if idle_timeout > Duration::from_millis(0) {
+                if let Err(e) = reap_idle_sessions(idle_timeout) {
+                    janus_err!("Failed to start reaping idle sessions: {}", e);
+                }
+            }
 
-                thread::Builder::new()
-                    .name(format!("sfu msg {}", i))
-                    .spawn(move || {
-                        for msg in messages_rx.iter() {
-                            if let Err(e) = handle_message_async(msg) {
-                                janus_err!("Error processing message: {}", e);
-                            }
-                        }
-                    })
-                    .expect("Failed to spawn message thread.");
+            if let Some(ref channel_config) = current_config().event_channel {
+                match Channel::start(channel_config, handle_datagram) {
+                    Ok(channel) => {
+                        janus_info!("Event channel is listening on {:?}.", channel_config.incoming_path);
+                        let _ = EVENT_CHANNEL.set(channel);
+                    }
+                    Err(e) => janus_err!("Failed to start event channel: {}", e),
+                }
+            }
 
-                janus_verb!("Message processing thread {} is alive.", i);
+            if let Some(ref url) = current_config().webhook_url {
+                match Webhook::start(url, Backoff::default()) {
+                    Ok(webhook) => {
+                        janus_info!("Posting room events to webhook at {}.", url);
+                        let _ = WEBHOOK.set(webhook);
+                    }
+                    Err(e) => janus_err!("Failed to start webhook: {}", e),
+                }
             }
 
-            let _ = MESSAGE_SENDERS.set(senders);
+            if let Some(addr) = current_config().status_http_addr {
+                let stats = || current_gauges(&read_or_recover(&SWITCHBOARD, "Switchboard"));
+                match StatusServer::start(addr, stats) {
+                    Ok(server) => {
+                        janus_info!("Serving status over HTTP at {}.", addr);
+                        let _ = STATUS_SERVER.set(server);
+                    }
+                    Err(e) => janus_err!("Failed to start status server: {}", e),
+                }
+            }
 
             janus_info!("Janus SFU plugin initialized!");
             0
@@ -297,23 +658,92 @@ extern "C" fn init(callbacks: *mut PluginCallbacks, config_path: *const c_char)
     }
 }
 
+/// Sends the given datagram over the event channel, if it's enabled.
+fn send_datagram(datagram: DatagramKind) {
+    if let Some(channel) = EVENT_CHANNEL.get() {
+        channel.send(datagram);
+    }
+}
+
+/// Queues an event to be posted to the webhook, if it's enabled.
+fn send_webhook_event(event: &'static str, user_id: Option<&UserId>, room_id: &RoomId) {
+    if let Some(webhook) = WEBHOOK.get() {
+        webhook.send(WebhookEvent::now(event, user_id, room_id));
+    }
+}
+
+/// Handles a datagram received over the event channel, other than subscriptions, which the channel manages itself.
+fn handle_datagram(datagram: DatagramKind) {
+    match datagram {
+        DatagramKind::Message(Topic::UserData(user_id), bytes) => match String::from_utf8(bytes) {
+            Ok(body) => {
+                janus_huge!("Processing data message from event channel for {}: {:?}", user_id, body);
+                let payload = json!({ "event": "data", "body": body });
+                let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
+                let publisher = switchboard.get_publisher(&user_id).into_iter();
+                let subscribers = switchboard.get_subscribers(&user_id).into_iter().flatten();
+                if let Err(e) = send_data_user(&payload, &user_id, publisher.chain(subscribers)) {
+                    janus_warn!("Failed to deliver data message from event channel to {}: {}", user_id, e);
+                }
+            }
+            Err(e) => janus_warn!("Discarding data message from event channel with invalid body: {}", e),
+        },
+        other => janus_verb!("Ignoring datagram from event channel: {:?}", other),
+    }
+}
+
 extern "C" fn destroy() {
+    if let Some(server) = STATUS_SERVER.get() {
+        server.stop();
+    }
+    // finish the messages we've already accepted, so that nobody who sent one is left hanging
+    if let Some(threads) = MESSAGE_THREADS.get() {
+        janus_info!("Finishing queued signalling messages...");
+        threads.drain();
+    }
+    let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
+    notify_everyone(&json!({ "event": "server_shutdown" }), switchboard.connected_sessions());
     janus_info!("Janus SFU plugin destroyed!");
 }
 
 extern "C" fn create_session(handle: *mut PluginSession, error: *mut c_int) {
     let initial_state = SessionState {
         destroyed: AtomicBool::new(false),
-        join_state: OnceCell::new(),
+        join_state: JoinCell::default(),
         subscriber_offer: Arc::new(Mutex::new(None)),
-        subscription: OnceCell::new(),
+        subscription: Mutex::new(None),
         fir_seq: AtomicIsize::new(0),
+        subscription_changes: Mutex::new(subscription_bucket(&current_config(), Instant::now())),
+        traffic: TrafficCounters::default(),
+        audio_level: AudioLevel::default(),
+        keyframe_wanted: AtomicBool::new(false),
+        keyframe_requests: KeyframeThrottle::default(),
+        rtp_buffer: RtpBuffer::default(),
+        simulcast: SimulcastLayers::default(),
+        layer_choice: LayerChoice::default(),
+        remb_reported: AtomicU32::new(0),
+        remb_estimate: AtomicU32::new(0),
+        recording: Mutex::new(None),
+        is_recording: AtomicBool::new(false),
+        nack_buffer_size: AtomicUsize::new(0),
+        sequence_reliable_data: AtomicBool::new(false),
+        muted: MediaMutes::default(),
+        sendrecv: AtomicBool::new(false),
+        negotiated: Mutex::new(None),
+        multi_source: AtomicBool::new(false),
+        last_seen: LastSeen::new(Instant::now()),
+        switching: SwitchingContext::default(),
+        data_sequence: DataSequencer::default(),
+        claims: Mutex::new(None),
     };
 
     match unsafe { Session::associate(handle, initial_state) } {
         Ok(sess) => {
             janus_info!("Initializing SFU session {:p}...", sess.handle);
-            SWITCHBOARD.write().expect("Switchboard is poisoned :(").connect(sess);
+            let mut switchboard = write_or_recover(&SWITCHBOARD, "Switchboard");
+            // a reload either happens before this reads the configuration, or finds this session connected afterwards
+            cache_packet_config(&sess, &current_config());
+            switchboard.connect(sess);
         }
         Err(e) => {
             janus_err!("{}", e);
@@ -322,23 +752,96 @@ extern "C" fn create_session(handle: *mut PluginSession, error: *mut c_int) {
     }
 }
 
+/// Removes the given session from whatever room it's in. Returns the state it had in the room if it was admitted to
+/// one, along with the notifications that let everyone else there know.
+fn leave_room(switchboard: &mut Switchboard, sess: &Session) -> (Option<Arc<JoinState>>, Vec<Notification>) {
+    let mut notifications = Vec::new();
+    // people still in a waiting room were never admitted, so nobody else knows they were here
+    let was_waiting = switchboard.leave_waiting_room(sess);
+    let joined = match sess.join_state.get().filter(|_| !was_waiting) {
+        Some(joined) => joined,
+        None => return (None, notifications),
+    };
+    match joined.kind {
+        JoinKind::Publisher => {
+            stop_recording(sess);
+            // let anyone watching this publisher know to clean up the media they were getting from it
+            let (orphans, destroyed) = switchboard.leave_publisher(sess);
+            notifications.push(Notification {
+                event: json!({ "event": "publisher_gone", "user_id": &joined.user_id, "room_id": &joined.room_id }),
+                recipients: orphans,
+            });
+            if destroyed {
+                notifications.push(room_event(switchboard, "room_destroyed", &joined.room_id));
+            }
+        }
+        JoinKind::Subscriber => switchboard.leave_subscriber(sess),
+    }
+    // roommates heard about this user when their publisher joined, so they hear that they left when it leaves
+    if joined.kind == JoinKind::Publisher {
+        notifications.extend(announce_leave(switchboard, &joined));
+    }
+    // blocks belong to users rather than sessions, so they're only forgotten once all of the user's sessions are gone
+    if !switchboard.is_user_present(&joined.user_id) {
+        let now = Instant::now();
+        let expires_at = now + Duration::from_millis(current_config().block_grace_period_ms);
+        switchboard.remove_user_blocks(&joined.user_id, expires_at, now);
+    }
+    (Some(joined), notifications)
+}
+
+/// Announces that the given publisher's user left, unless we already did, returning the `leave` event that its
+/// roommates should get.
+fn announce_leave(switchboard: &Switchboard, joined: &JoinState) -> Option<Notification> {
+    if joined.departed.swap(true, Ordering::Relaxed) {
+        return None;
+    }
+    let notifiees = switchboard.publishers_occupying(&joined.room_id).iter().filter(|s| {
+        let subscription_state = s.subscription.lock().unwrap();
+        match (&*subscription_state, s.join_state.get()) {
+            (Some(subscription), Some(roommate)) => subscription.notifications && roommate.user_id != joined.user_id,
+            _ => false,
+        }
+    });
+    let notification = Notification {
+        event: json!({ "event": "leave", "user_id": &joined.user_id, "room_id": &joined.room_id }),
+        recipients: notifiees.cloned().collect(),
+    };
+    send_datagram(DatagramKind::Leave {
+        room_id: joined.room_id.clone(),
+        user_id: joined.user_id.clone(),
+    });
+    send_webhook_event("leave", Some(&joined.user_id), &joined.room_id);
+    Some(notification)
+}
+
+/// Announces that a publisher left as soon as its media hangs up, since its session may linger for a while before
+/// Janus destroys it. Returns the `leave` event that its roommates should get, if we hadn't already announced it.
+fn announce_hangup(switchboard: &Switchboard, sess: &Session) -> Option<Notification> {
+    match sess.join_state.get() {
+        Some(joined) if joined.kind == JoinKind::Publisher && !switchboard.is_waiting(sess) => announce_leave(switchboard, &joined),
+        _ => None,
+    }
+}
+
 extern "C" fn destroy_session(handle: *mut PluginSession, error: *mut c_int) {
     match unsafe { Session::from_ptr(handle) } {
         Ok(sess) => {
             janus_info!("Destroying SFU session {:p}...", sess.handle);
-            let mut switchboard = SWITCHBOARD.write().expect("Switchboard is poisoned :(");
+            stop_recording(&sess);
+            let mut switchboard = write_or_recover(&SWITCHBOARD, "Switchboard");
             switchboard.disconnect(&sess);
-            if let Some(joined) = sess.join_state.get() {
-                match joined.kind {
-                    JoinKind::Publisher => switchboard.leave_publisher(&sess),
-                    JoinKind::Subscriber => switchboard.leave_subscriber(&sess),
-                }
-                // if this user is entirely disconnected, notify their roommates.
-                // todo: is it better if this is instead when their publisher disconnects?
-                if !switchboard.is_connected(&joined.user_id) {
-                    let response = json!({ "event": "leave", "user_id": &joined.user_id, "room_id": &joined.room_id });
-                    let occupants = switchboard.publishers_occupying(&joined.room_id);
-                    notify_except(&response, &joined.user_id, occupants);
+            let (joined, notifications) = leave_room(&mut switchboard, &sess);
+            send_notifications(notifications);
+            if let Some(joined) = joined {
+                // keep their subscriber offer around for a bit in case they're about to reconnect
+                let grace_period_ms = current_config().offer_grace_period_ms;
+                if joined.kind == JoinKind::Publisher && grace_period_ms > 0 {
+                    if let Some(offer) = sess.subscriber_offer.lock().unwrap().take() {
+                        let now = Instant::now();
+                        let expires_at = now + Duration::from_millis(grace_period_ms);
+                        switchboard.retain_offer(joined.user_id.clone(), offer, expires_at, now);
+                    }
                 }
             }
             sess.destroyed.store(true, Ordering::Relaxed);
@@ -350,46 +853,205 @@ extern "C" fn destroy_session(handle: *mut PluginSession, error: *mut c_int) {
     }
 }
 
-extern "C" fn query_session(_handle: *mut PluginSession) -> *mut RawJanssonValue {
-    let output = json!({});
+/// Describes the given session's state, for debugging. This never touches the switchboard, so that it still works
+/// when something is stuck holding the switchboard lock.
+fn describe_session(sess: &Session, now: Instant) -> JsonValue {
+    let joined = sess.join_state.get();
+    let kind = joined.as_ref().map(|j| match j.kind {
+        JoinKind::Publisher => "publisher",
+        JoinKind::Subscriber => "subscriber",
+    });
+    let subscription = sess.subscription.lock().unwrap().clone();
+    let has_subscriber_offer = sess.subscriber_offer.lock().unwrap().is_some();
+    let negotiated = sess.negotiated.lock().unwrap().clone();
+    json!({
+        "joined": joined.is_some(),
+        "kind": kind,
+        "room_id": joined.as_ref().map(|j| &j.room_id),
+        "user_id": joined.as_ref().map(|j| &j.user_id),
+        "joined_at": joined.as_ref().map(|j| j.joined_at_millis()),
+        "time_in_room_ms": joined.as_ref().map(|j| j.time_in_room(now).as_millis() as u64),
+        "destroyed": sess.destroyed.load(Ordering::Relaxed),
+        "has_subscriber_offer": has_subscriber_offer,
+        "negotiated": negotiated,
+        "remb_estimate": sess.remb_estimate.load(Ordering::Relaxed),
+        "subscription": subscription,
+        "traffic": {
+            "packets_in": sess.traffic.packets_in.load(Ordering::Relaxed),
+            "bytes_in": sess.traffic.bytes_in.load(Ordering::Relaxed),
+            "packets_out": sess.traffic.packets_out.load(Ordering::Relaxed),
+            "bytes_out": sess.traffic.bytes_out.load(Ordering::Relaxed),
+        },
+    })
+}
+
+extern "C" fn query_session(handle: *mut PluginSession) -> *mut RawJanssonValue {
+    let output = match unsafe { Session::from_ptr(handle) } {
+        Ok(sess) => describe_session(&sess, Instant::now()),
+        Err(_) => json!({}),
+    };
     serde_to_jansson(&output).into_raw()
 }
 
 extern "C" fn setup_media(handle: *mut PluginSession) {
     let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    let switchboard = SWITCHBOARD.read().expect("Switchboard is poisoned :(");
+    let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
     send_fir(switchboard.media_senders_to(&sess));
     janus_info!("WebRTC media is now available on {:p}.", sess.handle);
 }
 
+/// Relays a media packet of the given size from the given session along its routes, counting the traffic on both
+/// ends. If the packet is from one layer of a simulcast, it's only relayed to sessions which chose that layer. Returns
+/// how many sessions it was relayed to.
+fn relay_media<F>(routes: &RouteTable, sess: &Session, video: bool, bytes: u64, layer: Option<Layer>, mut relay: F) -> u64
+where
+    F: FnMut(&Session),
+{
+    let joined = match sess.join_state.get() {
+        Some(joined) => joined,
+        None => return 0,
+    };
+    sess.traffic.received(bytes);
+    let mut relayed = 0;
+    routes.for_each_recipient(&joined.room_id, sess, video, |other| {
+        if let Some(layer) = layer {
+            if !other.layer_choice.selects(layer) {
+                return;
+            }
+        }
+        relay(&**other);
+        other.traffic.sent(bytes);
+        relayed += 1;
+    });
+    relayed
+}
+
 extern "C" fn incoming_rtp(handle: *mut PluginSession, packet: *mut PluginRtpPacket) {
     let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    let switchboard = SWITCHBOARD.read().expect("Switchboard lock poisoned; can't continue.");
     let relay_rtp = gateway_callbacks().relay_rtp;
-    for other in switchboard.media_recipients_for(&sess) {
-        relay_rtp(other.as_ptr(), packet);
+    let (video, bytes) = unsafe { ((*packet).video == 1, (*packet).length as u64) };
+    if sess.muted.is_muted(video) {
+        return;
+    }
+    let data = unsafe { slice::from_raw_parts_mut((*packet).buffer as *mut u8, (*packet).length as usize) };
+    if video {
+        if sess.keyframe_wanted.load(Ordering::Relaxed) && sess.keyframe_wanted.swap(false, Ordering::Relaxed) {
+            send_fir(iter::once(&sess));
+        }
+    } else {
+        sess.audio_level.observe(data);
+    }
+    if sess.is_recording.load(Ordering::Relaxed) {
+        record_rtp(&sess, data, video);
+    }
+    if video {
+        sess.rtp_buffer.push(data, sess.nack_buffer_size.load(Ordering::Relaxed));
+    }
+    let layer = if video { sess.simulcast.layer_of(data) } else { None };
+    let relayed = relay_media(&MEDIA_ROUTES, &sess, video, bytes, layer, |other| {
+        if other.multi_source.load(Ordering::Relaxed) {
+            relay_rtp(other.as_ptr(), packet);
+        } else {
+            other.switching.relay(data, video, || relay_rtp(other.as_ptr(), packet));
+        }
+    });
+    bump(&COUNTERS.rtp_relayed, relayed);
+}
+
+/// Writes the given RTP packet to the session's recording, if it's being recorded. If that fails, the recording is
+/// stopped, rather than failing again for every packet.
+fn record_rtp(sess: &Session, packet: &[u8], video: bool) {
+    let mut recording = sess.recording.lock().unwrap();
+    if let Some(ref mut current) = *recording {
+        if let Err(e) = current.write(packet, video, now_micros()) {
+            janus_err!("Failed to record media from {:p}; stopping recording: {}", sess.handle, e);
+            let _ = current.stop();
+            *recording = None;
+            sess.is_recording.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns which of the given publishers a subscriber's keyframe request made at the given time should be passed along
+/// to, leaving out any which were asked for one too recently.
+fn throttle_keyframe_requests<'a, T>(publishers: T, config: &Config, now: Instant) -> Vec<&'a Arc<Session>>
+where
+    T: IntoIterator<Item = &'a Arc<Session>>,
+{
+    let min_interval = Duration::from_millis(config.keyframe_min_interval_ms);
+    let (passed, dropped): (Vec<_>, Vec<_>) = publishers
+        .into_iter()
+        .partition(|publisher| publisher.keyframe_requests.try_request(min_interval, now));
+    bump(&COUNTERS.keyframe_requests_dropped, dropped.len() as u64);
+    passed
+}
+
+/// Records the bandwidth estimate which the given subscriber reported, and returns each publisher it's getting video
+/// from along with the lowest estimate reported by any of that publisher's subscribers, which it should be sent.
+fn aggregate_remb<'a>(switchboard: &'a Switchboard, subscriber: &Session, bitrate: u32) -> Vec<(&'a Arc<Session>, u32)> {
+    subscriber.remb_reported.store(bitrate, Ordering::Relaxed);
+    let mut estimates = Vec::new();
+    for publisher in switchboard.media_senders_to(subscriber) {
+        let reported = switchboard
+            .media_recipients_for(publisher, true)
+            .map(|other| other.remb_reported.load(Ordering::Relaxed));
+        if let Some(estimate) = lowest_estimate(reported) {
+            publisher.remb_estimate.store(estimate, Ordering::Relaxed);
+            estimates.push((publisher, estimate));
+        }
+    }
+    estimates
+}
+
+/// Returns the buffered video packets, as their publishers sent them, which the given subscriber lost and wants again.
+fn find_nacked_packets(switchboard: &Switchboard, subscriber: &Session, nacked: &[u16]) -> Vec<Vec<u8>> {
+    let mut found = Vec::new();
+    for &seq in nacked {
+        if let Some((ssrc, source_seq)) = subscriber.switching.source_of(seq, true) {
+            let mut publishers = switchboard.media_senders_to(subscriber);
+            found.extend(publishers.find_map(|publisher| publisher.rtp_buffer.get(ssrc, source_seq)));
+        }
     }
+    found
 }
 
 extern "C" fn incoming_rtcp(handle: *mut PluginSession, packet: *mut PluginRtcpPacket) {
     let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    let switchboard = SWITCHBOARD.read().expect("Switchboard lock poisoned; can't continue.");
+    sess.last_seen.touch(Instant::now());
+    let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
     let data = unsafe { slice::from_raw_parts((*packet).buffer, (*packet).length as usize) };
     let video = unsafe { (*packet).video };
+    sess.traffic.received(data.len() as u64);
+    let config = current_config();
+    if video == 1 && config.nack_buffer_size > 0 {
+        let nacked = nacked_sequence_numbers(data);
+        for lost in find_nacked_packets(&switchboard, &sess, &nacked) {
+            retransmit_rtp(&sess, lost);
+        }
+    }
+    if video == 1 && config.remb_relay_enabled {
+        if let Some(bitrate) = remb_bitrate(data) {
+            for (publisher, estimate) in aggregate_remb(&switchboard, &sess, bitrate) {
+                send_remb(publisher, estimate);
+            }
+        }
+    }
     match video {
         1 if has_pli(data) => {
             let send_pli = gateway_callbacks().send_pli;
-            for publisher in switchboard.media_senders_to(&sess) {
+            for publisher in throttle_keyframe_requests(switchboard.media_senders_to(&sess), &config, Instant::now()) {
                 send_pli(publisher.as_ptr());
+                bump(&COUNTERS.plis_sent, 1);
             }
         }
         1 if has_fir(data) => {
-            send_fir(switchboard.media_senders_to(&sess));
+            send_fir(throttle_keyframe_requests(switchboard.media_senders_to(&sess), &config, Instant::now()));
         }
         _ => {
             let relay_rtcp = gateway_callbacks().relay_rtcp;
-            for subscriber in switchboard.media_recipients_for(&sess) {
+            for subscriber in switchboard.media_recipients_for(&sess, video == 1) {
                 relay_rtcp(subscriber.as_ptr(), packet);
+                subscriber.traffic.sent(data.len() as u64);
             }
         }
     }
@@ -397,15 +1059,44 @@ extern "C" fn incoming_rtcp(handle: *mut PluginSession, packet: *mut PluginRtcpP
 
 extern "C" fn incoming_data(handle: *mut PluginSession, packet: *mut PluginDataPacket) {
     let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    let switchboard = SWITCHBOARD.read().expect("Switchboard lock poisoned; can't continue.");
+    let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
     let relay_data = gateway_callbacks().relay_data;
+    let bytes = unsafe { (*packet).length as u64 };
+    sess.traffic.received(bytes);
+    if sess.sequence_reliable_data.load(Ordering::Relaxed) && unsafe { has_label(packet, RELIABLE_LABEL) } {
+        let payload = unsafe { slice::from_raw_parts((*packet).buffer as *const u8, (*packet).length as usize) };
+        if payload.len() > MAX_SEQUENCED_DATA_LENGTH {
+            janus_warn!("Dropping data from {:p} which is too long to sequence ({} bytes).", sess.handle, payload.len());
+            return;
+        }
+        sess.data_sequence.relay(payload, |stamped| {
+            let mut copy = unsafe { ptr::read(packet) };
+            copy.buffer = stamped.as_ptr() as *mut c_char;
+            copy.length = stamped.len() as u16;
+            for other in switchboard.data_recipients_for(&sess) {
+                relay_data(other.as_ptr(), &mut copy);
+                other.traffic.sent(stamped.len() as u64);
+            }
+        });
+        return;
+    }
     for other in switchboard.data_recipients_for(&sess) {
         // we presume that clients have matching labels on their channels -- in our case we have one
         // reliable one called "reliable" and one unreliable one called "unreliable"
         relay_data(other.as_ptr(), packet);
+        other.traffic.sent(bytes);
     }
 }
 
+/// The longest data message which can be relayed with a sequence number, leaving room in a packet for the longest stamp.
+const MAX_SEQUENCED_DATA_LENGTH: usize = u16::MAX as usize - "18446744073709551615:".len();
+
+/// Returns whether the given data packet came over a data channel with the given label.
+unsafe fn has_label(packet: *const PluginDataPacket, label: &str) -> bool {
+    let raw = (*packet).label;
+    !raw.is_null() && CStr::from_ptr(raw).to_bytes() == label.as_bytes()
+}
+
 extern "C" fn data_ready(_handle: *mut PluginSession) {
     // Skip data channels.
 }
@@ -418,12 +1109,92 @@ extern "C" fn slow_link(handle: *mut PluginSession, _uplink: c_int, _video: c_in
 extern "C" fn hangup_media(handle: *mut PluginSession) {
     let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
     janus_info!("Hanging up WebRTC media on {:p}.", sess.handle);
+    let switchboard = read_or_recover(&SWITCHBOARD, "Switchboard");
+    send_notifications(announce_hangup(&switchboard, &sess));
 }
 
-fn process_join(from: &Arc<Session>, room_id: RoomId, user_id: UserId, subscribe: Option<Subscription>, token: Option<String>) -> MessageResult {
-    // todo: holy shit clean this function up somehow
-    let config = CONFIG.get().unwrap();
-    match (&config.auth_key, token) {
+/// Checks whether a join of the given kind would exceed the configured limits, given the number of users currently in
+/// the room and on the server. Moderators may additionally use the server's reserved slots.
+fn check_capacity(config: &Config, join_kind: JoinKind, is_moderator: bool, room_size: usize, ccu: usize) -> Result<(), SfuError> {
+    if config.max_room_size_scope.applies_to(join_kind) && config.max_room_size > 0 && room_size >= config.max_room_size {
+        return Err(SfuError::RoomFull);
+    }
+    let reserved = if is_moderator { config.reserved_moderator_slots } else { 0 };
+    if config.max_ccu_scope.applies_to(join_kind) && config.max_ccu > 0 && ccu >= config.max_ccu + reserved {
+        return Err(SfuError::ServerFull);
+    }
+    Ok(())
+}
+
+/// Makes sure that a publisher joining the given room wouldn't open up more rooms than the server allows.
+fn check_room_limit(config: &Config, switchboard: &Switchboard, room_id: &RoomId) -> Result<(), SfuError> {
+    let is_new_room = switchboard.publishers_occupying(room_id).is_empty();
+    if is_new_room && config.max_rooms > 0 && switchboard.room_count() >= config.max_rooms {
+        return Err(SfuError::RoomLimit);
+    }
+    Ok(())
+}
+
+/// Makes sure that the given publisher can take on another subscriber to its media.
+fn check_publisher_capacity(config: &Config, switchboard: &Switchboard, publisher: &Session) -> Result<(), SfuError> {
+    let max = config.max_subscribers_per_publisher;
+    if max > 0 && switchboard.subscribers_to(publisher).len() >= max {
+        return Err(SfuError::SubscriberLimit("Publisher subscriber limit reached."));
+    }
+    Ok(())
+}
+
+/// Makes sure that moving a publisher between rooms wouldn't open up more rooms than the server allows. The last
+/// publisher leaving a room closes it, so moving them out of it doesn't open up any more rooms.
+fn check_room_change_limit(config: &Config, switchboard: &Switchboard, from_room: &RoomId, to_room: &RoomId) -> Result<(), SfuError> {
+    if switchboard.publishers_occupying(from_room).len() > 1 {
+        check_room_limit(config, switchboard, to_room)
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes sure that there's room for another subscriber in a room which already has the given number of them.
+fn check_subscriber_capacity(config: &Config, room_subscribers: usize) -> Result<(), SfuError> {
+    if config.max_subscribers_per_room > 0 && room_subscribers >= config.max_subscribers_per_room {
+        return Err(SfuError::SubscriberLimit("Room subscriber limit reached."));
+    }
+    Ok(())
+}
+
+/// Returns the number of concurrent users to check against the CCU limit: either the number of publishing users, or
+/// the number of connections of any kind, depending on the configuration.
+fn count_ccu(config: &Config, switchboard: &Switchboard) -> usize {
+    if config.count_subscribers_in_ccu {
+        switchboard.total_connections()
+    } else {
+        switchboard.get_all_users().count()
+    }
+}
+
+/// Makes sure that the bearer of the given claims may open up the room, if nobody is publishing in it yet.
+fn check_room_creation(claims: Option<&ValidatedToken>, room_size: usize) -> Result<(), SfuError> {
+    match claims {
+        Some(claims) if room_size == 0 && !claims.create_room => Err(SfuError::Unauthorized(AuthError::Forbidden("Not permitted to create rooms."))),
+        _ => Ok(()),
+    }
+}
+
+/// If the server protects user IDs, makes sure that a join as the given user which conflicts with another kind of
+/// connection they already have in the room comes with a token vouching for that user, so that nobody can pass
+/// themselves off as someone else's subscriber or publisher.
+fn check_identity(config: &Config, claims: Option<&ValidatedToken>, user_id: &UserId, conflicting: bool) -> Result<(), SfuError> {
+    match claims {
+        _ if !config.protect_user_ids || !conflicting => Ok(()),
+        Some(claims) if claims.may_act_as(user_id) => Ok(()),
+        _ => Err(SfuError::Unauthorized(AuthError::Forbidden("Rejecting join as a user already in the room!"))),
+    }
+}
+
+/// Checks that the bearer of the given token may join the given room as the given user, returning their claims if the
+/// server requires authentication.
+fn authorize_join(config: &Config, from: &Session, room_id: &RoomId, user_id: &UserId, token: Option<&str>) -> Result<Option<ValidatedToken>, SfuError> {
+    match (&config.auth, token) {
         (None, _) => {
             janus_verb!(
                 "No auth_key configured. Allowing join from {:p} to room {} as user {}.",
@@ -431,366 +1202,1712 @@ fn process_join(from: &Arc<Session>, room_id: RoomId, user_id: UserId, subscribe
                 room_id,
                 user_id
             );
+            Ok(None)
         }
         (Some(_), None) => {
             janus_warn!("Rejecting anonymous join from {:p} to room {} as user {}.", from.handle, room_id, user_id);
-            return Err(From::from("Rejecting anonymous join!"));
+            Err(SfuError::Unauthorized(AuthError::Forbidden("Rejecting anonymous join!")))
         }
-        (Some(key), Some(ref token)) => match ValidatedToken::from_str(token, key) {
-            Ok(ref claims) => {
-                if claims.may_join(&room_id) {
+        (Some(auth), Some(token)) => match session_claims(from, Some(token), auth) {
+            Ok(claims) => {
+                if claims.may_join(room_id) {
                     janus_verb!("Allowing join from {:p} to room {} as user {}.", from.handle, room_id, user_id);
+                    Ok(Some(claims))
                 } else {
                     janus_warn!("Rejecting join from {:p} to room {} as user {}.", from.handle, room_id, user_id);
-                    return Err(From::from("Rejecting join without permission!"));
+                    let msg = "Rejecting join without permission!";
+                    // a token that lets you join some rooms, but not this one, was presumably meant for another room
+                    let err = if claims.join_hub {
+                        AuthError::WrongRoom(msg)
+                    } else {
+                        AuthError::Forbidden(msg)
+                    };
+                    Err(SfuError::Unauthorized(err))
                 }
             }
             Err(e) => {
                 janus_warn!("Rejecting join from {:p} to room {} as user {}. Error: {}", from.handle, room_id, user_id, e);
-                return Err(From::from("Rejecting join with invalid token!"));
+                Err(SfuError::Unauthorized(match e.downcast_ref::<TokenError>() {
+                    Some(TokenError::Expired) => AuthError::Expired("Rejecting join with expired token!"),
+                    Some(TokenError::Premature) => AuthError::Forbidden("Rejecting join with token that isn't valid yet!"),
+                    Some(TokenError::Revoked) => AuthError::Forbidden("Rejecting join with revoked token!"),
+                    Some(TokenError::WrongIssuer) | Some(TokenError::WrongAudience) => {
+                        AuthError::Forbidden("Rejecting join with token meant for someone else!")
+                    }
+                    None => AuthError::Malformed("Rejecting join with invalid token!"),
+                }))
             }
         },
     }
+}
+
+/// Works out whether a join is a publisher's or a subscriber's, going by the role it declared if it declared one, and
+/// what it should be subscribed to, which is the configured default if it came without a subscription.
+fn join_subscription(config: &Config, role: Option<JoinKind>, subscribe: Option<Subscription>) -> (JoinKind, Option<Subscription>) {
+    // clients which don't declare a role are inferred to be publishers if they want data, as they always were
+    let gets_data_channel = subscribe.as_ref().map(|s| s.data).unwrap_or(false);
+    let inferred = if gets_data_channel { JoinKind::Publisher } else { JoinKind::Subscriber };
+    (role.unwrap_or(inferred), subscribe.or_else(|| config.default_subscription.clone()))
+}
+
+fn process_join(
+    from: &Arc<Session>,
+    room_id: RoomId,
+    user_id: UserId,
+    subscribe: Option<Subscription>,
+    token: Option<String>,
+    role: Option<JoinKind>,
+) -> MessageResult {
+    // todo: holy shit clean this function up somehow
+    let max_id_length = current_config().max_id_length;
+    validate_id("room_id", &room_id, max_id_length)?;
+    validate_id("user_id", &user_id, max_id_length)?;
+    // a client retrying a join whose response it lost should hear exactly that, rather than whatever else the first
+    // join makes fail (e.g. that its user is already publishing), and nothing about the first join should change
+    if from.join_state.get().is_some() {
+        janus_warn!("Rejecting join from {:p} to room {} as user {}: already joined.", from.handle, room_id, user_id);
+        return Err(SfuError::AlreadyJoined("Handles may only be in one room at a time!"));
+    }
+    let config = current_config();
+    let config = config.for_room(&room_id);
+    let claims = authorize_join(&config, from, &room_id, &user_id, token.as_deref())?;
+    let is_moderator = matches!(claims, Some(ref c) if c.may_moderate(&room_id));
 
     let mut switchboard = SWITCHBOARD.write()?;
+    let is_waiting = !is_moderator && switchboard.is_waiting_room(&room_id);
     let room_users = switchboard.get_room_users(&room_id).collect::<HashSet<_>>();
-    let body = json!({ "users": { room_id.as_str(): room_users }});
+    let mut body = json!({
+        "users": { room_id.as_str(): room_users },
+        "limits": config.limits(),
+        "presenter_only_audio": switchboard.is_presenter_only_audio(&room_id),
+        "waiting": is_waiting,
+    });
+    let is_rostered = switchboard.get_room_state(&room_id).and_then(|state| state.roster.as_ref()).is_some();
+    if is_rostered {
+        let absent_users = switchboard.get_absent_users(&room_id).filter(|u| *u != &user_id).collect::<HashSet<_>>();
+        body["expected"] = json!({ room_id.as_str(): absent_users });
+    }
 
-    // hack -- use data channel subscription to infer this, it would probably be nicer if
-    // connections announced explicitly whether they were a publisher or subscriber
-    let gets_data_channel = subscribe.as_ref().map(|s| s.data).unwrap_or(false);
-    let join_kind = if gets_data_channel { JoinKind::Publisher } else { JoinKind::Subscriber };
+    let (join_kind, subscribe) = join_subscription(&config, role, subscribe);
 
+    check_capacity(&config, join_kind, is_moderator, room_users.len(), count_ccu(&config, &switchboard))?;
+    if join_kind == JoinKind::Subscriber {
+        check_subscriber_capacity(&config, switchboard.count_subscribers_in(&room_id))?;
+    }
     if join_kind == JoinKind::Publisher {
-        if config.max_room_size > 0 && room_users.len() >= config.max_room_size {
-            return Err(From::from("Room is full."));
+        if let Err(e) = check_room_creation(claims.as_ref(), room_users.len()) {
+            janus_warn!("Rejecting join from {:p} to empty room {} as user {}.", from.handle, room_id, user_id);
+            return Err(e);
         }
-        if config.max_ccu > 0 && switchboard.get_all_users().count() >= config.max_ccu {
-            return Err(From::from("Server is full."));
+        if let Err(e) = check_room_limit(&config, &switchboard, &room_id) {
+            janus_warn!(
+                "Rejecting join from {:p} to new room {} as user {}: too many rooms.",
+                from.handle,
+                room_id,
+                user_id
+            );
+            return Err(e);
         }
     }
-
-    if let Err(_existing) = from.join_state.set(JoinState::new(join_kind, room_id.clone(), user_id.clone())) {
-        return Err(From::from("Handles may only join once!"));
+    if join_kind == JoinKind::Publisher && switchboard.get_publisher(&user_id).is_some() {
+        // only one connection may publish as a given user, since everything else finds their publisher by user ID
+        janus_warn!(
+            "Rejecting join from {:p} to room {} as user {}: already publishing.",
+            from.handle,
+            room_id,
+            user_id
+        );
+        return Err(SfuError::AlreadyPublishing);
+    }
+    let conflicting = switchboard.has_conflicting_join(&room_id, &user_id, join_kind);
+    if let Err(e) = check_identity(&config, claims.as_ref(), &user_id, conflicting) {
+        janus_warn!(
+            "Rejecting join from {:p} to room {} as user {}: their user ID is taken by another kind of connection.",
+            from.handle,
+            room_id,
+            user_id
+        );
+        return Err(e);
+    }
+    if join_kind == JoinKind::Publisher && !switchboard.is_expected(&room_id, &user_id) {
+        janus_warn!(
+            "Rejecting join from {:p} to room {} as user {}: not on the roster.",
+            from.handle,
+            room_id,
+            user_id
+        );
+        return Err(SfuError::NotOnRoster);
+    }
+    if join_kind == JoinKind::Publisher && !is_moderator && switchboard.is_locked(&room_id) {
+        janus_warn!("Rejecting join from {:p} to room {} as user {}: room is locked.", from.handle, room_id, user_id);
+        return Err(SfuError::RoomLocked);
+    }
+    if is_waiting && subscribe.as_ref().and_then(|s| s.media.as_ref()).is_some() {
+        return Err(SfuError::Waiting("Cannot subscribe to media while waiting to be admitted."));
+    }
+
+    let join_state = JoinState::new(join_kind, room_id.clone(), user_id.clone(), is_moderator).with_declared_kind(role.is_some());
+    if let Err(_existing) = from.join_state.set(join_state) {
+        return Err(SfuError::AlreadyJoined("Handles may only be in one room at a time!"));
+    }
+    cache_claims(from, token, claims);
+    bump(&COUNTERS.joins, 1);
+
+    if is_waiting {
+        janus_info!("Parking {:p} in the waiting room for room {} as user {}.", from.handle, room_id, user_id);
+        switchboard.join_waiting_room(Arc::clone(from), room_id.clone());
+        *from.subscription.lock().unwrap() = subscribe;
+        if join_kind == JoinKind::Publisher {
+            let notification = json!({ "event": "waiting", "user_id": user_id, "room_id": room_id });
+            notify_except(&notification, &user_id, switchboard.moderators_occupying(&room_id));
+        }
+        return Ok(MessageResponse::msg(body));
     }
 
     if join_kind == JoinKind::Publisher {
         let notification = json!({ "event": "join", "user_id": user_id, "room_id": room_id });
-        switchboard.join_publisher(Arc::clone(from), user_id.clone(), room_id.clone());
+        if switchboard.join_publisher(Arc::clone(from), user_id.clone(), room_id.clone()) {
+            send_notifications(iter::once(room_event(&switchboard, "room_created", &room_id)));
+        }
         notify_except(&notification, &user_id, switchboard.publishers_occupying(&room_id));
+        send_datagram(DatagramKind::Join {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+        });
+        send_webhook_event("join", Some(&user_id), &room_id);
+        if is_rostered {
+            let notification = json!({ "event": "roster_join", "user_id": user_id, "room_id": room_id });
+            notify_except(&notification, &user_id, switchboard.publishers_occupying(&room_id));
+        }
     } else {
         switchboard.join_subscriber(Arc::clone(from), user_id.clone(), room_id.clone());
     }
+    switchboard.restore_user_blocks(&user_id, Instant::now());
 
     if let Some(subscription) = subscribe {
         janus_info!("Processing join-time subscription from {:p}: {:?}.", from.handle, subscription);
-        if let Err(_existing) = from.subscription.set(subscription.clone()) {
-            return Err(From::from("Handles may only subscribe once!"));
-        };
-        if let Some(ref publisher_id) = subscription.media {
-            let publisher = switchboard
-                .get_publisher(publisher_id)
-                .ok_or("Can't subscribe to a nonexistent publisher.")?
-                .clone();
-            let jsep = json!({
-                "type": "offer",
-                "sdp": publisher.subscriber_offer.lock().unwrap().as_ref().unwrap()
-            });
-            switchboard.subscribe_to_user(Arc::clone(from), publisher);
-            return Ok(MessageResponse::new(body, jsep));
+        let mut current_subscription = from.subscription.lock().unwrap();
+        if current_subscription.is_some() {
+            return Err(SfuError::AlreadySubscribed("Handles may only subscribe once!"));
+        }
+        *current_subscription = Some(subscription.clone());
+        let publishers = media_publishers(&config, &switchboard, from, subscription.media_sources())?;
+        if let [publisher] = publishers.as_slice() {
+            let publisher = Arc::clone(publisher);
+            match subscriber_jsep(&publisher) {
+                Ok(jsep) => {
+                    switchboard.subscribe_to_user(Arc::clone(from), publisher);
+                    return Ok(MessageResponse::new(body, jsep));
+                }
+                Err(_) => {
+                    // the join itself worked, so don't fail it; they'll get the offer once the publisher has one
+                    janus_info!("Deferring join-time subscription from {:p} until the publisher is ready.", from.handle);
+                    switchboard.add_pending_subscriber(Arc::clone(from), publisher);
+                }
+            }
+        } else if !publishers.is_empty() {
+            match combined_subscriber_jsep(&publishers) {
+                Ok(jsep) => {
+                    subscribe_to_all(&mut switchboard, from, publishers);
+                    return Ok(MessageResponse::new(body, jsep));
+                }
+                Err(_) => {
+                    // there's no offer to send them until all of the publishers are ready, so they'll have to ask again
+                    janus_info!("Deferring join-time subscription from {:p} until its publishers are ready.", from.handle);
+                }
+            }
         }
     }
 
     Ok(MessageResponse::msg(body))
 }
 
-fn process_kick(from: &Arc<Session>, room_id: RoomId, user_id: UserId, token: String) -> MessageResult {
-    let config = CONFIG.get().unwrap();
-    if let Some(ref key) = config.auth_key {
-        match ValidatedToken::from_str(&token, key) {
-            Ok(tok) => {
-                if tok.kick_users {
-                    janus_info!("Processing kick from {:p} targeting user ID {} in room ID {}.", from.handle, user_id, room_id);
-                    let end_session = gateway_callbacks().end_session;
-                    let switchboard = SWITCHBOARD.read()?;
-                    if let Some(publisher) = switchboard.get_publisher(&user_id) {
-                        janus_info!("Kicking session {:p}.", publisher.handle);
-                        end_session(publisher.as_ptr());
-                    }
-                    if let Some(subscribers) = switchboard.get_subscribers(&user_id) {
-                        for subscriber in subscribers {
-                            janus_info!("Kicking session {:p}.", subscriber.handle);
-                            end_session(subscriber.as_ptr());
-                        }
-                    }
-                } else {
-                    janus_warn!("Ignoring kick from {:p} because they didn't have kick permissions.", from.handle);
-                }
+/// Checks that the bearer of the given token may look at what's going on in rooms without joining. If the server
+/// doesn't require authentication, anyone may; otherwise, only those whose token lets them join rooms.
+fn authorize_viewer(config: &Config, from: &Session, token: Option<String>) -> Result<Option<ValidatedToken>, SfuError> {
+    match (&config.auth, token) {
+        (None, _) => Ok(None),
+        (Some(_), None) => {
+            janus_warn!("Rejecting anonymous room inquiry from {:p}.", from.handle);
+            Err(SfuError::Unauthorized(AuthError::Forbidden("Rejecting anonymous room inquiry!")))
+        }
+        (Some(auth), Some(ref token)) => match session_claims(from, Some(token), auth) {
+            Ok(claims) if claims.join_hub => Ok(Some(claims)),
+            Ok(_) => {
+                janus_warn!("Rejecting room inquiry from {:p} without permission to join.", from.handle);
+                Err(SfuError::Unauthorized(AuthError::Forbidden("Rejecting room inquiry without permission!")))
             }
             Err(e) => {
-                janus_warn!("Ignoring kick from {:p} due to invalid token: {}.", from.handle, e);
+                janus_warn!("Rejecting room inquiry from {:p} due to invalid token: {}.", from.handle, e);
+                Err(SfuError::Unauthorized(AuthError::Malformed("Rejecting room inquiry with invalid token!")))
             }
+        },
+    }
+}
+
+/// Lists the rooms which the bearer of the given claims may join, or all of them if there are no claims to go by.
+fn visible_rooms(switchboard: &Switchboard, claims: Option<&ValidatedToken>) -> Vec<JsonValue> {
+    let mut rooms = switchboard
+        .rooms()
+        .filter(|(room_id, _)| claims.map_or(true, |c| c.may_join(room_id)))
+        .collect::<Vec<_>>();
+    rooms.sort();
+    rooms
+        .into_iter()
+        .map(|(room_id, publisher_count)| json!({ "room_id": room_id, "publisher_count": publisher_count }))
+        .collect()
+}
+
+fn process_list_rooms(from: &Arc<Session>, token: Option<String>) -> MessageResult {
+    janus_verb!("Processing room listing from {:p}.", from.handle);
+    let claims = authorize_viewer(&current_config(), from, token)?;
+    let switchboard = SWITCHBOARD.read()?;
+    Ok(MessageResponse::msg(json!({ "rooms": visible_rooms(&switchboard, claims.as_ref()) })))
+}
+
+/// Lists the users publishing in the given room, if the bearer of the given claims may join it.
+fn visible_users(switchboard: &Switchboard, claims: Option<&ValidatedToken>, room_id: &RoomId) -> Result<Vec<UserId>, SfuError> {
+    if let Some(claims) = claims {
+        if !claims.may_join(room_id) {
+            return Err(SfuError::Unauthorized(AuthError::WrongRoom("Rejecting user listing without permission!")));
         }
-    } else {
-        janus_warn!("Ignoring kick from {:p} because no secret was configured.", from.handle);
     }
-    Ok(MessageResponse::msg(json!({})))
+    let mut users = switchboard.get_room_users(room_id).collect::<Vec<_>>();
+    users.sort();
+    Ok(users)
 }
 
-fn process_block(from: &Arc<Session>, whom: UserId) -> MessageResult {
-    janus_info!("Processing block from {:p} to {}", from.handle, whom);
-    if let Some(joined) = from.join_state.get() {
-        let mut switchboard = SWITCHBOARD.write()?;
-        let event = json!({ "event": "blocked", "by": &joined.user_id });
-        notify_user(&event, &whom, switchboard.publishers_occupying(&joined.room_id));
-        switchboard.establish_block(joined.user_id.clone(), whom);
-        Ok(MessageResponse::msg(json!({})))
-    } else {
-        Err(From::from("Cannot block when not in a room."))
+/// Returns when each of the given users started publishing, in milliseconds since the Unix epoch.
+fn publisher_join_times(switchboard: &Switchboard, users: &[UserId]) -> serde_json::Map<String, JsonValue> {
+    users
+        .iter()
+        .filter_map(|user_id| {
+            let joined = switchboard.get_publisher(user_id)?.join_state.get()?;
+            Some((user_id.clone(), json!(joined.joined_at_millis())))
+        })
+        .collect()
+}
+
+/// Returns the codecs which each of the given users negotiated for publishing, if they've negotiated yet.
+fn negotiated_codecs_by_user(switchboard: &Switchboard, users: &[UserId]) -> serde_json::Map<String, JsonValue> {
+    users
+        .iter()
+        .filter_map(|user_id| {
+            let negotiated = switchboard.get_publisher(user_id)?.negotiated.lock().unwrap().clone()?;
+            Some((user_id.clone(), json!(negotiated)))
+        })
+        .collect()
+}
+
+fn process_list_users(from: &Arc<Session>, room_id: RoomId, token: Option<String>) -> MessageResult {
+    janus_verb!("Processing user listing from {:p} for room {}.", from.handle, room_id);
+    let claims = authorize_viewer(&current_config(), from, token)?;
+    let switchboard = SWITCHBOARD.read()?;
+    let users = visible_users(&switchboard, claims.as_ref(), &room_id)?;
+    let joined_at = publisher_join_times(&switchboard, &users);
+    let codecs = negotiated_codecs_by_user(&switchboard, &users);
+    Ok(MessageResponse::msg(json!({ "users": users, "joined_at": joined_at, "codecs": codecs })))
+}
+
+fn process_room_count(from: &Arc<Session>, room_id: RoomId, token: Option<String>) -> MessageResult {
+    janus_verb!("Processing room count from {:p} for room {}.", from.handle, room_id);
+    if let Some(claims) = authorize_viewer(&current_config(), from, token)? {
+        if !claims.may_join(&room_id) {
+            return Err(SfuError::Unauthorized(AuthError::WrongRoom("Rejecting room count without permission!")));
+        }
     }
+    let (publishers, subscribers) = SWITCHBOARD.read()?.room_counts(&room_id);
+    Ok(MessageResponse::msg(json!({ "publishers": publishers, "subscribers": subscribers })))
 }
 
-fn process_unblock(from: &Arc<Session>, whom: UserId) -> MessageResult {
-    janus_info!("Processing unblock from {:p} to {}", from.handle, whom);
-    if let Some(joined) = from.join_state.get() {
-        let mut switchboard = SWITCHBOARD.write()?;
-        switchboard.lift_block(&joined.user_id, &whom);
-        if let Some(publisher) = switchboard.get_publisher(&whom) {
-            send_fir(&[publisher]);
+/// Returns the claims of the given token, or of the token which the given session joined with or last refreshed if
+/// none is given. The session's token is only decoded once; its claims are reused until they expire, after which a
+/// token must be given again.
+fn session_claims(from: &Session, token: Option<&str>, auth: &AuthConfig) -> Result<ValidatedToken, Box<dyn Error>> {
+    let cached = from.claims.lock().unwrap().clone().filter(|c| token.map_or(true, |t| t == c.token));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    match (cached, token) {
+        (Some(cached), _) if !cached.claims.is_expired_at(now, auth.leeway_secs) => check_revocation(cached.claims),
+        (_, Some(token)) => validate_token(token, auth),
+        (Some(_), None) => Err(Box::new(TokenError::Expired)),
+        (None, None) => Err(From::from("No token was given, and none is on file.")),
+    }
+}
+
+/// Remembers the token which the given session presented, if any, so that later permission checks can reuse its claims.
+fn cache_claims(from: &Session, token: Option<String>, claims: Option<ValidatedToken>) {
+    *from.claims.lock().unwrap() = token.zip(claims).map(|(token, claims)| CachedClaims { token, claims });
+}
+
+/// Makes sure that the given session may moderate the given room, going by the given token, or the one it joined with
+/// or last refreshed if none is given. `what` names the request being checked, for the logs.
+fn require_moderator(from: &Session, room_id: &RoomId, token: Option<&str>, what: &'static str) -> Result<ValidatedToken, SfuError> {
+    require_permission(from, token, what, |claims| claims.may_moderate(room_id))
+}
+
+/// Makes sure that the given token, or the one that the given session joined with or last refreshed if none is given,
+/// passes the given permission check, returning its claims.
+fn require_permission<F>(from: &Session, token: Option<&str>, what: &'static str, permitted: F) -> Result<ValidatedToken, SfuError>
+where
+    F: FnOnce(&ValidatedToken) -> bool,
+{
+    let config = current_config();
+    let auth = config
+        .auth
+        .as_ref()
+        .ok_or(SfuError::NotConfigured("Checking permissions requires a configured secret."))?;
+    match session_claims(from, token, auth) {
+        Ok(claims) if permitted(&claims) => Ok(claims),
+        Ok(_) => {
+            janus_warn!("Rejecting {} from {:p} because they didn't have permission.", what, from.handle);
+            Err(SfuError::Unauthorized(AuthError::Forbidden("Rejecting request without permission!")))
+        }
+        Err(e) => {
+            janus_warn!("Rejecting {} from {:p} due to invalid token: {}.", what, from.handle, e);
+            Err(SfuError::Unauthorized(AuthError::Malformed("Rejecting request with invalid token!")))
         }
-        let event = json!({ "event": "unblocked", "by": &joined.user_id });
-        notify_user(&event, &whom, switchboard.publishers_occupying(&joined.room_id));
-        Ok(MessageResponse::msg(json!({})))
-    } else {
-        Err(From::from("Cannot unblock when not in a room."))
     }
 }
 
-fn process_subscribe(from: &Arc<Session>, what: &Subscription) -> MessageResult {
-    janus_info!("Processing subscription from {:p}: {:?}", from.handle, what);
-    if let Err(_existing) = from.subscription.set(what.clone()) {
-        return Err(From::from("Users may only subscribe once!"));
+/// Checks that the given token lets the given joined session carry on where it is, i.e. in the same room as the same
+/// user, returning its claims.
+fn refreshed_claims(auth: &AuthConfig, joined: &JoinState, token: &str) -> Result<ValidatedToken, SfuError> {
+    let claims = validate_token(token, auth).map_err(|e| {
+        janus_warn!("Rejecting token refresh from user {}. Error: {}", joined.user_id, e);
+        SfuError::Unauthorized(match e.downcast_ref::<TokenError>() {
+            Some(TokenError::Expired) => AuthError::Expired("Rejecting token refresh with expired token!"),
+            Some(_) => AuthError::Forbidden("Rejecting token refresh with unacceptable token!"),
+            None => AuthError::Malformed("Rejecting token refresh with invalid token!"),
+        })
+    })?;
+    if !claims.may_join(&joined.room_id) {
+        return Err(SfuError::Unauthorized(AuthError::WrongRoom("Rejecting token refresh for another room!")));
     }
+    if claims.user_id.is_some() && !claims.may_act_as(&joined.user_id) {
+        return Err(SfuError::Unauthorized(AuthError::Forbidden("Rejecting token refresh for another user!")));
+    }
+    Ok(claims)
+}
 
-    let mut switchboard = SWITCHBOARD.write()?;
-    if let Some(ref publisher_id) = what.media {
-        let publisher = switchboard
-            .get_publisher(publisher_id)
-            .ok_or("Can't subscribe to a nonexistent publisher.")?
-            .clone();
-        let jsep = json!({
-            "type": "offer",
-            "sdp": publisher.subscriber_offer.lock().unwrap().as_ref().unwrap()
-        });
-        switchboard.subscribe_to_user(from.clone(), publisher);
-        return Ok(MessageResponse::new(json!({}), jsep));
+fn process_refresh_token(from: &Arc<Session>, token: String) -> MessageResult {
+    janus_info!("Processing token refresh from {:p}.", from.handle);
+    let joined = from.join_state.get().ok_or(SfuError::NotJoined("Cannot refresh a token when not in a room."))?;
+    let config = current_config();
+    let auth = config
+        .auth
+        .as_ref()
+        .ok_or(SfuError::NotConfigured("Refreshing tokens requires a configured secret."))?;
+    let claims = refreshed_claims(auth, &joined, &token)?;
+    let is_moderator = claims.may_moderate(&joined.room_id);
+    cache_claims(from, Some(token), Some(claims));
+    SWITCHBOARD.write()?.set_moderator(from, is_moderator);
+    Ok(MessageResponse::msg(json!({ "moderator": is_moderator })))
+}
+
+/// Validates the given token against the configured rules, and makes sure that it hasn't been revoked.
+fn validate_token(token: &str, auth: &AuthConfig) -> Result<ValidatedToken, Box<dyn Error>> {
+    check_revocation(ValidatedToken::from_str(token, auth)?)
+}
+
+/// Makes sure that the given claims' token hasn't been revoked.
+fn check_revocation(claims: ValidatedToken) -> Result<ValidatedToken, Box<dyn Error>> {
+    match claims.id {
+        Some(ref id) if REVOKED_TOKEN_IDS.read()?.contains(id) => Err(Box::new(TokenError::Revoked)),
+        _ => Ok(claims),
     }
+}
+
+fn process_revoke_token(from: &Arc<Session>, jti: String, token: String) -> MessageResult {
+    require_permission(from, Some(&token), "revocation", ValidatedToken::may_administer)?;
+    janus_info!("Processing revocation from {:p} of token ID {}.", from.handle, jti);
+    REVOKED_TOKEN_IDS.write()?.insert(jti);
     Ok(MessageResponse::msg(json!({})))
 }
 
-fn process_data(from: &Arc<Session>, whom: Option<UserId>, body: &str) -> MessageResult {
-    janus_huge!("Processing data message from {:p}: {:?}", from.handle, body);
-    let payload = json!({ "event": "data", "body": body });
-    let switchboard = SWITCHBOARD.read().expect("Switchboard lock poisoned; can't continue.");
-    if let Some(joined) = from.join_state.get() {
-        let occupants = switchboard.publishers_occupying(&joined.room_id);
-        if let Some(user_id) = whom {
-            send_data_user(&payload, &user_id, occupants);
-        } else {
-            send_data_except(&payload, &joined.user_id, occupants);
+/// Asks Janus to end the given session.
+fn end_session(session: &Session) {
+    (gateway_callbacks().end_session)(session.as_ptr());
+}
+
+fn send_kick_event(event: &JsonValue, sessions: Vec<&Arc<Session>>) {
+    send_message(event, sessions);
+}
+
+/// Kicks every session the given user has in the given room, publisher and subscribers alike, by passing each to the
+/// given `end` function. Beforehand, a `kicked` event saying who did it, if anyone, is passed to the given `send`
+/// function, once for the rest of the room and once for the kicked sessions themselves. Returns how many sessions were
+/// kicked.
+fn kick_user<S, F>(switchboard: &Switchboard, room_id: &RoomId, user_id: &UserId, by: Option<&UserId>, mut send: S, mut end: F) -> usize
+where
+    S: FnMut(&JsonValue, Vec<&Arc<Session>>),
+    F: FnMut(&Session),
+{
+    let sessions = switchboard.get_user_sessions_in(room_id, user_id).collect::<Vec<_>>();
+    if sessions.is_empty() {
+        return 0;
+    }
+    send_webhook_event("kick", Some(user_id), room_id);
+    let event = json!({ "event": "kicked", "user_id": user_id, "room_id": room_id, "by": by });
+    let notifiees = switchboard.publishers_occupying(room_id).iter().filter(|s| {
+        let subscription_state = s.subscription.lock().unwrap();
+        match (&*subscription_state, s.join_state.get()) {
+            (Some(subscription), Some(joined)) => subscription.notifications && &joined.user_id != user_id,
+            _ => false,
         }
-        Ok(MessageResponse::msg(json!({})))
-    } else {
-        Err(From::from("Cannot send data when not in a room."))
+    });
+    send(&event, notifiees.collect());
+    // the kicked sessions hear about it whether or not they want notifications, since it's the last thing they'll hear
+    send(&event, sessions.clone());
+    for session in &sessions {
+        janus_info!("Kicking session {:p}.", session.handle);
+        end(session);
+        bump(&COUNTERS.kicks, 1);
     }
+    sessions.len()
 }
 
-fn process_message(from: &Arc<Session>, msg: MessageKind) -> MessageResult {
-    match msg {
-        MessageKind::Join {
-            room_id,
-            user_id,
-            subscribe,
-            token,
-        } => process_join(from, room_id, user_id, subscribe, token),
-        MessageKind::Kick { room_id, user_id, token } => process_kick(from, room_id, user_id, token),
-        MessageKind::Subscribe { what } => process_subscribe(from, &what),
-        MessageKind::Block { whom } => process_block(from, whom),
-        MessageKind::Unblock { whom } => process_unblock(from, whom),
-        MessageKind::Data { whom, body } => process_data(from, whom, &body),
+fn process_kick(from: &Arc<Session>, room_id: RoomId, user_id: UserId, token: Option<String>) -> MessageResult {
+    require_moderator(from, &room_id, token.as_deref(), "kick")?;
+    janus_info!("Processing kick from {:p} targeting user ID {} in room ID {}.", from.handle, user_id, room_id);
+    let switchboard = SWITCHBOARD.read()?;
+    let by = from.join_state.get().map(|joined| joined.user_id.clone());
+    kick_user(&switchboard, &room_id, &user_id, by.as_ref(), send_kick_event, end_session);
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_roster(from: &Arc<Session>, room_id: RoomId, user_ids: Vec<UserId>, token: String) -> MessageResult {
+    require_moderator(from, &room_id, Some(&token), "roster")?;
+    janus_info!("Processing roster from {:p} for room ID {}: {:?}.", from.handle, room_id, user_ids);
+    let roster = if user_ids.is_empty() { None } else { Some(user_ids.into_iter().collect()) };
+    SWITCHBOARD.write()?.set_roster(room_id, roster);
+    Ok(MessageResponse::msg(json!({})))
+}
+
+/// Mutes or unmutes the given kind of media from the given user's publisher in the given room, returning the
+/// notification to send the room if that changed anything.
+fn set_muted(switchboard: &Switchboard, room_id: &RoomId, user_id: &UserId, media_kind: MediaKind, muted: bool) -> Result<Option<JsonValue>, SfuError> {
+    let publisher = switchboard
+        .get_publisher(user_id)
+        .filter(|publisher| matches!(publisher.join_state.get(), Some(joined) if &joined.room_id == room_id))
+        .ok_or(SfuError::PublisherNotFound("That user isn't publishing in this room."))?;
+    if !publisher.muted.set(media_kind.is_video(), muted) {
+        return Ok(None);
     }
+    if !muted && media_kind.is_video() {
+        // subscribers can't decode anything until the publisher's next keyframe
+        publisher.keyframe_wanted.store(true, Ordering::Relaxed);
+    }
+    let event = if muted { "mute" } else { "unmute" };
+    Ok(Some(
+        json!({ "event": event, "user_id": user_id, "room_id": room_id, "media_kind": media_kind }),
+    ))
 }
 
-fn process_offer(from: &Session, offer: &Sdp) -> JsepResult {
-    // enforce publication of the codecs that we know our client base will be compatible with
-    janus_info!("Processing JSEP offer from {:p}: {:?}", from.handle, offer);
-    let h264_profile = c_str!("42e01f");
-    let mut answer = answer_sdp!(
-        offer,
-        OfferAnswerParameters::AudioCodec,
-        AUDIO_CODEC.to_cstr().as_ptr(),
-        OfferAnswerParameters::AudioDirection,
-        MediaDirection::JANUS_SDP_RECVONLY,
-        OfferAnswerParameters::VideoCodec,
-        VIDEO_CODEC.to_cstr().as_ptr(),
-        OfferAnswerParameters::H264Profile,
-        h264_profile.as_ptr(),
-        OfferAnswerParameters::VideoDirection,
-        MediaDirection::JANUS_SDP_RECVONLY,
+fn process_mute(from: &Arc<Session>, user_id: UserId, media_kind: MediaKind, muted: bool, token: String) -> MessageResult {
+    let joined = from.join_state.get().ok_or(SfuError::NotJoined("Cannot mute users when not in a room."))?;
+    require_moderator(from, &joined.room_id, Some(&token), "mute")?;
+    janus_info!(
+        "Processing mute from {:p} targeting user ID {}: {:?} {}.",
+        from.handle,
+        user_id,
+        media_kind,
+        muted
     );
-    let audio_payload_type = answer.get_payload_type(AUDIO_CODEC.to_cstr());
-    let video_payload_type = answer.get_payload_type_full(VIDEO_CODEC.to_cstr(), h264_profile);
-    if let Some(pt) = audio_payload_type {
-        // todo: figure out some more principled way to keep track of this stuff per room
-        let settings = CString::new(format!("{} stereo=0; sprop-stereo=0; usedtx=1;", pt))?;
-        answer.add_attribute(pt, c_str!("fmtp"), &settings);
+    let switchboard = SWITCHBOARD.read()?;
+    if let Some(notification) = set_muted(&switchboard, &joined.room_id, &user_id, media_kind, muted)? {
+        notify_everyone(&notification, switchboard.publishers_occupying(&joined.room_id));
     }
+    Ok(MessageResponse::msg(json!({})))
+}
 
-    janus_verb!("Providing answer to {:p}: {:?}", from.handle, answer);
-
-    // it's fishy, but we provide audio and video streams to subscribers regardless of whether the client is sending
-    // audio and video right now or not -- this is basically working around pains in renegotiation to do with
-    // reordering/removing media streams on an existing connection. to improve this, we'll want to keep the same offer
-    // around and mutate it, instead of generating a new one every time the publisher changes something.
+/// Mutes the given kind of media from every publisher in the given room who isn't a moderator, returning the
+/// notifications to send the room for those who weren't already muted.
+fn mute_all(switchboard: &Switchboard, room_id: &RoomId, media_kind: MediaKind) -> Result<Vec<JsonValue>, SfuError> {
+    let mut notifications = Vec::new();
+    for publisher in switchboard.publishers_occupying(room_id) {
+        if let Some(joined) = publisher.join_state.get() {
+            if !joined.is_moderator() {
+                notifications.extend(set_muted(switchboard, room_id, &joined.user_id, media_kind, true)?);
+            }
+        }
+    }
+    Ok(notifications)
+}
 
-    let mut subscriber_offer = offer_sdp!(
-        ptr::null(),
-        answer.c_addr as *const _,
-        OfferAnswerParameters::Data,
-        1,
-        OfferAnswerParameters::Audio,
-        1,
-        OfferAnswerParameters::AudioCodec,
-        AUDIO_CODEC.to_cstr().as_ptr(),
-        OfferAnswerParameters::AudioPayloadType,
-        audio_payload_type.unwrap_or(100),
-        OfferAnswerParameters::AudioDirection,
-        MediaDirection::JANUS_SDP_SENDONLY,
-        OfferAnswerParameters::Video,
-        1,
-        OfferAnswerParameters::VideoCodec,
-        VIDEO_CODEC.to_cstr().as_ptr(),
-        OfferAnswerParameters::VideoPayloadType,
-        video_payload_type.unwrap_or(100),
-        OfferAnswerParameters::H264Profile,
-        h264_profile.as_ptr(),
-        OfferAnswerParameters::VideoDirection,
-        MediaDirection::JANUS_SDP_SENDONLY,
+fn process_mute_all(from: &Arc<Session>, media_kind: MediaKind, token: String) -> MessageResult {
+    let joined = from.join_state.get().ok_or(SfuError::NotJoined("Cannot mute users when not in a room."))?;
+    require_moderator(from, &joined.room_id, Some(&token), "mute of everyone")?;
+    janus_info!(
+        "Processing mute of everyone from {:p} in room ID {}: {:?}.",
+        from.handle,
+        joined.room_id,
+        media_kind
     );
-    if let Some(pt) = audio_payload_type {
-        // todo: figure out some more principled way to keep track of this stuff per room
-        let settings = CString::new(format!("{} stereo=0; sprop-stereo=0; usedtx=1;", pt))?;
-        subscriber_offer.add_attribute(pt, c_str!("fmtp"), &settings);
+    let switchboard = SWITCHBOARD.read()?;
+    let notifications = mute_all(&switchboard, &joined.room_id, media_kind)?;
+    for notification in &notifications {
+        notify_everyone(notification, switchboard.publishers_occupying(&joined.room_id));
     }
-    janus_verb!("Storing subscriber offer for {:p}: {:?}", from.handle, subscriber_offer);
+    let muted = notifications.iter().map(|n| n["user_id"].clone()).collect::<Vec<_>>();
+    Ok(MessageResponse::msg(json!({ "muted": muted })))
+}
 
-    let switchboard = SWITCHBOARD.read().expect("Switchboard lock poisoned; can't continue.");
-    let jsep = json!({ "type": "offer", "sdp": subscriber_offer });
-    send_offer(&jsep, switchboard.subscribers_to(from));
-    *from.subscriber_offer.lock().unwrap() = Some(subscriber_offer);
-    Ok(json!({ "type": "answer", "sdp": answer }))
+fn process_lock_room(from: &Arc<Session>, room_id: RoomId, locked: bool, token: String) -> MessageResult {
+    require_moderator(from, &room_id, Some(&token), "room lock")?;
+    janus_info!("Processing room lock from {:p} for room ID {}: {}.", from.handle, room_id, locked);
+    SWITCHBOARD.write()?.set_locked(room_id, locked);
+    Ok(MessageResponse::msg(json!({})))
 }
 
-fn process_answer(from: &Session, answer: &Sdp) -> JsepResult {
-    janus_info!("Processing JSEP answer from {:p}: {:?}", from.handle, answer);
-    Ok(json!({})) // todo: check that this guy should actually be sending us an answer?
+fn process_presenter_only_audio(from: &Arc<Session>, room_id: RoomId, enabled: bool, token: String) -> MessageResult {
+    require_moderator(from, &room_id, Some(&token), "presenter-only audio")?;
+    janus_info!("Processing presenter-only audio from {:p} for room ID {}: {}.", from.handle, room_id, enabled);
+    SWITCHBOARD.write()?.set_presenter_only_audio(room_id, enabled);
+    Ok(MessageResponse::msg(json!({})))
 }
 
-fn process_jsep(from: &Session, jsep: JsepKind) -> JsepResult {
-    match jsep {
-        JsepKind::Offer { sdp } => process_offer(from, &sdp),
-        JsepKind::Answer { sdp } => process_answer(from, &sdp),
-    }
+fn process_waiting_room(from: &Arc<Session>, room_id: RoomId, enabled: bool, token: String) -> MessageResult {
+    require_moderator(from, &room_id, Some(&token), "waiting room")?;
+    janus_info!("Processing waiting room from {:p} for room ID {}: {}.", from.handle, room_id, enabled);
+    let mut switchboard = SWITCHBOARD.write()?;
+    let released = switchboard.set_waiting_room(room_id, enabled);
+    admit_sessions(&mut switchboard, released);
+    Ok(MessageResponse::msg(json!({})))
 }
 
-fn push_response(from: &Session, txn: &TransactionId, body: &JsonValue, jsep: Option<JsonValue>) -> JanusResult {
-    let push_event = gateway_callbacks().push_event;
-    let jsep = jsep.unwrap_or_else(|| json!({}));
-    janus_huge!("Responding to {:p} for txid {}: body={}, jsep={}", from.handle, txn, body, jsep);
-    JanusError::from(push_event(
-        from.as_ptr(),
-        &PLUGIN as *const Plugin as *mut Plugin,
-        txn.0,
-        serde_to_jansson(body).as_mut_ref(),
-        serde_to_jansson(&jsep).as_mut_ref(),
-    ))
+fn process_recording(from: &Arc<Session>, room_id: RoomId, enabled: bool, token: String) -> MessageResult {
+    let config = current_config();
+    let dir = config
+        .recording_dir
+        .as_ref()
+        .ok_or(SfuError::NotConfigured("Recording requires a configured recording directory."))?;
+    require_permission(from, Some(&token), "recording", |claims| claims.may_record(&room_id))?;
+    janus_info!("Processing recording from {:p} for room ID {}: {}.", from.handle, room_id, enabled);
+    let mut switchboard = SWITCHBOARD.write()?;
+    switchboard.set_recording(room_id.clone(), enabled);
+    for publisher in switchboard.publishers_occupying(&room_id) {
+        if enabled {
+            start_recording(publisher, dir, &config);
+        } else {
+            stop_recording(publisher);
+        }
+    }
+    Ok(MessageResponse::msg(json!({})))
 }
 
-fn try_parse_jansson<T: DeserializeOwned>(json: &JanssonValue) -> Result<Option<T>, Box<dyn Error>> {
-    jansson_to_str(json).and_then(|x| OptionalField::try_parse(x.to_string_lossy()))
+/// Starts recording the given publisher's media to the given directory, unless it's being recorded already.
+fn start_recording(publisher: &Session, dir: &Path, config: &Config) {
+    let joined = match publisher.join_state.get() {
+        Some(joined) if joined.kind == JoinKind::Publisher => joined,
+        _ => return,
+    };
+    let mut recording = publisher.recording.lock().unwrap();
+    if recording.is_some() {
+        return;
+    }
+    let config = config.for_room(&joined.room_id);
+    let audio_codec = config.audio_codec.to_cstr().to_string_lossy();
+    let video_codec = config.video_codec.to_cstr().to_string_lossy();
+    match PublisherRecording::create(dir, &joined.room_id, &joined.user_id, &audio_codec, &video_codec) {
+        Ok(started) => {
+            janus_info!("Recording {:p} in room {} to {:?}.", publisher.handle, joined.room_id, dir);
+            *recording = Some(started);
+            publisher.is_recording.store(true, Ordering::Relaxed);
+        }
+        Err(e) => janus_err!("Failed to start recording {:p}: {}", publisher.handle, e),
+    }
 }
 
-fn handle_message_async(RawMessage { jsep, msg, txn, from }: RawMessage) -> JanusResult {
-    if let Some(ref from) = from.upgrade() {
-        janus_huge!("Processing txid {} from {:p}: msg={:?}, jsep={:?}", txn, from.handle, msg, jsep);
-        if !from.destroyed.load(Ordering::Relaxed) {
-            let parsed_msg = msg.and_then(|x| try_parse_jansson(&x).transpose());
-            let parsed_jsep = jsep.and_then(|x| try_parse_jansson(&x).transpose());
-            let msg_result = parsed_msg.map(|x| x.and_then(|msg| process_message(from, msg)));
-            let jsep_result = parsed_jsep.map(|x| x.and_then(|jsep| process_jsep(from, jsep)));
-            return match (msg_result, jsep_result) {
-                (Some(Err(msg_err)), _) => {
-                    let resp = json!({ "success": false, "error": { "msg": format!("{}", msg_err) }});
-                    push_response(from, &txn, &resp, None)
-                }
-                (_, Some(Err(jsep_err))) => {
-                    let resp = json!({ "success": false, "error": { "msg": format!("{}", jsep_err) }});
-                    push_response(from, &txn, &resp, None)
-                }
-                (Some(Ok(msg_resp)), None) => {
-                    let msg_body = msg_resp.body.map_or(json!({ "success": true }), |x| json!({ "success": true, "response": x }));
-                    push_response(from, &txn, &msg_body, msg_resp.jsep)
-                }
-                (None, Some(Ok(jsep_resp))) => push_response(from, &txn, &json!({ "success": true }), Some(jsep_resp)),
-                (Some(Ok(msg_resp)), Some(Ok(jsep_resp))) => {
-                    let msg_body = msg_resp.body.map_or(json!({ "success": true }), |x| json!({ "success": true, "response": x }));
-                    push_response(from, &txn, &msg_body, Some(jsep_resp))
-                }
-                (None, None) => push_response(from, &txn, &json!({ "success": true }), None),
-            };
+/// Stops recording the given publisher's media, if it's being recorded, flushing everything recorded so far.
+fn stop_recording(publisher: &Session) {
+    let mut recording = publisher.recording.lock().unwrap();
+    publisher.is_recording.store(false, Ordering::Relaxed);
+    if let Some(mut current) = recording.take() {
+        if let Err(e) = current.stop() {
+            janus_err!("Failed to finish recording {:p}: {}", publisher.handle, e);
         }
     }
+}
 
-    // getting messages for destroyed connections is slightly concerning,
-    // because messages shouldn't be backed up for that long, so warn if it happens
-    janus_warn!("Message with txid {} received for destroyed session; discarding.", txn);
+/// Checks that the given waiting session may still be let into the room it joined, now that it's being admitted.
+fn check_admission(switchboard: &Switchboard, joined: &JoinState) -> Result<(), SfuError> {
+    // another connection may have started publishing as the same user while this one waited
+    if joined.kind == JoinKind::Publisher && switchboard.get_publisher(&joined.user_id).is_some() {
+        return Err(SfuError::AlreadyPublishing);
+    }
     Ok(())
 }
 
-extern "C" fn handle_message(
-    handle: *mut PluginSession,
-    transaction: *mut c_char,
-    message: *mut RawJanssonValue,
-    jsep: *mut RawJanssonValue,
-) -> *mut RawPluginResult {
-    let result = match unsafe { Session::from_ptr(handle) } {
-        Ok(sess) => {
-            let msg = RawMessage {
-                from: Arc::downgrade(&sess),
-                txn: TransactionId(transaction),
-                msg: unsafe { JanssonValue::from_raw(message) },
-                jsep: unsafe { JanssonValue::from_raw(jsep) },
-            };
-            janus_verb!("Queueing signalling message on {:p}.", sess.handle);
-            let message_count = MESSAGE_COUNTER.fetch_add(1, Ordering::Relaxed);
-            let senders = MESSAGE_SENDERS.get().unwrap();
-            let sender = &senders[message_count % senders.len()];
-            sender.send(msg).ok();
-
-            PluginResult::ok_wait(Some(c_str!("Processing.")))
+/// Lets the given sessions, which were waiting to be admitted, into the rooms they joined. Any which can't be let in
+/// after all are denied and ended instead.
+fn admit_sessions(switchboard: &mut Switchboard, sessions: Vec<Arc<Session>>) {
+    for session in sessions {
+        if let Some(joined) = session.join_state.get() {
+            if let Err(e) = check_admission(switchboard, &joined) {
+                janus_warn!(
+                    "Turning away {:p} from room {} as user {}: {}",
+                    session.handle,
+                    joined.room_id,
+                    joined.user_id,
+                    e
+                );
+                let error = json!({ "msg": e.to_string(), "code": e.code() });
+                let event = json!({ "event": "denied", "user_id": &joined.user_id, "room_id": &joined.room_id, "error": error });
+                send_message(&event, iter::once(&session));
+                end_session(&session);
+                continue;
+            }
+            janus_info!("Admitting {:p} to room {} as user {}.", session.handle, joined.room_id, joined.user_id);
+            let event = json!({ "event": "admitted", "user_id": &joined.user_id, "room_id": &joined.room_id });
+            send_message(&event, iter::once(&session));
+            match joined.kind {
+                JoinKind::Publisher => {
+                    let notification = json!({ "event": "join", "user_id": &joined.user_id, "room_id": &joined.room_id });
+                    if switchboard.join_publisher(Arc::clone(&session), joined.user_id.clone(), joined.room_id.clone()) {
+                        send_notifications(iter::once(room_event(switchboard, "room_created", &joined.room_id)));
+                    }
+                    notify_except(&notification, &joined.user_id, switchboard.publishers_occupying(&joined.room_id));
+                    send_datagram(DatagramKind::Join {
+                        room_id: joined.room_id.clone(),
+                        user_id: joined.user_id.clone(),
+                    });
+                    send_webhook_event("join", Some(&joined.user_id), &joined.room_id);
+                }
+                JoinKind::Subscriber => switchboard.join_subscriber(Arc::clone(&session), joined.user_id.clone(), joined.room_id.clone()),
+            }
+            switchboard.restore_user_blocks(&joined.user_id, Instant::now());
         }
-        Err(_) => PluginResult::error(c_str!("No handle associated with message!")),
-    };
-    result.into_raw()
+    }
 }
 
-extern "C" fn handle_admin_message(_message: *mut RawJanssonValue) -> *mut RawJanssonValue {
-    // for now we don't use this for anything
-    let output = json!({});
-    serde_to_jansson(&output).into_raw()
+fn process_admit(from: &Arc<Session>, user_id: UserId) -> MessageResult {
+    janus_info!("Processing admission from {:p} of {}.", from.handle, user_id);
+    match from.join_state.get() {
+        Some(joined) if joined.is_moderator() => {
+            let mut switchboard = SWITCHBOARD.write()?;
+            let admitted = switchboard.take_waiting_sessions(&joined.room_id, &user_id);
+            if admitted.is_empty() {
+                return Err(SfuError::NotWaiting);
+            }
+            admit_sessions(&mut switchboard, admitted);
+            Ok(MessageResponse::msg(json!({})))
+        }
+        Some(_) => {
+            janus_warn!("Rejecting admission from {:p} because they didn't have moderator permissions.", from.handle);
+            Err(SfuError::Unauthorized(AuthError::Forbidden("Rejecting admission without permission!")))
+        }
+        None => Err(SfuError::NotJoined("Cannot admit users when not in a room.")),
+    }
 }
 
-static PLUGIN: Plugin = build_plugin!(
+fn process_deny(from: &Arc<Session>, user_id: UserId) -> MessageResult {
+    janus_info!("Processing denial from {:p} of {}.", from.handle, user_id);
+    match from.join_state.get() {
+        Some(joined) if joined.is_moderator() => {
+            let mut switchboard = SWITCHBOARD.write()?;
+            let denied = switchboard.take_waiting_sessions(&joined.room_id, &user_id);
+            if denied.is_empty() {
+                return Err(SfuError::NotWaiting);
+            }
+            let event = json!({ "event": "denied", "user_id": &user_id, "room_id": &joined.room_id });
+            send_message(&event, &denied);
+            for session in denied {
+                janus_info!("Ending denied session {:p}.", session.handle);
+                end_session(&session);
+            }
+            Ok(MessageResponse::msg(json!({})))
+        }
+        Some(_) => {
+            janus_warn!("Rejecting denial from {:p} because they didn't have moderator permissions.", from.handle);
+            Err(SfuError::Unauthorized(AuthError::Forbidden("Rejecting denial without permission!")))
+        }
+        None => Err(SfuError::NotJoined("Cannot deny users when not in a room.")),
+    }
+}
+
+fn process_leave(from: &Arc<Session>) -> MessageResult {
+    janus_info!("Processing leave from {:p}.", from.handle);
+    let mut switchboard = SWITCHBOARD.write()?;
+    if from.join_state.get().is_none() {
+        return Err(SfuError::NotJoined("Cannot leave when not in a room."));
+    }
+    let (_, notifications) = leave_room(&mut switchboard, from);
+    send_notifications(notifications);
+    from.join_state.take();
+    *from.subscription.lock().unwrap() = None;
+    from.switching.reset();
+    from.multi_source.store(false, Ordering::Relaxed);
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_change_room(from: &Arc<Session>, room_id: RoomId, token: Option<String>) -> MessageResult {
+    let (response, notifications) = change_room(from, room_id, token)?;
+    send_notifications(notifications);
+    Ok(response)
+}
+
+/// Moves the given session into another room, returning the response along with the notifications that its old and
+/// new roommates should get.
+fn change_room(from: &Arc<Session>, room_id: RoomId, token: Option<String>) -> Result<(MessageResponse, Vec<Notification>), SfuError> {
+    validate_id("room_id", &room_id, current_config().max_id_length)?;
+    janus_info!("Processing room change from {:p} to room {}.", from.handle, room_id);
+    let joined = from.join_state.get().ok_or(SfuError::NotJoined("Cannot change rooms when not in a room."))?;
+    if joined.room_id == room_id {
+        return Err(SfuError::AlreadyJoined("Already in that room."));
+    }
+    let config = current_config();
+    let config = config.for_room(&room_id);
+    let claims = authorize_join(&config, from, &room_id, &joined.user_id, token.as_deref())?;
+    let is_moderator = matches!(claims, Some(ref c) if c.may_moderate(&room_id));
+
+    let mut switchboard = SWITCHBOARD.write()?;
+    if switchboard.is_waiting(from) {
+        return Err(SfuError::Waiting("Cannot change rooms while waiting to be admitted."));
+    }
+    if !is_moderator && switchboard.is_waiting_room(&room_id) {
+        return Err(SfuError::Waiting("Cannot change into a room with a waiting room; join it instead."));
+    }
+    let room_users = switchboard.get_room_users(&room_id).collect::<HashSet<_>>();
+    // the connection that's moving is already counted, so leave it out
+    let ccu = if config.count_subscribers_in_ccu {
+        switchboard.total_connections().saturating_sub(1)
+    } else {
+        switchboard.get_all_users().filter(|u| *u != &joined.user_id).count()
+    };
+    check_capacity(&config, joined.kind, is_moderator, room_users.len(), ccu)?;
+    match joined.kind {
+        JoinKind::Subscriber => check_subscriber_capacity(&config, switchboard.count_subscribers_in(&room_id))?,
+        JoinKind::Publisher => {
+            check_room_creation(claims.as_ref(), room_users.len())?;
+            check_room_change_limit(&config, &switchboard, &joined.room_id, &room_id)?;
+            if !switchboard.is_expected(&room_id, &joined.user_id) {
+                return Err(SfuError::NotOnRoster);
+            }
+            if !is_moderator && switchboard.is_locked(&room_id) {
+                return Err(SfuError::RoomLocked);
+            }
+        }
+    }
+
+    let orphans = switchboard.change_room(from, room_id.clone(), is_moderator);
+    let mut notifications = Vec::new();
+    cache_claims(from, token, claims);
+    if let Some(subscription) = from.subscription.lock().unwrap().as_mut() {
+        subscription.media = None;
+    }
+    from.multi_source.store(false, Ordering::Relaxed);
+    if joined.kind == JoinKind::Publisher {
+        stop_recording(from);
+        if let Some(ref dir) = config.recording_dir {
+            if switchboard.is_recording(&room_id) {
+                start_recording(from, dir, &config);
+            }
+        }
+        notifications.push(Notification {
+            event: json!({ "event": "publisher_gone", "user_id": &joined.user_id, "room_id": &joined.room_id }),
+            recipients: orphans,
+        });
+        if switchboard.publishers_occupying(&joined.room_id).is_empty() {
+            notifications.push(room_event(&switchboard, "room_destroyed", &joined.room_id));
+        }
+        if switchboard.publishers_occupying(&room_id).len() == 1 {
+            notifications.push(room_event(&switchboard, "room_created", &room_id));
+        }
+        notifications.extend(announce_leave(&switchboard, &joined));
+        let notifiees = switchboard.publishers_occupying(&room_id).iter().filter(|s| {
+            let subscription_state = s.subscription.lock().unwrap();
+            match (&*subscription_state, s.join_state.get()) {
+                (Some(subscription), Some(roommate)) => subscription.notifications && roommate.user_id != joined.user_id,
+                _ => false,
+            }
+        });
+        notifications.push(Notification {
+            event: json!({ "event": "join", "user_id": &joined.user_id, "room_id": &room_id }),
+            recipients: notifiees.cloned().collect(),
+        });
+        send_datagram(DatagramKind::Join {
+            room_id: room_id.clone(),
+            user_id: joined.user_id.clone(),
+        });
+        send_webhook_event("join", Some(&joined.user_id), &room_id);
+    }
+
+    let body = json!({
+        "users": { room_id.as_str(): room_users },
+        "limits": config.limits(),
+        "presenter_only_audio": switchboard.is_presenter_only_audio(&room_id),
+    });
+    Ok((MessageResponse::msg(body), notifications))
+}
+
+fn process_block(from: &Arc<Session>, whom: UserId) -> MessageResult {
+    janus_info!("Processing block from {:p} to {}", from.handle, whom);
+    if let Some(joined) = from.join_state.get() {
+        let mut switchboard = SWITCHBOARD.write()?;
+        let event = json!({ "event": "blocked", "by": &joined.user_id });
+        notify_user(&event, &whom, switchboard.publishers_occupying(&joined.room_id));
+        switchboard.establish_block(joined.user_id.clone(), whom.clone());
+        for (subscriber, publisher) in drop_blocked_media(&mut switchboard, &joined.user_id, &whom) {
+            // the subscriber's media would just freeze, so renegotiate it away to make the block clear to them
+            match inactive_jsep(&publisher) {
+                Ok(Some(jsep)) => {
+                    send_offer(&jsep, iter::once(&subscriber));
+                }
+                Ok(None) => (),
+                Err(e) => janus_warn!("Failed to renegotiate blocked media for {:p}: {}", subscriber.handle, e),
+            }
+        }
+        Ok(MessageResponse::msg(json!({})))
+    } else {
+        Err(SfuError::NotJoined("Cannot block when not in a room."))
+    }
+}
+
+/// Stops each of the given users' subscribers from getting media from the other, returning each subscriber which had
+/// been getting it, along with the publisher it came from. Their subscriptions still name that publisher, so that they
+/// can answer the offer we send them and switch to someone else afterwards.
+fn drop_blocked_media(switchboard: &mut Switchboard, blocker: &UserId, miscreant: &UserId) -> Vec<(Arc<Session>, Arc<Session>)> {
+    let dropped = switchboard.drop_mutual_subscriptions(blocker, miscreant);
+    for (subscriber, _) in &dropped {
+        subscriber.switching.reset();
+    }
+    dropped
+}
+
+/// Returns an offer for subscribers to the given publisher which makes all of its media inactive, if it has any.
+fn inactive_jsep(publisher: &Session) -> Result<Option<JsonValue>, SfuError> {
+    match *publisher.subscriber_offer.lock().unwrap() {
+        Some(ref offer) => Ok(Some(json!({ "type": "offer", "sdp": deactivate_sdp(offer)? }))),
+        None => Ok(None),
+    }
+}
+
+fn process_unblock(from: &Arc<Session>, whom: UserId) -> MessageResult {
+    janus_info!("Processing unblock from {:p} to {}", from.handle, whom);
+    if let Some(joined) = from.join_state.get() {
+        let mut switchboard = SWITCHBOARD.write()?;
+        switchboard.lift_block(&joined.user_id, &whom);
+        if let Some(publisher) = switchboard.get_publisher(&whom) {
+            send_fir(&[publisher]);
+        }
+        let event = json!({ "event": "unblocked", "by": &joined.user_id });
+        notify_user(&event, &whom, switchboard.publishers_occupying(&joined.room_id));
+        Ok(MessageResponse::msg(json!({})))
+    } else {
+        Err(SfuError::NotJoined("Cannot unblock when not in a room."))
+    }
+}
+
+fn process_list_blocks(from: &Arc<Session>) -> MessageResult {
+    janus_verb!("Processing block listing from {:p}.", from.handle);
+    let joined = from.join_state.get().ok_or(SfuError::NotJoined("Cannot list blocks when not in a room."))?;
+    let switchboard = SWITCHBOARD.read()?;
+    let mut blocking = switchboard.blocked_by(&joined.user_id).to_vec();
+    let mut blocked_by = switchboard.blockers_of(&joined.user_id).to_vec();
+    // blocking someone twice records the block twice, but there's only one block to speak of
+    for users in &mut [&mut blocking, &mut blocked_by] {
+        users.sort();
+        users.dedup();
+    }
+    Ok(MessageResponse::msg(json!({ "blocking": blocking, "blocked_by": blocked_by })))
+}
+
+/// Returns a token bucket limiting a new session's subscription changes, or none if they aren't limited.
+fn subscription_bucket(config: &Config, now: Instant) -> Option<TokenBucket> {
+    match config.max_subscription_rate {
+        0 => None,
+        rate => Some(TokenBucket::new(rate, f64::from(rate), now)),
+    }
+}
+
+/// Returns an error if the given session is changing its subscription too often.
+fn check_subscription_rate(from: &Session) -> Result<(), SfuError> {
+    if let Some(ref mut bucket) = *from.subscription_changes.lock().unwrap() {
+        if !bucket.try_take(Instant::now()) {
+            janus_warn!("Rejecting subscription change from {:p} due to rate limit.", from.handle);
+            return Err(SfuError::RateLimited);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the JSEP offer for subscribing to the given publisher, or an error if it hasn't negotiated its media yet.
+fn subscriber_jsep(publisher: &Session) -> Result<JsonValue, SfuError> {
+    match *publisher.subscriber_offer.lock().unwrap() {
+        Some(ref offer) => Ok(json!({ "type": "offer", "sdp": offer })),
+        None => Err(SfuError::PublisherNotReady),
+    }
+}
+
+/// Looks up the publishers of the given users' media, making sure that each can take on the given subscriber if it
+/// isn't already getting their media.
+fn media_publishers(config: &Config, switchboard: &Switchboard, subscriber: &Session, user_ids: &[UserId]) -> Result<Vec<Arc<Session>>, SfuError> {
+    let mut publishers = Vec::new();
+    for user_id in user_ids {
+        let publisher = switchboard
+            .get_publisher(user_id)
+            .ok_or(SfuError::PublisherNotFound("Can't subscribe to a nonexistent publisher."))?;
+        if !switchboard.publishers_to(subscriber).contains(publisher) {
+            check_publisher_capacity(config, switchboard, publisher)?;
+        }
+        publishers.push(Arc::clone(publisher));
+    }
+    Ok(publishers)
+}
+
+/// Returns the JSEP offer for subscribing to all of the given publishers' media on one connection, or an error if any
+/// of them hasn't negotiated its media yet.
+fn combined_subscriber_jsep(publishers: &[Arc<Session>]) -> Result<JsonValue, SfuError> {
+    let mut offers = Vec::new();
+    for publisher in publishers {
+        match *publisher.subscriber_offer.lock().unwrap() {
+            Some(ref offer) => offers.push(sdp_text(offer)?),
+            None => return Err(SfuError::PublisherNotReady),
+        }
+    }
+    Ok(json!({ "type": "offer", "sdp": combine(&offers) }))
+}
+
+/// Subscribes the given subscriber to each of the given publishers' media which it isn't getting yet. With more than
+/// one publisher, each stream is relayed as it was sent, since they're on media sections of their own rather than
+/// taking turns on the same ones.
+fn subscribe_to_all(switchboard: &mut Switchboard, subscriber: &Arc<Session>, publishers: Vec<Arc<Session>>) {
+    subscriber.multi_source.store(publishers.len() > 1, Ordering::Relaxed);
+    for publisher in publishers {
+        if !switchboard.publishers_to(subscriber).contains(&publisher) {
+            publisher.keyframe_wanted.store(true, Ordering::Relaxed);
+            switchboard.subscribe_to_user(Arc::clone(subscriber), publisher);
+        }
+    }
+}
+
+/// Describes the given session's subscription, and the publishers whose media it's receiving. Sessions which haven't
+/// joined a room aren't subscribed to anything.
+fn describe_subscriptions(switchboard: &Switchboard, sess: &Session) -> JsonValue {
+    if sess.join_state.get().is_none() {
+        return json!({ "subscription": null, "media": [] });
+    }
+    let subscription = sess.subscription.lock().unwrap().clone();
+    let media = switchboard
+        .publishers_to(sess)
+        .iter()
+        .filter_map(|publisher| publisher.join_state.get().map(|joined| joined.user_id.clone()))
+        .collect::<Vec<_>>();
+    json!({ "subscription": subscription, "media": media })
+}
+
+fn process_query_subscriptions(from: &Arc<Session>) -> MessageResult {
+    janus_verb!("Processing subscription query from {:p}.", from.handle);
+    let switchboard = SWITCHBOARD.read()?;
+    Ok(MessageResponse::msg(describe_subscriptions(&switchboard, from)))
+}
+
+/// Returns the publishers whose media the given subscriber is receiving over the connection it negotiated with us, in
+/// the order it subscribed to them, which is the order of their media in its offer.
+fn offered_publishers(switchboard: &Switchboard, subscriber: &Session) -> Result<Vec<Arc<Session>>, SfuError> {
+    if subscriber.sendrecv.load(Ordering::Relaxed) {
+        return Err(SfuError::Invalid("Publishers' connections receive media without an offer of their own.".into()));
+    }
+    let subscription = subscriber.subscription.lock().unwrap();
+    let user_ids = subscription.as_ref().map(Subscription::media_sources).unwrap_or(&[]);
+    if user_ids.is_empty() {
+        return Err(SfuError::NotSubscribed("Cannot request an offer without a media subscription."));
+    }
+    let mut publishers = Vec::new();
+    for user_id in user_ids {
+        let publisher = switchboard
+            .get_publisher(user_id)
+            .ok_or(SfuError::PublisherNotFound("Can't request an offer for a nonexistent publisher."))?;
+        // a subscription which is still waiting on its publisher gets the offer as soon as there is one
+        if !switchboard.publishers_to(subscriber).contains(publisher) {
+            return Err(SfuError::PublisherNotReady);
+        }
+        publishers.push(Arc::clone(publisher));
+    }
+    Ok(publishers)
+}
+
+fn process_request_offer(from: &Arc<Session>) -> MessageResult {
+    janus_info!("Processing offer request from {:p}.", from.handle);
+    let switchboard = SWITCHBOARD.read()?;
+    let jsep = match offered_publishers(&switchboard, from)?.as_slice() {
+        [publisher] if !from.multi_source.load(Ordering::Relaxed) => subscriber_jsep(publisher)?,
+        publishers => combined_subscriber_jsep(publishers)?,
+    };
+    Ok(MessageResponse::new(json!({}), jsep))
+}
+
+fn process_ping(from: &Arc<Session>) -> MessageResult {
+    janus_huge!("Processing ping from {:p}.", from.handle);
+    Ok(MessageResponse::msg(json!({ "pong": true })))
+}
+
+fn process_subscribe(from: &Arc<Session>, what: &Subscription) -> MessageResult {
+    janus_info!("Processing subscription from {:p}: {:?}", from.handle, what);
+    let mut switchboard = SWITCHBOARD.write()?;
+    if what.media.is_some() && switchboard.is_waiting(from) {
+        return Err(SfuError::Waiting("Cannot subscribe to media while waiting to be admitted."));
+    }
+    let mut subscription = from.subscription.lock().unwrap();
+    if let Some(ref existing) = *subscription {
+        if existing != what {
+            return Err(SfuError::AlreadySubscribed("Users may only subscribe once!"));
+        }
+        // the client probably lost our response to their first attempt, so just tell them the same thing again
+        janus_verb!("Repeating subscription response for {:p}.", from.handle);
+        match what.media_sources() {
+            [] => (),
+            [publisher_id] => {
+                let publisher = switchboard
+                    .get_publisher(publisher_id)
+                    .ok_or(SfuError::PublisherNotFound("Can't subscribe to a nonexistent publisher."))?;
+                if !from.sendrecv.load(Ordering::Relaxed) {
+                    return Ok(MessageResponse::new(json!({}), subscriber_jsep(publisher)?));
+                }
+            }
+            user_ids => {
+                // a join-time subscription to several publishers waits for the client to ask again once they're ready
+                let publishers = media_publishers(&current_config(), &switchboard, from, user_ids)?;
+                let jsep = combined_subscriber_jsep(&publishers)?;
+                subscribe_to_all(&mut switchboard, from, publishers);
+                return Ok(MessageResponse::new(json!({}), jsep));
+            }
+        }
+        return Ok(MessageResponse::msg(json!({})));
+    }
+
+    check_subscription_rate(from)?;
+    if what.media_sources().len() > 1 {
+        if from.sendrecv.load(Ordering::Relaxed) {
+            return Err(SfuError::Invalid("Publishers' connections can only receive one user's media.".into()));
+        }
+        let publishers = media_publishers(&current_config(), &switchboard, from, what.media_sources())?;
+        // don't record the subscription until all of them are ready, so that the client can try again
+        let jsep = combined_subscriber_jsep(&publishers)?;
+        subscribe_to_all(&mut switchboard, from, publishers);
+        *subscription = Some(what.clone());
+        return Ok(MessageResponse::new(json!({}), jsep));
+    }
+    if let Some(publisher_id) = what.media_sources().first() {
+        let publisher = switchboard
+            .get_publisher(publisher_id)
+            .ok_or(SfuError::PublisherNotFound("Can't subscribe to a nonexistent publisher."))?
+            .clone();
+        // don't record the subscription until we know it'll work, so that the client can try again
+        check_publisher_capacity(&current_config(), &switchboard, &publisher)?;
+        *subscription = Some(what.clone());
+        // a publisher whose connection can receive media gets it there, so there's nothing more to negotiate
+        if from.sendrecv.load(Ordering::Relaxed) {
+            switchboard.subscribe_to_user(from.clone(), Arc::clone(&publisher));
+            publisher.keyframe_wanted.store(true, Ordering::Relaxed);
+            return Ok(MessageResponse::msg(json!({})));
+        }
+        return match subscriber_jsep(&publisher) {
+            Ok(jsep) => {
+                switchboard.subscribe_to_user(from.clone(), publisher);
+                Ok(MessageResponse::new(json!({}), jsep))
+            }
+            Err(e) => {
+                // keep the subscription, so that they get the offer as soon as there is one, or can ask again for it
+                janus_info!("Deferring subscription from {:p} until the publisher is ready.", from.handle);
+                switchboard.add_pending_subscriber(from.clone(), publisher);
+                Err(e)
+            }
+        };
+    }
+    *subscription = Some(what.clone());
+    Ok(MessageResponse::msg(json!({})))
+}
+
+/// Returns the codecs in the given publisher's subscriber offer, or an error if it hasn't negotiated its media yet.
+fn publisher_codecs(publisher: &Session) -> Result<Vec<String>, SfuError> {
+    match *publisher.subscriber_offer.lock().unwrap() {
+        Some(ref offer) => Ok(codecs(&sdp_text(offer)?)),
+        None => Err(SfuError::PublisherNotReady),
+    }
+}
+
+/// Switches the given subscriber to the given publisher's media. Unless it's renegotiating to receive it, its media
+/// headers are rewritten so that it sees one continuous stream. Either way, the publisher is asked for a keyframe, since
+/// the subscriber can't show its video until it gets one.
+fn switch_media_source(switchboard: &mut Switchboard, subscriber: &Arc<Session>, publisher: Arc<Session>, renegotiate: bool) {
+    if renegotiate {
+        subscriber.switching.reset();
+    }
+    // layers are numbered per publisher, so a choice made for one doesn't carry over to another
+    subscriber.layer_choice.set(None);
+    publisher.keyframe_wanted.store(true, Ordering::Relaxed);
+    switchboard.switch_publisher(Arc::clone(subscriber), publisher);
+}
+
+fn process_switch_media(from: &Arc<Session>, media: UserId) -> MessageResult {
+    janus_info!("Processing media switch from {:p} to {}.", from.handle, media);
+    let mut switchboard = SWITCHBOARD.write()?;
+    if switchboard.is_waiting(from) {
+        return Err(SfuError::Waiting("Cannot switch media while waiting to be admitted."));
+    }
+    let mut subscription = from.subscription.lock().unwrap();
+    let current = subscription
+        .as_mut()
+        .ok_or(SfuError::NotSubscribed("Cannot switch media without a subscription."))?;
+    match current.media_sources() {
+        [] => Err(SfuError::NotSubscribed("Cannot switch media without a media subscription.")),
+        [publisher_id] if publisher_id == &media => Ok(MessageResponse::msg(json!({}))),
+        [_] => {
+            check_subscription_rate(from)?;
+            let publisher = switchboard
+                .get_publisher(&media)
+                .ok_or(SfuError::PublisherNotFound("Can't subscribe to a nonexistent publisher."))?
+                .clone();
+            check_publisher_capacity(&current_config(), &switchboard, &publisher)?;
+            // if the new publisher's media uses the same codecs, the subscriber can keep its connection as it is
+            let renegotiate = match switchboard.publishers_to(from).first() {
+                Some(previous) => publisher_codecs(previous)? != publisher_codecs(&publisher)?,
+                None => true,
+            };
+            let jsep = if renegotiate { Some(subscriber_jsep(&publisher)?) } else { None };
+            switch_media_source(&mut switchboard, from, publisher, renegotiate);
+            current.media = Some(vec![media]);
+            Ok(MessageResponse { body: Some(json!({})), jsep })
+        }
+        _ => Err(SfuError::Invalid("Cannot switch media while receiving several users' media.".into())),
+    }
+}
+
+fn process_set_layer(from: &Arc<Session>, media: UserId, spatial: u8) -> MessageResult {
+    janus_info!("Processing layer choice from {:p} for {}: {}.", from.handle, media, spatial);
+    let switchboard = SWITCHBOARD.read()?;
+    match *from.subscription.lock().unwrap() {
+        Some(ref subscription) if subscription.media_sources().contains(&media) => (),
+        _ => return Err(SfuError::NotSubscribed("Cannot choose a layer of media you aren't subscribed to.")),
+    }
+    from.layer_choice.set(Some(spatial));
+    let mut layers = 0;
+    for publisher in switchboard.publishers_to(from) {
+        // the subscriber can't decode the new layer until it gets a keyframe from it
+        publisher.keyframe_wanted.store(true, Ordering::Relaxed);
+        layers = publisher.simulcast.count();
+    }
+    Ok(MessageResponse::msg(json!({ "layers": layers })))
+}
+
+/// Builds the event which relays a data message's text and binary content, whichever it has.
+fn data_payload(body: Option<String>, body_bytes: Option<Binary>) -> Result<JsonValue, SfuError> {
+    if body.is_none() && body_bytes.is_none() {
+        return Err(SfuError::Invalid("Data messages must have a body or body_bytes.".into()));
+    }
+    let mut payload = json!({ "event": "data" });
+    if let Some(body) = body {
+        payload["body"] = json!(body);
+    }
+    if let Some(body_bytes) = body_bytes {
+        payload["body_bytes"] = json!(body_bytes);
+    }
+    Ok(payload)
+}
+
+/// Returns an error if a data message with the given content would be bigger than the configured limit.
+fn check_data_size(config: &Config, body: Option<&str>, body_bytes: Option<&Binary>) -> Result<(), SfuError> {
+    let size = body.map_or(0, str::len) + body_bytes.map_or(0, |b| b.0.len());
+    if config.max_data_message_bytes > 0 && size > config.max_data_message_bytes {
+        return Err(SfuError::TooLarge(config.max_data_message_bytes));
+    }
+    Ok(())
+}
+
+fn process_data(from: &Arc<Session>, whom: Option<UserId>, body: Option<String>, body_bytes: Option<Binary>) -> MessageResult {
+    janus_huge!("Processing data message from {:p}: {:?} {:?}", from.handle, body, body_bytes);
+    check_data_size(&current_config(), body.as_deref(), body_bytes.as_ref())?;
+    let payload = data_payload(body, body_bytes)?;
+    let switchboard = SWITCHBOARD.read()?;
+    if switchboard.is_waiting(from) {
+        return Err(SfuError::Waiting("Cannot send data while waiting to be admitted."));
+    }
+    if let Some(joined) = from.join_state.get() {
+        let delivery = if let Some(user_id) = whom {
+            send_data_user(&payload, &user_id, switchboard.get_user_sessions_in(&joined.room_id, &user_id))?
+        } else {
+            send_data_except(&payload, &joined.user_id, switchboard.occupants_of(&joined.room_id))
+        };
+        Ok(MessageResponse::msg(json!({ "reached": delivery.reached, "failed": delivery.failed })))
+    } else {
+        Err(SfuError::NotJoined("Cannot send data when not in a room."))
+    }
+}
+
+fn process_message(from: &Arc<Session>, msg: MessageKind) -> MessageResult {
+    match msg {
+        MessageKind::Join {
+            room_id,
+            user_id,
+            subscribe,
+            token,
+            role,
+        } => process_join(from, room_id, user_id, subscribe, token, role),
+        MessageKind::ListRooms { token } => process_list_rooms(from, token),
+        MessageKind::ListUsers { room_id, token } => process_list_users(from, room_id, token),
+        MessageKind::RoomCount { room_id, token } => process_room_count(from, room_id, token),
+        MessageKind::Leave {} => process_leave(from),
+        MessageKind::ChangeRoom { room_id, token } => process_change_room(from, room_id, token),
+        MessageKind::Kick { room_id, user_id, token } => process_kick(from, room_id, user_id, token),
+        MessageKind::RefreshToken { token } => process_refresh_token(from, token),
+        MessageKind::Roster { room_id, user_ids, token } => process_roster(from, room_id, user_ids, token),
+        MessageKind::RevokeToken { jti, token } => process_revoke_token(from, jti, token),
+        MessageKind::PresenterOnlyAudio { room_id, enabled, token } => process_presenter_only_audio(from, room_id, enabled, token),
+        MessageKind::WaitingRoom { room_id, enabled, token } => process_waiting_room(from, room_id, enabled, token),
+        MessageKind::LockRoom { room_id, locked, token } => process_lock_room(from, room_id, locked, token),
+        MessageKind::Mute { user_id, media_kind, token } => process_mute(from, user_id, media_kind, true, token),
+        MessageKind::MuteAll { media_kind, token } => process_mute_all(from, media_kind, token),
+        MessageKind::Unmute { user_id, media_kind, token } => process_mute(from, user_id, media_kind, false, token),
+        MessageKind::StartRecording { room_id, token } => process_recording(from, room_id, true, token),
+        MessageKind::StopRecording { room_id, token } => process_recording(from, room_id, false, token),
+        MessageKind::AdmitUser { user_id } => process_admit(from, user_id),
+        MessageKind::DenyUser { user_id } => process_deny(from, user_id),
+        MessageKind::Subscribe { what } => process_subscribe(from, &what),
+        MessageKind::QuerySubscriptions {} => process_query_subscriptions(from),
+        MessageKind::RequestOffer {} => process_request_offer(from),
+        MessageKind::Ping {} => process_ping(from),
+        MessageKind::SwitchMedia { media } => process_switch_media(from, media),
+        MessageKind::SetLayer { media, spatial } => process_set_layer(from, media, spatial),
+        MessageKind::Block { whom } => process_block(from, whom),
+        MessageKind::Unblock { whom } => process_unblock(from, whom),
+        MessageKind::ListBlocks {} => process_list_blocks(from),
+        MessageKind::Data { whom, body, body_bytes } => process_data(from, whom, body, body_bytes),
+    }
+}
+
+/// The format parameters to add to the given payload type of the configured audio codec, if there are any to add.
+fn audio_fmtp(config: &Config, payload_type: i32) -> Option<String> {
+    match config.audio_codec {
+        // todo: figure out some more principled way to keep track of this stuff per room
+        AudioCodec::Opus => Some(format!(
+            "{} stereo={}; sprop-stereo={}; usedtx={};",
+            payload_type, config.opus_stereo as u8, config.opus_sprop_stereo as u8, config.opus_usedtx as u8
+        )),
+        _ => None,
+    }
+}
+
+/// The profile which participants must use with the given video codec, if it has profiles. For H.264, this is
+/// constrained baseline, which every browser can decode.
+fn video_profile(codec: VideoCodec) -> Option<&'static CStr> {
+    match codec {
+        VideoCodec::H264 => Some(c_str!("42e01f")),
+        _ => None,
+    }
+}
+
+/// Describes what a publisher's offer was answered with, given the codecs we asked for and the payload types which the
+/// answer ended up using for them.
+fn negotiated_codecs(audio_codec: AudioCodec, video_codec: VideoCodec, audio_payload_type: Option<i32>, video_payload_type: Option<i32>) -> NegotiatedCodecs {
+    NegotiatedCodecs {
+        audio_codec: audio_codec.to_cstr().to_string_lossy().into_owned(),
+        audio_payload_type,
+        video_codec: video_codec.to_cstr().to_string_lossy().into_owned(),
+        video_payload_type,
+        video_profile: video_profile(video_codec).map(|p| p.to_string_lossy().into_owned()),
+    }
+}
+
+/// Generates the offer which subscribers to a publisher will be given, based on the answer to the publisher's offer.
+fn make_subscriber_offer(
+    config: &Config,
+    answer: &Sdp,
+    kinds: MediaKinds,
+    audio_payload_type: Option<i32>,
+    video_payload_type: Option<i32>,
+) -> Result<Sdp, Box<dyn Error>> {
+    // it's fishy, but we provide each kind of media that the publisher offered to subscribers regardless of whether
+    // the client is sending it right now or not -- this is basically working around pains in renegotiation to do with
+    // reordering/removing media streams on an existing connection. to improve this, we'll want to keep the same offer
+    // around and mutate it, instead of generating a new one every time the publisher changes something. kinds of
+    // media which the publisher never offered, e.g. video from a phone bridge, are left out, rather than offering
+    // subscribers a stream which will never have anything on it.
+
+    let (audio_codec, video_codec) = (config.audio_codec, config.video_codec);
+    let mut subscriber_offer = offer_sdp!(
+        ptr::null(),
+        answer.c_addr as *const _,
+        OfferAnswerParameters::Data,
+        1,
+        OfferAnswerParameters::Audio,
+        c_int::from(kinds.audio),
+        OfferAnswerParameters::AudioCodec,
+        audio_codec.to_cstr().as_ptr(),
+        OfferAnswerParameters::AudioPayloadType,
+        audio_payload_type.unwrap_or(100),
+        OfferAnswerParameters::AudioDirection,
+        MediaDirection::JANUS_SDP_SENDONLY,
+        OfferAnswerParameters::Video,
+        c_int::from(kinds.video),
+        OfferAnswerParameters::VideoCodec,
+        video_codec.to_cstr().as_ptr(),
+        OfferAnswerParameters::VideoPayloadType,
+        video_payload_type.unwrap_or(100),
+        OfferAnswerParameters::H264Profile,
+        video_profile(video_codec).map_or(ptr::null(), CStr::as_ptr),
+        OfferAnswerParameters::VideoDirection,
+        MediaDirection::JANUS_SDP_SENDONLY,
+    );
+    if let Some(pt) = audio_payload_type {
+        if let Some(settings) = audio_fmtp(&config, pt) {
+            subscriber_offer.add_attribute(pt, c_str!("fmtp"), &CString::new(settings)?);
+        }
+    }
+    Ok(subscriber_offer)
+}
+
+/// The direction to answer publishers' media with: they always send it, and may receive it too if configured to.
+fn publisher_direction(config: &Config) -> MediaDirection {
+    if config.publisher_sendrecv {
+        MediaDirection::JANUS_SDP_SENDRECV
+    } else {
+        MediaDirection::JANUS_SDP_RECVONLY
+    }
+}
+
+/// Makes sure that each kind of media in the given offer could be answered with the codec configured for it, i.e. that
+/// the answer has a payload type for it, since otherwise the publisher's stream would be unusable.
+fn check_offered_codecs(config: &Config, offer: &str, audio_payload_type: Option<i32>, video_payload_type: Option<i32>) -> Result<(), SfuError> {
+    let required = [
+        ("audio", audio_payload_type, config.audio_codec.to_cstr()),
+        ("video", video_payload_type, config.video_codec.to_cstr()),
+    ];
+    for &(kind, payload_type, codec) in &required {
+        if payload_type.is_none() && has_media(offer, kind) {
+            let offered = codecs(offer)
+                .iter()
+                .filter_map(|c| c.strip_prefix(kind)?.trim().split('/').next().map(String::from))
+                .collect::<Vec<_>>();
+            let codec = codec.to_string_lossy();
+            return Err(SfuError::CodecMismatch(format!(
+                "Offered {} codecs ({}) don't include {}, which this room requires.",
+                kind,
+                offered.join(", "),
+                codec
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Makes sure that a session which declared itself a publisher offers some media to publish, rather than just data.
+fn check_offered_media(joined: &JoinState, kinds: MediaKinds) -> Result<(), SfuError> {
+    if joined.declared_kind && joined.kind == JoinKind::Publisher && !kinds.audio && !kinds.video {
+        return Err(SfuError::Invalid("Publishers must offer audio or video.".into()));
+    }
+    Ok(())
+}
+
+fn process_offer(from: &Arc<Session>, offer: &Sdp) -> JsepResult {
+    // enforce publication of the codecs that we know our client base will be compatible with
+    janus_info!("Processing JSEP offer from {:p}: {:?}", from.handle, offer);
+    let config = current_config();
+    // publishers who join before offering get their room's settings; otherwise, they get the general ones
+    let config = match from.join_state.get() {
+        Some(joined) => config.for_room(&joined.room_id),
+        None => Cow::Borrowed(config.as_ref()),
+    };
+    let (audio_codec, video_codec) = (config.audio_codec, config.video_codec);
+    let video_profile = video_profile(video_codec);
+    let direction = publisher_direction(&config);
+    let offer_text = sdp_text(offer)?;
+    // only answer and relay the kinds of media that the publisher actually offered
+    let kinds = MediaKinds::of(&offer_text);
+    if let Some(joined) = from.join_state.get() {
+        check_offered_media(&joined, kinds)?;
+    }
+    let mut answer = answer_sdp!(
+        offer,
+        OfferAnswerParameters::Audio,
+        c_int::from(kinds.audio),
+        OfferAnswerParameters::AudioCodec,
+        audio_codec.to_cstr().as_ptr(),
+        OfferAnswerParameters::AudioDirection,
+        direction,
+        OfferAnswerParameters::Video,
+        c_int::from(kinds.video),
+        OfferAnswerParameters::VideoCodec,
+        video_codec.to_cstr().as_ptr(),
+        OfferAnswerParameters::H264Profile,
+        video_profile.map_or(ptr::null(), CStr::as_ptr),
+        OfferAnswerParameters::VideoDirection,
+        direction,
+    );
+    let audio_payload_type = answer.get_payload_type(audio_codec.to_cstr());
+    let video_payload_type = match video_profile {
+        Some(profile) => answer.get_payload_type_full(video_codec.to_cstr(), profile),
+        None => answer.get_payload_type(video_codec.to_cstr()),
+    };
+    check_offered_codecs(&config, &offer_text, audio_payload_type, video_payload_type)?;
+    let negotiated = negotiated_codecs(audio_codec, video_codec, audio_payload_type, video_payload_type);
+    janus_verb!("Negotiated codecs with {:p}: {:?}", from.handle, negotiated);
+    *from.negotiated.lock().unwrap() = Some(negotiated);
+    if let Some(pt) = audio_payload_type {
+        if let Some(settings) = audio_fmtp(&config, pt) {
+            answer.add_attribute(pt, c_str!("fmtp"), &CString::new(settings)?);
+        }
+    }
+
+    janus_verb!("Providing answer to {:p}: {:?}", from.handle, answer);
+
+    if config.active_speaker_interval_ms > 0 {
+        if let Some(id) = audio_level_extension_id(&offer_text) {
+            from.audio_level.extension_id.store(id, Ordering::Relaxed);
+        }
+    }
+    from.simulcast.negotiate(&offer_text);
+    // media relayed to a sendrecv publisher has to use the payload types in this answer, not those of its source
+    from.sendrecv.store(config.publisher_sendrecv, Ordering::Relaxed);
+    if config.publisher_sendrecv {
+        from.switching
+            .set_payload_types(audio_payload_type.map(|pt| pt as u8), video_payload_type.map(|pt| pt as u8));
+    }
+
+    // if this publisher is quickly reconnecting, reuse their old subscriber offer, so that subscribers can keep it
+    let retained = match from.join_state.get() {
+        Some(joined) if joined.kind == JoinKind::Publisher => SWITCHBOARD.write()?.take_retained_offer(&joined.user_id, Instant::now()),
+        _ => None,
+    };
+    let subscriber_offer = match retained {
+        Some(offer) => {
+            janus_info!("Reusing retained subscriber offer for {:p}.", from.handle);
+            offer
+        }
+        None => {
+            let fresh = make_subscriber_offer(&config, &answer, kinds, audio_payload_type, video_payload_type)?;
+            // if this is a renegotiation, subscribers need an offer which they can apply to their existing connection
+            match from.subscriber_offer.lock().unwrap().as_ref() {
+                Some(previous) => renegotiate_sdp(previous, &fresh)?,
+                None => fresh,
+            }
+        }
+    };
+    janus_verb!("Storing subscriber offer for {:p}: {:?}", from.handle, subscriber_offer);
+
+    let mut switchboard = SWITCHBOARD.write()?;
+    if let (Some(joined), Some(dir)) = (from.join_state.get(), config.recording_dir.as_ref()) {
+        if switchboard.is_recording(&joined.room_id) {
+            start_recording(from, dir, &config);
+        }
+    }
+    let jsep = json!({ "type": "offer", "sdp": subscriber_offer });
+    *from.subscriber_offer.lock().unwrap() = Some(subscriber_offer);
+    // anyone who tried to subscribe before we had an offer gets it along with everyone else
+    let admitted = switchboard.admit_pending_subscribers(from);
+    if !admitted.is_empty() {
+        janus_info!("Subscribing {} waiting subscriber(s) to {:p}.", admitted.len(), from.handle);
+    }
+    send_offer(&jsep, switchboard.subscribers_to(from));
+    Ok(json!({ "type": "answer", "sdp": answer }))
+}
+
+/// Returns an error unless the given session is one that we've sent an offer to, i.e. a subscriber to someone's media.
+fn check_answerable(from: &Session) -> Result<(), SfuError> {
+    match from.join_state.get() {
+        None => Err(SfuError::NotJoined("Cannot answer before joining a room.")),
+        Some(joined) if joined.kind == JoinKind::Publisher => Err(SfuError::Invalid("Publishers make offers; they may not answer.".into())),
+        Some(_) => match *from.subscription.lock().unwrap() {
+            Some(Subscription { media: Some(_), .. }) => Ok(()),
+            _ => Err(SfuError::NotSubscribed("Cannot answer without a media subscription.")),
+        },
+    }
+}
+
+fn process_answer(from: &Session, answer: &Sdp) -> JsepResult {
+    janus_info!("Processing JSEP answer from {:p}: {:?}", from.handle, answer);
+    if let Err(e) = check_answerable(from) {
+        janus_warn!("Rejecting JSEP answer from {:p}: {}", from.handle, e);
+        return Err(e);
+    }
+    // Janus applies the answer to the connection itself; all we need to do is accept it
+    Ok(json!({}))
+}
+
+fn process_jsep(from: &Arc<Session>, jsep: JsepKind) -> JsepResult {
+    match jsep {
+        JsepKind::Offer { sdp } => process_offer(from, &sdp),
+        JsepKind::Answer { sdp } => process_answer(from, &sdp),
+    }
+}
+
+fn push_response(from: &Session, txn: &TransactionId, body: &JsonValue, jsep: Option<JsonValue>) -> JanusResult {
+    let push_event = gateway_callbacks().push_event;
+    let jsep = jsep.unwrap_or_else(|| json!({}));
+    janus_huge!("Responding to {:p} for txid {}: body={}, jsep={}", from.handle, txn, body, jsep);
+    JanusError::from(push_event(
+        from.as_ptr(),
+        &PLUGIN as *const Plugin as *mut Plugin,
+        txn.0,
+        serde_to_jansson(body).as_mut_ref(),
+        serde_to_jansson(&jsep).as_mut_ref(),
+    ))
+}
+
+fn try_parse_jansson<T: DeserializeOwned>(json: &JanssonValue) -> Result<Option<T>, SfuError> {
+    let parsed = jansson_to_str(json).and_then(|x| OptionalField::try_parse(x.to_string_lossy()));
+    // anything we can't parse is the client's mistake
+    parsed.map_err(|e| SfuError::Invalid(e.to_string()))
+}
+
+/// Runs the given handler, logging and counting it if it takes longer than the given threshold. A zero threshold
+/// disables timing entirely.
+fn time_handler<T>(from: &Session, txn: &TransactionId, kind: &str, threshold: Duration, handler: impl FnOnce() -> T) -> T {
+    if threshold == Duration::default() {
+        return handler();
+    }
+    let start = Instant::now();
+    let result = handler();
+    let elapsed = start.elapsed();
+    if elapsed > threshold {
+        let total = SLOW_MESSAGE_COUNTER.fetch_add(1, Ordering::Relaxed) + 1;
+        janus_warn!(
+            "Slow {} handler for txid {} from {:p}: took {} ms ({} slow handlers so far).",
+            kind,
+            txn,
+            from.handle,
+            elapsed.as_millis(),
+            total
+        );
+    }
+    result
+}
+
+/// Builds the response to a message which couldn't be processed, with a machine-readable code saying what went wrong.
+fn error_response(err: &SfuError) -> JsonValue {
+    json!({ "success": false, "error": { "msg": err.to_string(), "code": err.code() } })
+}
+
+fn handle_message_async(RawMessage { jsep, msg, txn, from }: RawMessage) -> JanusResult {
+    if let Some(ref from) = from.upgrade() {
+        janus_huge!("Processing txid {} from {:p}: msg={:?}, jsep={:?}", txn, from.handle, msg, jsep);
+        if !from.destroyed.load(Ordering::Relaxed) {
+            let threshold = Duration::from_millis(current_config().slow_message_threshold_ms);
+            let parsed_msg = msg.and_then(|x| try_parse_jansson(&x).transpose());
+            let parsed_jsep = jsep.and_then(|x| try_parse_jansson(&x).transpose());
+            let msg_result = parsed_msg.map(|x| x.and_then(|msg| time_handler(from, &txn, "message", threshold, || process_message(from, msg))));
+            let jsep_result = parsed_jsep.map(|x| x.and_then(|jsep| time_handler(from, &txn, "JSEP", threshold, || process_jsep(from, jsep))));
+            return match (msg_result, jsep_result) {
+                (Some(Err(msg_err)), _) => push_response(from, &txn, &error_response(&msg_err), None),
+                (_, Some(Err(jsep_err))) => push_response(from, &txn, &error_response(&jsep_err), None),
+                (Some(Ok(msg_resp)), None) => {
+                    let msg_body = msg_resp.body.map_or(json!({ "success": true }), |x| json!({ "success": true, "response": x }));
+                    push_response(from, &txn, &msg_body, msg_resp.jsep)
+                }
+                (None, Some(Ok(jsep_resp))) => push_response(from, &txn, &json!({ "success": true }), Some(jsep_resp)),
+                (Some(Ok(msg_resp)), Some(Ok(jsep_resp))) => {
+                    let msg_body = msg_resp.body.map_or(json!({ "success": true }), |x| json!({ "success": true, "response": x }));
+                    push_response(from, &txn, &msg_body, Some(jsep_resp))
+                }
+                (None, None) => push_response(from, &txn, &json!({ "success": true }), None),
+            };
+        }
+    }
+
+    // getting messages for destroyed connections is slightly concerning,
+    // because messages shouldn't be backed up for that long, so warn if it happens
+    janus_warn!("Message with txid {} received for destroyed session; discarding.", txn);
+    Ok(())
+}
+
+extern "C" fn handle_message(
+    handle: *mut PluginSession,
+    transaction: *mut c_char,
+    message: *mut RawJanssonValue,
+    jsep: *mut RawJanssonValue,
+) -> *mut RawPluginResult {
+    let result = match unsafe { Session::from_ptr(handle) } {
+        Ok(sess) => {
+            let msg = RawMessage {
+                from: Arc::downgrade(&sess),
+                txn: TransactionId(transaction),
+                msg: unsafe { JanssonValue::from_raw(message) },
+                jsep: unsafe { JanssonValue::from_raw(jsep) },
+            };
+            janus_verb!("Queueing signalling message on {:p}.", sess.handle);
+            sess.last_seen.touch(Instant::now());
+            let sender = MESSAGE_THREADS.get().and_then(|threads| threads.sender(|n| message_thread_for(handle, n)));
+            let queued = match sender {
+                Some(sender) => queue_message(&sender, msg),
+                None => Err(c_str!("Message processing has stopped!")),
+            };
+            match queued {
+                Ok(()) => PluginResult::ok_wait(Some(c_str!("Processing."))),
+                Err(reason) => {
+                    janus_warn!("Refusing signalling message on {:p}: {}", sess.handle, reason.to_string_lossy());
+                    PluginResult::error(reason)
+                }
+            }
+        }
+        Err(_) => PluginResult::error(c_str!("No handle associated with message!")),
+    };
+    result.into_raw()
+}
+
+/// Reads the gauges reported in metrics and stats off the given switchboard.
+fn current_gauges(switchboard: &Switchboard) -> Gauges {
+    let publishers = switchboard.get_all_users().count();
+    Gauges {
+        publishers,
+        subscribers: switchboard.total_connections() - publishers,
+        rooms: switchboard.room_count(),
+    }
+}
+
+/// Answers an operator's admin command from the state of the given switchboard.
+fn process_admin_command(switchboard: &Switchboard, command: AdminCommand) -> JsonValue {
+    match command {
+        AdminCommand::ListRooms => json!({ "rooms": visible_rooms(switchboard, None) }),
+        AdminCommand::RoomInfo { room_id } => {
+            let mut users = switchboard.get_room_users(&room_id).collect::<Vec<_>>();
+            users.sort();
+            let (publishers, subscribers) = switchboard.room_counts(&room_id);
+            json!({
+                "room_id": room_id,
+                "users": users,
+                "publishers": publishers,
+                "subscribers": subscribers,
+                "presenter_only_audio": switchboard.is_presenter_only_audio(&room_id),
+                "waiting_room": switchboard.is_waiting_room(&room_id),
+            })
+        }
+        AdminCommand::Metrics => json!({ "metrics": metrics::render(&COUNTERS, current_gauges(switchboard)) }),
+        AdminCommand::Ccu => json!({
+            "users": switchboard.get_all_users().count(),
+            "connections": switchboard.total_connections(),
+        }),
+        // operators are trusted, so unlike kicks over signalling, these don't need a token
+        AdminCommand::Kick { room_id, user_id } => {
+            json!({ "kicked": kick_user(switchboard, &room_id, &user_id, None, send_kick_event, end_session) })
+        }
+    }
+}
+
+fn handle_admin_command(message: &JanssonValue) -> Result<JsonValue, SfuError> {
+    let text = jansson_to_str(message)?;
+    let command = serde_json::from_str(&text.to_string_lossy()).map_err(|e| SfuError::Invalid(e.to_string()))?;
+    janus_info!("Processing admin command: {:?}", command);
+    let switchboard = SWITCHBOARD.read()?;
+    Ok(process_admin_command(&switchboard, command))
+}
+
+extern "C" fn handle_admin_message(message: *mut RawJanssonValue) -> *mut RawJanssonValue {
+    let output = match unsafe { JanssonValue::from_raw(message) } {
+        Some(message) => {
+            let result = handle_admin_command(&message);
+            // the gateway still owns the message, so don't free it
+            message.into_raw();
+            match result {
+                Ok(response) => json!({ "success": true, "response": response }),
+                Err(e) => error_response(&e),
+            }
+        }
+        None => error_response(&SfuError::from("Admin message was empty.")),
+    };
+    serde_to_jansson(&output).into_raw()
+}
+
+static PLUGIN: Plugin = build_plugin!(
     LibraryMetadata {
         api_version: 15,
         version: 1,
@@ -816,4 +2933,2333 @@ static PLUGIN: Plugin = build_plugin!(
     query_session
 );
 
-export_plugin!(&PLUGIN);
+export_plugin!(&PLUGIN);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use sessions::tests::{make_session, make_unjoined_session};
+    use std::sync::{PoisonError, RwLockReadGuard, RwLockWriteGuard};
+
+    /// Guards the global switchboard and config. Tests which change the config hold it exclusively, and every other
+    /// test which goes through the globals shares it, so that none of them sees a config meant for another test.
+    static GLOBALS: Lazy<RwLock<()>> = Lazy::new(|| RwLock::new(()));
+
+    fn share_globals() -> RwLockReadGuard<'static, ()> {
+        GLOBALS.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn own_globals() -> RwLockWriteGuard<'static, ()> {
+        GLOBALS.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn join_publisher(room: &str, user: &str) -> Arc<Session> {
+        let session = make_session(JoinKind::Publisher, room, user);
+        SWITCHBOARD.write().unwrap().join_publisher(Arc::clone(&session), user.into(), room.into());
+        session
+    }
+
+    mod join {
+
+        use super::*;
+        use config::{LimitScope, RoomConfig};
+
+        #[test]
+        fn joins_without_a_subscription_get_the_default() {
+            let notifications = Subscription {
+                notifications: true,
+                data: true,
+                media: None,
+            };
+            let config = Config {
+                default_subscription: Some(notifications.clone()),
+                ..Config::default()
+            };
+            // even a default with data doesn't make someone a publisher
+            assert_eq!(join_subscription(&config, None, None), (JoinKind::Subscriber, Some(notifications)));
+            assert_eq!(join_subscription(&Config::default(), None, None), (JoinKind::Subscriber, None));
+            let publish = Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            };
+            assert_eq!(join_subscription(&config, None, Some(publish.clone())), (JoinKind::Publisher, Some(publish)));
+        }
+
+        #[test]
+        fn declared_roles_override_inferred_ones() {
+            let config = Config::default();
+            let data = Subscription {
+                notifications: true,
+                data: true,
+                media: None,
+            };
+            let (kind, _) = join_subscription(&config, Some(JoinKind::Subscriber), Some(data));
+            assert_eq!(kind, JoinKind::Subscriber);
+            let (kind, _) = join_subscription(&config, Some(JoinKind::Publisher), None);
+            assert_eq!(kind, JoinKind::Publisher);
+        }
+
+        #[test]
+        fn declared_roles_are_kept() {
+            let _globals = share_globals();
+            let data = Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            };
+            let from = make_unjoined_session();
+            let room_id = RoomId::from("declared_roles_are_kept");
+            process_join(&from, room_id, "10".into(), Some(data), None, Some(JoinKind::Subscriber)).unwrap();
+            let joined = from.join_state.get().unwrap();
+            assert_eq!(joined.kind, JoinKind::Subscriber);
+            assert!(joined.declared_kind);
+        }
+
+        #[test]
+        fn declared_publishers_must_offer_media() {
+            let none = MediaKinds { audio: false, video: false };
+            let audio = MediaKinds { audio: true, video: false };
+            let declared = JoinState::new(JoinKind::Publisher, "alpha".into(), "10".into(), false).with_declared_kind(true);
+            let err = SfuError::Invalid("Publishers must offer audio or video.".into());
+            assert_eq!(check_offered_media(&declared, none), Err(err));
+            assert_eq!(check_offered_media(&declared, audio), Ok(()));
+            // publishers who were only inferred to be publishers may just want data, as they always could
+            let inferred = JoinState::new(JoinKind::Publisher, "alpha".into(), "10".into(), false);
+            assert_eq!(check_offered_media(&inferred, none), Ok(()));
+        }
+
+        #[test]
+        fn joining_with_invalid_ids_fails() {
+            let _globals = share_globals();
+            let overlong = "x".repeat(Config::default().max_id_length + 1);
+            let invalid = [
+                ("", "invalid_ids_a", "room_id must not be empty."),
+                ("invalid_ids", "", "user_id must not be empty."),
+                (overlong.as_str(), "invalid_ids_a", "room_id must be at most 256 characters long."),
+                ("invalid_ids", overlong.as_str(), "user_id must be at most 256 characters long."),
+                ("invalid\nids", "invalid_ids_a", "room_id must not contain control characters."),
+                ("invalid_ids", "invalid_ids\u{1b}[31m", "user_id must not contain control characters."),
+            ];
+            for &(room_id, user_id, msg) in &invalid {
+                let from = make_unjoined_session();
+                let err = process_join(&from, room_id.into(), user_id.into(), None, None, None).unwrap_err();
+                assert_eq!(err, SfuError::Invalid(msg.into()));
+                assert!(from.join_state.get().is_none());
+            }
+            let from = make_session(JoinKind::Subscriber, "invalid_ids", "invalid_ids_a");
+            let err = process_change_room(&from, "invalid\tids".into(), None).unwrap_err();
+            assert_eq!(err, SfuError::Invalid("room_id must not contain control characters.".into()));
+        }
+
+        fn limited_config(max_room_size_scope: LimitScope, max_ccu_scope: LimitScope) -> Config {
+            Config {
+                max_room_size: 2,
+                max_ccu: 10,
+                max_room_size_scope,
+                max_ccu_scope,
+                ..Config::default()
+            }
+        }
+
+        #[test]
+        fn default_limits_gate_publishers() {
+            let config = limited_config(LimitScope::Publishers, LimitScope::Publishers);
+            assert!(check_capacity(&config, JoinKind::Publisher, false, 1, 9).is_ok());
+            assert_eq!(check_capacity(&config, JoinKind::Publisher, false, 2, 9), Err(SfuError::RoomFull));
+            assert_eq!(check_capacity(&config, JoinKind::Publisher, false, 1, 10), Err(SfuError::ServerFull));
+            assert!(check_capacity(&config, JoinKind::Subscriber, false, 2, 10).is_ok());
+        }
+
+        #[test]
+        fn ccu_limit_can_gate_only_subscribers() {
+            let config = limited_config(LimitScope::Publishers, LimitScope::Subscribers);
+            assert!(check_capacity(&config, JoinKind::Publisher, false, 1, 10).is_ok());
+            assert!(check_capacity(&config, JoinKind::Publisher, false, 2, 10).is_err());
+            assert!(check_capacity(&config, JoinKind::Subscriber, false, 2, 9).is_ok());
+            assert!(check_capacity(&config, JoinKind::Subscriber, false, 2, 10).is_err());
+        }
+
+        #[test]
+        fn limits_can_gate_everyone() {
+            let config = limited_config(LimitScope::Everyone, LimitScope::Everyone);
+            for &kind in &[JoinKind::Publisher, JoinKind::Subscriber] {
+                assert!(check_capacity(&config, kind, false, 1, 9).is_ok());
+                assert!(check_capacity(&config, kind, false, 2, 9).is_err());
+                assert!(check_capacity(&config, kind, false, 1, 10).is_err());
+            }
+        }
+
+        #[test]
+        fn limits_can_gate_nobody() {
+            let config = limited_config(LimitScope::Nobody, LimitScope::Nobody);
+            for &kind in &[JoinKind::Publisher, JoinKind::Subscriber] {
+                assert!(check_capacity(&config, kind, false, 2, 10).is_ok());
+            }
+        }
+
+        #[test]
+        fn moderators_may_use_reserved_slots() {
+            let config = Config {
+                reserved_moderator_slots: 2,
+                ..limited_config(LimitScope::Publishers, LimitScope::Publishers)
+            };
+            assert!(check_capacity(&config, JoinKind::Publisher, false, 1, 10).is_err());
+            assert!(check_capacity(&config, JoinKind::Publisher, true, 1, 10).is_ok());
+            assert!(check_capacity(&config, JoinKind::Publisher, true, 1, 11).is_ok());
+            assert!(check_capacity(&config, JoinKind::Publisher, true, 1, 12).is_err());
+        }
+
+        #[test]
+        fn ccu_may_count_subscribers() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            switchboard.join_publisher(make_session(JoinKind::Publisher, "alpha", "10"), "10".into(), "alpha".into());
+            switchboard.join_subscriber(make_session(JoinKind::Subscriber, "alpha", "10"), "10".into(), "alpha".into());
+            switchboard.join_subscriber(make_session(JoinKind::Subscriber, "alpha", "11"), "11".into(), "alpha".into());
+            assert_eq!(count_ccu(&Config::default(), &switchboard), 1);
+            let config = Config {
+                count_subscribers_in_ccu: true,
+                ..Config::default()
+            };
+            assert_eq!(count_ccu(&config, &switchboard), 3);
+        }
+
+        #[test]
+        fn subscribers_are_limited_per_publisher() {
+            let config = Config {
+                max_subscribers_per_publisher: 1,
+                ..Config::default()
+            };
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            assert!(check_publisher_capacity(&config, &switchboard, &publisher).is_ok());
+            switchboard.subscribe_to_user(make_session(JoinKind::Subscriber, "alpha", "11"), Arc::clone(&publisher));
+            let err = check_publisher_capacity(&config, &switchboard, &publisher).unwrap_err();
+            assert_eq!(err, SfuError::SubscriberLimit("Publisher subscriber limit reached."));
+            assert!(check_publisher_capacity(&Config::default(), &switchboard, &publisher).is_ok());
+        }
+
+        #[test]
+        fn subscribers_are_limited_per_room() {
+            let config = Config {
+                max_subscribers_per_room: 2,
+                ..Config::default()
+            };
+            assert!(check_subscriber_capacity(&config, 1).is_ok());
+            let err = check_subscriber_capacity(&config, 2).unwrap_err();
+            assert_eq!(err, SfuError::SubscriberLimit("Room subscriber limit reached."));
+            assert!(check_subscriber_capacity(&Config::default(), 1000).is_ok());
+        }
+
+        #[test]
+        fn room_size_and_subscriber_limits_are_independent() {
+            let config = Config {
+                max_room_size: 2,
+                max_subscribers_per_room: 5,
+                ..Config::default()
+            };
+            assert!(check_capacity(&config, JoinKind::Publisher, false, 2, 0).is_err());
+            assert!(check_subscriber_capacity(&config, 2).is_ok());
+            assert!(check_capacity(&config, JoinKind::Publisher, false, 1, 0).is_ok());
+            assert!(check_subscriber_capacity(&config, 5).is_err());
+        }
+
+        fn token(create_room: bool) -> ValidatedToken {
+            ValidatedToken {
+                join_hub: true,
+                kick_users: false,
+                room_ids: None,
+                create_room,
+                record: false,
+                id: None,
+                user_id: None,
+                expires_at: u64::MAX,
+            }
+        }
+
+        #[test]
+        fn conflicting_joins_need_a_token_for_the_user_when_protected() {
+            let protected = Config {
+                protect_user_ids: true,
+                ..Config::default()
+            };
+            let alice = ValidatedToken {
+                user_id: Some("alice".into()),
+                ..token(false)
+            };
+            let user_id = "alice".into();
+            let err = SfuError::Unauthorized(AuthError::Forbidden("Rejecting join as a user already in the room!"));
+            assert_eq!(check_identity(&protected, None, &user_id, true), Err(err.clone()));
+            assert_eq!(check_identity(&protected, Some(&token(false)), &user_id, true), Err(err.clone()));
+            assert_eq!(check_identity(&protected, Some(&alice), &"bob".into(), true), Err(err));
+            assert_eq!(check_identity(&protected, Some(&alice), &user_id, true), Ok(()));
+            assert_eq!(check_identity(&protected, None, &user_id, false), Ok(()));
+            assert_eq!(check_identity(&Config::default(), None, &user_id, true), Ok(()));
+        }
+
+        #[test]
+        fn subscribers_cant_share_a_publishers_user_id_when_protected() {
+            let _globals = share_globals();
+            let room_id = "impersonation";
+            join_publisher(room_id, "impersonation_a");
+            let config = Config {
+                protect_user_ids: true,
+                ..Config::default()
+            };
+            let conflicting = SWITCHBOARD
+                .read()
+                .unwrap()
+                .has_conflicting_join(&room_id.into(), &"impersonation_a".into(), JoinKind::Subscriber);
+            assert!(check_identity(&config, None, &"impersonation_a".into(), conflicting).is_err());
+        }
+
+        #[test]
+        fn empty_rooms_require_create_permission() {
+            let err = check_room_creation(Some(&token(false)), 0).unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::Forbidden("Not permitted to create rooms.")));
+            assert!(check_room_creation(Some(&token(true)), 0).is_ok());
+        }
+
+        #[test]
+        fn populated_rooms_do_not_require_create_permission() {
+            assert!(check_room_creation(Some(&token(false)), 1).is_ok());
+            assert!(check_room_creation(Some(&token(true)), 1).is_ok());
+        }
+
+        #[test]
+        fn rooms_may_be_created_without_auth() {
+            assert!(check_room_creation(None, 0).is_ok());
+        }
+
+        #[test]
+        fn new_rooms_are_capped() {
+            let _globals = share_globals();
+            let config = Config {
+                max_rooms: 2,
+                ..Config::default()
+            };
+            let mut switchboard = Switchboard::new();
+            assert!(check_room_limit(&config, &switchboard, &"alpha".into()).is_ok());
+            switchboard.join_publisher(make_session(JoinKind::Publisher, "alpha", "10"), "10".into(), "alpha".into());
+            assert!(check_room_limit(&config, &switchboard, &"beta".into()).is_ok());
+            switchboard.join_publisher(make_session(JoinKind::Publisher, "beta", "11"), "11".into(), "beta".into());
+            assert_eq!(check_room_limit(&config, &switchboard, &"gamma".into()), Err(SfuError::RoomLimit));
+            // rooms which are already open may still be joined
+            assert!(check_room_limit(&config, &switchboard, &"alpha".into()).is_ok());
+            assert!(check_room_limit(&Config::default(), &switchboard, &"gamma".into()).is_ok());
+        }
+
+        #[test]
+        fn room_changes_are_capped_unless_they_close_a_room() {
+            let _globals = share_globals();
+            let config = Config {
+                max_rooms: 2,
+                ..Config::default()
+            };
+            let mut switchboard = Switchboard::new();
+            switchboard.join_publisher(make_session(JoinKind::Publisher, "alpha", "10"), "10".into(), "alpha".into());
+            switchboard.join_publisher(make_session(JoinKind::Publisher, "beta", "11"), "11".into(), "beta".into());
+            switchboard.join_publisher(make_session(JoinKind::Publisher, "beta", "12"), "12".into(), "beta".into());
+            // a lone publisher moving closes their old room on the way out
+            assert!(check_room_change_limit(&config, &switchboard, &"alpha".into(), &"gamma".into()).is_ok());
+            assert_eq!(
+                check_room_change_limit(&config, &switchboard, &"beta".into(), &"gamma".into()),
+                Err(SfuError::RoomLimit)
+            );
+            assert!(check_room_change_limit(&config, &switchboard, &"beta".into(), &"alpha".into()).is_ok());
+        }
+
+        #[test]
+        fn join_response_includes_limits() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            let response = process_join(&from, "join_response_includes_limits".into(), "10".into(), None, None, None).unwrap();
+            let limits = &response.body.unwrap()["limits"];
+            assert_eq!(limits, &json!({ "max_room_size": 0, "max_ccu": 0 }));
+        }
+
+        #[test]
+        fn join_time_subscription_waits_for_publisher_offer() {
+            let _globals = share_globals();
+            let publisher = join_publisher("join_before_offer", "join_before_offer_publisher");
+            let from = make_unjoined_session();
+            let subscribe = Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec!["join_before_offer_publisher".into()]),
+            };
+            let response = process_join(
+                &from,
+                "join_before_offer".into(),
+                "join_before_offer_subscriber".into(),
+                Some(subscribe),
+                None,
+                None,
+            )
+            .unwrap();
+            assert!(response.jsep.is_none());
+            let mut switchboard = SWITCHBOARD.write().unwrap();
+            assert_eq!(switchboard.admit_pending_subscribers(&publisher), vec![from]);
+        }
+
+        #[test]
+        fn joins_see_reloaded_limits() {
+            let _globals = own_globals();
+            let room_id = RoomId::from("joins_see_reloaded_limits");
+            join_publisher("joins_see_reloaded_limits", "10");
+            let data = Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            };
+            set_config(Config {
+                max_room_size: 1,
+                ..Config::default()
+            });
+            let from = make_unjoined_session();
+            let result = process_join(&from, room_id.clone(), "joins_see_reloaded_limits".into(), Some(data), None, None);
+            set_config(Config::default());
+            assert_eq!(result.err().unwrap().to_string(), "Room is full.");
+            // once the limit is reloaded away, the same join fits again
+            let room_size = SWITCHBOARD.read().unwrap().get_room_users(&room_id).count();
+            assert!(check_capacity(&current_config(), JoinKind::Publisher, false, room_size, 0).is_ok());
+        }
+
+        #[test]
+        fn reloads_reach_connected_sessions() {
+            let _globals = own_globals();
+            let sess = make_unjoined_session();
+            SWITCHBOARD.write().unwrap().connect(Box::new(Arc::clone(&sess)));
+            set_config(Config {
+                nack_buffer_size: 64,
+                sequence_reliable_data: true,
+                ..Config::default()
+            });
+            assert_eq!(sess.nack_buffer_size.load(Ordering::Relaxed), 64);
+            assert!(sess.sequence_reliable_data.load(Ordering::Relaxed));
+            set_config(Config::default());
+            assert_eq!(sess.nack_buffer_size.load(Ordering::Relaxed), 0);
+            assert!(!sess.sequence_reliable_data.load(Ordering::Relaxed));
+            SWITCHBOARD.write().unwrap().disconnect(&sess);
+        }
+
+        #[test]
+        fn joins_use_room_overrides() {
+            let _globals = own_globals();
+            let mut config = Config::default();
+            let overrides = RoomConfig {
+                max_room_size: Some(5),
+                ..RoomConfig::default()
+            };
+            config.rooms.insert("joins_use_room_overrides".into(), overrides);
+            set_config(config);
+            let from = make_unjoined_session();
+            let overridden = process_join(&from, "joins_use_room_overrides".into(), "10".into(), None, None, None);
+            let from = make_unjoined_session();
+            let general = process_join(&from, "joins_use_general_config".into(), "10".into(), None, None, None);
+            set_config(Config::default());
+            assert_eq!(overridden.unwrap().body.unwrap()["limits"]["max_room_size"], json!(5));
+            assert_eq!(general.unwrap().body.unwrap()["limits"]["max_room_size"], json!(0));
+        }
+
+        #[test]
+        fn users_may_only_publish_once() {
+            let _globals = share_globals();
+            let data = Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            };
+            let first = join_publisher("users_may_only_publish_once", "users_may_only_publish_once");
+            let second = make_unjoined_session();
+            let result = process_join(
+                &second,
+                "users_may_only_publish_once".into(),
+                "users_may_only_publish_once".into(),
+                Some(data),
+                None,
+                None,
+            );
+            assert_eq!(result.err().unwrap(), SfuError::AlreadyPublishing);
+            assert!(second.join_state.get().is_none());
+            let switchboard = SWITCHBOARD.read().unwrap();
+            assert_eq!(switchboard.get_publisher(&"users_may_only_publish_once".into()), Some(&first));
+            assert_eq!(switchboard.publishers_occupying(&"users_may_only_publish_once".into()), &[first.clone()]);
+        }
+
+        #[test]
+        fn join_response_includes_presenter_only_audio() {
+            let _globals = share_globals();
+            let room_id = RoomId::from("join_response_includes_presenter_only_audio");
+            SWITCHBOARD.write().unwrap().set_presenter_only_audio(room_id.clone(), true);
+            let from = make_unjoined_session();
+            let response = process_join(&from, room_id, "10".into(), None, None, None).unwrap();
+            assert_eq!(response.body.unwrap()["presenter_only_audio"], json!(true));
+        }
+    }
+
+    mod waiting_room {
+
+        use super::*;
+        use sessions::tests::make_moderator_session;
+
+        #[test]
+        fn joins_are_parked() {
+            let _globals = share_globals();
+            let room_id = RoomId::from("joins_are_parked");
+            SWITCHBOARD.write().unwrap().set_waiting_room(room_id.clone(), true);
+            let from = make_unjoined_session();
+            let response = process_join(&from, room_id, "10".into(), None, None, None).unwrap();
+            assert_eq!(response.body.unwrap()["waiting"], json!(true));
+            assert!(SWITCHBOARD.read().unwrap().is_waiting(&from));
+        }
+
+        #[test]
+        fn waiting_users_may_not_receive_traffic() {
+            let _globals = share_globals();
+            let room_id = RoomId::from("waiting_users_may_not_receive_traffic");
+            SWITCHBOARD.write().unwrap().set_waiting_room(room_id.clone(), true);
+            let from = make_unjoined_session();
+            process_join(&from, room_id, "waiting_users_may_not_receive_traffic".into(), None, None, None).unwrap();
+            let what = Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec!["11".into()]),
+            };
+            let err = process_subscribe(&from, &what).unwrap_err();
+            assert_eq!(err, SfuError::Waiting("Cannot subscribe to media while waiting to be admitted."));
+            let err = process_data(&from, None, Some("hello".into()), None).unwrap_err();
+            assert_eq!(err, SfuError::Waiting("Cannot send data while waiting to be admitted."));
+        }
+
+        #[test]
+        fn only_moderators_may_admit_or_deny() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Publisher, "only_moderators_may_admit", "10");
+            let err = process_admit(&from, "11".into()).unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::Forbidden("Rejecting admission without permission!")));
+            let err = process_deny(&from, "11".into()).unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::Forbidden("Rejecting denial without permission!")));
+        }
+
+        #[test]
+        fn admitting_or_denying_nobody_fails() {
+            let _globals = share_globals();
+            let from = make_moderator_session(JoinKind::Publisher, "admitting_nobody", "10");
+            assert_eq!(process_admit(&from, "11".into()).unwrap_err(), SfuError::NotWaiting);
+            assert_eq!(process_deny(&from, "11".into()).unwrap_err(), SfuError::NotWaiting);
+        }
+
+        #[test]
+        fn waiting_publishers_are_turned_away_if_their_user_started_publishing() {
+            let _globals = share_globals();
+            let room_id = RoomId::from("waiting_publishers_turned_away");
+            let user_id = UserId::from("waiting_publishers_turned_away");
+            let waiting = make_session(JoinKind::Publisher, &room_id, &user_id);
+            let mut switchboard = SWITCHBOARD.write().unwrap();
+            switchboard.set_waiting_room(room_id.clone(), true);
+            switchboard.join_waiting_room(Arc::clone(&waiting), room_id);
+            drop(switchboard);
+            let joined = waiting.join_state.get().unwrap();
+            assert_eq!(check_admission(&SWITCHBOARD.read().unwrap(), &joined), Ok(()));
+            // meanwhile, the same user publishes from elsewhere
+            join_publisher("waiting_publishers_turned_away_elsewhere", &user_id);
+            assert_eq!(check_admission(&SWITCHBOARD.read().unwrap(), &joined), Err(SfuError::AlreadyPublishing));
+        }
+    }
+
+    mod room_locks {
+
+        use super::*;
+
+        #[test]
+        fn locked_rooms_turn_away_new_publishers() {
+            let _globals = share_globals();
+            let room_id = RoomId::from("locked_rooms_turn_away_new_publishers");
+            let user_id = UserId::from("locked_rooms_turn_away_new_publishers");
+            let from = make_unjoined_session();
+            let err = process_lock_room(&from, room_id.clone(), true, "token".into()).unwrap_err();
+            assert_eq!(err, SfuError::NotConfigured("Checking permissions requires a configured secret."));
+            SWITCHBOARD.write().unwrap().set_locked(room_id.clone(), true);
+            let publish = Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            };
+            let err = process_join(&from, room_id.clone(), user_id.clone(), Some(publish), None, None).unwrap_err();
+            assert_eq!(err, SfuError::RoomLocked);
+            assert!(from.join_state.get().is_none());
+            // people may still watch
+            assert!(process_join(&from, room_id, user_id, None, None, None).is_ok());
+        }
+    }
+
+    mod listing {
+
+        use super::*;
+
+        fn token(room_ids: Option<Vec<RoomId>>) -> ValidatedToken {
+            ValidatedToken {
+                join_hub: true,
+                kick_users: false,
+                room_ids,
+                create_room: false,
+                record: false,
+                id: None,
+                user_id: None,
+                expires_at: u64::MAX,
+            }
+        }
+
+        fn switchboard_with_rooms() -> Switchboard {
+            let mut switchboard = Switchboard::new();
+            for &(room, user) in &[("alpha", "10"), ("alpha", "11"), ("beta", "12"), ("gamma", "13")] {
+                switchboard.join_publisher(make_session(JoinKind::Publisher, room, user), user.into(), room.into());
+            }
+            switchboard
+        }
+
+        #[test]
+        fn all_rooms_are_listed_without_auth() {
+            let rooms = visible_rooms(&switchboard_with_rooms(), None);
+            let expected = vec![
+                json!({ "room_id": "alpha", "publisher_count": 2 }),
+                json!({ "room_id": "beta", "publisher_count": 1 }),
+                json!({ "room_id": "gamma", "publisher_count": 1 }),
+            ];
+            assert_eq!(rooms, expected);
+            assert_eq!(visible_rooms(&switchboard_with_rooms(), Some(&token(None))), expected);
+        }
+
+        #[test]
+        fn users_are_listed_without_auth() {
+            let switchboard = switchboard_with_rooms();
+            let users = visible_users(&switchboard, None, &"alpha".into()).unwrap();
+            assert_eq!(users, vec!["10", "11"]);
+            assert!(visible_users(&switchboard, None, &"delta".into()).unwrap().is_empty());
+        }
+
+        #[test]
+        fn users_are_listed_with_when_they_joined() {
+            let switchboard = switchboard_with_rooms();
+            let users = vec!["10".into(), "11".into(), "99".into()];
+            let joined_at = publisher_join_times(&switchboard, &users);
+            assert_eq!(joined_at.keys().collect::<Vec<_>>(), vec!["10", "11"]);
+            let expected = switchboard.get_publisher(&"10".into()).unwrap().join_state.get().unwrap().joined_at_millis();
+            assert_eq!(joined_at["10"], json!(expected));
+        }
+
+        #[test]
+        fn users_are_listed_with_their_negotiated_codecs() {
+            let switchboard = switchboard_with_rooms();
+            let negotiated = negotiated_codecs(AudioCodec::Opus, VideoCodec::H264, Some(111), Some(126));
+            *switchboard.get_publisher(&"11".into()).unwrap().negotiated.lock().unwrap() = Some(negotiated.clone());
+            let users = vec!["10".into(), "11".into()];
+            let codecs = negotiated_codecs_by_user(&switchboard, &users);
+            assert_eq!(codecs.keys().collect::<Vec<_>>(), vec!["11"]);
+            assert_eq!(codecs["11"], json!(negotiated));
+        }
+
+        #[test]
+        fn users_are_listed_only_for_joinable_rooms() {
+            let switchboard = switchboard_with_rooms();
+            let claims = token(Some(vec!["beta".into()]));
+            assert_eq!(visible_users(&switchboard, Some(&claims), &"beta".into()).unwrap(), vec!["12"]);
+            let err = visible_users(&switchboard, Some(&claims), &"alpha".into()).unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::WrongRoom("Rejecting user listing without permission!")));
+        }
+
+        #[test]
+        fn viewers_need_a_token_if_auth_is_configured() {
+            let config = Config {
+                auth: Some(AuthConfig {
+                    keys: vec![b"secret".to_vec()],
+                    algorithm: jsonwebtoken::Algorithm::HS256,
+                    expected_issuer: None,
+                    expected_audience: None,
+                    leeway_secs: 0,
+                }),
+                ..Config::default()
+            };
+            let from = make_unjoined_session();
+            let err = authorize_viewer(&config, &from, None).unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::Forbidden("Rejecting anonymous room inquiry!")));
+            let err = authorize_viewer(&config, &from, Some("garbage".into())).unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::Malformed("Rejecting room inquiry with invalid token!")));
+            assert_eq!(authorize_viewer(&Config::default(), &from, None).unwrap(), None);
+        }
+
+        #[test]
+        fn room_counts_are_reported() {
+            let _globals = share_globals();
+            join_publisher("room_counts_are_reported", "10");
+            // a listener who never publishes still counts
+            let listener = make_session(JoinKind::Subscriber, "room_counts_are_reported", "11");
+            SWITCHBOARD
+                .write()
+                .unwrap()
+                .join_subscriber(listener, "11".into(), "room_counts_are_reported".into());
+            let from = make_unjoined_session();
+            let response = process_room_count(&from, "room_counts_are_reported".into(), None).unwrap();
+            assert_eq!(response.body.unwrap(), json!({ "publishers": 1, "subscribers": 1 }));
+        }
+
+        #[test]
+        fn only_joinable_rooms_are_listed() {
+            let claims = token(Some(vec!["alpha".into(), "gamma".into(), "delta".into()]));
+            let rooms = visible_rooms(&switchboard_with_rooms(), Some(&claims));
+            let expected = vec![
+                json!({ "room_id": "alpha", "publisher_count": 2 }),
+                json!({ "room_id": "gamma", "publisher_count": 1 }),
+            ];
+            assert_eq!(rooms, expected);
+        }
+    }
+
+    mod threads {
+
+        use super::*;
+
+        #[test]
+        fn zero_threads_means_one_per_cpu() {
+            assert_eq!(message_thread_count(0), num_cpus::get().min(MAX_MESSAGE_THREADS));
+            assert_eq!(message_thread_count(3), 3);
+        }
+
+        #[test]
+        fn messages_from_a_handle_always_go_to_the_same_thread() {
+            let sessions = (0..64).map(|_| make_unjoined_session()).collect::<Vec<_>>();
+            for session in &sessions {
+                let thread = message_thread_for(session.handle, 4);
+                assert!(thread < 4);
+                for _ in 0..10 {
+                    assert_eq!(message_thread_for(session.handle, 4), thread);
+                }
+            }
+            // handles are still spread across threads
+            let used = sessions.iter().map(|s| message_thread_for(s.handle, 4)).collect::<HashSet<_>>();
+            assert!(used.len() > 1);
+        }
+
+        #[test]
+        fn messages_are_refused_when_the_queue_is_full() {
+            let message = || RawMessage {
+                from: Weak::new(),
+                txn: TransactionId(ptr::null_mut()),
+                msg: None,
+                jsep: None,
+            };
+            let (tx, rx) = mpsc::sync_channel(1);
+            assert_eq!(queue_message(&tx, message()), Ok(()));
+            assert_eq!(queue_message(&tx, message()), Err(c_str!("Server is busy; try again.")));
+            rx.recv().unwrap();
+            assert_eq!(queue_message(&tx, message()), Ok(()));
+            drop(rx);
+            assert_eq!(queue_message(&tx, message()), Err(c_str!("Message processing has stopped!")));
+        }
+    }
+
+    mod codecs {
+
+        use super::*;
+
+        fn with_audio_codec(audio_codec: AudioCodec) -> Config {
+            Config {
+                audio_codec,
+                ..Config::default()
+            }
+        }
+
+        #[test]
+        fn only_opus_gets_fmtp() {
+            let expected = "111 stereo=0; sprop-stereo=0; usedtx=1;";
+            assert_eq!(audio_fmtp(&with_audio_codec(AudioCodec::Opus), 111), Some(expected.into()));
+            assert_eq!(audio_fmtp(&with_audio_codec(AudioCodec::Pcmu), 0), None);
+            assert_eq!(audio_fmtp(&with_audio_codec(AudioCodec::Pcma), 8), None);
+        }
+
+        #[test]
+        fn opus_fmtp_is_configurable() {
+            let config = Config {
+                opus_stereo: true,
+                opus_sprop_stereo: true,
+                opus_usedtx: false,
+                ..Config::default()
+            };
+            assert_eq!(audio_fmtp(&config, 111), Some("111 stereo=1; sprop-stereo=1; usedtx=0;".into()));
+        }
+
+        #[test]
+        fn only_h264_has_a_profile() {
+            assert_eq!(video_profile(VideoCodec::H264), Some(c_str!("42e01f")));
+            assert_eq!(video_profile(VideoCodec::Vp8), None);
+            assert_eq!(video_profile(VideoCodec::Vp9), None);
+        }
+
+        #[test]
+        fn publishers_are_answered_sendrecv_if_configured() {
+            let config = Config::default();
+            assert!(matches!(publisher_direction(&config), MediaDirection::JANUS_SDP_RECVONLY));
+            let config = Config {
+                publisher_sendrecv: true,
+                ..config
+            };
+            assert!(matches!(publisher_direction(&config), MediaDirection::JANUS_SDP_SENDRECV));
+        }
+
+        #[test]
+        fn offers_without_the_configured_codec_are_rejected() {
+            let offer = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\nm=video 9 UDP/TLS/RTP/SAVPF 98 100\r\na=rtpmap:98 VP9/90000\r\na=rtpmap:100 AV1/90000\r\n";
+            let config = Config {
+                video_codec: VideoCodec::H264,
+                ..Config::default()
+            };
+            let err = check_offered_codecs(&config, offer, Some(111), None).unwrap_err();
+            assert_eq!(err.code(), "codec_mismatch");
+            assert_eq!(err.to_string(), "Offered video codecs (vp9, av1) don't include h264, which this room requires.");
+            let err = check_offered_codecs(&with_audio_codec(AudioCodec::Pcmu), offer, None, Some(100)).unwrap_err();
+            assert_eq!(
+                err,
+                SfuError::CodecMismatch("Offered audio codecs (opus) don't include pcmu, which this room requires.".into())
+            );
+            assert!(check_offered_codecs(&config, offer, Some(111), Some(100)).is_ok());
+        }
+
+        #[test]
+        fn offers_without_some_media_need_no_codec_for_it() {
+            let audio_only = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\nm=video 0 UDP/TLS/RTP/SAVPF 0\r\n";
+            assert!(check_offered_codecs(&Config::default(), audio_only, Some(111), None).is_ok());
+        }
+    }
+
+    mod mute {
+
+        use super::*;
+        use sessions::tests::make_moderator_session;
+
+        #[test]
+        fn muting_flags_the_publisher_and_notifies_the_room() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "alpha".into());
+            let notification = set_muted(&switchboard, &"alpha".into(), &"10".into(), MediaKind::Audio, true).unwrap();
+            let expected = json!({ "event": "mute", "user_id": "10", "room_id": "alpha", "media_kind": "audio" });
+            assert_eq!(notification, Some(expected));
+            assert!(publisher.muted.is_muted(false));
+            assert!(!publisher.muted.is_muted(true));
+            // muting again changes nothing, so nobody needs telling
+            assert_eq!(set_muted(&switchboard, &"alpha".into(), &"10".into(), MediaKind::Audio, true).unwrap(), None);
+        }
+
+        #[test]
+        fn unmuting_video_asks_for_a_keyframe() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "alpha".into());
+            set_muted(&switchboard, &"alpha".into(), &"10".into(), MediaKind::Video, true).unwrap();
+            assert!(!publisher.keyframe_wanted.load(Ordering::Relaxed));
+            let notification = set_muted(&switchboard, &"alpha".into(), &"10".into(), MediaKind::Video, false).unwrap();
+            let expected = json!({ "event": "unmute", "user_id": "10", "room_id": "alpha", "media_kind": "video" });
+            assert_eq!(notification, Some(expected));
+            assert!(!publisher.muted.is_muted(true));
+            assert!(publisher.keyframe_wanted.load(Ordering::Relaxed));
+        }
+
+        #[test]
+        fn only_publishers_in_the_room_may_be_muted() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "beta", "10");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "beta".into());
+            assert!(set_muted(&switchboard, &"alpha".into(), &"10".into(), MediaKind::Audio, true).is_err());
+            assert!(set_muted(&switchboard, &"alpha".into(), &"11".into(), MediaKind::Audio, true).is_err());
+            assert!(!publisher.muted.is_muted(false));
+        }
+
+        #[test]
+        fn muting_everyone_spares_moderators() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            let moderator = make_moderator_session(JoinKind::Publisher, "alpha", "11");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "alpha".into());
+            switchboard.join_publisher(Arc::clone(&moderator), "11".into(), "alpha".into());
+            let notifications = mute_all(&switchboard, &"alpha".into(), MediaKind::Audio).unwrap();
+            let expected = json!({ "event": "mute", "user_id": "10", "room_id": "alpha", "media_kind": "audio" });
+            assert_eq!(notifications, vec![expected]);
+            assert!(publisher.muted.is_muted(false));
+            assert!(!moderator.muted.is_muted(false));
+            // everyone who could be muted already is
+            assert!(mute_all(&switchboard, &"alpha".into(), MediaKind::Audio).unwrap().is_empty());
+        }
+
+        #[test]
+        fn muting_requires_a_room_and_a_secret() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            let err = process_mute(&from, "10".into(), MediaKind::Audio, true, "token".into()).unwrap_err();
+            assert_eq!(err, SfuError::NotJoined("Cannot mute users when not in a room."));
+            let from = make_session(JoinKind::Publisher, "muting_requires_a_secret", "10");
+            let err = process_mute(&from, "11".into(), MediaKind::Audio, true, "token".into()).unwrap_err();
+            assert_eq!(err, SfuError::NotConfigured("Checking permissions requires a configured secret."));
+            let err = process_mute_all(&from, MediaKind::Audio, "token".into()).unwrap_err();
+            assert_eq!(err, SfuError::NotConfigured("Checking permissions requires a configured secret."));
+        }
+    }
+
+    mod errors {
+
+        use super::*;
+
+        #[test]
+        fn errors_have_a_message_and_code() {
+            let expected = json!({ "success": false, "error": { "msg": "Room is full.", "code": "room_full" } });
+            assert_eq!(error_response(&SfuError::RoomFull), expected);
+        }
+
+        #[test]
+        fn auth_errors_have_a_code() {
+            let cases = vec![
+                (AuthError::Expired("expired!"), "expired"),
+                (AuthError::Forbidden("forbidden!"), "forbidden"),
+                (AuthError::Malformed("malformed!"), "malformed"),
+                (AuthError::WrongRoom("wrong room!"), "wrong_room"),
+            ];
+            for (auth_err, code) in cases {
+                let msg = auth_err.to_string();
+                let err = SfuError::Unauthorized(auth_err);
+                assert_eq!(error_response(&err), json!({ "success": false, "error": { "msg": msg, "code": code } }));
+            }
+        }
+
+        #[test]
+        fn recording_requires_a_directory() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Publisher, "recording_requires_a_directory", "10");
+            let err = process_recording(&from, "recording_requires_a_directory".into(), true, "token".into()).unwrap_err();
+            assert_eq!(err, SfuError::NotConfigured("Recording requires a configured recording directory."));
+            assert!(!SWITCHBOARD.read().unwrap().is_recording(&"recording_requires_a_directory".into()));
+        }
+
+        #[test]
+        fn recording_flag_follows_the_recording() {
+            let dir = std::env::temp_dir().join("janus_sfu_recording_flag_follows_the_recording");
+            std::fs::create_dir_all(&dir).unwrap();
+            let from = make_session(JoinKind::Publisher, "recording_flag", "10");
+            start_recording(&from, &dir, &Config::default());
+            assert!(from.is_recording.load(Ordering::Relaxed));
+            stop_recording(&from);
+            assert!(!from.is_recording.load(Ordering::Relaxed));
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    mod revocation {
+
+        use super::*;
+        use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+
+        fn auth_config() -> AuthConfig {
+            AuthConfig {
+                keys: vec![b"secret".to_vec()],
+                algorithm: Algorithm::HS256,
+                expected_issuer: None,
+                expected_audience: None,
+                leeway_secs: 0,
+            }
+        }
+
+        fn sign(jti: &str) -> String {
+            let claims = json!({ "join_hub": true, "exp": u32::MAX, "jti": jti });
+            encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+        }
+
+        #[test]
+        fn revoked_tokens_are_rejected() {
+            REVOKED_TOKEN_IDS.write().unwrap().insert("revoked_tokens_are_rejected".into());
+            let err = validate_token(&sign("revoked_tokens_are_rejected"), &auth_config()).unwrap_err();
+            assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::Revoked));
+        }
+
+        #[test]
+        fn unrevoked_tokens_are_valid() {
+            REVOKED_TOKEN_IDS.write().unwrap().insert("some_other_token".into());
+            assert!(validate_token(&sign("unrevoked_tokens_are_valid"), &auth_config()).is_ok());
+        }
+
+        fn sign_claims(claims: JsonValue) -> String {
+            encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+        }
+
+        #[test]
+        fn refreshed_tokens_grant_moderation() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Publisher, "refreshed_tokens_grant_moderation", "10");
+            let joined = from.join_state.get().unwrap();
+            let token = sign_claims(json!({ "join_hub": true, "kick_users": true, "exp": u32::MAX }));
+            let claims = refreshed_claims(&auth_config(), &joined, &token).unwrap();
+            assert!(claims.may_moderate(&joined.room_id));
+            assert!(process_admit(&from, "11".into()).is_err());
+            cache_claims(&from, Some(token), Some(claims.clone()));
+            SWITCHBOARD.write().unwrap().set_moderator(&from, true);
+            assert_eq!(process_admit(&from, "11".into()).unwrap_err(), SfuError::NotWaiting);
+            assert_eq!(session_claims(&from, None, &auth_config()).unwrap(), claims);
+        }
+
+        #[test]
+        fn refreshed_tokens_must_fit_the_session() {
+            let from = make_session(JoinKind::Publisher, "refreshed_tokens_must_fit", "10");
+            let joined = from.join_state.get().unwrap();
+            let token = sign_claims(json!({ "join_hub": true, "room_ids": ["elsewhere"], "exp": u32::MAX }));
+            let err = refreshed_claims(&auth_config(), &joined, &token).unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::WrongRoom("Rejecting token refresh for another room!")));
+            let token = sign_claims(json!({ "join_hub": true, "user_id": "11", "exp": u32::MAX }));
+            let err = refreshed_claims(&auth_config(), &joined, &token).unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::Forbidden("Rejecting token refresh for another user!")));
+            let err = refreshed_claims(&auth_config(), &joined, "garbage").unwrap_err();
+            assert_eq!(err, SfuError::Unauthorized(AuthError::Malformed("Rejecting token refresh with invalid token!")));
+        }
+
+        #[test]
+        fn moderation_is_scoped_to_the_token_rooms() {
+            let _globals = own_globals();
+            let from = make_session(JoinKind::Publisher, "alpha", "10");
+            let token = sign_claims(json!({ "join_hub": true, "kick_users": true, "room_ids": ["alpha"], "exp": u32::MAX }));
+            set_config(Config {
+                auth: Some(auth_config()),
+                ..Config::default()
+            });
+            let allowed = require_moderator(&from, &"alpha".into(), Some(&token), "kick");
+            let forbidden = require_moderator(&from, &"gamma".into(), Some(&token), "kick");
+            set_config(Config::default());
+            assert!(allowed.is_ok());
+            assert_eq!(
+                forbidden.unwrap_err(),
+                SfuError::Unauthorized(AuthError::Forbidden("Rejecting request without permission!"))
+            );
+        }
+
+        #[test]
+        fn moderation_needs_a_valid_token_and_a_secret() {
+            let _globals = own_globals();
+            let from = make_session(JoinKind::Publisher, "alpha", "10");
+            set_config(Config {
+                auth: Some(auth_config()),
+                ..Config::default()
+            });
+            let malformed = require_moderator(&from, &"alpha".into(), Some("garbage"), "kick");
+            set_config(Config::default());
+            let unconfigured = require_moderator(&from, &"alpha".into(), Some("garbage"), "kick");
+            assert_eq!(
+                malformed.unwrap_err(),
+                SfuError::Unauthorized(AuthError::Malformed("Rejecting request with invalid token!"))
+            );
+            assert_eq!(
+                unconfigured.unwrap_err(),
+                SfuError::NotConfigured("Checking permissions requires a configured secret.")
+            );
+        }
+
+        #[test]
+        fn refreshed_tokens_may_lock_rooms() {
+            let _globals = own_globals();
+            let room_id = RoomId::from("refreshed_tokens_may_lock_rooms");
+            let from = make_session(JoinKind::Publisher, &room_id, "10");
+            let token = sign_claims(json!({ "join_hub": true, "kick_users": true, "room_ids": [&room_id], "exp": u32::MAX }));
+            set_config(Config {
+                auth: Some(auth_config()),
+                ..Config::default()
+            });
+            let before = process_lock_room(&from, room_id.clone(), true, "not a token".into());
+            let refreshed = process_refresh_token(&from, token.clone());
+            // once refreshed, the cached claims stand in for the token without decoding it again
+            from.claims.lock().unwrap().as_mut().unwrap().token = "not a token".into();
+            let locked = process_lock_room(&from, room_id.clone(), true, "not a token".into());
+            let muted = process_mute_all(&from, MediaKind::Audio, "not a token".into());
+            set_config(Config::default());
+            assert!(before.is_err());
+            assert_eq!(refreshed.unwrap().body.unwrap(), json!({ "moderator": true }));
+            assert!(locked.is_ok());
+            assert_eq!(muted.unwrap().body.unwrap(), json!({ "muted": [] }));
+            assert!(SWITCHBOARD.read().unwrap().is_locked(&room_id));
+        }
+
+        #[test]
+        fn cached_claims_are_checked_for_revocation() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            assert!(session_claims(&from, None, &auth_config()).is_err());
+            let token = sign("cached_claims_are_checked");
+            let claims = validate_token(&token, &auth_config()).unwrap();
+            cache_claims(&from, Some(token), Some(claims));
+            assert!(session_claims(&from, None, &auth_config()).is_ok());
+            REVOKED_TOKEN_IDS.write().unwrap().insert("cached_claims_are_checked".into());
+            let err = session_claims(&from, None, &auth_config()).unwrap_err();
+            assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::Revoked));
+        }
+
+        #[test]
+        fn cached_claims_are_reused_for_the_same_token() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            let claims = validate_token(&sign("cached_claims_are_reused"), &auth_config()).unwrap();
+            // the cached token isn't even a token, so these claims can only have come from the cache
+            cache_claims(&from, Some("not a token".into()), Some(claims.clone()));
+            assert_eq!(session_claims(&from, Some("not a token"), &auth_config()).unwrap(), claims);
+            assert!(session_claims(&from, Some("another non-token"), &auth_config()).is_err());
+        }
+
+        #[test]
+        fn moderation_reuses_cached_claims() {
+            let _globals = own_globals();
+            let room_id = RoomId::from("moderation_reuses_cached_claims");
+            let from = make_session(JoinKind::Publisher, &room_id, "10");
+            let token = sign_claims(json!({ "join_hub": true, "kick_users": true, "exp": u32::MAX }));
+            let claims = validate_token(&token, &auth_config()).unwrap();
+            // the cached token isn't even a token, so these requests can only be allowed by the cached claims
+            cache_claims(&from, Some("not a token".into()), Some(claims));
+            set_config(Config {
+                auth: Some(auth_config()),
+                ..Config::default()
+            });
+            let roster = process_roster(&from, room_id.clone(), Vec::new(), "not a token".into());
+            let mute = process_mute(&from, "11".into(), MediaKind::Audio, true, "not a token".into());
+            let presenter = process_presenter_only_audio(&from, room_id.clone(), false, "not a token".into());
+            let waiting = process_waiting_room(&from, room_id, false, "not a token".into());
+            set_config(Config::default());
+            assert!(roster.is_ok());
+            assert_eq!(mute.unwrap_err(), SfuError::PublisherNotFound("That user isn't publishing in this room."));
+            assert!(presenter.is_ok());
+            assert!(waiting.is_ok());
+        }
+
+        #[test]
+        fn expired_cached_claims_force_revalidation() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            let token = sign("expired_cached_claims");
+            let claims = validate_token(&token, &auth_config()).unwrap();
+            let expired = ValidatedToken {
+                expires_at: 0,
+                ..claims.clone()
+            };
+            cache_claims(&from, Some(token.clone()), Some(expired));
+            let err = session_claims(&from, None, &auth_config()).unwrap_err();
+            assert_eq!(err.downcast_ref::<TokenError>(), Some(&TokenError::Expired));
+            assert_eq!(session_claims(&from, Some(&token), &auth_config()).unwrap(), claims);
+        }
+    }
+
+    mod timing {
+
+        use super::*;
+
+        #[test]
+        fn slow_handlers_are_counted() {
+            let from = make_unjoined_session();
+            let txn = TransactionId(ptr::null_mut());
+            let before = SLOW_MESSAGE_COUNTER.load(Ordering::Relaxed);
+            let result = time_handler(&from, &txn, "message", Duration::from_millis(1), || {
+                thread::sleep(Duration::from_millis(20));
+                42
+            });
+            assert_eq!(result, 42);
+            assert!(SLOW_MESSAGE_COUNTER.load(Ordering::Relaxed) > before);
+        }
+
+        #[test]
+        fn disabled_threshold_runs_handler() {
+            let from = make_unjoined_session();
+            let txn = TransactionId(ptr::null_mut());
+            assert_eq!(time_handler(&from, &txn, "message", Duration::default(), || 42), 42);
+        }
+    }
+
+    mod subscribe {
+
+        use super::*;
+
+        #[test]
+        fn repeated_subscription_succeeds() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Subscriber, "repeated_subscription", "10");
+            let what = Subscription {
+                notifications: true,
+                data: false,
+                media: None,
+            };
+            assert!(process_subscribe(&from, &what).is_ok());
+            assert!(process_subscribe(&from, &what).is_ok());
+        }
+
+        fn media_subscription(user_ids: &[&str]) -> Option<Subscription> {
+            Some(Subscription {
+                notifications: false,
+                data: false,
+                media: Some(user_ids.iter().map(|&user_id| user_id.into()).collect()),
+            })
+        }
+
+        #[test]
+        fn offers_can_be_requested_again_once_publishers_are_being_received() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "alpha", "10");
+            let bob = make_session(JoinKind::Publisher, "alpha", "11");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "alpha".into());
+            switchboard.join_publisher(Arc::clone(&bob), "11".into(), "alpha".into());
+            let subscriber = make_session(JoinKind::Subscriber, "alpha", "12");
+            *subscriber.subscription.lock().unwrap() = media_subscription(&["11", "10"]);
+            assert_eq!(offered_publishers(&switchboard, &subscriber), Err(SfuError::PublisherNotReady));
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&alice));
+            assert_eq!(offered_publishers(&switchboard, &subscriber), Err(SfuError::PublisherNotReady));
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&bob));
+            // the publishers come in the order they were subscribed to, matching the offer the client got before
+            assert_eq!(offered_publishers(&switchboard, &subscriber), Ok(vec![bob, alice]));
+        }
+
+        #[test]
+        fn offers_are_only_for_media_subscriptions() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "alpha", "10");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "alpha".into());
+            let subscriber = make_session(JoinKind::Subscriber, "alpha", "12");
+            assert!(matches!(offered_publishers(&switchboard, &subscriber), Err(SfuError::NotSubscribed(_))));
+            *subscriber.subscription.lock().unwrap() = media_subscription(&[]);
+            assert!(matches!(offered_publishers(&switchboard, &subscriber), Err(SfuError::NotSubscribed(_))));
+            *subscriber.subscription.lock().unwrap() = media_subscription(&["13"]);
+            assert!(matches!(offered_publishers(&switchboard, &subscriber), Err(SfuError::PublisherNotFound(_))));
+
+            let publisher = make_session(JoinKind::Publisher, "alpha", "14");
+            publisher.sendrecv.store(true, Ordering::Relaxed);
+            *publisher.subscription.lock().unwrap() = media_subscription(&["10"]);
+            switchboard.subscribe_to_user(Arc::clone(&publisher), alice);
+            assert!(matches!(offered_publishers(&switchboard, &publisher), Err(SfuError::Invalid(_))));
+        }
+
+        #[test]
+        fn offers_requested_before_the_publisher_negotiates_can_be_retried() {
+            let _globals = share_globals();
+            let room_id = "offer_before_negotiating";
+            let publisher = join_publisher(room_id, "offer_before_negotiating_a");
+            let subscriber = make_session(JoinKind::Subscriber, room_id, "offer_before_negotiating_b");
+            *subscriber.subscription.lock().unwrap() = media_subscription(&["offer_before_negotiating_a"]);
+            SWITCHBOARD.write().unwrap().subscribe_to_user(Arc::clone(&subscriber), publisher);
+            assert_eq!(process_request_offer(&subscriber).unwrap_err(), SfuError::PublisherNotReady);
+        }
+
+        #[test]
+        fn changed_subscription_fails() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Subscriber, "changed_subscription", "10");
+            let what = Subscription {
+                notifications: true,
+                data: false,
+                media: None,
+            };
+            let other = Subscription {
+                media: Some(vec!["11".into()]),
+                ..what.clone()
+            };
+            assert!(process_subscribe(&from, &what).is_ok());
+            assert!(process_subscribe(&from, &other).is_err());
+            assert_eq!(*from.subscription.lock().unwrap(), Some(what));
+        }
+
+        #[test]
+        fn subscription_changes_are_rate_limited() {
+            let _globals = share_globals();
+            let what = Subscription {
+                notifications: true,
+                data: false,
+                media: None,
+            };
+            let from = make_session(JoinKind::Subscriber, "rate_limited_subscription", "10");
+            *from.subscription_changes.lock().unwrap() = Some(TokenBucket::new(1, 0.0, Instant::now()));
+            assert!(process_subscribe(&from, &what).is_ok());
+            assert!(process_subscribe(&from, &what).is_ok());
+            let other = make_session(JoinKind::Subscriber, "rate_limited_subscription", "11");
+            *other.subscription_changes.lock().unwrap() = Some(TokenBucket::new(0, 0.0, Instant::now()));
+            assert!(process_subscribe(&other, &what).is_err());
+            assert_eq!(*other.subscription.lock().unwrap(), None);
+        }
+
+        #[test]
+        fn subscribing_before_publisher_offers_waits_for_offer() {
+            let _globals = share_globals();
+            let publisher = join_publisher("subscribing_before_offer", "subscribing_before_offer_publisher");
+            let from = make_session(JoinKind::Subscriber, "subscribing_before_offer", "subscribing_before_offer_subscriber");
+            let what = Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec!["subscribing_before_offer_publisher".into()]),
+            };
+            let err = process_subscribe(&from, &what).unwrap_err();
+            assert_eq!(err, SfuError::PublisherNotReady);
+            assert_eq!(*from.subscription.lock().unwrap(), Some(what.clone()));
+            assert!(process_subscribe(&from, &what).is_err());
+
+            let mut switchboard = SWITCHBOARD.write().unwrap();
+            assert!(switchboard.subscribers_to(&publisher).is_empty());
+            assert_eq!(switchboard.admit_pending_subscribers(&publisher), vec![Arc::clone(&from)]);
+            assert_eq!(switchboard.subscribers_to(&publisher), &[from]);
+        }
+
+        #[test]
+        fn sendrecv_publishers_get_media_on_their_own_connection() {
+            let _globals = share_globals();
+            let publisher = join_publisher("sendrecv_publishers", "sendrecv_publishers_10");
+            let from = join_publisher("sendrecv_publishers", "sendrecv_publishers_11");
+            from.sendrecv.store(true, Ordering::Relaxed);
+            let what = Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec!["sendrecv_publishers_10".into()]),
+            };
+            assert_eq!(process_subscribe(&from, &what).unwrap().jsep, None);
+            assert_eq!(SWITCHBOARD.read().unwrap().subscribers_to(&publisher), &[Arc::clone(&from)]);
+            assert!(publisher.keyframe_wanted.load(Ordering::Relaxed));
+            assert_eq!(process_subscribe(&from, &what).unwrap().jsep, None);
+        }
+
+        #[test]
+        fn subscriptions_are_described() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            let from = make_session(JoinKind::Subscriber, "alpha", "11");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "alpha".into());
+            switchboard.join_subscriber(Arc::clone(&from), "11".into(), "alpha".into());
+            assert_eq!(describe_subscriptions(&switchboard, &from), json!({ "subscription": null, "media": [] }));
+            let what = Subscription {
+                notifications: true,
+                data: true,
+                media: None,
+            };
+            *from.subscription.lock().unwrap() = Some(what);
+            let expected = json!({ "subscription": { "notifications": true, "data": true, "media": null }, "media": [] });
+            assert_eq!(describe_subscriptions(&switchboard, &from), expected);
+        }
+
+        #[test]
+        fn media_subscriptions_are_described() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            let from = make_session(JoinKind::Subscriber, "alpha", "11");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "alpha".into());
+            switchboard.join_subscriber(Arc::clone(&from), "11".into(), "alpha".into());
+            *from.subscription.lock().unwrap() = Some(Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec!["10".into()]),
+            });
+            switchboard.subscribe_to_user(Arc::clone(&from), Arc::clone(&publisher));
+            let expected = json!({ "subscription": { "notifications": false, "data": false, "media": ["10"] }, "media": ["10"] });
+            assert_eq!(describe_subscriptions(&switchboard, &from), expected);
+        }
+
+        #[test]
+        fn several_publishers_are_subscribed_at_once() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "alpha", "10");
+            let bob = make_session(JoinKind::Publisher, "alpha", "11");
+            let from = make_session(JoinKind::Subscriber, "alpha", "12");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "alpha".into());
+            switchboard.join_publisher(Arc::clone(&bob), "11".into(), "alpha".into());
+            switchboard.join_subscriber(Arc::clone(&from), "12".into(), "alpha".into());
+            let user_ids = ["11".to_owned(), "10".to_owned()];
+            let publishers = media_publishers(&Config::default(), &switchboard, &from, &user_ids).unwrap();
+            assert_eq!(publishers, vec![bob.clone(), alice.clone()]);
+            subscribe_to_all(&mut switchboard, &from, publishers.clone());
+            assert!(from.multi_source.load(Ordering::Relaxed));
+            assert!(alice.keyframe_wanted.load(Ordering::Relaxed));
+            assert_eq!(switchboard.publishers_to(&from).len(), 2);
+            assert_eq!(switchboard.subscribers_to(&alice), &[from.clone()]);
+            assert_eq!(switchboard.subscribers_to(&bob), &[from.clone()]);
+            // repeating the subscription doesn't subscribe anyone twice
+            subscribe_to_all(&mut switchboard, &from, publishers);
+            assert_eq!(switchboard.publishers_to(&from).len(), 2);
+            assert_eq!(switchboard.subscribers_to(&alice).len(), 1);
+        }
+
+        #[test]
+        fn each_of_several_publishers_is_checked() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "alpha", "10");
+            let bob = make_session(JoinKind::Publisher, "alpha", "11");
+            let from = make_session(JoinKind::Subscriber, "alpha", "12");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "alpha".into());
+            switchboard.join_publisher(Arc::clone(&bob), "11".into(), "alpha".into());
+            let user_ids = ["10".to_owned(), "13".to_owned()];
+            let err = media_publishers(&Config::default(), &switchboard, &from, &user_ids).unwrap_err();
+            assert_eq!(err, SfuError::PublisherNotFound("Can't subscribe to a nonexistent publisher."));
+            let config = Config {
+                max_subscribers_per_publisher: 1,
+                ..Config::default()
+            };
+            switchboard.subscribe_to_user(make_session(JoinKind::Subscriber, "alpha", "13"), Arc::clone(&bob));
+            let user_ids = ["10".to_owned(), "11".to_owned()];
+            let err = media_publishers(&config, &switchboard, &from, &user_ids).unwrap_err();
+            assert_eq!(err, SfuError::SubscriberLimit("Publisher subscriber limit reached."));
+            // publishers which are already sending their media to the subscriber don't need room for it again
+            switchboard.subscribe_to_user(Arc::clone(&from), Arc::clone(&alice));
+            let user_ids = ["10".to_owned()];
+            assert_eq!(media_publishers(&config, &switchboard, &from, &user_ids).unwrap(), vec![alice]);
+        }
+
+        #[test]
+        fn subscribing_to_several_unready_publishers_can_be_retried() {
+            let _globals = share_globals();
+            join_publisher("several_unready_publishers", "several_unready_publishers_10");
+            join_publisher("several_unready_publishers", "several_unready_publishers_11");
+            let from = make_unjoined_session();
+            process_join(
+                &from,
+                "several_unready_publishers".into(),
+                "several_unready_publishers_12".into(),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            let what = Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec!["several_unready_publishers_10".into(), "several_unready_publishers_11".into()]),
+            };
+            assert_eq!(process_subscribe(&from, &what).unwrap_err(), SfuError::PublisherNotReady);
+            assert_eq!(*from.subscription.lock().unwrap(), None);
+            assert!(SWITCHBOARD.read().unwrap().publishers_to(&from).is_empty());
+            assert!(!from.multi_source.load(Ordering::Relaxed));
+        }
+
+        #[test]
+        fn unjoined_sessions_have_no_subscriptions() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            let response = process_query_subscriptions(&from).unwrap();
+            assert_eq!(response.body, Some(json!({ "subscription": null, "media": [] })));
+        }
+
+        #[test]
+        fn switching_to_publisher_without_offer_fails() {
+            let _globals = share_globals();
+            join_publisher("switching_before_offer", "switching_before_offer_publisher");
+            let from = make_session(JoinKind::Subscriber, "switching_before_offer", "switching_before_offer_subscriber");
+            *from.subscription.lock().unwrap() = Some(Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec!["switching_before_offer_other".into()]),
+            });
+            let err = process_switch_media(&from, "switching_before_offer_publisher".into()).unwrap_err();
+            assert_eq!(err, SfuError::PublisherNotReady);
+        }
+
+        #[test]
+        fn switching_needs_a_media_subscription() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Subscriber, "switching_unsubscribed", "switching_unsubscribed");
+            let err = process_switch_media(&from, "switching_unsubscribed_publisher".into()).unwrap_err();
+            assert_eq!(err, SfuError::NotSubscribed("Cannot switch media without a subscription."));
+            *from.subscription.lock().unwrap() = Some(Subscription {
+                notifications: true,
+                data: false,
+                media: None,
+            });
+            let err = process_switch_media(&from, "switching_unsubscribed_publisher".into()).unwrap_err();
+            assert_eq!(err, SfuError::NotSubscribed("Cannot switch media without a media subscription."));
+        }
+
+        #[test]
+        fn switching_media_requests_a_keyframe() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "switching_media", "10");
+            let bob = make_session(JoinKind::Publisher, "switching_media", "11");
+            let subscriber = make_session(JoinKind::Subscriber, "switching_media", "12");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "switching_media".into());
+            switchboard.join_publisher(Arc::clone(&bob), "11".into(), "switching_media".into());
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&alice));
+            switch_media_source(&mut switchboard, &subscriber, Arc::clone(&bob), false);
+            assert_eq!(switchboard.publishers_to(&subscriber), &[Arc::clone(&bob)]);
+            assert!(switchboard.subscribers_to(&alice).is_empty());
+            assert!(bob.keyframe_wanted.load(Ordering::Relaxed));
+            assert!(!alice.keyframe_wanted.load(Ordering::Relaxed));
+        }
+
+        #[test]
+        fn choosing_a_layer_needs_a_matching_media_subscription() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Subscriber, "choosing_layer", "10");
+            assert!(matches!(process_set_layer(&from, "11".into(), 0), Err(SfuError::NotSubscribed(_))));
+            *from.subscription.lock().unwrap() = Some(Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec!["11".into()]),
+            });
+            assert!(matches!(process_set_layer(&from, "12".into(), 0), Err(SfuError::NotSubscribed(_))));
+            let layer = |index| Layer { index: Some(index), count: 3 };
+            assert!(from.layer_choice.selects(layer(2)));
+            let response = process_set_layer(&from, "11".into(), 0).unwrap();
+            assert_eq!(response.body, Some(json!({ "layers": 0 })));
+            assert!(from.layer_choice.selects(layer(0)));
+            assert!(!from.layer_choice.selects(layer(2)));
+        }
+
+        #[test]
+        fn switching_media_forgets_the_chosen_layer() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "switching_layers", "10");
+            let subscriber = make_session(JoinKind::Subscriber, "switching_layers", "12");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "switching_layers".into());
+            subscriber.layer_choice.set(Some(0));
+            switch_media_source(&mut switchboard, &subscriber, alice, false);
+            assert!(subscriber.layer_choice.selects(Layer { index: Some(2), count: 3 }));
+        }
+
+        #[test]
+        fn unlimited_subscription_rate_has_no_bucket() {
+            let config = Config::default();
+            assert!(subscription_bucket(&config, Instant::now()).is_none());
+            let config = Config {
+                max_subscription_rate: 2,
+                ..Config::default()
+            };
+            assert!(subscription_bucket(&config, Instant::now()).is_some());
+        }
+    }
+
+    mod answers {
+
+        use super::*;
+
+        fn media_subscription(publisher: &str) -> Option<Subscription> {
+            Some(Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec![publisher.into()]),
+            })
+        }
+
+        #[test]
+        fn subscribers_may_answer_offers() {
+            let from = make_session(JoinKind::Subscriber, "subscribers_may_answer", "11");
+            *from.subscription.lock().unwrap() = media_subscription("10");
+            assert!(check_answerable(&from).is_ok());
+        }
+
+        #[test]
+        fn subscribers_without_media_may_not_answer() {
+            let from = make_session(JoinKind::Subscriber, "subscribers_without_media", "11");
+            assert_eq!(
+                check_answerable(&from),
+                Err(SfuError::NotSubscribed("Cannot answer without a media subscription."))
+            );
+            *from.subscription.lock().unwrap() = Some(Subscription {
+                notifications: true,
+                data: false,
+                media: None,
+            });
+            assert_eq!(check_answerable(&from).unwrap_err().code(), "not_subscribed");
+        }
+
+        #[test]
+        fn publishers_may_not_answer() {
+            let from = make_session(JoinKind::Publisher, "publishers_may_not_answer", "10");
+            *from.subscription.lock().unwrap() = media_subscription("11");
+            assert_eq!(
+                check_answerable(&from),
+                Err(SfuError::Invalid("Publishers make offers; they may not answer.".into()))
+            );
+        }
+
+        #[test]
+        fn unjoined_sessions_may_not_answer() {
+            assert!(check_answerable(&make_unjoined_session()).is_err());
+        }
+    }
+
+    mod data {
+
+        use super::*;
+
+        #[test]
+        fn delivery_counts_live_and_dead_recipients() {
+            let live = make_session(JoinKind::Publisher, "delivery_counts", "10");
+            let destroyed = make_session(JoinKind::Publisher, "delivery_counts", "11");
+            destroyed.destroyed.store(true, Ordering::Relaxed);
+            let missing = make_session(JoinKind::Publisher, "delivery_counts", "12");
+            let recipients = vec![Arc::clone(&live), destroyed, Arc::clone(&missing)];
+            let delivery = deliver(&recipients, |s| if s.handle == missing.handle { JanusError::from(458) } else { Ok(()) });
+            assert_eq!(delivery, Delivery { reached: 1, failed: 2 });
+        }
+
+        #[test]
+        fn data_to_nonexistent_user_fails() {
+            let _globals = share_globals();
+            let from = join_publisher("data_to_nonexistent_user", "10");
+            assert!(process_data(&from, Some("11".into()), Some("hello".into()), None).is_err());
+        }
+
+        #[test]
+        fn data_to_subscriber_without_data_subscription_fails() {
+            let _globals = share_globals();
+            let from = join_publisher("data_to_unsubscribed_user", "data_to_unsubscribed_user_a");
+            let subscriber = make_unjoined_session();
+            process_join(
+                &subscriber,
+                "data_to_unsubscribed_user".into(),
+                "data_to_unsubscribed_user_b".into(),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            let result = process_data(&from, Some("data_to_unsubscribed_user_b".into()), Some("hello".into()), None);
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn whispers_reach_publishers_whatever_their_subscription() {
+            let notifications_only = Some(Subscription {
+                notifications: true,
+                data: false,
+                media: None,
+            });
+            let publisher = make_session(JoinKind::Publisher, "whispers", "10");
+            *publisher.subscription.lock().unwrap() = notifications_only.clone();
+            assert!(receives_whisper(&publisher, &"10".into()));
+            assert!(!receives_whisper(&publisher, &"11".into()));
+            assert!(!receives_broadcast(&publisher, &"11".into()));
+
+            let subscriber = make_session(JoinKind::Subscriber, "whispers", "10");
+            *subscriber.subscription.lock().unwrap() = notifications_only;
+            assert!(!receives_whisper(&subscriber, &"10".into()));
+            assert!(!receives_broadcast(&subscriber, &"11".into()));
+        }
+
+        #[test]
+        fn data_subscribers_get_whispers_and_broadcasts() {
+            let data = Some(Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            });
+            let subscriber = make_session(JoinKind::Subscriber, "data_subscribers", "10");
+            *subscriber.subscription.lock().unwrap() = data;
+            assert!(receives_whisper(&subscriber, &"10".into()));
+            assert!(receives_broadcast(&subscriber, &"11".into()));
+            assert!(!receives_broadcast(&subscriber, &"10".into()));
+        }
+
+        #[test]
+        fn data_subscribing_listeners_get_room_broadcasts() {
+            let _globals = share_globals();
+            let room_id = "listeners_get_broadcasts";
+            join_publisher(room_id, "listeners_get_broadcasts_a");
+            let listener = make_unjoined_session();
+            let subscription = Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            };
+            let role = Some(JoinKind::Subscriber);
+            process_join(&listener, room_id.into(), "listeners_get_broadcasts_b".into(), Some(subscription), None, role).unwrap();
+            let switchboard = SWITCHBOARD.read().unwrap();
+            let receivers = switchboard
+                .occupants_of(&room_id.into())
+                .filter(|s| receives_broadcast(s, &"listeners_get_broadcasts_a".into()))
+                .collect::<Vec<_>>();
+            assert_eq!(receivers, vec![&listener]);
+        }
+
+        #[test]
+        fn data_without_body_fails() {
+            let _globals = share_globals();
+            let from = join_publisher("data_without_body", "data_without_body");
+            let err = process_data(&from, None, None, None).unwrap_err();
+            assert_eq!(err, SfuError::Invalid("Data messages must have a body or body_bytes.".into()));
+        }
+
+        #[test]
+        fn data_size_is_limited() {
+            let config = Config {
+                max_data_message_bytes: 8,
+                ..Config::default()
+            };
+            // "héllo" is five characters, but six bytes
+            assert!(check_data_size(&config, Some("héllo"), Some(&Binary(vec![0; 1]))).is_ok());
+            assert!(check_data_size(&config, Some("héllo"), Some(&Binary(vec![0; 2]))).is_ok());
+            let err = check_data_size(&config, Some("héllo"), Some(&Binary(vec![0; 3]))).unwrap_err();
+            assert_eq!(err, SfuError::TooLarge(8));
+            assert_eq!(err.to_string(), "Data message is too large; the limit is 8 bytes.");
+            assert_eq!(check_data_size(&config, Some("héllo!!!"), None), Err(SfuError::TooLarge(8)));
+            assert!(check_data_size(&Config::default(), Some(&"x".repeat(100_000)), None).is_ok());
+        }
+
+        #[test]
+        fn binary_data_is_relayed_intact() {
+            let bytes = Binary((0..=255).rev().collect());
+            let payload = data_payload(None, Some(bytes.clone())).unwrap();
+            assert_eq!(payload["event"], "data");
+            assert!(payload.get("body").is_none());
+            let relayed: Binary = serde_json::from_value(payload["body_bytes"].clone()).unwrap();
+            assert_eq!(relayed, bytes);
+            let both = data_payload(Some("hello".into()), Some(bytes)).unwrap();
+            assert_eq!(both["body"], "hello");
+            assert!(both["body_bytes"].is_string());
+        }
+    }
+
+    mod leave {
+
+        use super::*;
+
+        // publishers' joins and leaves are announced to the room, which needs the gateway, so these join as subscribers
+        fn quiet() -> Option<Subscription> {
+            Some(Subscription {
+                notifications: false,
+                data: false,
+                media: None,
+            })
+        }
+
+        #[test]
+        fn leaving_allows_rejoining() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            process_join(
+                &from,
+                "leaving_allows_rejoining_a".into(),
+                "leaving_allows_rejoining".into(),
+                quiet(),
+                None,
+                None,
+            )
+            .unwrap();
+            process_leave(&from).unwrap();
+            assert!(from.join_state.get().is_none());
+            assert!(from.subscription.lock().unwrap().is_none());
+            let switchboard = SWITCHBOARD.read().unwrap();
+            assert_eq!(switchboard.count_subscribers_in(&"leaving_allows_rejoining_a".into()), 0);
+            drop(switchboard);
+
+            process_join(
+                &from,
+                "leaving_allows_rejoining_b".into(),
+                "leaving_allows_rejoining".into(),
+                quiet(),
+                None,
+                None,
+            )
+            .unwrap();
+            assert_eq!(from.join_state.get().unwrap().room_id, "leaving_allows_rejoining_b");
+            let switchboard = SWITCHBOARD.read().unwrap();
+            assert_eq!(switchboard.subscribers_occupying(&"leaving_allows_rejoining_b".into()), &[from.clone()]);
+        }
+
+        #[test]
+        fn joining_twice_leaves_the_first_join_alone() {
+            let _globals = share_globals();
+            let data = Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            };
+            let room_id = RoomId::from("joining_twice_leaves_the_first_join_alone");
+            let from = join_publisher("joining_twice_leaves_the_first_join_alone", "joining_twice");
+            *from.subscription.lock().unwrap() = Some(data.clone());
+            let before = SWITCHBOARD.read().unwrap().room_counts(&room_id);
+            // e.g. a retry after a lost response, which would otherwise find that its user is already publishing
+            let err = process_join(&from, room_id.clone(), "joining_twice".into(), Some(data.clone()), None, None).unwrap_err();
+            assert_eq!(err, SfuError::AlreadyJoined("Handles may only be in one room at a time!"));
+            let err = process_join(&from, "joining_twice_elsewhere".into(), "joining_twice_elsewhere".into(), None, None, None).unwrap_err();
+            assert_eq!(err.code(), "already_joined");
+            let switchboard = SWITCHBOARD.read().unwrap();
+            assert_eq!(switchboard.room_counts(&room_id), before);
+            assert_eq!(switchboard.get_room_users(&"joining_twice_elsewhere".into()).count(), 0);
+            assert_eq!(switchboard.get_publisher(&"joining_twice".into()), Some(&from));
+            assert_eq!(from.join_state.get().unwrap().room_id, room_id);
+            assert_eq!(*from.subscription.lock().unwrap(), Some(data));
+        }
+
+        #[test]
+        fn joining_twice_without_leaving_fails() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            process_join(&from, "joining_twice_without_leaving".into(), "10".into(), None, None, None).unwrap();
+            let err = process_join(&from, "joining_twice_without_leaving".into(), "10".into(), None, None, None).unwrap_err();
+            assert_eq!(err, SfuError::AlreadyJoined("Handles may only be in one room at a time!"));
+        }
+
+        #[test]
+        fn hanging_up_then_destroying_announces_leave_once() {
+            let _globals = share_globals();
+            let from = join_publisher("hangup_then_destroy", "hangup_then_destroy");
+            let joined = from.join_state.get().unwrap();
+            let mut switchboard = SWITCHBOARD.write().unwrap();
+            assert_eq!(announce_hangup(&switchboard, &from).unwrap().event["event"], "leave");
+            assert!(announce_hangup(&switchboard, &from).is_none());
+            switchboard.disconnect(&from);
+            let (left, notifications) = leave_room(&mut switchboard, &from);
+            assert!(left.is_some());
+            assert!(notifications.iter().all(|n| n.event["event"] != "leave"));
+            assert!(announce_leave(&switchboard, &joined).is_none());
+        }
+
+        #[test]
+        fn destroying_without_hanging_up_announces_leave() {
+            let _globals = share_globals();
+            let from = join_publisher("destroy_without_hangup", "destroy_without_hangup");
+            let joined = from.join_state.get().unwrap();
+            let mut switchboard = SWITCHBOARD.write().unwrap();
+            let (_, notifications) = leave_room(&mut switchboard, &from);
+            assert!(joined.departed.load(Ordering::Relaxed));
+            assert!(announce_hangup(&switchboard, &from).is_none());
+            let events = notifications.iter().map(|n| n.event["event"].clone()).collect::<Vec<_>>();
+            assert_eq!(events, vec!["publisher_gone", "room_destroyed", "leave"]);
+        }
+
+        #[test]
+        fn subscribers_hanging_up_announce_nothing() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Subscriber, "subscriber_hangup", "subscriber_hangup");
+            let switchboard = SWITCHBOARD.read().unwrap();
+            assert!(announce_hangup(&switchboard, &from).is_none());
+        }
+
+        #[test]
+        fn leaving_without_joining_fails() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            let err = process_leave(&from).unwrap_err();
+            assert_eq!(err, SfuError::NotJoined("Cannot leave when not in a room."));
+        }
+    }
+
+    mod change_room {
+
+        use super::*;
+
+        #[test]
+        fn publishers_change_rooms_as_the_same_user() {
+            let _globals = share_globals();
+            join_publisher("publishers_change_rooms_b", "11");
+            let from = join_publisher("publishers_change_rooms_a", "10");
+            let (response, notifications) = change_room(&from, "publishers_change_rooms_b".into(), None).unwrap();
+            assert_eq!(response.body.unwrap()["users"], json!({ "publishers_change_rooms_b": ["11"] }));
+            let events = notifications.iter().map(|n| n.event["event"].clone()).collect::<Vec<_>>();
+            assert_eq!(events, vec!["publisher_gone", "room_destroyed", "leave", "join"]);
+            let joined = from.join_state.get().unwrap();
+            assert_eq!((joined.room_id.as_str(), joined.user_id.as_str()), ("publishers_change_rooms_b", "10"));
+            let switchboard = SWITCHBOARD.read().unwrap();
+            assert_eq!(switchboard.get_room_users(&"publishers_change_rooms_a".into()).count(), 0);
+            assert_eq!(switchboard.room_counts(&"publishers_change_rooms_b".into()), (2, 0));
+        }
+
+        #[test]
+        fn changing_rooms_requires_a_room() {
+            let _globals = share_globals();
+            let from = make_unjoined_session();
+            let err = process_change_room(&from, "changing_rooms_requires_a_room".into(), None).unwrap_err();
+            assert_eq!(err, SfuError::NotJoined("Cannot change rooms when not in a room."));
+            let from = join_publisher("changing_rooms_requires_a_room", "10");
+            let err = process_change_room(&from, "changing_rooms_requires_a_room".into(), None).unwrap_err();
+            assert_eq!(err, SfuError::AlreadyJoined("Already in that room."));
+        }
+
+        #[test]
+        fn changing_rooms_cannot_bypass_a_waiting_room() {
+            let _globals = share_globals();
+            let room_id = RoomId::from("changing_rooms_cannot_bypass_a_waiting_room_b");
+            SWITCHBOARD.write().unwrap().set_waiting_room(room_id.clone(), true);
+            let from = join_publisher("changing_rooms_cannot_bypass_a_waiting_room_a", "10");
+            let err = process_change_room(&from, room_id, None).unwrap_err();
+            assert_eq!(err, SfuError::Waiting("Cannot change into a room with a waiting room; join it instead."));
+        }
+    }
+
+    mod query {
+
+        use super::*;
+
+        #[test]
+        fn unjoined_sessions_are_described() {
+            let _globals = share_globals();
+            let sess = make_unjoined_session();
+            let expected = json!({
+                "joined": false,
+                "kind": null,
+                "room_id": null,
+                "user_id": null,
+                "joined_at": null,
+                "time_in_room_ms": null,
+                "destroyed": false,
+                "has_subscriber_offer": false,
+                "negotiated": null,
+                "remb_estimate": 0,
+                "subscription": null,
+                "traffic": { "packets_in": 0, "bytes_in": 0, "packets_out": 0, "bytes_out": 0 },
+            });
+            assert_eq!(describe_session(&sess, Instant::now()), expected);
+        }
+
+        #[test]
+        fn joined_sessions_are_described() {
+            let _globals = share_globals();
+            let sess = make_session(JoinKind::Subscriber, "alpha", "10");
+            *sess.subscription.lock().unwrap() = Some(Subscription {
+                notifications: true,
+                data: false,
+                media: Some(vec!["11".into()]),
+            });
+            let joined = sess.join_state.get().unwrap();
+            let now = joined.joined_instant + Duration::from_millis(2500);
+            let expected = json!({
+                "joined": true,
+                "kind": "subscriber",
+                "room_id": "alpha",
+                "user_id": "10",
+                "joined_at": joined.joined_at_millis(),
+                "time_in_room_ms": 2500,
+                "destroyed": false,
+                "has_subscriber_offer": false,
+                "negotiated": null,
+                "remb_estimate": 0,
+                "subscription": { "notifications": true, "data": false, "media": ["11"] },
+                "traffic": { "packets_in": 0, "bytes_in": 0, "packets_out": 0, "bytes_out": 0 },
+            });
+            assert_eq!(describe_session(&sess, now), expected);
+        }
+
+        #[test]
+        fn negotiated_codecs_are_described() {
+            let _globals = share_globals();
+            let sess = make_session(JoinKind::Publisher, "alpha", "12");
+            let negotiated = negotiated_codecs(AudioCodec::Opus, VideoCodec::H264, Some(111), Some(126));
+            assert_eq!(negotiated.video_profile.as_deref(), Some("42e01f"));
+            *sess.negotiated.lock().unwrap() = Some(negotiated);
+            let expected = json!({
+                "audio_codec": "opus",
+                "audio_payload_type": 111,
+                "video_codec": "h264",
+                "video_payload_type": 126,
+                "video_profile": "42e01f",
+            });
+            assert_eq!(describe_session(&sess, Instant::now())["negotiated"], expected);
+
+            let negotiated = negotiated_codecs(AudioCodec::Opus, VideoCodec::Vp8, Some(111), None);
+            assert_eq!(negotiated.video_codec, "vp8");
+            assert_eq!(negotiated.video_payload_type, None);
+            assert_eq!(negotiated.video_profile, None);
+        }
+    }
+
+    mod admin {
+
+        use super::*;
+
+        fn switchboard_with_rooms() -> Switchboard {
+            let mut switchboard = Switchboard::new();
+            for &(room, user) in &[("alpha", "10"), ("alpha", "11"), ("beta", "12")] {
+                switchboard.join_publisher(make_session(JoinKind::Publisher, room, user), user.into(), room.into());
+            }
+            switchboard.join_subscriber(make_session(JoinKind::Subscriber, "alpha", "10"), "10".into(), "alpha".into());
+            switchboard
+        }
+
+        #[test]
+        fn rooms_are_listed() {
+            let _globals = share_globals();
+            let response = process_admin_command(&switchboard_with_rooms(), AdminCommand::ListRooms);
+            let expected = json!({
+                "rooms": [
+                    { "room_id": "alpha", "publisher_count": 2 },
+                    { "room_id": "beta", "publisher_count": 1 },
+                ]
+            });
+            assert_eq!(response, expected);
+        }
+
+        #[test]
+        fn rooms_are_described() {
+            let _globals = share_globals();
+            let mut switchboard = switchboard_with_rooms();
+            switchboard.set_presenter_only_audio("alpha".into(), true);
+            let response = process_admin_command(&switchboard, AdminCommand::RoomInfo { room_id: "alpha".into() });
+            let expected = json!({
+                "room_id": "alpha",
+                "users": ["10", "11"],
+                "publishers": 2,
+                "subscribers": 1,
+                "presenter_only_audio": true,
+                "waiting_room": false,
+            });
+            assert_eq!(response, expected);
+        }
+
+        #[test]
+        fn metrics_are_reported() {
+            let _globals = share_globals();
+            let response = process_admin_command(&switchboard_with_rooms(), AdminCommand::Metrics);
+            let text = response["metrics"].as_str().unwrap();
+            assert!(text.contains("\njanus_sfu_publishers 3\n"));
+            assert!(text.contains("\njanus_sfu_subscribers 1\n"));
+            assert!(text.contains("\njanus_sfu_rooms 2\n"));
+        }
+
+        #[test]
+        fn ccu_is_reported() {
+            let _globals = share_globals();
+            let response = process_admin_command(&switchboard_with_rooms(), AdminCommand::Ccu);
+            assert_eq!(response, json!({ "users": 3, "connections": 4 }));
+        }
+
+        #[test]
+        fn kicks_end_all_of_a_users_sessions_in_the_room() {
+            let _globals = share_globals();
+            let mut switchboard = switchboard_with_rooms();
+            switchboard.join_subscriber(make_session(JoinKind::Subscriber, "alpha", "10"), "10".into(), "alpha".into());
+            let mut kicked = Vec::new();
+            let count = kick_user(
+                &switchboard,
+                &"alpha".into(),
+                &"10".into(),
+                None,
+                |_, _| {},
+                |s| {
+                    let joined = s.join_state.get().unwrap();
+                    kicked.push((joined.kind, joined.user_id.clone()));
+                },
+            );
+            assert_eq!(count, 3);
+            kicked.sort_by_key(|&(kind, _)| kind == JoinKind::Subscriber);
+            let expected = vec![
+                (JoinKind::Publisher, "10".to_owned()),
+                (JoinKind::Subscriber, "10".to_owned()),
+                (JoinKind::Subscriber, "10".to_owned()),
+            ];
+            assert_eq!(kicked, expected);
+        }
+
+        #[test]
+        fn kicks_leave_other_rooms_and_users_alone() {
+            let _globals = share_globals();
+            let switchboard = switchboard_with_rooms();
+            let send = |_: &JsonValue, _: Vec<&Arc<Session>>| panic!("Notified about the wrong kick.");
+            let end = |_: &Session| panic!("Kicked the wrong session.");
+            assert_eq!(kick_user(&switchboard, &"beta".into(), &"10".into(), None, send, end), 0);
+            assert_eq!(kick_user(&switchboard, &"alpha".into(), &"13".into(), None, send, end), 0);
+        }
+
+        #[test]
+        fn kicks_are_announced_to_the_room_and_the_kicked_user() {
+            let _globals = share_globals();
+            let switchboard = switchboard_with_rooms();
+            for session in switchboard.publishers_occupying(&"alpha".into()) {
+                *session.subscription.lock().unwrap() = Some(Subscription {
+                    notifications: true,
+                    data: true,
+                    media: None,
+                });
+            }
+            let mut events = Vec::new();
+            let count = kick_user(
+                &switchboard,
+                &"alpha".into(),
+                &"10".into(),
+                Some(&"11".into()),
+                |event, sessions| {
+                    let mut recipients = sessions.iter().map(|s| s.join_state.get().unwrap().user_id.clone()).collect::<Vec<_>>();
+                    recipients.sort();
+                    events.push((event.clone(), recipients));
+                },
+                |_| {},
+            );
+            assert_eq!(count, 2);
+            let event = json!({ "event": "kicked", "user_id": "10", "room_id": "alpha", "by": "11" });
+            let expected = vec![(event.clone(), vec!["11".to_owned()]), (event, vec!["10".to_owned(), "10".to_owned()])];
+            assert_eq!(events, expected);
+        }
+    }
+
+    mod blocks {
+
+        use super::*;
+
+        fn watch(switchboard: &mut Switchboard, user: &str, publisher: &Arc<Session>) -> Arc<Session> {
+            let subscriber = make_session(JoinKind::Subscriber, "alpha", user);
+            let publisher_id = publisher.join_state.get().unwrap().user_id.clone();
+            *subscriber.subscription.lock().unwrap() = Some(Subscription {
+                notifications: false,
+                data: false,
+                media: Some(vec![publisher_id]),
+            });
+            switchboard.join_subscriber(Arc::clone(&subscriber), user.into(), "alpha".into());
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(publisher));
+            subscriber
+        }
+
+        #[test]
+        fn blocks_drop_media_subscriptions_both_ways() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "alpha", "10");
+            let bob = make_session(JoinKind::Publisher, "alpha", "11");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "alpha".into());
+            switchboard.join_publisher(Arc::clone(&bob), "11".into(), "alpha".into());
+            let alice_watching_bob = watch(&mut switchboard, "10", &bob);
+            let bob_watching_alice = watch(&mut switchboard, "11", &alice);
+
+            switchboard.establish_block("10".into(), "11".into());
+            let dropped = drop_blocked_media(&mut switchboard, &"10".into(), &"11".into());
+            assert_eq!(dropped.len(), 2);
+            for (subscriber, publisher) in &dropped {
+                assert!(switchboard.publishers_to(subscriber).is_empty());
+                assert!(check_answerable(subscriber).is_ok());
+                // the publisher hasn't negotiated any media in this test, so there's nothing to renegotiate
+                assert_eq!(inactive_jsep(publisher).unwrap(), None);
+            }
+            assert!(dropped.contains(&(alice_watching_bob, Arc::clone(&bob))));
+            assert!(dropped.contains(&(bob_watching_alice, Arc::clone(&alice))));
+        }
+
+        #[test]
+        fn blocks_leave_other_subscriptions_alone() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "alpha", "10");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "alpha".into());
+            let carol_watching_alice = watch(&mut switchboard, "12", &alice);
+            assert!(drop_blocked_media(&mut switchboard, &"10".into(), &"11".into()).is_empty());
+            assert_eq!(switchboard.subscribers_to(&alice), &[Arc::clone(&carol_watching_alice)]);
+            assert_eq!(
+                carol_watching_alice.subscription.lock().unwrap().as_ref().unwrap().media,
+                Some(vec!["10".into()])
+            );
+        }
+
+        #[test]
+        fn blocks_are_listed_for_joined_users() {
+            let _globals = share_globals();
+            let from = make_session(JoinKind::Publisher, "list_blocks", "list_blocks_alice");
+            {
+                let mut switchboard = SWITCHBOARD.write().unwrap();
+                switchboard.establish_block("list_blocks_alice".into(), "list_blocks_bob".into());
+                switchboard.establish_block("list_blocks_alice".into(), "list_blocks_bob".into());
+                switchboard.establish_block("list_blocks_carol".into(), "list_blocks_alice".into());
+            }
+            let body = process_list_blocks(&from).unwrap().body.unwrap();
+            assert_eq!(body, json!({ "blocking": ["list_blocks_bob"], "blocked_by": ["list_blocks_carol"] }));
+
+            let err = process_list_blocks(&make_unjoined_session()).unwrap_err();
+            assert_eq!(err, SfuError::NotJoined("Cannot list blocks when not in a room."));
+        }
+    }
+
+    mod traffic {
+
+        use super::*;
+        use switching::RtpHeader;
+
+        #[test]
+        fn publishers_hear_when_their_subscriber_count_changes() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let alice = make_session(JoinKind::Publisher, "alpha", "10");
+            let bob = make_session(JoinKind::Publisher, "alpha", "11");
+            switchboard.join_publisher(Arc::clone(&alice), "10".into(), "alpha".into());
+            switchboard.join_publisher(Arc::clone(&bob), "11".into(), "alpha".into());
+            let mut counts = HashMap::new();
+            assert!(changed_subscriber_counts(&switchboard, &mut counts).is_empty());
+
+            // several subscribers arriving between checks make for one change
+            for user_id in &["12", "13", "14"] {
+                let subscriber = make_session(JoinKind::Subscriber, "alpha", user_id);
+                switchboard.join_subscriber(Arc::clone(&subscriber), (*user_id).into(), "alpha".into());
+                switchboard.subscribe_to_user(subscriber, Arc::clone(&alice));
+            }
+            assert_eq!(changed_subscriber_counts(&switchboard, &mut counts), vec![(alice.clone(), 3)]);
+            assert!(changed_subscriber_counts(&switchboard, &mut counts).is_empty());
+
+            let leaving = switchboard.subscribers_to(&alice)[0].clone();
+            switchboard.leave_subscriber(&leaving);
+            switchboard.subscribe_to_user(make_session(JoinKind::Subscriber, "alpha", "15"), Arc::clone(&bob));
+            let mut changed = changed_subscriber_counts(&switchboard, &mut counts);
+            changed.sort_by_key(|(_, count)| *count);
+            assert_eq!(changed, vec![(bob.clone(), 1), (alice.clone(), 2)]);
+
+            // a publisher who leaves is forgotten, so they'd hear their count afresh if they came back
+            switchboard.leave_publisher(&alice);
+            assert!(changed_subscriber_counts(&switchboard, &mut counts).is_empty());
+            assert_eq!(counts.get("10"), None);
+        }
+
+        #[test]
+        fn only_sessions_unheard_from_for_the_timeout_are_idle() {
+            let mut switchboard = Switchboard::new();
+            let start = Instant::now();
+            let timeout = Duration::from_secs(30);
+            let quiet = make_unjoined_session();
+            let chatty = make_unjoined_session();
+            let silent = make_unjoined_session();
+            quiet.last_seen.touch(start);
+            chatty.last_seen.touch(start + Duration::from_secs(20));
+            for session in &[&quiet, &chatty, &silent] {
+                switchboard.connect(Box::new(Arc::clone(session)));
+            }
+            assert!(idle_sessions(&switchboard, timeout, start + Duration::from_secs(30)).is_empty());
+            assert_eq!(idle_sessions(&switchboard, timeout, start + Duration::from_secs(31)), vec![quiet]);
+        }
+
+        #[test]
+        fn pings_are_answered() {
+            let _globals = share_globals();
+            let response = process_ping(&make_unjoined_session()).unwrap();
+            assert_eq!(response.body, Some(json!({ "pong": true })));
+        }
+
+        #[test]
+        fn relaying_media_counts_traffic() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            let subscriber = make_session(JoinKind::Subscriber, "alpha", "11");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "alpha".into());
+            switchboard.join_subscriber(Arc::clone(&subscriber), "11".into(), "alpha".into());
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&publisher));
+            let mut deliveries = 0;
+            for _ in 0..3 {
+                let relayed = relay_media(switchboard.routes(), &publisher, true, 100, None, |other| {
+                    assert_eq!(other, subscriber.as_ref());
+                    deliveries += 1;
+                });
+                assert_eq!(relayed, 1);
+            }
+            assert_eq!(deliveries, 3);
+            let traffic = &describe_session(&publisher, Instant::now())["traffic"];
+            assert_eq!(traffic, &json!({ "packets_in": 3, "bytes_in": 300, "packets_out": 0, "bytes_out": 0 }));
+            let traffic = &describe_session(&subscriber, Instant::now())["traffic"];
+            assert_eq!(traffic, &json!({ "packets_in": 0, "bytes_in": 0, "packets_out": 3, "bytes_out": 300 }));
+        }
+
+        #[test]
+        fn nacked_packets_are_found_as_their_publisher_sent_them() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let first = make_session(JoinKind::Publisher, "alpha", "10");
+            let second = make_session(JoinKind::Publisher, "alpha", "11");
+            let subscriber = make_session(JoinKind::Subscriber, "alpha", "12");
+            switchboard.join_publisher(Arc::clone(&first), "10".into(), "alpha".into());
+            switchboard.join_publisher(Arc::clone(&second), "11".into(), "alpha".into());
+            switchboard.join_subscriber(Arc::clone(&subscriber), "12".into(), "alpha".into());
+            let packet = |seq: u16, ssrc: u32| {
+                let mut packet = vec![0x80, 96, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xAA];
+                RtpHeader {
+                    payload_type: 96,
+                    seq,
+                    timestamp: 0,
+                    ssrc,
+                }
+                .write(&mut packet);
+                packet
+            };
+            // the subscriber sees 100 and 101 from the first publisher, then 102 and 103 from the second
+            switchboard.switch_publisher(Arc::clone(&subscriber), Arc::clone(&first));
+            for &(publisher, seq, ssrc) in &[(&first, 100, 1), (&first, 101, 1), (&second, 5000, 2), (&second, 5001, 2)] {
+                let mut sent = packet(seq, ssrc);
+                publisher.rtp_buffer.push(&sent, 100);
+                subscriber.switching.relay(&mut sent, true, || ());
+            }
+            switchboard.switch_publisher(Arc::clone(&subscriber), Arc::clone(&second));
+            let found = find_nacked_packets(&switchboard, &subscriber, &[103, 102, 104]);
+            assert_eq!(found, vec![packet(5001, 2), packet(5000, 2)]);
+            // packets from a previous source can't be traced anymore
+            assert!(find_nacked_packets(&switchboard, &subscriber, &[100]).is_empty());
+        }
+
+        #[test]
+        fn publishers_get_their_subscribers_lowest_estimate() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            let fast = make_session(JoinKind::Subscriber, "alpha", "11");
+            let slow = make_session(JoinKind::Subscriber, "alpha", "12");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "alpha".into());
+            for &(subscriber, user_id) in &[(&fast, "11"), (&slow, "12")] {
+                switchboard.join_subscriber(Arc::clone(subscriber), user_id.into(), "alpha".into());
+                switchboard.subscribe_to_user(Arc::clone(subscriber), Arc::clone(&publisher));
+            }
+            let estimates = aggregate_remb(&switchboard, &fast, 2_000_000);
+            assert_eq!(estimates, vec![(&publisher, 2_000_000)]);
+            let estimates = aggregate_remb(&switchboard, &slow, 300_000);
+            assert_eq!(estimates, vec![(&publisher, 300_000)]);
+            let estimates = aggregate_remb(&switchboard, &fast, 1_500_000);
+            assert_eq!(estimates, vec![(&publisher, 300_000)]);
+            assert_eq!(publisher.remb_estimate.load(Ordering::Relaxed), 300_000);
+            // once the slow subscriber switches to someone else, the next estimate lets the publisher speed up
+            let other = make_session(JoinKind::Publisher, "alpha", "13");
+            switchboard.join_publisher(Arc::clone(&other), "13".into(), "alpha".into());
+            switchboard.switch_publisher(Arc::clone(&slow), Arc::clone(&other));
+            let estimates = aggregate_remb(&switchboard, &fast, 1_500_000);
+            assert_eq!(estimates, vec![(&publisher, 1_500_000)]);
+        }
+
+        #[test]
+        fn unjoined_sessions_relay_nothing() {
+            let _globals = share_globals();
+            let sess = make_unjoined_session();
+            assert_eq!(relay_media(&RouteTable::new(), &sess, false, 100, None, |_| panic!("Nobody to relay to!")), 0);
+            assert_eq!(sess.traffic.packets_in.load(Ordering::Relaxed), 0);
+        }
+
+        #[test]
+        fn simulcast_layers_are_relayed_to_whoever_chose_them() {
+            let _globals = share_globals();
+            let mut switchboard = Switchboard::new();
+            let publisher = make_session(JoinKind::Publisher, "alpha", "10");
+            let low = make_session(JoinKind::Subscriber, "alpha", "11");
+            let high = make_session(JoinKind::Subscriber, "alpha", "12");
+            switchboard.join_publisher(Arc::clone(&publisher), "10".into(), "alpha".into());
+            for subscriber in &[&low, &high] {
+                switchboard.subscribe_to_user(Arc::clone(subscriber), Arc::clone(&publisher));
+            }
+            low.layer_choice.set(Some(0));
+            let layer = |index| Some(Layer { index, count: 2 });
+            let mut relayed_to = Vec::new();
+            for &index in &[Some(0), Some(1), None] {
+                relay_media(switchboard.routes(), &publisher, true, 100, layer(index), |other| {
+                    relayed_to.push((index, other.join_state.get().unwrap().user_id.clone()));
+                });
+            }
+            assert_eq!(relayed_to, vec![(Some(0), "11".to_owned()), (Some(1), "12".to_owned())]);
+            assert_eq!(high.traffic.packets_out.load(Ordering::Relaxed), 1);
+        }
+
+        #[test]
+        fn keyframe_requests_are_throttled_per_publisher() {
+            let config = Config {
+                keyframe_min_interval_ms: 1000,
+                ..Config::default()
+            };
+            let alice = make_session(JoinKind::Publisher, "alpha", "10");
+            let bob = make_session(JoinKind::Publisher, "alpha", "11");
+            let now = Instant::now();
+            assert_eq!(throttle_keyframe_requests(&[Arc::clone(&alice)], &config, now), vec![&alice]);
+            let both = [Arc::clone(&alice), Arc::clone(&bob)];
+            assert_eq!(throttle_keyframe_requests(&both, &config, now), vec![&bob]);
+            assert!(throttle_keyframe_requests(&both, &config, now + Duration::from_millis(999)).is_empty());
+            let later = now + Duration::from_millis(1000);
+            assert_eq!(throttle_keyframe_requests(&both, &config, later), vec![&alice, &bob]);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+/// Tools for carrying on when a thread panicked while holding a lock, on paths like media relaying where panicking in
+/// turn would stop all media on the server.
+use janus_plugin::janus_warn;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Whether we've already warned about using a poisoned lock. Once one is poisoned, it stays that way, so warning every
+/// time would mean warning for every packet.
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Locks the given lock for reading. If it's poisoned, warns about it, and reads whatever state it was left in.
+pub fn read_or_recover<'a, T>(lock: &'a RwLock<T>, name: &str) -> RwLockReadGuard<'a, T> {
+    lock.read().unwrap_or_else(|poisoned| recover(poisoned, name))
+}
+
+/// Locks the given lock for writing. If it's poisoned, warns about it, and writes to whatever state it was left in.
+pub fn write_or_recover<'a, T>(lock: &'a RwLock<T>, name: &str) -> RwLockWriteGuard<'a, T> {
+    lock.write().unwrap_or_else(|poisoned| recover(poisoned, name))
+}
+
+fn recover<G>(poisoned: PoisonError<G>, name: &str) -> G {
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        janus_warn!("{} lock poisoned by a panic; carrying on with the state it was left in.", name);
+    }
+    poisoned.into_inner()
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    /// Poisons the given lock by panicking while holding it.
+    pub fn poison<T>(lock: &RwLock<T>) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = lock.write().unwrap();
+            panic!("poisoning the lock");
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn poisoned_locks_can_still_be_read() {
+        let lock = RwLock::new(5);
+        poison(&lock);
+        assert!(lock.read().is_err());
+        assert_eq!(*read_or_recover(&lock, "Test"), 5);
+        assert_eq!(*read_or_recover(&lock, "Test"), 5);
+    }
+
+    #[test]
+    fn poisoned_locks_can_still_be_written() {
+        let lock = RwLock::new(5);
+        poison(&lock);
+        *write_or_recover(&lock, "Test") += 1;
+        assert_eq!(*read_or_recover(&lock, "Test"), 6);
+    }
+}
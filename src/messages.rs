@@ -1,7 +1,8 @@
 /// Types and code related to handling signalling messages.
+use crate::sessions::JoinKind;
 use janus_plugin::sdp::Sdp;
-use serde::de::DeserializeOwned;
-use serde::Deserialize;
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::borrow::Borrow;
 use std::error::Error;
 
@@ -12,6 +13,21 @@ pub type RoomId = String;
 /// conceptual user for managing subscriptions.
 pub type UserId = String;
 
+/// Checks that the given room or user ID is something we're willing to log and hand to other clients: not empty, no
+/// longer than the given number of characters, and free of control characters. The name is used in the error.
+pub fn validate_id(name: &str, id: &str, max_length: usize) -> Result<(), String> {
+    if id.is_empty() {
+        return Err(format!("{} must not be empty.", name));
+    }
+    if id.chars().count() > max_length {
+        return Err(format!("{} must be at most {} characters long.", name, max_length));
+    }
+    if id.chars().any(char::is_control) {
+        return Err(format!("{} must not contain control characters.", name));
+    }
+    Ok(())
+}
+
 /// Useful to represent a JSON message field which may or may not be present.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(untagged)]
@@ -52,27 +68,118 @@ pub enum JsepKind {
 
 /// The enumeration of all (non-JSEP) signalling messages which can be received from a client.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
-#[serde(rename_all = "lowercase", tag = "kind")]
+#[serde(rename_all = "snake_case", tag = "kind")]
 pub enum MessageKind {
     /// Indicates that a client wishes to "join" a room on the server. Prior to this, no audio, video, or data
     /// received from the client will be forwarded to anyone.
     ///
     /// The "subscribe" field specifies which kind of traffic this client will receive. (Useful for saving a round
     /// trip if you wanted to both join and subscribe, as is typical.)
+    ///
+    /// The "role" field says whether this is a publisher's or a subscriber's connection. Without it, connections which
+    /// subscribe to data are taken to be publishers, and everyone else subscribers.
     Join {
         room_id: RoomId,
         user_id: UserId,
         subscribe: Option<Subscription>,
         token: Option<String>,
+        role: Option<JoinKind>,
     },
 
+    /// Indicates that a client wishes to leave the room it joined, while keeping its connection, so that it can join
+    /// another room later.
+    Leave {},
+
+    /// Indicates that a client wishes to move to another room as the same user, keeping its connection. Any media
+    /// subscriptions it had are dropped. If the server requires authentication, requires a token letting the bearer
+    /// join the new room.
+    ChangeRoom { room_id: RoomId, token: Option<String> },
+
+    /// Lists the rooms which people are publishing in. If the server requires authentication, requires a token letting
+    /// the bearer join rooms, and only lists the rooms it lets them join.
+    ListRooms { token: Option<String> },
+
+    /// Lists the users publishing in the given room, without joining it. If the server requires authentication,
+    /// requires a token letting the bearer join the room.
+    ListUsers { room_id: RoomId, token: Option<String> },
+
+    /// Counts the publishers and subscribers in the given room, without joining it. If the server requires
+    /// authentication, requires a token letting the bearer join the room.
+    RoomCount { room_id: RoomId, token: Option<String> },
+
     /// Indicates that the given user should be disconnected from the given room. Requires a token bequeathing
-    /// this permission for the given room.
-    Kick { room_id: RoomId, user_id: UserId, token: String },
+    /// this permission for the given room; without one, the token the sender joined with or last refreshed is used.
+    Kick { room_id: RoomId, user_id: UserId, token: Option<String> },
+
+    /// Replaces the token which the sender joined its room with, e.g. because they've been made a moderator, so that
+    /// their permissions change without reconnecting. The token must let them stay in the room as the same user.
+    RefreshToken { token: String },
+
+    /// Indicates that only the given users should be allowed to publish in the given room, so that a room can be
+    /// prepared before anyone shows up. An empty list lifts the restriction. Requires a token bequeathing moderator
+    /// permissions for the given room.
+    Roster { room_id: RoomId, user_ids: Vec<UserId>, token: String },
+
+    /// Revokes the token with the given ID, so that it can no longer be used. Requires a token bequeathing
+    /// server-wide moderator permissions.
+    RevokeToken { jti: String, token: String },
+
+    /// Indicates whether only moderators' audio should be relayed in the given room, as in a town hall where
+    /// everyone else only listens. Requires a token bequeathing moderator permissions for the given room.
+    PresenterOnlyAudio { room_id: RoomId, enabled: bool, token: String },
+
+    /// Indicates whether people joining the given room must wait for a moderator to admit them. Turning it off admits
+    /// everyone who is waiting. Requires a token bequeathing moderator permissions for the given room.
+    WaitingRoom { room_id: RoomId, enabled: bool, token: String },
+
+    /// Indicates whether the given room should be closed to new publishers, e.g. once a town hall has started.
+    /// Moderators may still join. Requires a token bequeathing moderator permissions for the given room.
+    LockRoom { room_id: RoomId, locked: bool, token: String },
+
+    /// Starts recording each publisher's media in the given room to disk. Requires a token bequeathing record
+    /// permissions for the given room.
+    StartRecording { room_id: RoomId, token: String },
+
+    /// Stops recording the given room. Requires a token bequeathing record permissions for the given room.
+    StopRecording { room_id: RoomId, token: String },
+
+    /// Admits the given user, who is waiting to join the sender's room. Only moderators may admit users.
+    AdmitUser { user_id: UserId },
+
+    /// Turns away the given user, who is waiting to join the sender's room. Only moderators may deny users.
+    DenyUser { user_id: UserId },
 
     /// Indicates that a client wishes to subscribe to traffic described by the given subscription specification.
     Subscribe { what: Subscription },
 
+    /// Asks what the client is subscribed to, and whose media it's receiving, e.g. after reconnecting.
+    QuerySubscriptions {},
+
+    /// Asks for the offer for the media the client is subscribed to again, e.g. because it never got the first one.
+    RequestOffer {},
+
+    /// Tells the server that the client is still around, so that it isn't ended for being idle.
+    Ping {},
+
+    /// Indicates that a client with a media subscription wishes to receive media from a different user instead,
+    /// using the same connection.
+    SwitchMedia { media: UserId },
+
+    /// Indicates that a client with a media subscription to the given user wishes to receive the given layer of their
+    /// simulcast video, counting up from 0 for the lowest quality.
+    SetLayer { media: UserId, spatial: u8 },
+
+    /// Indicates that the given kind of media from the given user, who's publishing in the sender's room, should be
+    /// dropped instead of relayed. Requires a token bequeathing moderator permissions for the sender's room.
+    Mute { user_id: UserId, media_kind: MediaKind, token: String },
+
+    /// Undoes a mute of the given kind of media from the given user. Requires the same permissions as muting.
+    Unmute { user_id: UserId, media_kind: MediaKind, token: String },
+
+    /// Mutes the given kind of media from every publisher in the sender's room who isn't a moderator. Requires the
+    /// same permissions as muting.
+    MuteAll { media_kind: MediaKind, token: String },
+
     /// Indicates that a given user should be blocked from receiving your traffic, and that you should not
     /// receive their traffic (superseding any subscriptions you have.)
     Block { whom: UserId },
@@ -80,12 +187,80 @@ pub enum MessageKind {
     /// Undoes a block targeting the given user.
     Unblock { whom: UserId },
 
-    /// Sends arbitrary data to either all other clients in the room with you, or to a single other client.
-    Data { whom: Option<UserId>, body: String },
+    /// Asks which users the client is blocking, and which users are blocking it.
+    ListBlocks {},
+
+    /// Sends arbitrary data to either all other clients in the room with you, or to a single other client. The data
+    /// may be text, binary, or both.
+    Data {
+        whom: Option<UserId>,
+        body: Option<String>,
+        body_bytes: Option<Binary>,
+    },
+}
+
+/// A kind of media which a publisher sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Audio,
+    Video,
+}
+
+impl MediaKind {
+    pub fn is_video(self) -> bool {
+        self == MediaKind::Video
+    }
+}
+
+/// Arbitrary binary data, which clients may send either as an array of bytes or as a base64 string, and which we send
+/// to clients as a base64 string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binary(pub Vec<u8>);
+
+impl<'de> Deserialize<'de> for Binary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Encoded {
+            Bytes(Vec<u8>),
+            Base64(String),
+        }
+        match Encoded::deserialize(deserializer)? {
+            Encoded::Bytes(bytes) => Ok(Binary(bytes)),
+            Encoded::Base64(text) => base64::decode(&text).map(Binary).map_err(de::Error::custom),
+        }
+    }
+}
+
+impl Serialize for Binary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(&self.0))
+    }
+}
+
+/// The enumeration of all commands which can be received from operators over the Janus admin API.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "command")]
+pub enum AdminCommand {
+    /// Lists every room which anyone is publishing in.
+    ListRooms,
+
+    /// Describes who is in the given room and how it's set up.
+    RoomInfo { room_id: RoomId },
+
+    /// Reports how many users and connections are on the server.
+    Ccu,
+
+    /// Renders the plugin's metrics in the Prometheus text format.
+    Metrics,
+
+    /// Ends all of the given user's connections in the given room, like a moderator's kick.
+    Kick { room_id: RoomId, user_id: UserId },
 }
 
 /// Information about which traffic a client will get pushed to them.
-#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Subscription {
     /// Whether to subscribe to server-wide notifications (e.g. user joins and leaves, room creates and destroys).
@@ -94,8 +269,33 @@ pub struct Subscription {
     /// Whether to subscribe to data in the currently-joined room.
     pub data: bool,
 
-    /// Whether to subscribe to media (audio and video) from a particular user.
-    pub media: Option<UserId>,
+    /// Whether to subscribe to media (audio and video) from particular users. Clients may name a single user, or a
+    /// list of them to receive all of their media on one connection.
+    #[serde(deserialize_with = "one_or_many")]
+    pub media: Option<Vec<UserId>>,
+}
+
+impl Subscription {
+    /// The users whose media this subscribes to, if any.
+    pub fn media_sources(&self) -> &[UserId] {
+        self.media.as_deref().unwrap_or(&[])
+    }
+}
+
+/// Reads a list of user IDs which may also be given as just one, as they were before lists were allowed. An empty list
+/// is the same as none at all.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<UserId>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(UserId),
+        Many(Vec<UserId>),
+    }
+    match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(user_id)) => Ok(Some(vec![user_id])),
+        Some(OneOrMany::Many(user_ids)) if !user_ids.is_empty() => Ok(Some(user_ids)),
+        _ => Ok(None),
+    }
 }
 
 #[cfg(test)]
@@ -139,9 +339,28 @@ mod tests {
                     user_id: "10".into(),
                     room_id: "alpha".into(),
                     subscribe: None,
-                    token: Some(String::from("foo"))
+                    token: Some(String::from("foo")),
+                    role: None
+                }
+            );
+        }
+
+        #[test]
+        fn parse_join_role() {
+            let json = r#"{"kind": "join", "user_id": "10", "room_id": "alpha", "role": "publisher"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::Join {
+                    user_id: "10".into(),
+                    room_id: "alpha".into(),
+                    subscribe: None,
+                    token: None,
+                    role: Some(JoinKind::Publisher)
                 }
             );
+            let json = r#"{"kind": "join", "user_id": "10", "room_id": "alpha", "role": "lurker"}"#;
+            assert!(serde_json::from_str::<MessageKind>(json).is_err());
         }
 
         #[test]
@@ -158,11 +377,326 @@ mod tests {
                         data: false,
                         media: None
                     }),
+                    token: None,
+                    role: None
+                }
+            );
+        }
+
+        #[test]
+        fn parse_roster() {
+            let json = r#"{"kind": "roster", "room_id": "alpha", "user_ids": ["10", "11"], "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::Roster {
+                    room_id: "alpha".into(),
+                    user_ids: vec!["10".into(), "11".into()],
+                    token: "foo".into()
+                }
+            );
+        }
+
+        #[test]
+        fn parse_recording() {
+            let json = r#"{"kind": "start_recording", "room_id": "alpha", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::StartRecording {
+                    room_id: "alpha".into(),
+                    token: "foo".into()
+                }
+            );
+            let json = r#"{"kind": "stop_recording", "room_id": "alpha", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::StopRecording {
+                    room_id: "alpha".into(),
+                    token: "foo".into()
+                }
+            );
+        }
+
+        #[test]
+        fn parse_presenter_only_audio() {
+            let json = r#"{"kind": "presenter_only_audio", "room_id": "alpha", "enabled": true, "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::PresenterOnlyAudio {
+                    room_id: "alpha".into(),
+                    enabled: true,
+                    token: "foo".into()
+                }
+            );
+        }
+
+        #[test]
+        fn parse_lock_room() {
+            let json = r#"{"kind": "lock_room", "room_id": "alpha", "locked": true, "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::LockRoom {
+                    room_id: "alpha".into(),
+                    locked: true,
+                    token: "foo".into()
+                }
+            );
+        }
+
+        #[test]
+        fn parse_revoke_token() {
+            let json = r#"{"kind": "revoke_token", "jti": "abc", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::RevokeToken {
+                    jti: "abc".into(),
+                    token: "foo".into()
+                }
+            );
+        }
+
+        #[test]
+        fn parse_admit_user() {
+            let json = r#"{"kind": "admit_user", "user_id": "10"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::AdmitUser { user_id: "10".into() });
+        }
+
+        #[test]
+        fn parse_leave() {
+            let json = r#"{"kind": "leave"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Leave {});
+        }
+
+        #[test]
+        fn parse_change_room() {
+            let json = r#"{"kind": "change_room", "room_id": "beta", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::ChangeRoom {
+                    room_id: "beta".into(),
+                    token: Some("foo".into())
+                }
+            );
+        }
+
+        #[test]
+        fn parse_list_rooms() {
+            let json = r#"{"kind": "list_rooms"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::ListRooms { token: None });
+            let json = r#"{"kind": "list_rooms", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::ListRooms { token: Some("foo".into()) });
+        }
+
+        #[test]
+        fn parse_list_users() {
+            let json = r#"{"kind": "list_users", "room_id": "alpha", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::ListUsers {
+                    room_id: "alpha".into(),
+                    token: Some("foo".into())
+                }
+            );
+        }
+
+        #[test]
+        fn parse_admin_commands() {
+            let parse = |json| serde_json::from_str::<AdminCommand>(json).unwrap();
+            assert_eq!(parse(r#"{"command": "list_rooms"}"#), AdminCommand::ListRooms);
+            assert_eq!(
+                parse(r#"{"command": "room_info", "room_id": "alpha"}"#),
+                AdminCommand::RoomInfo { room_id: "alpha".into() }
+            );
+            assert_eq!(parse(r#"{"command": "ccu"}"#), AdminCommand::Ccu);
+            assert_eq!(parse(r#"{"command": "metrics"}"#), AdminCommand::Metrics);
+            assert_eq!(
+                parse(r#"{"command": "kick", "room_id": "alpha", "user_id": "10"}"#),
+                AdminCommand::Kick {
+                    room_id: "alpha".into(),
+                    user_id: "10".into()
+                }
+            );
+        }
+
+        #[test]
+        fn parse_room_count() {
+            let json = r#"{"kind": "room_count", "room_id": "alpha"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::RoomCount {
+                    room_id: "alpha".into(),
+                    token: None
+                }
+            );
+        }
+
+        #[test]
+        fn parse_query_subscriptions() {
+            let json = r#"{"kind": "query_subscriptions"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::QuerySubscriptions {});
+        }
+
+        #[test]
+        fn parse_request_offer() {
+            let json = r#"{"kind": "request_offer"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::RequestOffer {});
+        }
+
+        #[test]
+        fn parse_kick_with_and_without_token() {
+            let json = r#"{"kind": "kick", "room_id": "alpha", "user_id": "10", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::Kick {
+                    room_id: "alpha".into(),
+                    user_id: "10".into(),
+                    token: Some("foo".into())
+                }
+            );
+            let json = r#"{"kind": "kick", "room_id": "alpha", "user_id": "10"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::Kick {
+                    room_id: "alpha".into(),
+                    user_id: "10".into(),
                     token: None
                 }
             );
         }
 
+        #[test]
+        fn parse_refresh_token() {
+            let json = r#"{"kind": "refresh_token", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::RefreshToken { token: "foo".into() });
+        }
+
+        #[test]
+        fn parse_ping() {
+            let json = r#"{"kind": "ping"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Ping {});
+        }
+
+        #[test]
+        fn parse_list_blocks() {
+            let json = r#"{"kind": "list_blocks"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::ListBlocks {});
+        }
+
+        #[test]
+        fn parse_switch_media() {
+            let json = r#"{"kind": "switch_media", "media": "steve"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::SwitchMedia { media: "steve".into() });
+        }
+
+        #[test]
+        fn parse_set_layer() {
+            let json = r#"{"kind": "set_layer", "media": "steve", "spatial": 1}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::SetLayer {
+                    media: "steve".into(),
+                    spatial: 1
+                }
+            );
+        }
+
+        #[test]
+        fn parse_mute() {
+            let json = r#"{"kind": "mute", "user_id": "steve", "media_kind": "audio", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::Mute {
+                    user_id: "steve".into(),
+                    media_kind: MediaKind::Audio,
+                    token: "foo".into()
+                }
+            );
+            let json = r#"{"kind": "unmute", "user_id": "steve", "media_kind": "video", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::Unmute {
+                    user_id: "steve".into(),
+                    media_kind: MediaKind::Video,
+                    token: "foo".into()
+                }
+            );
+            let json = r#"{"kind": "mute", "user_id": "steve", "media_kind": "data", "token": "foo"}"#;
+            assert!(serde_json::from_str::<MessageKind>(json).is_err());
+        }
+
+        #[test]
+        fn parse_mute_all() {
+            let json = r#"{"kind": "mute_all", "media_kind": "audio", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::MuteAll {
+                    media_kind: MediaKind::Audio,
+                    token: "foo".into()
+                }
+            );
+        }
+
+        #[test]
+        fn parse_text_data() {
+            let json = r#"{"kind": "data", "body": "hello"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                result,
+                MessageKind::Data {
+                    whom: None,
+                    body: Some("hello".into()),
+                    body_bytes: None
+                }
+            );
+        }
+
+        #[test]
+        fn parse_binary_data() {
+            let array = r#"{"kind": "data", "whom": "steve", "body_bytes": [0, 159, 255]}"#;
+            let base64 = r#"{"kind": "data", "whom": "steve", "body_bytes": "AJ//"}"#;
+            let expected = MessageKind::Data {
+                whom: Some("steve".into()),
+                body: None,
+                body_bytes: Some(Binary(vec![0, 159, 255])),
+            };
+            assert_eq!(serde_json::from_str::<MessageKind>(array).unwrap(), expected);
+            assert_eq!(serde_json::from_str::<MessageKind>(base64).unwrap(), expected);
+            assert!(serde_json::from_str::<MessageKind>(r#"{"kind": "data", "body_bytes": "not base64!"}"#).is_err());
+            assert!(serde_json::from_str::<MessageKind>(r#"{"kind": "data", "body_bytes": [256]}"#).is_err());
+        }
+
+        #[test]
+        fn binary_round_trips_as_base64() {
+            let binary = Binary((0..=255).collect());
+            let json = serde_json::to_value(&binary).unwrap();
+            assert!(json.is_string());
+            assert_eq!(serde_json::from_value::<Binary>(json).unwrap(), binary);
+        }
+
         #[test]
         fn parse_subscribe() {
             let json = r#"{"kind": "subscribe", "what": {"notifications": false, "data": true, "media": "steve"}}"#;
@@ -173,10 +707,32 @@ mod tests {
                     what: Subscription {
                         notifications: false,
                         data: true,
-                        media: Some("steve".into())
+                        media: Some(vec!["steve".into()])
                     }
                 }
             );
         }
+
+        #[test]
+        fn parse_subscribe_to_many() {
+            let json = r#"{"notifications": false, "data": false, "media": ["steve", "sue"]}"#;
+            let result: Subscription = serde_json::from_str(json).unwrap();
+            assert_eq!(result.media_sources(), &["steve".to_owned(), "sue".to_owned()]);
+            let json = r#"{"notifications": false, "data": false, "media": []}"#;
+            assert_eq!(serde_json::from_str::<Subscription>(json).unwrap().media, None);
+            let json = r#"{"notifications": false, "data": false, "media": null}"#;
+            assert_eq!(serde_json::from_str::<Subscription>(json).unwrap().media, None);
+        }
+    }
+
+    #[test]
+    fn ids_are_validated() {
+        assert_eq!(validate_id("room_id", "lobby", 8), Ok(()));
+        assert_eq!(validate_id("room_id", "défense", 7), Ok(()));
+        assert_eq!(validate_id("room_id", "", 8), Err("room_id must not be empty.".into()));
+        assert_eq!(validate_id("user_id", "123456789", 8), Err("user_id must be at most 8 characters long.".into()));
+        assert_eq!(validate_id("user_id", "al\nice", 8), Err("user_id must not contain control characters.".into()));
+        assert!(validate_id("user_id", "al\u{7f}ce", 8).is_err());
+        assert!(validate_id("user_id", "alice\u{0}", 8).is_err());
     }
 }
@@ -0,0 +1,135 @@
+/// Operational counters, rendered in the Prometheus text exposition format.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters which are bumped as things happen. Every update is a single relaxed atomic add, so that they're cheap
+/// enough to keep on the media hot paths.
+#[derive(Debug)]
+pub struct Counters {
+    pub rtp_relayed: AtomicU64,
+    pub firs_sent: AtomicU64,
+    pub plis_sent: AtomicU64,
+    pub keyframe_requests_dropped: AtomicU64,
+    pub rtp_retransmitted: AtomicU64,
+    pub joins: AtomicU64,
+    pub kicks: AtomicU64,
+    pub messages_rejected: AtomicU64,
+    pub map_inconsistencies: AtomicU64,
+}
+
+impl Counters {
+    pub const fn new() -> Self {
+        Self {
+            rtp_relayed: AtomicU64::new(0),
+            firs_sent: AtomicU64::new(0),
+            plis_sent: AtomicU64::new(0),
+            keyframe_requests_dropped: AtomicU64::new(0),
+            rtp_retransmitted: AtomicU64::new(0),
+            joins: AtomicU64::new(0),
+            kicks: AtomicU64::new(0),
+            messages_rejected: AtomicU64::new(0),
+            map_inconsistencies: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Values which describe the current state of the server, read off the switchboard when metrics are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Gauges {
+    pub publishers: usize,
+    pub subscribers: usize,
+    pub rooms: usize,
+}
+
+/// The counters for the whole plugin.
+pub static COUNTERS: Counters = Counters::new();
+
+/// Adds to the given counter.
+pub fn bump(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    // writing to a string can't fail
+    let _ = writeln!(out, "# HELP janus_sfu_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE janus_sfu_{} {}", name, kind);
+    let _ = writeln!(out, "janus_sfu_{} {}", name, value);
+}
+
+/// Renders the given counters and gauges in the Prometheus text exposition format.
+pub fn render(counters: &Counters, gauges: Gauges) -> String {
+    let mut out = String::new();
+    let totals = [
+        ("rtp_packets_relayed_total", "RTP packets relayed to subscribers.", &counters.rtp_relayed),
+        ("firs_sent_total", "RTCP FIR requests sent to publishers.", &counters.firs_sent),
+        ("plis_sent_total", "RTCP PLI requests sent to publishers.", &counters.plis_sent),
+        (
+            "keyframe_requests_dropped_total",
+            "Keyframe requests from subscribers which were coalesced with recent ones.",
+            &counters.keyframe_requests_dropped,
+        ),
+        (
+            "rtp_packets_retransmitted_total",
+            "RTP packets sent again to subscribers who NACKed them.",
+            &counters.rtp_retransmitted,
+        ),
+        ("joins_total", "Successful joins to rooms.", &counters.joins),
+        ("kicks_total", "Sessions ended by kicks.", &counters.kicks),
+        (
+            "messages_rejected_total",
+            "Signalling messages turned away because their message thread's queue was full.",
+            &counters.messages_rejected,
+        ),
+        (
+            "map_inconsistencies_total",
+            "Times the switchboard found its bookkeeping out of sync with itself, and repaired it.",
+            &counters.map_inconsistencies,
+        ),
+    ];
+    for &(name, help, counter) in &totals {
+        write_metric(&mut out, name, "counter", help, counter.load(Ordering::Relaxed));
+    }
+    let levels = [
+        ("publishers", "Publisher connections in rooms.", gauges.publishers),
+        ("subscribers", "Subscriber connections in rooms.", gauges.subscribers),
+        ("rooms", "Rooms which anyone is publishing in.", gauges.rooms),
+    ];
+    for &(name, help, value) in &levels {
+        write_metric(&mut out, name, "gauge", help, value as u64);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_as_prometheus_text() {
+        let counters = Counters::new();
+        bump(&counters.rtp_relayed, 3);
+        bump(&counters.joins, 1);
+        let gauges = Gauges {
+            publishers: 2,
+            subscribers: 5,
+            rooms: 1,
+        };
+        let text = render(&counters, gauges);
+        assert!(text.starts_with("# HELP janus_sfu_rtp_packets_relayed_total RTP packets relayed to subscribers.\n"));
+        assert!(text.contains("# TYPE janus_sfu_rtp_packets_relayed_total counter\njanus_sfu_rtp_packets_relayed_total 3\n"));
+        assert!(text.contains("\njanus_sfu_joins_total 1\n"));
+        assert!(text.contains("\njanus_sfu_kicks_total 0\n"));
+        assert!(text.contains("# TYPE janus_sfu_subscribers gauge\njanus_sfu_subscribers 5\n"));
+        assert!(text.ends_with("janus_sfu_rooms 1\n"));
+    }
+
+    #[test]
+    fn every_metric_has_help_and_type() {
+        let text = render(&Counters::new(), Gauges::default());
+        let samples = text.lines().filter(|line| !line.starts_with('#')).count();
+        assert_eq!(samples, 12);
+        assert_eq!(text.lines().filter(|line| line.starts_with("# HELP ")).count(), samples);
+        assert_eq!(text.lines().filter(|line| line.starts_with("# TYPE ")).count(), samples);
+    }
+}
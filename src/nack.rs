@@ -0,0 +1,171 @@
+/// Tools for answering subscribers' NACKs, which ask for lost packets to be sent again, from a buffer of recent packets.
+use crate::switching::RtpHeader;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// The RTCP packet type of transport-layer feedback, which generic NACKs are a kind of.
+const RTPFB: u8 = 205;
+
+/// The feedback message type of generic NACKs.
+const GENERIC_NACK: u8 = 1;
+
+/// Returns the sequence numbers of every packet which the NACKs in the given compound RTCP packet ask for.
+pub fn nacked_sequence_numbers(packet: &[u8]) -> Vec<u16> {
+    let mut nacked = Vec::new();
+    let mut rest = packet;
+    while rest.len() >= 4 {
+        let version = rest[0] >> 6;
+        if version != 2 {
+            break;
+        }
+        let len = 4 * (u16::from_be_bytes([rest[2], rest[3]]) as usize + 1);
+        let current = match rest.get(..len) {
+            Some(current) => current,
+            None => break,
+        };
+        if current[0] & 0x1F == GENERIC_NACK && current[1] == RTPFB && current.len() >= 12 {
+            // each entry names one lost packet, and has a bitmask of which of the 16 after it were lost too
+            for entry in current[12..].chunks_exact(4) {
+                let first = u16::from_be_bytes([entry[0], entry[1]]);
+                let following = u16::from_be_bytes([entry[2], entry[3]]);
+                nacked.push(first);
+                for i in 0..16 {
+                    if following & (1 << i) != 0 {
+                        nacked.push(first.wrapping_add(i + 1));
+                    }
+                }
+            }
+        }
+        rest = &rest[len..];
+    }
+    nacked
+}
+
+/// The most recent RTP packets from a publisher, oldest first, so that lost ones can be sent again.
+#[derive(Debug, Default)]
+pub struct RtpBuffer {
+    packets: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl RtpBuffer {
+    /// Remembers a copy of the given packet, forgetting the oldest ones so that no more than the given number are kept.
+    pub fn push(&self, packet: &[u8], capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let mut packets = self.packets.lock().unwrap();
+        while packets.len() > capacity {
+            packets.pop_front();
+        }
+        // reuse the oldest packet's allocation, if we're about to forget it anyway
+        let mut copy = if packets.len() == capacity {
+            packets.pop_front().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        copy.clear();
+        copy.extend_from_slice(packet);
+        packets.push_back(copy);
+    }
+
+    /// Returns a copy of the most recent packet we have with the given SSRC and sequence number, if any. Since sequence
+    /// numbers wrap around, the most recent is the one which was asked for.
+    pub fn get(&self, ssrc: u32, seq: u16) -> Option<Vec<u8>> {
+        let packets = self.packets.lock().unwrap();
+        let found = packets.iter().rev().find(|packet| match RtpHeader::read(packet) {
+            Some(header) => header.ssrc == ssrc && header.seq == seq,
+            None => false,
+        });
+        found.cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn packet(ssrc: u32, seq: u16, payload: u8) -> Vec<u8> {
+        let mut packet = vec![0x80, 96];
+        packet.extend(&seq.to_be_bytes());
+        packet.extend(&[0; 4]); // timestamp
+        packet.extend(&ssrc.to_be_bytes());
+        packet.push(payload);
+        packet
+    }
+
+    fn nack(entries: &[(u16, u16)]) -> Vec<u8> {
+        let mut nack = vec![0x80 | GENERIC_NACK, RTPFB, 0, 2 + entries.len() as u8];
+        nack.extend(&[0; 8]); // sender and media source SSRCs
+        for &(first, following) in entries {
+            nack.extend(&first.to_be_bytes());
+            nack.extend(&following.to_be_bytes());
+        }
+        nack
+    }
+
+    #[test]
+    fn nacks_name_lost_packets() {
+        assert_eq!(nacked_sequence_numbers(&nack(&[(100, 0)])), vec![100]);
+        assert_eq!(nacked_sequence_numbers(&nack(&[(100, 0b101)])), vec![100, 101, 103]);
+        assert_eq!(nacked_sequence_numbers(&nack(&[(65535, 0b11), (7, 0)])), vec![65535, 0, 1, 7]);
+    }
+
+    #[test]
+    fn nacks_are_found_in_compound_packets() {
+        let mut packet = vec![0x80, 201, 0, 1, 0, 0, 0, 1]; // receiver report
+        packet.extend(nack(&[(5, 0)]));
+        assert_eq!(nacked_sequence_numbers(&packet), vec![5]);
+        assert!(nacked_sequence_numbers(&packet[..packet.len() - 1]).is_empty());
+        let mut pli = vec![0x81, 206, 0, 2];
+        pli.extend(&[0; 8]);
+        assert!(nacked_sequence_numbers(&pli).is_empty());
+    }
+
+    #[test]
+    fn buffered_packets_are_found_by_ssrc_and_sequence_number() {
+        let buffer = RtpBuffer::default();
+        for seq in 10..20 {
+            buffer.push(&packet(1, seq, 0), 100);
+            buffer.push(&packet(2, seq, 0), 100);
+        }
+        assert_eq!(buffer.get(1, 15), Some(packet(1, 15, 0)));
+        assert_eq!(buffer.get(2, 10), Some(packet(2, 10, 0)));
+        assert_eq!(buffer.get(1, 20), None);
+        assert_eq!(buffer.get(3, 15), None);
+    }
+
+    #[test]
+    fn old_packets_are_forgotten() {
+        let buffer = RtpBuffer::default();
+        for seq in 0..10 {
+            buffer.push(&packet(1, seq, 0), 4);
+        }
+        assert_eq!(buffer.get(1, 5), None);
+        assert_eq!(buffer.get(1, 6), Some(packet(1, 6, 0)));
+        assert_eq!(buffer.get(1, 9), Some(packet(1, 9, 0)));
+        // a smaller capacity takes effect right away
+        buffer.push(&packet(1, 10, 0), 2);
+        assert_eq!(buffer.get(1, 8), None);
+        assert_eq!(buffer.get(1, 9), Some(packet(1, 9, 0)));
+        let disabled = RtpBuffer::default();
+        disabled.push(&packet(1, 0, 0), 0);
+        assert_eq!(disabled.get(1, 0), None);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let buffer = RtpBuffer::default();
+        for i in 0..10 {
+            buffer.push(&packet(1, 65530u16.wrapping_add(i), 0), 100);
+        }
+        assert_eq!(buffer.get(1, 65535), Some(packet(1, 65535, 0)));
+        assert_eq!(buffer.get(1, 0), Some(packet(1, 0, 0)));
+        assert_eq!(buffer.get(1, 3), Some(packet(1, 3, 0)));
+        // once a sequence number comes around again, the newer packet is the one that's wanted
+        let small = RtpBuffer::default();
+        small.push(&packet(1, 7, 1), 3);
+        small.push(&packet(1, 7, 2), 3);
+        assert_eq!(small.get(1, 7), Some(packet(1, 7, 2)));
+    }
+}
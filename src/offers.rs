@@ -0,0 +1,349 @@
+/// Tools for keeping the offers we send to subscribers stable when a publisher renegotiates its media.
+use janus_plugin::sdp::Sdp;
+use serde_json::Value as JsonValue;
+use std::error::Error;
+
+/// Returns the text of the given SDP.
+pub fn sdp_text(sdp: &Sdp) -> Result<String, Box<dyn Error>> {
+    match serde_json::to_value(sdp)? {
+        JsonValue::String(text) => Ok(text),
+        other => Err(From::from(format!("SDP serialized as something other than text: {}", other))),
+    }
+}
+
+/// Parses the given SDP text.
+pub fn parse_sdp(text: String) -> Result<Sdp, Box<dyn Error>> {
+    Ok(serde_json::from_value(JsonValue::String(text))?)
+}
+
+/// Splits the given SDP into the lines which describe the whole session, and the lines of each media section.
+fn split_sections(sdp: &str) -> (Vec<&str>, Vec<Vec<&str>>) {
+    let mut session = Vec::new();
+    let mut media: Vec<Vec<&str>> = Vec::new();
+    for line in sdp.lines().map(str::trim_end).filter(|line| !line.is_empty()) {
+        if line.starts_with("m=") {
+            media.push(vec![line]);
+        } else if let Some(section) = media.last_mut() {
+            section.push(line);
+        } else {
+            session.push(line);
+        }
+    }
+    (session, media)
+}
+
+/// Returns the kind of media, e.g. "audio" or "video", described by the given media section.
+fn media_kind<'a>(section: &[&'a str]) -> &'a str {
+    section[0][2..].split(' ').next().unwrap_or("")
+}
+
+/// Returns the mid of the given media section, if it has one.
+fn media_id<'a>(section: &[&'a str]) -> Option<&'a str> {
+    section.iter().find_map(|line| line.strip_prefix("a=mid:"))
+}
+
+/// Increments the session version in the given `o=` line, as each new offer in a session must.
+fn bump_version(origin: &str) -> String {
+    let mut fields = origin.split(' ').map(String::from).collect::<Vec<_>>();
+    if let Some(version) = fields.get_mut(2) {
+        if let Ok(n) = version.parse::<u64>() {
+            *version = (n + 1).to_string();
+        }
+    }
+    fields.join(" ")
+}
+
+/// Builds an offer for the media described by `fresh` which renegotiates the session that `previous` established.
+/// The media sections keep the order and mids they had in `previous`, since a renegotiation can't move or rename
+/// them, but their codecs, payload types, and directions come from `fresh`. Kinds of media which `fresh` doesn't have
+/// keep their old sections, and kinds which `previous` didn't have are added at the end.
+pub fn renegotiate(previous: &str, fresh: &str) -> String {
+    let (session, previous_media) = split_sections(previous);
+    let (_, mut fresh_media) = split_sections(fresh);
+    let mut media = Vec::new();
+    for old in &previous_media {
+        let kind = media_kind(old);
+        let section = match fresh_media.iter().position(|new| media_kind(new) == kind) {
+            Some(i) => {
+                let new = fresh_media.remove(i);
+                let mid = media_id(old).or_else(|| media_id(&new));
+                let mut section = new
+                    .iter()
+                    .filter(|line| !line.starts_with("a=mid:"))
+                    .map(|line| line.to_string())
+                    .collect::<Vec<_>>();
+                section.extend(mid.map(|mid| format!("a=mid:{}", mid)));
+                section
+            }
+            None => old.iter().map(|line| line.to_string()).collect(),
+        };
+        media.push(section);
+    }
+    media.extend(fresh_media.iter().map(|new| new.iter().map(|line| line.to_string()).collect()));
+
+    let mids = media
+        .iter()
+        .filter_map(|section| section.iter().find_map(|line| line.strip_prefix("a=mid:")))
+        .collect::<Vec<_>>();
+    let mut lines = Vec::new();
+    for line in session {
+        if line.starts_with("o=") {
+            lines.push(bump_version(line));
+        } else if line.starts_with("a=group:BUNDLE") {
+            lines.push(format!("a=group:BUNDLE {}", mids.join(" ")));
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    lines.extend(media.into_iter().flatten());
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+/// Builds an offer which renegotiates the session that `previous` established so that all of its media is inactive,
+/// e.g. because the subscriber can no longer receive it. The media sections stay where they are, since a renegotiation
+/// can't remove them.
+pub fn deactivate(previous: &str) -> String {
+    let (session, media) = split_sections(previous);
+    let mut lines = Vec::new();
+    for line in session {
+        if line.starts_with("o=") {
+            lines.push(bump_version(line));
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    for section in &media {
+        for line in section {
+            match *line {
+                "a=sendrecv" | "a=sendonly" | "a=recvonly" => lines.push("a=inactive".to_string()),
+                other => lines.push(other.to_string()),
+            }
+        }
+    }
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+/// Builds a single offer with the media of each of the given offers, e.g. so that one subscriber can receive several
+/// publishers' media. Each offer's media sections are kept in order, with their mids prefixed by the offer's index so
+/// that they stay unique. Only the first offer's data channel is kept, since a connection only has one.
+pub fn combine<T: AsRef<str>>(offers: &[T]) -> String {
+    let mut session = Vec::new();
+    let mut media = Vec::new();
+    for (i, offer) in offers.iter().enumerate() {
+        let (offer_session, offer_media) = split_sections(offer.as_ref());
+        if i == 0 {
+            session = offer_session;
+        }
+        for section in offer_media.into_iter().filter(|section| i == 0 || media_kind(section) != "application") {
+            let renamed = section
+                .iter()
+                .map(|line| match line.strip_prefix("a=mid:") {
+                    Some(mid) => format!("a=mid:{}-{}", i, mid),
+                    None => line.to_string(),
+                })
+                .collect::<Vec<_>>();
+            media.push(renamed);
+        }
+    }
+
+    let mids = media
+        .iter()
+        .filter_map(|section| section.iter().find_map(|line| line.strip_prefix("a=mid:")))
+        .collect::<Vec<_>>();
+    let mut lines = Vec::new();
+    for line in session {
+        if line.starts_with("a=group:BUNDLE") {
+            lines.push(format!("a=group:BUNDLE {}", mids.join(" ")));
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    lines.extend(media.iter().flatten().cloned());
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+/// Returns whether the given SDP has a media section of the given kind, e.g. "audio" or "video", which isn't disabled.
+pub fn has_media(sdp: &str, kind: &str) -> bool {
+    let (_, media) = split_sections(sdp);
+    media
+        .iter()
+        .any(|section| media_kind(section) == kind && section[0].split(' ').nth(1) != Some("0"))
+}
+
+/// Which kinds of media an offer has, so that we only negotiate the kinds that a publisher is actually sending, e.g.
+/// no video for a phone bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaKinds {
+    pub audio: bool,
+    pub video: bool,
+}
+
+impl MediaKinds {
+    /// Returns which kinds of media the given SDP has sections for which aren't disabled.
+    pub fn of(sdp: &str) -> Self {
+        Self {
+            audio: has_media(sdp, "audio"),
+            video: has_media(sdp, "video"),
+        }
+    }
+}
+
+/// Returns each kind of media in the given SDP along with the codecs offered for it, e.g. "audio opus/48000/2", in
+/// order, ignoring payload types.
+pub fn codecs(sdp: &str) -> Vec<String> {
+    let (_, media) = split_sections(sdp);
+    let mut codecs = Vec::new();
+    for section in &media {
+        let kind = media_kind(section);
+        for line in section {
+            if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+                let encoding = rtpmap.split_whitespace().nth(1).unwrap_or("");
+                codecs.push(format!("{} {}", kind, encoding.to_lowercase()));
+            }
+        }
+    }
+    codecs
+}
+
+/// Builds an offer for the media described by `fresh` which renegotiates the session that `previous` established.
+/// See `renegotiate`.
+pub fn renegotiate_sdp(previous: &Sdp, fresh: &Sdp) -> Result<Sdp, Box<dyn Error>> {
+    parse_sdp(renegotiate(&sdp_text(previous)?, &sdp_text(fresh)?))
+}
+
+/// Builds an offer which renegotiates the session that `previous` established so that all of its media is inactive.
+/// See `deactivate`.
+pub fn deactivate_sdp(previous: &Sdp) -> Result<Sdp, Box<dyn Error>> {
+    parse_sdp(deactivate(&sdp_text(previous)?))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn offer(version: u32, sections: &[(&str, &str, u32)]) -> String {
+        let mids = sections.iter().map(|&(_, mid, _)| mid).collect::<Vec<_>>();
+        let mut sdp = format!(
+            "v=0\r\no=- 1234 {} IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\na=group:BUNDLE {}\r\n",
+            version,
+            mids.join(" ")
+        );
+        for &(kind, mid, pt) in sections {
+            sdp.push_str(&format!(
+                "m={} 9 UDP/TLS/RTP/SAVPF {}\r\nc=IN IP4 127.0.0.1\r\na=sendonly\r\na=mid:{}\r\n",
+                kind, pt, mid
+            ));
+        }
+        sdp
+    }
+
+    fn m_lines(sdp: &str) -> Vec<&str> {
+        sdp.lines().filter(|line| line.starts_with("m=")).collect()
+    }
+
+    fn mids(sdp: &str) -> Vec<&str> {
+        sdp.lines().filter_map(|line| line.strip_prefix("a=mid:")).collect()
+    }
+
+    #[test]
+    fn renegotiation_keeps_media_order_and_mids() {
+        let previous = offer(1, &[("video", "v", 96), ("audio", "a", 111)]);
+        let fresh = offer(1, &[("audio", "audio", 109), ("video", "video", 100)]);
+        let renegotiated = renegotiate(&previous, &fresh);
+        assert_eq!(
+            m_lines(&renegotiated),
+            vec!["m=video 9 UDP/TLS/RTP/SAVPF 100", "m=audio 9 UDP/TLS/RTP/SAVPF 109"]
+        );
+        assert_eq!(mids(&renegotiated), vec!["v", "a"]);
+        assert!(renegotiated.contains("\r\na=group:BUNDLE v a\r\n"));
+        let again = renegotiate(&renegotiated, &fresh);
+        assert_eq!(m_lines(&again), m_lines(&renegotiated));
+        assert_eq!(mids(&again), mids(&renegotiated));
+    }
+
+    #[test]
+    fn renegotiation_bumps_session_version() {
+        let previous = offer(7, &[("audio", "0", 111)]);
+        let renegotiated = renegotiate(&previous, &offer(1, &[("audio", "0", 111)]));
+        assert!(renegotiated.contains("\r\no=- 1234 8 IN IP4 127.0.0.1\r\n"));
+    }
+
+    #[test]
+    fn deactivation_makes_all_media_inactive() {
+        let previous = offer(3, &[("audio", "0", 111), ("video", "1", 96)]);
+        let deactivated = deactivate(&previous);
+        assert_eq!(m_lines(&deactivated), m_lines(&previous));
+        assert_eq!(mids(&deactivated), vec!["0", "1"]);
+        assert!(!deactivated.contains("a=sendonly"));
+        assert_eq!(deactivated.matches("\r\na=inactive\r\n").count(), 2);
+        assert!(deactivated.contains("\r\no=- 1234 4 IN IP4 127.0.0.1\r\n"));
+    }
+
+    #[test]
+    fn combined_offers_have_each_offers_media() {
+        let first = offer(3, &[("audio", "0", 111), ("video", "1", 96), ("application", "2", 5000)]);
+        let second = offer(1, &[("audio", "0", 109), ("video", "1", 100), ("application", "2", 5000)]);
+        let combined = combine(&[&first, &second]);
+        assert_eq!(
+            m_lines(&combined),
+            vec![
+                "m=audio 9 UDP/TLS/RTP/SAVPF 111",
+                "m=video 9 UDP/TLS/RTP/SAVPF 96",
+                "m=application 9 UDP/TLS/RTP/SAVPF 5000",
+                "m=audio 9 UDP/TLS/RTP/SAVPF 109",
+                "m=video 9 UDP/TLS/RTP/SAVPF 100",
+            ]
+        );
+        assert_eq!(mids(&combined), vec!["0-0", "0-1", "0-2", "1-0", "1-1"]);
+        assert!(combined.contains("\r\na=group:BUNDLE 0-0 0-1 0-2 1-0 1-1\r\n"));
+        assert!(combined.contains("\r\no=- 1234 3 IN IP4 127.0.0.1\r\n"));
+        assert_eq!(combine(&[&first]), combine(&[first.clone()]));
+        assert_eq!(mids(&combine(&[&first])), vec!["0-0", "0-1", "0-2"]);
+    }
+
+    #[test]
+    fn codecs_ignore_payload_types() {
+        let opus = "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 H264/90000\r\n";
+        let renumbered = "m=audio 9 UDP/TLS/RTP/SAVPF 109\r\na=rtpmap:109 OPUS/48000/2\r\nm=video 9 UDP/TLS/RTP/SAVPF 100\r\na=rtpmap:100 H264/90000\r\n";
+        let vp8 = "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n";
+        assert_eq!(codecs(opus), vec!["audio opus/48000/2", "video h264/90000"]);
+        assert_eq!(codecs(opus), codecs(renumbered));
+        assert_ne!(codecs(opus), codecs(vp8));
+    }
+
+    #[test]
+    fn disabled_media_is_not_had() {
+        let sdp = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\nm=video 0 UDP/TLS/RTP/SAVPF 96\r\n";
+        assert!(has_media(sdp, "audio"));
+        assert!(!has_media(sdp, "video"));
+        assert!(!has_media(sdp, "application"));
+    }
+
+    #[test]
+    fn audio_only_offers_have_only_audio() {
+        let sdp = offer(1, &[("audio", "0", 111), ("application", "1", 5000)]);
+        assert_eq!(MediaKinds::of(&sdp), MediaKinds { audio: true, video: false });
+        let disabled = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\nm=video 0 UDP/TLS/RTP/SAVPF 96\r\n";
+        assert_eq!(MediaKinds::of(disabled), MediaKinds { audio: true, video: false });
+    }
+
+    #[test]
+    fn video_only_offers_have_only_video() {
+        let sdp = offer(1, &[("video", "0", 96), ("application", "1", 5000)]);
+        assert_eq!(MediaKinds::of(&sdp), MediaKinds { audio: false, video: true });
+        let both = offer(1, &[("audio", "0", 111), ("video", "1", 96)]);
+        assert_eq!(MediaKinds::of(&both), MediaKinds { audio: true, video: true });
+    }
+
+    #[test]
+    fn renegotiation_keeps_missing_media_and_adds_new_media() {
+        let previous = offer(1, &[("audio", "0", 111)]);
+        let renegotiated = renegotiate(&previous, &offer(1, &[("video", "1", 100)]));
+        assert_eq!(
+            m_lines(&renegotiated),
+            vec!["m=audio 9 UDP/TLS/RTP/SAVPF 111", "m=video 9 UDP/TLS/RTP/SAVPF 100"]
+        );
+        assert_eq!(mids(&renegotiated), vec!["0", "1"]);
+    }
+}
@@ -0,0 +1,188 @@
+/// Tools for archiving publishers' media to disk, in the same MJR format as Janus's own recordings, so that the usual
+/// `janus-pp-rec` tool can turn them into playable files.
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The magic number which starts every MJR file.
+const MJR_HEADER: &[u8] = b"MJR00002";
+
+/// The marker which starts every packet in an MJR file.
+const PACKET_HEADER: &[u8] = b"MEET";
+
+/// Returns the current time in microseconds since the Unix epoch.
+pub fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+/// Makes the given ID safe to use in a file name, since clients choose it.
+fn sanitize(id: &str) -> String {
+    id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Where the given user's recording of the given kind of media in the given room, started at the given time, is saved.
+pub fn recording_path(dir: &Path, room_id: &str, user_id: &str, video: bool, created_us: u64) -> PathBuf {
+    let kind = if video { "video" } else { "audio" };
+    dir.join(format!("{}-{}-{}-{}.mjr", sanitize(room_id), sanitize(user_id), created_us, kind))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Nothing has been written yet; the header is written along with the first packet.
+    Waiting,
+    /// The header has been written, and packets are being written as they come in.
+    Recording { first_packet_us: u64 },
+    /// The recording has been flushed, and any more packets are ignored.
+    Stopped,
+}
+
+/// A recording of one kind of media from one publisher.
+#[derive(Debug)]
+pub struct Recording<W: Write> {
+    writer: W,
+    video: bool,
+    codec: String,
+    created_us: u64,
+    state: State,
+}
+
+impl<W: Write> Recording<W> {
+    pub fn new(writer: W, video: bool, codec: &str, created_us: u64) -> Self {
+        Self {
+            writer,
+            video,
+            codec: codec.to_owned(),
+            created_us,
+            state: State::Waiting,
+        }
+    }
+
+    /// Writes the given RTP packet, which arrived at the given time in microseconds since the Unix epoch.
+    pub fn write(&mut self, packet: &[u8], now_us: u64) -> io::Result<()> {
+        let first_packet_us = match self.state {
+            State::Stopped => return Ok(()),
+            State::Recording { first_packet_us } => first_packet_us,
+            State::Waiting => {
+                let info = json!({
+                    "t": if self.video { "v" } else { "a" },
+                    "c": self.codec,
+                    "s": self.created_us,
+                    "u": now_us,
+                });
+                let info = info.to_string();
+                self.writer.write_all(MJR_HEADER)?;
+                self.writer.write_all(&(info.len() as u16).to_be_bytes())?;
+                self.writer.write_all(info.as_bytes())?;
+                self.state = State::Recording { first_packet_us: now_us };
+                now_us
+            }
+        };
+        let elapsed_ms = (now_us.saturating_sub(first_packet_us) / 1000) as u32;
+        self.writer.write_all(PACKET_HEADER)?;
+        self.writer.write_all(&elapsed_ms.to_be_bytes())?;
+        self.writer.write_all(&(packet.len() as u16).to_be_bytes())?;
+        self.writer.write_all(packet)
+    }
+
+    /// Flushes everything written so far, and ignores any more packets.
+    pub fn stop(&mut self) -> io::Result<()> {
+        self.state = State::Stopped;
+        self.writer.flush()
+    }
+}
+
+/// The recordings of a publisher's audio and video.
+#[derive(Debug)]
+pub struct PublisherRecording {
+    pub audio: Recording<BufWriter<File>>,
+    pub video: Recording<BufWriter<File>>,
+}
+
+impl PublisherRecording {
+    /// Creates files in the given directory for recording the given user's media in the given room.
+    pub fn create(dir: &Path, room_id: &str, user_id: &str, audio_codec: &str, video_codec: &str) -> io::Result<Self> {
+        let created_us = now_micros();
+        let open = |video| File::create(recording_path(dir, room_id, user_id, video, created_us)).map(BufWriter::new);
+        Ok(Self {
+            audio: Recording::new(open(false)?, false, audio_codec, created_us),
+            video: Recording::new(open(true)?, true, video_codec, created_us),
+        })
+    }
+
+    /// Writes the given RTP packet to the audio or video recording.
+    pub fn write(&mut self, packet: &[u8], video: bool, now_us: u64) -> io::Result<()> {
+        if video {
+            self.video.write(packet, now_us)
+        } else {
+            self.audio.write(packet, now_us)
+        }
+    }
+
+    /// Flushes both recordings.
+    pub fn stop(&mut self) -> io::Result<()> {
+        let audio = self.audio.stop();
+        let video = self.video.stop();
+        audio.and(video)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn header_len(recording: &[u8]) -> usize {
+        10 + u16::from_be_bytes([recording[8], recording[9]]) as usize
+    }
+
+    #[test]
+    fn nothing_is_written_before_the_first_packet() {
+        let mut recording = Recording::new(Vec::new(), false, "opus", 1_000_000);
+        assert!(recording.writer.is_empty());
+        recording.stop().unwrap();
+        assert!(recording.writer.is_empty());
+    }
+
+    #[test]
+    fn header_is_written_with_the_first_packet() {
+        let mut recording = Recording::new(Vec::new(), true, "vp8", 1_000_000);
+        recording.write(&[1, 2, 3], 1_500_000).unwrap();
+        let written = &recording.writer;
+        assert_eq!(&written[..8], b"MJR00002");
+        let info: serde_json::Value = serde_json::from_slice(&written[10..header_len(written)]).unwrap();
+        assert_eq!(info, json!({ "t": "v", "c": "vp8", "s": 1_000_000, "u": 1_500_000 }));
+        assert_eq!(&written[header_len(written)..], &[b'M', b'E', b'E', b'T', 0, 0, 0, 0, 0, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn packets_are_timed_from_the_first() {
+        let mut recording = Recording::new(Vec::new(), false, "opus", 0);
+        recording.write(&[1], 2_000_000).unwrap();
+        let start = recording.writer.len();
+        recording.write(&[2, 2], 2_020_000).unwrap();
+        let written = &recording.writer[start..];
+        assert_eq!(written, &[b'M', b'E', b'E', b'T', 0, 0, 0, 20, 0, 2, 2, 2]);
+    }
+
+    #[test]
+    fn stopped_recordings_ignore_packets() {
+        let mut recording = Recording::new(Vec::new(), false, "opus", 0);
+        recording.write(&[1], 0).unwrap();
+        recording.stop().unwrap();
+        let len = recording.writer.len();
+        recording.write(&[2], 20_000).unwrap();
+        assert_eq!(recording.writer.len(), len);
+        recording.stop().unwrap();
+        assert_eq!(recording.state, State::Stopped);
+    }
+
+    #[test]
+    fn recording_paths_are_sanitized() {
+        let path = recording_path(Path::new("/recordings"), "../alpha", "user/10", true, 1234);
+        assert_eq!(path, PathBuf::from("/recordings/___alpha-user_10-1234-video.mjr"));
+    }
+}
@@ -0,0 +1,99 @@
+//! Tools for reading and writing REMB (receiver estimated maximum bitrate) RTCP feedback, which is how subscribers tell
+//! us how much bandwidth they have, and how we tell publishers how much to send.
+
+/// The RTCP packet type of payload-specific feedback, which REMB is a kind of.
+pub const PSFB: u8 = 206;
+
+/// The feedback message type of application layer feedback, which REMB is a kind of.
+const AFB: u8 = 15;
+
+/// The largest mantissa which fits in a REMB's 18 bits.
+const MAX_MANTISSA: u64 = (1 << 18) - 1;
+
+/// Returns the bitrate, in bits per second, of the first REMB in the given compound RTCP packet, if it has one.
+pub fn remb_bitrate(packet: &[u8]) -> Option<u32> {
+    let mut rest = packet;
+    while rest.len() >= 4 {
+        let version = rest[0] >> 6;
+        if version != 2 {
+            return None;
+        }
+        let len = 4 * (u16::from_be_bytes([rest[2], rest[3]]) as usize + 1);
+        let current = rest.get(..len)?;
+        if current[0] & 0x1F == AFB && current[1] == PSFB && current.len() >= 20 && current[12..16] == *b"REMB" {
+            // anything shifted further than this is more than we could represent anyway
+            let exponent = (current[17] >> 2).min(32);
+            let mantissa = u32::from_be_bytes([0, current[17] & 0x03, current[18], current[19]]) as u64;
+            return Some((mantissa << exponent).min(u32::MAX as u64) as u32);
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+/// Builds a REMB asking for the given bitrate, in bits per second. The SSRCs are left blank, since Janus fills them in
+/// when relaying it to a peer.
+pub fn gen_remb(bitrate: u32) -> Vec<u8> {
+    let mut mantissa = bitrate as u64;
+    let mut exponent = 0;
+    while mantissa > MAX_MANTISSA {
+        mantissa >>= 1;
+        exponent += 1;
+    }
+    let mut packet = vec![0x80 | AFB, PSFB, 0, 5];
+    packet.extend(&[0; 8]); // sender and media source SSRCs
+    packet.extend(b"REMB");
+    packet.push(1); // the number of SSRCs below
+    packet.push((exponent << 2) | (mantissa >> 16) as u8);
+    packet.extend(&(mantissa as u16).to_be_bytes());
+    packet.extend(&[0; 4]);
+    packet
+}
+
+/// Returns the lowest of the given bandwidth estimates, ignoring zeroes, which come from subscribers who haven't
+/// estimated anything yet.
+pub fn lowest_estimate<T: IntoIterator<Item = u32>>(estimates: T) -> Option<u32> {
+    estimates.into_iter().filter(|&estimate| estimate > 0).min()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn rembs_round_trip() {
+        for &bitrate in &[0, 1, 262_143, 300_000, 2_500_000] {
+            assert_eq!(remb_bitrate(&gen_remb(bitrate)), Some(bitrate));
+        }
+        // large bitrates lose precision, but never come out higher
+        let big = remb_bitrate(&gen_remb(123_456_789)).unwrap();
+        assert!(big <= 123_456_789 && big > 123_000_000);
+    }
+
+    #[test]
+    fn rembs_are_found_in_compound_packets() {
+        let receiver_report = [0x80, 201, 0, 1, 0, 0, 0, 1];
+        let mut packet = receiver_report.to_vec();
+        packet.extend(gen_remb(500_000));
+        assert_eq!(remb_bitrate(&packet), Some(500_000));
+        assert_eq!(remb_bitrate(&receiver_report), None);
+        assert_eq!(remb_bitrate(&packet[..packet.len() - 4]), None);
+        assert_eq!(remb_bitrate(&[]), None);
+    }
+
+    #[test]
+    fn other_feedback_is_not_a_remb() {
+        let mut pli = vec![0x81, PSFB, 0, 2];
+        pli.extend(&[0; 8]);
+        assert_eq!(remb_bitrate(&pli), None);
+    }
+
+    #[test]
+    fn the_lowest_estimate_wins() {
+        assert_eq!(lowest_estimate(vec![800_000, 300_000, 1_200_000]), Some(300_000));
+        assert_eq!(lowest_estimate(vec![0, 800_000, 0]), Some(800_000));
+        assert_eq!(lowest_estimate(vec![0, 0]), None);
+        assert_eq!(lowest_estimate(Vec::new()), None);
+    }
+}
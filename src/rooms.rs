@@ -0,0 +1,44 @@
+/// Types for representing per-room state which outlives any individual session.
+use crate::messages::UserId;
+use crate::sessions::Session;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Room-level settings and bookkeeping, established by moderators and kept around whether or not anyone is
+/// currently in the room.
+#[derive(Debug, Clone, Default)]
+pub struct RoomState {
+    /// If present, the set of user IDs which are expected to publish in this room. Nobody else may join the
+    /// room as a publisher.
+    pub roster: Option<HashSet<UserId>>,
+
+    /// Whether only moderators' audio is relayed in this room, e.g. for a town hall where everyone else listens.
+    pub presenter_only_audio: bool,
+
+    /// Whether people joining this room must wait for a moderator to admit them.
+    pub waiting_room: bool,
+
+    /// Whether this room is closed to new publishers, other than moderators.
+    pub locked: bool,
+
+    /// Whether publishers' media in this room is being recorded.
+    pub recording: bool,
+
+    /// Sessions which have joined this room, but are waiting for a moderator to admit them.
+    pub waiting: Vec<Arc<Session>>,
+}
+
+impl RoomState {
+    /// Whether the given user is permitted to publish in this room according to the roster.
+    pub fn is_expected(&self, user: &UserId) -> bool {
+        match &self.roster {
+            Some(roster) => roster.contains(user),
+            None => true,
+        }
+    }
+
+    /// Whether this room has no interesting state and can be forgotten.
+    pub fn is_empty(&self) -> bool {
+        self.roster.is_none() && !self.presenter_only_audio && !self.waiting_room && !self.locked && !self.recording && self.waiting.is_empty()
+    }
+}
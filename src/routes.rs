@@ -0,0 +1,133 @@
+/// Per-room media routing tables, kept apart from the switchboard so that relaying media doesn't contend with joins
+/// and other changes in unrelated rooms.
+use crate::locks::{read_or_recover, write_or_recover};
+use crate::messages::RoomId;
+use crate::sessions::Session;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// The sessions which should receive a single publisher's audio and video.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaRoutes {
+    pub audio: Vec<Arc<Session>>,
+    pub video: Vec<Arc<Session>>,
+}
+
+/// The media routes for every publisher in a single room.
+pub type RoomRoutes = HashMap<Arc<Session>, MediaRoutes>;
+
+/// Media routes for every room, each behind its own lock. The switchboard recomputes a room's routes whenever
+/// something changes who should receive media there, and relaying media only ever locks the sender's room.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    rooms: RwLock<HashMap<RoomId, Arc<RwLock<RoomRoutes>>>>,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get_room(&self, room: &RoomId) -> Option<Arc<RwLock<RoomRoutes>>> {
+        // media is relayed on the way through here, so it has to carry on even if a panic poisoned the lock
+        let rooms = read_or_recover(&self.rooms, "Route table");
+        rooms.get(room).cloned()
+    }
+
+    /// Replaces the routes for the given room. Once this returns, nobody is relaying media along the old routes.
+    pub fn update(&self, room: &RoomId, routes: RoomRoutes) {
+        let shard = match self.get_room(room) {
+            Some(shard) => shard,
+            None if routes.is_empty() => return,
+            None => {
+                let mut rooms = write_or_recover(&self.rooms, "Route table");
+                Arc::clone(rooms.entry(room.clone()).or_default())
+            }
+        };
+        let is_empty = routes.is_empty();
+        // swap the routes under the room's own lock, so that anyone still holding the shard sees the change
+        *write_or_recover(&shard, "Room routes") = routes;
+        if is_empty {
+            let mut rooms = write_or_recover(&self.rooms, "Route table");
+            rooms.remove(room);
+        }
+    }
+
+    /// Calls the given function with each session which should receive the given sender's audio or video, depending
+    /// on `video`, while holding only the given room's lock.
+    pub fn for_each_recipient<F>(&self, room: &RoomId, sender: &Session, video: bool, mut f: F)
+    where
+        F: FnMut(&Arc<Session>),
+    {
+        if let Some(shard) = self.get_room(room) {
+            let routes = read_or_recover(&shard, "Room routes");
+            if let Some(media) = routes.get(sender) {
+                let recipients = if video { &media.video } else { &media.audio };
+                for recipient in recipients {
+                    f(recipient);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::locks::tests::poison;
+    use crate::sessions::tests::make_session;
+    use crate::sessions::JoinKind;
+
+    fn recipients(table: &RouteTable, room: &str, sender: &Session, video: bool) -> Vec<Arc<Session>> {
+        let mut result = Vec::new();
+        table.for_each_recipient(&room.into(), sender, video, |s| result.push(Arc::clone(s)));
+        result
+    }
+
+    #[test]
+    fn routes_are_kept_per_room() {
+        let table = RouteTable::new();
+        let alice = make_session(JoinKind::Publisher, "alpha", "10");
+        let bob = make_session(JoinKind::Subscriber, "alpha", "11");
+        let carol = make_session(JoinKind::Subscriber, "alpha", "12");
+        let media = MediaRoutes {
+            audio: vec![Arc::clone(&bob)],
+            video: vec![Arc::clone(&bob), Arc::clone(&carol)],
+        };
+        table.update(&"alpha".into(), vec![(Arc::clone(&alice), media)].into_iter().collect());
+        assert_eq!(recipients(&table, "alpha", &alice, false), vec![Arc::clone(&bob)]);
+        assert_eq!(recipients(&table, "alpha", &alice, true), vec![bob, carol]);
+        assert!(recipients(&table, "beta", &alice, true).is_empty());
+    }
+
+    #[test]
+    fn media_is_routed_even_if_a_panic_poisoned_the_routes() {
+        let table = RouteTable::new();
+        let alice = make_session(JoinKind::Publisher, "alpha", "10");
+        let bob = make_session(JoinKind::Subscriber, "alpha", "11");
+        let media = MediaRoutes {
+            audio: vec![Arc::clone(&bob)],
+            video: vec![Arc::clone(&bob)],
+        };
+        table.update(&"alpha".into(), vec![(Arc::clone(&alice), media)].into_iter().collect());
+        poison(&table.rooms.read().unwrap()["alpha"]);
+        assert_eq!(recipients(&table, "alpha", &alice, true), vec![Arc::clone(&bob)]);
+        poison(&table.rooms);
+        assert_eq!(recipients(&table, "alpha", &alice, false), vec![bob]);
+        // and the routes can still be changed afterwards
+        table.update(&"alpha".into(), RoomRoutes::new());
+        assert!(recipients(&table, "alpha", &alice, true).is_empty());
+    }
+
+    #[test]
+    fn empty_rooms_are_forgotten() {
+        let table = RouteTable::new();
+        let alice = make_session(JoinKind::Publisher, "alpha", "10");
+        table.update(&"alpha".into(), vec![(Arc::clone(&alice), MediaRoutes::default())].into_iter().collect());
+        assert_eq!(table.rooms.read().unwrap().len(), 1);
+        table.update(&"alpha".into(), RoomRoutes::new());
+        assert!(table.rooms.read().unwrap().is_empty());
+        assert!(recipients(&table, "alpha", &alice, false).is_empty());
+    }
+}
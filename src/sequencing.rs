@@ -0,0 +1,97 @@
+/// Sequence numbers for data relayed over the reliable data channel, so that recipients can check that they got each
+/// sender's messages in order and without gaps.
+use std::sync::Mutex;
+
+/// The label of the data channel whose messages are sequenced. Clients have one reliable channel with this label and
+/// one unreliable channel labeled "unreliable".
+pub const RELIABLE_LABEL: &str = "reliable";
+
+/// Numbers the data messages relayed from a single sender, counting up from zero.
+#[derive(Debug, Default)]
+pub struct DataSequencer(Mutex<u64>);
+
+impl DataSequencer {
+    /// Stamps the given payload with the sender's next sequence number and relays it with the given function, returning
+    /// the number it was stamped with. The sequence is held until the relay is done, so that if the same sender's
+    /// messages are ever relayed concurrently, a later message can't overtake an earlier one on its way to anybody.
+    pub fn relay<F>(&self, payload: &[u8], relay: F) -> u64
+    where
+        F: FnOnce(&[u8]),
+    {
+        let mut next = self.0.lock().unwrap();
+        let seq = *next;
+        *next += 1;
+        relay(&stamp(seq, payload));
+        seq
+    }
+}
+
+/// Prefixes the given payload with the given sequence number, in decimal, and a colon. Text stays text, so that the
+/// same stamp works for both text and binary messages.
+pub fn stamp(seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut stamped = format!("{}:", seq).into_bytes();
+    stamped.extend_from_slice(payload);
+    stamped
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::str;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn unstamp(stamped: &[u8]) -> (u64, &[u8]) {
+        let colon = stamped.iter().position(|&b| b == b':').unwrap();
+        (str::from_utf8(&stamped[..colon]).unwrap().parse().unwrap(), &stamped[colon + 1..])
+    }
+
+    #[test]
+    fn stamps_round_trip() {
+        assert_eq!(stamp(0, b"hello"), b"0:hello");
+        assert_eq!(stamp(42, b"a:b"), b"42:a:b");
+        assert_eq!(unstamp(&stamp(u64::MAX, &[0, 255, b':'])), (u64::MAX, &[0, 255, b':'][..]));
+        assert_eq!(unstamp(&stamp(7, b"")), (7, &b""[..]));
+    }
+
+    #[test]
+    fn each_message_gets_the_next_number() {
+        let sequencer = DataSequencer::default();
+        let mut relayed = Vec::new();
+        for payload in &[&b"first"[..], b"second", b"third"] {
+            sequencer.relay(payload, |stamped| relayed.push(stamped.to_vec()));
+        }
+        assert_eq!(relayed, vec![b"0:first".to_vec(), b"1:second".to_vec(), b"2:third".to_vec()]);
+    }
+
+    #[test]
+    fn senders_are_numbered_independently() {
+        let alice = DataSequencer::default();
+        let bob = DataSequencer::default();
+        assert_eq!(alice.relay(b"x", |_| {}), 0);
+        assert_eq!(alice.relay(b"x", |_| {}), 1);
+        assert_eq!(bob.relay(b"x", |_| {}), 0);
+    }
+
+    #[test]
+    fn concurrent_relays_arrive_in_order() {
+        let sequencer = Arc::new(DataSequencer::default());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let threads = (0..4)
+            .map(|_| {
+                let sequencer = Arc::clone(&sequencer);
+                let received = Arc::clone(&received);
+                thread::spawn(move || {
+                    for _ in 0..250 {
+                        sequencer.relay(b"data", |stamped| received.lock().unwrap().push(unstamp(stamped).0));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*received.lock().unwrap(), (0..1000).collect::<Vec<u64>>());
+    }
+}
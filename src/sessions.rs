@@ -1,39 +1,242 @@
+use crate::auth::ValidatedToken;
+use crate::bucket::TokenBucket;
+use crate::locks::{read_or_recover, write_or_recover};
 use crate::messages::{RoomId, Subscription, UserId};
+use crate::nack::RtpBuffer;
+use crate::recorder::PublisherRecording;
+use crate::sequencing::DataSequencer;
+use crate::simulcast::{LayerChoice, SimulcastLayers};
+use crate::speakers::AudioLevel;
+use crate::switching::SwitchingContext;
 use janus_plugin::sdp::Sdp;
 use janus_plugin::session::SessionWrapper;
-use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
 /// Types for representing Janus session state.
-use std::sync::atomic::{AtomicBool, AtomicIsize};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Once they join a room, all sessions are classified as either subscribers or publishers.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum JoinKind {
     Publisher,
     Subscriber,
 }
 
 /// State pertaining to all sessions that have joined a room.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct JoinState {
     /// Whether this session is a subscriber or a publisher.
     pub kind: JoinKind,
 
+    /// Whether the client said which kind of session this is when joining, rather than leaving us to infer it.
+    pub declared_kind: bool,
+
     /// The room ID that this session is in.
     pub room_id: RoomId,
 
     /// An opaque ID uniquely identifying this user.
     pub user_id: UserId,
+
+    /// Whether this user has moderator permissions for the room, either from joining with them or from refreshing
+    /// their token since.
+    moderator: AtomicBool,
+
+    /// Whether we've told this session's roommates that it left, so that we only tell them once.
+    pub departed: AtomicBool,
+
+    /// When this session joined the room, by the wall clock, for showing people.
+    pub joined_at: SystemTime,
+
+    /// When this session joined the room, by the monotonic clock, for measuring how long it's been there.
+    pub joined_instant: Instant,
 }
 
 impl JoinState {
-    pub fn new(kind: JoinKind, room_id: RoomId, user_id: UserId) -> Self {
-        Self { kind, room_id, user_id }
+    pub fn new(kind: JoinKind, room_id: RoomId, user_id: UserId, is_moderator: bool) -> Self {
+        Self {
+            kind,
+            declared_kind: false,
+            room_id,
+            user_id,
+            moderator: AtomicBool::new(is_moderator),
+            departed: AtomicBool::new(false),
+            joined_at: SystemTime::now(),
+            joined_instant: Instant::now(),
+        }
+    }
+
+    /// Marks whether the client said which kind of session this is.
+    pub fn with_declared_kind(self, declared_kind: bool) -> Self {
+        Self { declared_kind, ..self }
+    }
+
+    pub fn is_moderator(&self) -> bool {
+        self.moderator.load(Ordering::Relaxed)
+    }
+
+    pub fn set_moderator(&self, is_moderator: bool) {
+        self.moderator.store(is_moderator, Ordering::Relaxed);
+    }
+
+    /// When this session joined the room, in milliseconds since the Unix epoch.
+    pub fn joined_at_millis(&self) -> u64 {
+        self.joined_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+
+    /// How long this session had been in the room at the given time. This never goes backwards, even if the wall
+    /// clock does.
+    pub fn time_in_room(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.joined_instant)
+    }
+}
+
+/// Holds a session's join state while it's in a room. It's set when the session joins a room, and cleared if it leaves,
+/// so that it can join another.
+#[derive(Debug, Default)]
+pub struct JoinCell(RwLock<Option<Arc<JoinState>>>);
+
+impl JoinCell {
+    /// Returns the session's current join state, if it's in a room.
+    pub fn get(&self) -> Option<Arc<JoinState>> {
+        read_or_recover(&self.0, "Join state").clone()
+    }
+
+    /// Sets the session's join state, unless it's already in a room, in which case the given state is returned.
+    pub fn set(&self, state: JoinState) -> Result<(), JoinState> {
+        let mut current = write_or_recover(&self.0, "Join state");
+        match *current {
+            Some(_) => Err(state),
+            None => {
+                *current = Some(Arc::new(state));
+                Ok(())
+            }
+        }
+    }
+
+    /// Clears the session's join state, returning what it was.
+    pub fn take(&self) -> Option<Arc<JoinState>> {
+        write_or_recover(&self.0, "Join state").take()
+    }
+}
+
+/// Counts the packets and bytes which a session has sent us to relay, and which we've relayed to it. Every update is
+/// relaxed, so that counting stays cheap on the media paths.
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    pub packets_in: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub packets_out: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+impl TrafficCounters {
+    /// Counts a packet of the given size which this session sent us.
+    pub fn received(&self, bytes: u64) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a packet of the given size which we relayed to this session.
+    pub fn sent(&self, bytes: u64) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Remembers when we last passed a keyframe request along to a publisher, so that a burst of requests from its
+/// subscribers is coalesced into one.
+#[derive(Debug, Default)]
+pub struct KeyframeThrottle(Mutex<Option<Instant>>);
+
+impl KeyframeThrottle {
+    /// Returns whether a keyframe request made at the given time should be passed along, i.e. whether it's been at least
+    /// the given interval since the last one which was. If so, it counts as the last one from now on.
+    pub fn try_request(&self, min_interval: Duration, now: Instant) -> bool {
+        let mut last = self.0.lock().unwrap();
+        match *last {
+            Some(t) if now.saturating_duration_since(t) < min_interval => false,
+            _ => {
+                *last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Remembers when we last heard anything from a client, so that we can tell when it's gone away without saying so.
+#[derive(Debug, Default)]
+pub struct LastSeen(Mutex<Option<Instant>>);
+
+impl LastSeen {
+    pub fn new(now: Instant) -> Self {
+        Self(Mutex::new(Some(now)))
+    }
+
+    /// Records that we heard from the client at the given time.
+    pub fn touch(&self, now: Instant) {
+        *self.0.lock().unwrap() = Some(now);
+    }
+
+    /// Returns whether, at the given time, it's been longer than the given timeout since we last heard from the client.
+    /// Clients we've never heard from aren't idle, since we don't know how long they've been around.
+    pub fn is_idle(&self, timeout: Duration, now: Instant) -> bool {
+        match *self.0.lock().unwrap() {
+            Some(t) => now.saturating_duration_since(t) > timeout,
+            None => false,
+        }
+    }
+}
+
+/// Which kinds of a publisher's media a moderator has muted, so that we drop them instead of relaying them.
+#[derive(Debug, Default)]
+pub struct MediaMutes {
+    audio: AtomicBool,
+    video: AtomicBool,
+}
+
+impl MediaMutes {
+    /// Mutes or unmutes video or audio, returning whether that changed anything.
+    pub fn set(&self, video: bool, muted: bool) -> bool {
+        self.flag(video).swap(muted, Ordering::Relaxed) != muted
+    }
+
+    /// Returns whether video or audio is muted.
+    pub fn is_muted(&self, video: bool) -> bool {
+        self.flag(video).load(Ordering::Relaxed)
     }
+
+    fn flag(&self, video: bool) -> &AtomicBool {
+        if video {
+            &self.video
+        } else {
+            &self.audio
+        }
+    }
+}
+
+/// What a publisher's offer was answered with, so that we can spot clients which negotiated something unexpected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NegotiatedCodecs {
+    pub audio_codec: String,
+    pub audio_payload_type: Option<i32>,
+    pub video_codec: String,
+    pub video_payload_type: Option<i32>,
+    /// The H.264 profile-level-id which the video was negotiated with, if it's H.264.
+    pub video_profile: Option<String>,
+}
+
+/// A token which a session presented, along with the claims it was found to make, so that checking the same token
+/// again needn't decode it again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedClaims {
+    pub token: String,
+    pub claims: ValidatedToken,
 }
 
 /// The state associated with a single session.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct SessionState {
     /// Whether this session has been destroyed.
     pub destroyed: AtomicBool,
@@ -42,17 +245,192 @@ pub struct SessionState {
     pub fir_seq: AtomicIsize,
 
     /// Information pertaining to this session's user and room, if joined.
-    pub join_state: OnceCell<JoinState>,
+    pub join_state: JoinCell,
 
     // todo: these following fields should be unified with the JoinState, but it's
     // annoying in practice because they are established during JSEP negotiation
     // rather than during the join flow
-    /// If this is a subscriber, the subscription this user has established, if any.
-    pub subscription: OnceCell<Subscription>,
+    /// If this is a subscriber, the subscription this user has established, if any. If you need both this and
+    /// the switchboard lock, take the switchboard lock first.
+    pub subscription: Mutex<Option<Subscription>>,
 
     /// If this is a publisher, the offer for subscribing to it.
     pub subscriber_offer: Arc<Mutex<Option<Sdp>>>,
+
+    /// Limits how quickly this session may change its media subscription, if it's limited at all.
+    pub subscription_changes: Mutex<Option<TokenBucket>>,
+
+    /// How much media and data we've relayed from and to this session.
+    pub traffic: TrafficCounters,
+
+    /// If this is a publisher, how loud its audio has been lately.
+    pub audio_level: AudioLevel,
+
+    /// If this is a publisher, whether someone needs a keyframe from it, which we'll ask for with its next video.
+    pub keyframe_wanted: AtomicBool,
+
+    /// If this is a publisher, limits how often its subscribers' keyframe requests are passed along to it.
+    pub keyframe_requests: KeyframeThrottle,
+
+    /// If this is a publisher, its most recent video packets, so that subscribers who lose them can get them again.
+    pub rtp_buffer: RtpBuffer,
+
+    /// If this is a publisher, the simulcast layers it's sending, if any.
+    pub simulcast: SimulcastLayers,
+
+    /// If this is a subscriber, which layer it wants from a simulcasting publisher.
+    pub layer_choice: LayerChoice,
+
+    /// If this is a subscriber, the latest bandwidth estimate it reported in a REMB, in bits per second, or zero if it
+    /// hasn't reported one.
+    pub remb_reported: AtomicU32,
+
+    /// If this is a publisher, the lowest bandwidth estimate among its subscribers which we last relayed to it, in bits
+    /// per second, or zero if we haven't relayed one.
+    pub remb_estimate: AtomicU32,
+
+    /// If this is a publisher in a room which is being recorded, the recording of its media.
+    pub recording: Mutex<Option<PublisherRecording>>,
+
+    /// Whether `recording` holds a recording, so that relaying media needn't lock it to find out.
+    pub is_recording: AtomicBool,
+
+    /// How many of this session's video packets to buffer for retransmission, copied from the configuration whenever
+    /// it changes, so that relaying media needn't read the configuration.
+    pub nack_buffer_size: AtomicUsize,
+
+    /// Whether to number the messages this session sends over its reliable data channel, copied from the configuration
+    /// whenever it changes, so that relaying data needn't read the configuration.
+    pub sequence_reliable_data: AtomicBool,
+
+    /// If this is a publisher, which kinds of its media a moderator has muted.
+    pub muted: MediaMutes,
+
+    /// If this is a publisher, whether its connection was negotiated to receive media as well as send it.
+    pub sendrecv: AtomicBool,
+
+    /// If this is a publisher, the codecs and payload types its last offer was answered with.
+    pub negotiated: Mutex<Option<NegotiatedCodecs>>,
+
+    /// If this is a subscriber, whether it's receiving several publishers' media at once, each in media sections of its
+    /// own, in which case each stream is relayed as its publisher sent it.
+    pub multi_source: AtomicBool,
+
+    /// When we last got a signalling message or RTCP from this session.
+    pub last_seen: LastSeen,
+
+    /// If this is a subscriber, how to rewrite the media it receives, so that it sees one stream even if it switches
+    /// which publisher it's receiving media from.
+    pub switching: SwitchingContext,
+
+    /// Numbers the messages this session sends over its reliable data channel, if they're being numbered.
+    pub data_sequence: DataSequencer,
+
+    /// The token this session joined its room with, or last refreshed, if it gave one.
+    pub claims: Mutex<Option<CachedClaims>>,
 }
 
 /// Rust representation of a single Janus session, i.e. a single `RTCPeerConnection`.
 pub type Session = SessionWrapper<SessionState>;
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::locks::tests::poison;
+    use janus_plugin::PluginSession;
+
+    /// Makes a session backed by a fake Janus handle which hasn't joined anything yet.
+    pub fn make_unjoined_session() -> Arc<Session> {
+        let handle = Box::into_raw(Box::new(unsafe { std::mem::zeroed::<PluginSession>() }));
+        *unsafe { Session::associate(handle, SessionState::default()) }.unwrap()
+    }
+
+    /// Makes a session backed by a fake Janus handle which has joined the given room as the given user.
+    pub fn make_session(kind: JoinKind, room: &str, user: &str) -> Arc<Session> {
+        let session = make_unjoined_session();
+        session.join_state.set(JoinState::new(kind, room.into(), user.into(), false)).unwrap();
+        session
+    }
+
+    /// Makes a session backed by a fake Janus handle which has joined the given room as the given moderator.
+    pub fn make_moderator_session(kind: JoinKind, room: &str, user: &str) -> Arc<Session> {
+        let session = make_unjoined_session();
+        session.join_state.set(JoinState::new(kind, room.into(), user.into(), true)).unwrap();
+        session
+    }
+
+    #[test]
+    fn keyframe_requests_are_coalesced_within_the_interval() {
+        let throttle = KeyframeThrottle::default();
+        let interval = Duration::from_millis(500);
+        let start = Instant::now();
+        assert!(throttle.try_request(interval, start));
+        assert!(!throttle.try_request(interval, start));
+        assert!(!throttle.try_request(interval, start + Duration::from_millis(499)));
+        assert!(throttle.try_request(interval, start + Duration::from_millis(500)));
+        // dropped requests don't extend the window
+        assert!(!throttle.try_request(interval, start + Duration::from_millis(900)));
+        assert!(throttle.try_request(interval, start + Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn sessions_are_idle_once_unheard_from_for_the_timeout() {
+        let last_seen = LastSeen::default();
+        let timeout = Duration::from_secs(30);
+        let start = Instant::now();
+        assert!(!last_seen.is_idle(timeout, start + Duration::from_secs(3600)));
+        last_seen.touch(start);
+        assert!(!last_seen.is_idle(timeout, start));
+        assert!(!last_seen.is_idle(timeout, start + Duration::from_secs(30)));
+        assert!(last_seen.is_idle(timeout, start + Duration::from_secs(31)));
+        last_seen.touch(start + Duration::from_secs(31));
+        assert!(!last_seen.is_idle(timeout, start + Duration::from_secs(40)));
+        // a clock reading from before the last time we heard from it isn't idle either
+        assert!(!last_seen.is_idle(timeout, start));
+    }
+
+    #[test]
+    fn join_state_survives_a_panic_poisoning_it() {
+        let cell = JoinCell::default();
+        cell.set(JoinState::new(JoinKind::Publisher, "alpha".into(), "10".into(), false)).unwrap();
+        poison(&cell.0);
+        assert_eq!(cell.get().unwrap().user_id, "10");
+        assert_eq!(cell.take().unwrap().room_id, "alpha");
+        assert!(cell.set(JoinState::new(JoinKind::Subscriber, "beta".into(), "10".into(), false)).is_ok());
+    }
+
+    #[test]
+    fn time_in_room_only_increases() {
+        let joined = JoinState::new(JoinKind::Publisher, "alpha".into(), "10".into(), false);
+        let start = joined.joined_instant;
+        assert_eq!(joined.time_in_room(start), Duration::from_secs(0));
+        assert_eq!(joined.time_in_room(start + Duration::from_millis(1500)), Duration::from_millis(1500));
+        assert!(joined.time_in_room(start + Duration::from_secs(3)) > joined.time_in_room(start + Duration::from_secs(2)));
+        if let Some(before) = start.checked_sub(Duration::from_secs(1)) {
+            assert_eq!(joined.time_in_room(before), Duration::from_secs(0));
+        }
+        assert!(joined.joined_at_millis() > 0);
+    }
+
+    #[test]
+    fn muting_is_per_kind_of_media() {
+        let muted = MediaMutes::default();
+        assert!(!muted.is_muted(false) && !muted.is_muted(true));
+        assert!(muted.set(false, true));
+        assert!(muted.is_muted(false));
+        assert!(!muted.is_muted(true));
+        assert!(!muted.set(false, true));
+        assert!(muted.set(false, false));
+        assert!(!muted.set(true, false));
+        assert!(!muted.is_muted(false) && !muted.is_muted(true));
+    }
+
+    #[test]
+    fn keyframe_requests_are_unlimited_without_an_interval() {
+        let throttle = KeyframeThrottle::default();
+        let now = Instant::now();
+        assert!(throttle.try_request(Duration::from_millis(0), now));
+        assert!(throttle.try_request(Duration::from_millis(0), now));
+    }
+}
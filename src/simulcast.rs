@@ -0,0 +1,206 @@
+/// Tools for relaying just one of the layers which a simulcasting publisher sends, so that subscribers only get the
+/// quality they asked for.
+use crate::extensions::{extension_id, find_extension};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, RwLock};
+
+/// The URI of the RFC 8852 header extension which names the RTP stream, i.e. the simulcast layer, a packet is from.
+pub const RID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+
+/// What subscribers who haven't picked a layer get: the highest one available.
+const HIGHEST: u8 = u8::MAX;
+
+/// Returns the ID which the given SDP assigns to the RTP stream ID header extension, if any.
+pub fn rid_extension_id(sdp: &str) -> Option<u8> {
+    extension_id(sdp, RID_URI)
+}
+
+/// Returns the RIDs of the simulcast layers which the given SDP says will be sent, in the order listed, which should
+/// be from lowest to highest quality. Paused layers are included, and for layers with alternatives, the first is used.
+pub fn simulcast_rids(sdp: &str) -> Vec<String> {
+    let layers = sdp.lines().find_map(|line| {
+        let attribute = line.trim().strip_prefix("a=simulcast:")?.trim_start();
+        let mut parts = attribute.split_whitespace();
+        match parts.next() {
+            Some("send") => parts.next(),
+            _ => None,
+        }
+    });
+    match layers {
+        None => Vec::new(),
+        Some(layers) => layers
+            .split(';')
+            .filter_map(|layer| layer.split(',').next())
+            .map(|rid| rid.trim_start_matches("rid=").trim_start_matches('~').to_owned())
+            .filter(|rid| !rid.is_empty())
+            .collect(),
+    }
+}
+
+/// Which layer of a simulcasting publisher's video a packet is from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layer {
+    /// The layer's position, from 0 for the lowest quality upwards, or none if we can't tell which layer it is.
+    pub index: Option<u8>,
+    /// How many layers the publisher sends.
+    pub count: u8,
+}
+
+/// A publisher's simulcast layers, and which SSRC each one is being sent on.
+#[derive(Debug, Default)]
+pub struct SimulcastLayers {
+    /// The ID of the RTP stream ID header extension in this publisher's packets, or zero if it doesn't simulcast.
+    extension_id: AtomicU8,
+    /// The RIDs of each layer, from lowest to highest quality.
+    rids: RwLock<Vec<String>>,
+    /// The SSRC of each layer we've seen a packet from so far, along with its index.
+    ssrcs: Mutex<Vec<(u32, u8)>>,
+}
+
+impl SimulcastLayers {
+    /// Sets up the layers described by the given SDP, forgetting about any SSRCs from before.
+    pub fn negotiate(&self, sdp: &str) {
+        let rids = simulcast_rids(sdp);
+        let extension_id = match rid_extension_id(sdp) {
+            Some(id) if rids.len() > 1 => id,
+            _ => 0,
+        };
+        *self.rids.write().unwrap() = rids;
+        self.ssrcs.lock().unwrap().clear();
+        self.extension_id.store(extension_id, Ordering::Relaxed);
+    }
+
+    /// Returns how many layers the publisher sends, or zero if it doesn't simulcast.
+    pub fn count(&self) -> u8 {
+        if self.extension_id.load(Ordering::Relaxed) == 0 {
+            0
+        } else {
+            self.rids.read().unwrap().len() as u8
+        }
+    }
+
+    /// Returns which layer the given RTP packet is from, or none if the publisher doesn't simulcast.
+    pub fn layer_of(&self, packet: &[u8]) -> Option<Layer> {
+        let extension_id = self.extension_id.load(Ordering::Relaxed);
+        if extension_id == 0 || packet.len() < 12 {
+            return None;
+        }
+        let rids = self.rids.read().unwrap();
+        let count = rids.len() as u8;
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let mut ssrcs = self.ssrcs.lock().unwrap();
+        if let Some(&(_, index)) = ssrcs.iter().find(|&&(s, _)| s == ssrc) {
+            return Some(Layer { index: Some(index), count });
+        }
+        // packets only name their layer until the publisher knows we've learned its SSRC, so remember it
+        let rid = find_extension(packet, extension_id);
+        let index = rid.and_then(|rid| rids.iter().position(|r| r.as_bytes() == rid)).map(|i| i as u8);
+        if let Some(index) = index {
+            ssrcs.retain(|&(_, i)| i != index);
+            ssrcs.push((ssrc, index));
+        }
+        Some(Layer { index, count })
+    }
+}
+
+/// The layer which a subscriber wants to receive from simulcasting publishers.
+#[derive(Debug)]
+pub struct LayerChoice(AtomicU8);
+
+impl Default for LayerChoice {
+    fn default() -> Self {
+        Self(AtomicU8::new(HIGHEST))
+    }
+}
+
+impl LayerChoice {
+    /// Chooses the layer with the given index, or the highest layer available if it's none.
+    pub fn set(&self, index: Option<u8>) {
+        self.0.store(index.unwrap_or(HIGHEST), Ordering::Relaxed);
+    }
+
+    /// Returns whether packets from the given layer should be relayed to this subscriber. If the publisher doesn't
+    /// send the chosen layer, the closest one below it is relayed instead.
+    pub fn selects(&self, layer: Layer) -> bool {
+        match layer.index {
+            None => false,
+            Some(index) => index == self.0.load(Ordering::Relaxed).min(layer.count.saturating_sub(1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const OFFER: &str = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=extmap:4 urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id\r\na=rid:lo send\r\na=rid:mid send\r\na=rid:hi send\r\na=simulcast:send lo;~mid;hi,alt\r\n";
+
+    fn packet(ssrc: u32, rid: Option<&str>) -> Vec<u8> {
+        let mut packet = vec![0x80, 96, 0, 1, 0, 0, 0, 0];
+        packet.extend(&ssrc.to_be_bytes());
+        if let Some(rid) = rid {
+            packet[0] |= 0x10;
+            let mut elements = vec![0x40 | (rid.len() as u8 - 1)];
+            elements.extend(rid.as_bytes());
+            let words = (elements.len() + 3) / 4;
+            elements.resize(words * 4, 0);
+            packet.extend(&[0xBE, 0xDE, 0, words as u8]);
+            packet.extend(elements);
+        }
+        packet.extend(&[0xAA; 20]); // payload
+        packet
+    }
+
+    #[test]
+    fn rids_are_found_in_sdp() {
+        assert_eq!(simulcast_rids(OFFER), vec!["lo", "mid", "hi"]);
+        assert_eq!(simulcast_rids("a=simulcast: send rid=1;2 recv rid=3\r\n"), vec!["1", "2"]);
+        assert!(simulcast_rids("v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n").is_empty());
+        assert_eq!(rid_extension_id(OFFER), Some(4));
+    }
+
+    #[test]
+    fn layers_are_learned_from_packets() {
+        let layers = SimulcastLayers::default();
+        layers.negotiate(OFFER);
+        assert_eq!(layers.count(), 3);
+        assert_eq!(layers.layer_of(&packet(10, None)), Some(Layer { index: None, count: 3 }));
+        assert_eq!(layers.layer_of(&packet(20, Some("mid"))), Some(Layer { index: Some(1), count: 3 }));
+        assert_eq!(layers.layer_of(&packet(20, None)), Some(Layer { index: Some(1), count: 3 }));
+        assert_eq!(layers.layer_of(&packet(30, Some("hi"))), Some(Layer { index: Some(2), count: 3 }));
+        assert_eq!(layers.layer_of(&packet(40, Some("unknown"))), Some(Layer { index: None, count: 3 }));
+        // a layer which moves to a new SSRC is followed there
+        assert_eq!(layers.layer_of(&packet(50, Some("mid"))), Some(Layer { index: Some(1), count: 3 }));
+        assert_eq!(layers.layer_of(&packet(20, None)), Some(Layer { index: None, count: 3 }));
+    }
+
+    #[test]
+    fn publishers_without_simulcast_have_no_layers() {
+        let layers = SimulcastLayers::default();
+        assert_eq!(layers.layer_of(&packet(10, Some("lo"))), None);
+        layers.negotiate("v=0\r\na=extmap:4 urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id\r\n");
+        assert_eq!(layers.count(), 0);
+        assert_eq!(layers.layer_of(&packet(10, Some("lo"))), None);
+        layers.negotiate(OFFER);
+        layers.layer_of(&packet(10, Some("lo")));
+        layers.negotiate("v=0\r\n");
+        assert_eq!(layers.layer_of(&packet(10, None)), None);
+    }
+
+    #[test]
+    fn subscribers_get_their_chosen_layer() {
+        let choice = LayerChoice::default();
+        let layer = |index| Layer { index: Some(index), count: 3 };
+        assert!(choice.selects(layer(2)));
+        assert!(!choice.selects(layer(0)));
+        choice.set(Some(0));
+        assert!(choice.selects(layer(0)));
+        assert!(!choice.selects(layer(2)));
+        assert!(!choice.selects(Layer { index: None, count: 3 }));
+        choice.set(Some(5));
+        assert!(choice.selects(layer(2)));
+        choice.set(None);
+        assert!(choice.selects(Layer { index: Some(1), count: 2 }));
+    }
+}
@@ -0,0 +1,155 @@
+/// Tools for working out who's talking from the audio levels which publishers report in their RTP packets.
+use crate::extensions::{extension_id, find_extension};
+use crate::messages::UserId;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+/// The URI of the RFC 6464 client-to-mixer audio level header extension.
+pub const AUDIO_LEVEL_URI: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
+
+/// How much each new audio level counts for in the smoothed level, between 0 and 1.
+const SMOOTHING: f32 = 0.25;
+
+/// How much of the smoothed level is kept each time it's checked, so that publishers who stop sending audio
+/// altogether eventually stop being considered.
+const DECAY: f32 = 0.5;
+
+/// Returns the ID which the given SDP assigns to the audio level header extension, if any.
+pub fn audio_level_extension_id(sdp: &str) -> Option<u8> {
+    extension_id(sdp, AUDIO_LEVEL_URI)
+}
+
+/// Extracts the audio level from the given RTP packet's one-byte header extension with the given ID, if it has one.
+/// Levels are in -dBov, so 0 is as loud as possible and 127 is silence.
+pub fn parse_audio_level(packet: &[u8], extension_id: u8) -> Option<u8> {
+    find_extension(packet, extension_id).map(|data| data[0] & 0x7F)
+}
+
+/// Folds a new audio level, in -dBov, into a smoothed loudness, where 0 is silence and 127 is as loud as possible.
+pub fn smooth(previous: f32, level: u8) -> f32 {
+    let loudness = f32::from(127 - level.min(127));
+    previous + (loudness - previous) * SMOOTHING
+}
+
+/// Picks the loudest of the given speakers, if anyone is louder than the threshold.
+pub fn dominant_speaker<'a, T>(speakers: T, threshold: f32) -> Option<&'a UserId>
+where
+    T: IntoIterator<Item = (&'a UserId, f32)>,
+{
+    let mut loudest: Option<(&'a UserId, f32)> = None;
+    for (user, loudness) in speakers {
+        if loudness > threshold && loudest.map_or(true, |(_, max)| loudness > max) {
+            loudest = Some((user, loudness));
+        }
+    }
+    loudest.map(|(user, _)| user)
+}
+
+/// A publisher's smoothed loudness, updated as its audio comes in without taking any locks.
+#[derive(Debug, Default)]
+pub struct AudioLevel {
+    /// The ID of the audio level header extension in this publisher's packets, or zero if we aren't looking at them.
+    pub extension_id: AtomicU8,
+    loudness: AtomicU32,
+}
+
+impl AudioLevel {
+    /// Folds the level from the given RTP packet into the smoothed loudness, if the packet has one.
+    pub fn observe(&self, packet: &[u8]) {
+        let extension_id = self.extension_id.load(Ordering::Relaxed);
+        if extension_id == 0 {
+            return;
+        }
+        if let Some(level) = parse_audio_level(packet, extension_id) {
+            // only the publisher's own packets update this, and they come in one at a time, so this needn't be atomic
+            let previous = f32::from_bits(self.loudness.load(Ordering::Relaxed));
+            self.loudness.store(smooth(previous, level).to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the smoothed loudness, and lets it decay, so that it dies away if no more audio comes in.
+    pub fn check(&self) -> f32 {
+        let loudness = f32::from_bits(self.loudness.load(Ordering::Relaxed));
+        self.loudness.store((loudness * DECAY).to_bits(), Ordering::Relaxed);
+        loudness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn packet_with_extensions(csrc_count: u8, extensions: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x90 | csrc_count, 111, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend(vec![0; 4 * csrc_count as usize]);
+        let words = (extensions.len() + 3) / 4;
+        packet.extend(&[0xBE, 0xDE, 0, words as u8]);
+        packet.extend(extensions);
+        packet.extend(vec![0; words * 4 - extensions.len()]);
+        packet.extend(&[0xAA; 20]); // payload
+        packet
+    }
+
+    #[test]
+    fn extension_id_is_found_in_sdp() {
+        let sdp = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=extmap:3 urn:ietf:params:rtp-hdrext:sdes:mid\r\na=extmap:1/sendonly urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n";
+        assert_eq!(audio_level_extension_id(sdp), Some(1));
+        assert_eq!(audio_level_extension_id("v=0\r\na=extmap:3 urn:ietf:params:rtp-hdrext:sdes:mid\r\n"), None);
+    }
+
+    #[test]
+    fn audio_levels_are_parsed() {
+        // a 2-byte mid extension with ID 3, then padding, then a voice activity flag and level 30 with ID 1
+        let packet = packet_with_extensions(0, &[0x31, b'a', b'b', 0x00, 0x10, 0x80 | 30]);
+        assert_eq!(parse_audio_level(&packet, 1), Some(30));
+        assert_eq!(parse_audio_level(&packet, 2), None);
+        let packet = packet_with_extensions(2, &[0x10, 45]);
+        assert_eq!(parse_audio_level(&packet, 1), Some(45));
+    }
+
+    #[test]
+    fn packets_without_levels_are_ignored() {
+        let mut packet = packet_with_extensions(0, &[0x10, 45]);
+        packet[0] &= !0x10; // no extension
+        assert_eq!(parse_audio_level(&packet, 1), None);
+        assert_eq!(parse_audio_level(&packet[..14], 1), None);
+        assert_eq!(parse_audio_level(&[], 1), None);
+        let truncated = packet_with_extensions(0, &[0x13, 1]);
+        assert_eq!(parse_audio_level(&truncated[..17], 1), None);
+    }
+
+    #[test]
+    fn levels_are_smoothed() {
+        let mut loudness = 0.0;
+        for _ in 0..3 {
+            loudness = smooth(loudness, 27);
+        }
+        assert!(loudness > 50.0 && loudness < 100.0);
+        for _ in 0..50 {
+            loudness = smooth(loudness, 27);
+        }
+        assert!((loudness - 100.0).abs() < 0.1);
+        assert!(smooth(loudness, 127) < loudness);
+    }
+
+    #[test]
+    fn loudest_speaker_over_threshold_dominates() {
+        let (alice, bob, carol) = ("alice".to_owned(), "bob".to_owned(), "carol".to_owned());
+        let levels = vec![(&alice, 60.0), (&bob, 80.0), (&carol, 20.0)];
+        assert_eq!(dominant_speaker(levels.clone(), 50.0), Some(&bob));
+        assert_eq!(dominant_speaker(levels, 90.0), None);
+        assert_eq!(dominant_speaker(Vec::new(), 0.0), None);
+    }
+
+    #[test]
+    fn silent_publishers_decay() {
+        let level = AudioLevel::default();
+        level.extension_id.store(1, Ordering::Relaxed);
+        for _ in 0..50 {
+            level.observe(&packet_with_extensions(0, &[0x10, 27]));
+        }
+        let first = level.check();
+        assert!(first > 99.0);
+        assert!(level.check() < first);
+    }
+}
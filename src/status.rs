@@ -0,0 +1,144 @@
+/// A tiny HTTP server for load balancers and monitoring to check on the plugin without going through Janus.
+use crate::metrics::Gauges;
+use janus_plugin::{janus_verb, janus_warn};
+use serde_json::json;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the server checks whether it's been stopped while it waits for connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long to wait for a client to send its request before hanging up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running status server. It answers `/healthz` with a 200 as long as it's up, and `/stats` with the number of
+/// rooms, publishers, and subscribers, as JSON. The server stops when this is stopped or dropped.
+#[derive(Debug)]
+pub struct StatusServer {
+    closed: Arc<AtomicBool>,
+}
+
+impl StatusServer {
+    /// Binds to the given address and starts serving requests on a new thread, reading stats with the given function.
+    pub fn start<F>(addr: SocketAddr, stats: F) -> io::Result<Self>
+    where
+        F: Fn() -> Gauges + Send + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let server_closed = Arc::clone(&closed);
+        thread::Builder::new()
+            .name("sfu status".into())
+            .spawn(move || serve(&listener, &server_closed, &stats))?;
+        Ok(Self { closed })
+    }
+
+    /// Stops serving requests. The listening socket is closed within a poll interval.
+    pub fn stop(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn serve(listener: &TcpListener, closed: &AtomicBool, stats: &dyn Fn() -> Gauges) {
+    while !closed.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if let Err(e) = answer(stream, stats) {
+                    janus_verb!("Failed to answer status request from {}: {}", peer, e);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                janus_warn!("Failed to accept status connection: {}", e);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+fn answer(mut stream: TcpStream, stats: &dyn Fn() -> Gauges) -> io::Result<()> {
+    // on some platforms, accepted sockets inherit the listener's non-blocking mode
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // read the rest of the headers, so the client doesn't see a reset when we close the connection on it
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+    let (status, body) = respond(&request_line, stats);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Works out the status and JSON body of the response to the request with the given request line.
+fn respond(request_line: &str, stats: &dyn Fn() -> Gauges) -> (&'static str, String) {
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/healthz")) => ("200 OK", json!({ "status": "ok" }).to_string()),
+        (Some("GET"), Some("/stats")) => ("200 OK", stats_json(stats())),
+        (Some("GET"), Some(_)) => ("404 Not Found", json!({ "error": "Not found." }).to_string()),
+        _ => ("405 Method Not Allowed", json!({ "error": "Only GET is supported." }).to_string()),
+    }
+}
+
+/// Serializes the given gauges as the body of a `/stats` response.
+pub fn stats_json(gauges: Gauges) -> String {
+    json!({
+        "rooms": gauges.rooms,
+        "publishers": gauges.publishers,
+        "subscribers": gauges.subscribers,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::Value as JsonValue;
+
+    fn gauges() -> Gauges {
+        Gauges {
+            publishers: 3,
+            subscribers: 7,
+            rooms: 2,
+        }
+    }
+
+    #[test]
+    fn stats_are_serialized_as_counts() {
+        let stats: JsonValue = serde_json::from_str(&stats_json(gauges())).unwrap();
+        assert_eq!(stats, json!({ "rooms": 2, "publishers": 3, "subscribers": 7 }));
+        let empty: JsonValue = serde_json::from_str(&stats_json(Gauges::default())).unwrap();
+        assert_eq!(empty, json!({ "rooms": 0, "publishers": 0, "subscribers": 0 }));
+    }
+
+    #[test]
+    fn requests_are_routed_by_path() {
+        assert_eq!(respond("GET /healthz HTTP/1.1\r\n", &gauges).0, "200 OK");
+        assert_eq!(respond("GET /stats HTTP/1.1\r\n", &gauges), ("200 OK", stats_json(gauges())));
+        assert_eq!(respond("GET /metrics HTTP/1.1\r\n", &gauges).0, "404 Not Found");
+        assert_eq!(respond("POST /stats HTTP/1.1\r\n", &gauges).0, "405 Method Not Allowed");
+        assert_eq!(respond("", &gauges).0, "405 Method Not Allowed");
+    }
+}
@@ -1,13 +1,21 @@
+use crate::expiring::ExpiringMap;
 use crate::messages::{RoomId, UserId};
-use crate::sessions::Session;
+use crate::metrics::{bump, COUNTERS};
+use crate::rooms::RoomState;
+use crate::routes::{MediaRoutes, RoomRoutes, RouteTable};
+use crate::sessions::{JoinKind, JoinState, Session};
 use janus_plugin::janus_err;
+use janus_plugin::sdp::Sdp;
 use std::borrow::Borrow;
 use std::collections::hash_map::Entry;
 /// Tools for managing the set of subscriptions between connections.
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::iter;
+use std::mem;
 use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Debug)]
 pub struct BidirectionalMultimap<K: Eq + Hash, V: Eq + Hash> {
@@ -43,42 +51,70 @@ where
     {
         if let Some(vals) = self.forward_mapping.get_mut(k) {
             vals.retain(|x| x.borrow() != v);
+            if vals.is_empty() {
+                self.forward_mapping.remove(k);
+            }
         }
         if let Some(keys) = self.inverse_mapping.get_mut(v) {
             keys.retain(|x| x.borrow() != k);
+            if keys.is_empty() {
+                self.inverse_mapping.remove(v);
+            }
         }
     }
 
+    /// Removes the given key and everything associated with it, dropping any value that's left with no keys. If the
+    /// inverse side turns out to be missing any of the key's associations, that's counted.
     pub fn remove_key<T>(&mut self, k: &T)
     where
         K: Borrow<T>,
         T: Hash + Eq + Debug,
     {
-        if let Some(vs) = self.forward_mapping.remove(k) {
-            for v in vs {
-                if let Some(ks) = self.inverse_mapping.get_mut(&v) {
+        let mut consistent = true;
+        for v in self.forward_mapping.remove(k).unwrap_or_default() {
+            match self.inverse_mapping.get_mut(&v) {
+                Some(ks) if ks.iter().any(|x| x.borrow() == k) => {
                     ks.retain(|x| x.borrow() != k);
-                } else {
+                    if ks.is_empty() {
+                        self.inverse_mapping.remove(&v);
+                    }
+                }
+                _ => {
                     janus_err!("Map in inconsistent state: entry ({:?}, {:?}) has no corresponding entry.", k, v);
+                    consistent = false;
                 }
             }
         }
+        if !consistent {
+            bump(&COUNTERS.map_inconsistencies, 1);
+        }
     }
 
+    /// Removes the given value and everything associated with it, dropping any key that's left with no values. If the
+    /// forward side turns out to be missing any of the value's associations, that's counted.
     pub fn remove_value<U>(&mut self, v: &U)
     where
         V: Borrow<U>,
         U: Hash + Eq + Debug,
     {
-        if let Some(ks) = self.inverse_mapping.remove(v) {
-            for k in ks {
-                if let Some(vs) = self.forward_mapping.get_mut(&k) {
+        let mut consistent = true;
+        for k in self.inverse_mapping.remove(v).unwrap_or_default() {
+            match self.forward_mapping.get_mut(&k) {
+                Some(vs) if vs.iter().any(|x| x.borrow() == v) => {
                     vs.retain(|x| x.borrow() != v);
-                } else {
+                    if vs.is_empty() {
+                        self.forward_mapping.remove(&k);
+                    }
+                }
+                _ => {
                     janus_err!("Map in inconsistent state: entry ({:?}, {:?}) has no corresponding entry.", k, v);
+                    consistent = false;
                 }
             }
         }
+        if !consistent {
+            bump(&COUNTERS.map_inconsistencies, 1);
+        }
     }
 
     pub fn get_values<T>(&self, k: &T) -> &[V]
@@ -110,10 +146,22 @@ pub struct Switchboard {
     publishers_by_user: HashMap<UserId, Arc<Session>>,
     /// All joined subscriber connections, by which user they have joined as.
     subscribers_by_user: HashMap<UserId, Vec<Arc<Session>>>,
+    /// All joined subscriber connections, by which room they have joined.
+    subscribers_by_room: HashMap<RoomId, Vec<Arc<Session>>>,
     /// Which connections are subscribing to traffic from which other connections.
     publisher_to_subscribers: BidirectionalMultimap<Arc<Session>, Arc<Session>>,
+    /// Which connections want traffic from which other connections that haven't negotiated any media yet.
+    pending_subscribers: BidirectionalMultimap<Arc<Session>, Arc<Session>>,
     /// Which users have explicitly blocked traffic to and from other users.
     blockers_to_miscreants: BidirectionalMultimap<UserId, UserId>,
+    /// Moderator-established state for rooms, by room ID.
+    rooms: HashMap<RoomId, RoomState>,
+    /// Subscriber offers of recently departed publishers, by user, kept in case they quickly reconnect.
+    retained_offers: ExpiringMap<UserId, Sdp>,
+    /// Users whom recently departed users had blocked, by blocker, kept in case they quickly reconnect.
+    retained_blocks: ExpiringMap<UserId, Vec<UserId>>,
+    /// Precomputed media routes for each room, updated whenever they change, for relaying media without this lock.
+    routes: Arc<RouteTable>,
 }
 
 impl Switchboard {
@@ -123,11 +171,56 @@ impl Switchboard {
             publishers_by_room: HashMap::new(),
             publishers_by_user: HashMap::new(),
             subscribers_by_user: HashMap::new(),
+            subscribers_by_room: HashMap::new(),
             publisher_to_subscribers: BidirectionalMultimap::new(),
+            pending_subscribers: BidirectionalMultimap::new(),
             blockers_to_miscreants: BidirectionalMultimap::new(),
+            rooms: HashMap::new(),
+            retained_offers: ExpiringMap::new(),
+            retained_blocks: ExpiringMap::new(),
+            routes: Arc::new(RouteTable::new()),
         }
     }
 
+    pub fn routes(&self) -> &Arc<RouteTable> {
+        &self.routes
+    }
+
+    /// Recomputes the media routes for every publisher in the given room.
+    fn refresh_routes(&self, room: &RoomId) {
+        let routes = self
+            .publishers_occupying(room)
+            .iter()
+            .map(|publisher| {
+                let media = MediaRoutes {
+                    audio: self.media_recipients_for(publisher, false).cloned().collect(),
+                    video: self.media_recipients_for(publisher, true).cloned().collect(),
+                };
+                (Arc::clone(publisher), media)
+            })
+            .collect::<RoomRoutes>();
+        self.routes.update(room, routes);
+    }
+
+    /// Recomputes the media routes for the rooms which the given sessions are in.
+    fn refresh_routes_for<'a>(&self, sessions: impl IntoIterator<Item = &'a Arc<Session>>) {
+        let mut rooms = sessions
+            .into_iter()
+            .filter_map(|s| s.join_state.get())
+            .map(|joined| joined.room_id.clone())
+            .collect::<Vec<_>>();
+        rooms.sort();
+        rooms.dedup();
+        for room in rooms {
+            self.refresh_routes(&room);
+        }
+    }
+
+    /// Recomputes the media routes for the room where the given user is publishing, if any.
+    fn refresh_routes_of_user(&self, user: &UserId) {
+        self.refresh_routes_for(self.get_publisher(user));
+    }
+
     pub fn connect(&mut self, session: Box<Arc<Session>>) {
         self.sessions.push(session);
     }
@@ -136,45 +229,231 @@ impl Switchboard {
         self.sessions.retain(|s| s.handle != session.handle);
     }
 
-    pub fn is_connected(&self, user: &UserId) -> bool {
-        self.sessions.iter().any(|s| match s.join_state.get() {
-            None => false,
-            Some(other_state) => user == &other_state.user_id,
-        })
+    /// Returns every active connection, whether or not it has joined a room.
+    pub fn connected_sessions(&self) -> impl Iterator<Item = &Arc<Session>> {
+        self.sessions.iter().map(|s| &**s)
     }
 
     pub fn establish_block(&mut self, from: UserId, target: UserId) {
-        self.blockers_to_miscreants.associate(from, target);
+        self.blockers_to_miscreants.associate(from.clone(), target.clone());
+        self.refresh_routes_of_user(&from);
+        self.refresh_routes_of_user(&target);
     }
 
     pub fn lift_block(&mut self, from: &UserId, target: &UserId) {
         self.blockers_to_miscreants.disassociate(from, target);
+        self.refresh_routes_of_user(from);
+        self.refresh_routes_of_user(target);
+    }
+
+    /// Returns the users whom the given user has blocked.
+    pub fn blocked_by(&self, user: &UserId) -> &[UserId] {
+        self.blockers_to_miscreants.get_values(user)
+    }
+
+    /// Returns the users who have blocked the given user.
+    pub fn blockers_of(&self, user: &UserId) -> &[UserId] {
+        self.blockers_to_miscreants.get_keys(user)
+    }
+
+    /// Removes the blocks which the given user established, e.g. because they've left, keeping them around until the
+    /// given deadline so that they can be restored if the user comes back before then. Blocks which others established
+    /// against them stay in place, so that a blocked user can't shake a block off by leaving and rejoining; those are
+    /// removed when the users who established them leave in turn.
+    pub fn remove_user_blocks(&mut self, user: &UserId, expires_at: Instant, now: Instant) {
+        let targets = self.blockers_to_miscreants.get_values(user).to_vec();
+        if targets.is_empty() {
+            return;
+        }
+        self.blockers_to_miscreants.remove_key(user);
+        self.refresh_routes_of_user(user);
+        for target in &targets {
+            self.refresh_routes_of_user(target);
+        }
+        self.retained_blocks.insert(user.clone(), targets, expires_at, now);
+    }
+
+    /// Puts back the blocks which the given user established before they last left, if they haven't expired.
+    pub fn restore_user_blocks(&mut self, user: &UserId, now: Instant) {
+        for target in self.retained_blocks.take(user, now).unwrap_or_default() {
+            if !self.blockers_to_miscreants.get_values(user).contains(&target) {
+                self.establish_block(user.clone(), target);
+            }
+        }
+    }
+
+    /// Returns whether any of the given user's sessions are still in a room.
+    pub fn is_user_present(&self, user: &UserId) -> bool {
+        self.publishers_by_user.contains_key(user) || self.subscribers_by_user.contains_key(user)
+    }
+
+    /// Forgets the state for the given room if there's nothing interesting in it.
+    fn tidy_room(&mut self, room: &RoomId) {
+        if let Entry::Occupied(state) = self.rooms.entry(room.clone()) {
+            if state.get().is_empty() {
+                state.remove_entry();
+            }
+        }
+    }
+
+    pub fn set_roster(&mut self, room: RoomId, roster: Option<HashSet<UserId>>) {
+        self.rooms.entry(room.clone()).or_default().roster = roster;
+        self.tidy_room(&room);
+    }
+
+    pub fn set_presenter_only_audio(&mut self, room: RoomId, enabled: bool) {
+        self.rooms.entry(room.clone()).or_default().presenter_only_audio = enabled;
+        self.tidy_room(&room);
+        self.refresh_routes(&room);
+    }
+
+    /// Turns the given room's waiting room on or off. Turning it off releases everyone who was waiting, returning
+    /// their sessions so that they can be admitted.
+    pub fn set_waiting_room(&mut self, room: RoomId, enabled: bool) -> Vec<Arc<Session>> {
+        let state = self.rooms.entry(room.clone()).or_default();
+        state.waiting_room = enabled;
+        let released = if enabled { Vec::new() } else { mem::take(&mut state.waiting) };
+        self.tidy_room(&room);
+        released
+    }
+
+    pub fn is_waiting_room(&self, room: &RoomId) -> bool {
+        self.get_room_state(room).map(|state| state.waiting_room).unwrap_or(false)
+    }
+
+    /// Parks the given session in the given room's waiting room, without routing any traffic to or from it.
+    pub fn join_waiting_room(&mut self, session: Arc<Session>, room: RoomId) {
+        self.rooms.entry(room).or_default().waiting.push(session);
+    }
+
+    /// Removes the given session from whatever waiting room it's in, returning whether it was in one.
+    pub fn leave_waiting_room(&mut self, session: &Session) -> bool {
+        let joined = match session.join_state.get() {
+            Some(joined) => joined,
+            None => return false,
+        };
+        let room = &joined.room_id;
+        let removed = match self.rooms.get_mut(room) {
+            Some(state) => {
+                let count = state.waiting.len();
+                state.waiting.retain(|x| x.as_ref() != session);
+                state.waiting.len() != count
+            }
+            None => false,
+        };
+        self.tidy_room(room);
+        removed
+    }
+
+    /// Removes all of the given user's sessions from the given room's waiting room, returning them.
+    pub fn take_waiting_sessions(&mut self, room: &RoomId, user: &UserId) -> Vec<Arc<Session>> {
+        let taken = match self.rooms.get_mut(room) {
+            Some(state) => {
+                let (taken, remaining): (Vec<_>, Vec<_>) = mem::take(&mut state.waiting)
+                    .into_iter()
+                    .partition(|s| matches!(s.join_state.get(), Some(joined) if &joined.user_id == user));
+                state.waiting = remaining;
+                taken
+            }
+            None => Vec::new(),
+        };
+        self.tidy_room(room);
+        taken
+    }
+
+    /// Whether the given session is waiting to be admitted to the room it joined.
+    pub fn is_waiting(&self, session: &Session) -> bool {
+        match session.join_state.get() {
+            Some(joined) => match self.get_room_state(&joined.room_id) {
+                Some(state) => state.waiting.iter().any(|s| s.as_ref() == session),
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    pub fn set_locked(&mut self, room: RoomId, locked: bool) {
+        self.rooms.entry(room.clone()).or_default().locked = locked;
+        self.tidy_room(&room);
+    }
+
+    pub fn is_locked(&self, room: &RoomId) -> bool {
+        self.get_room_state(room).map(|state| state.locked).unwrap_or(false)
     }
 
-    pub fn join_publisher(&mut self, session: Arc<Session>, user: UserId, room: RoomId) {
+    pub fn is_presenter_only_audio(&self, room: &RoomId) -> bool {
+        self.get_room_state(room).map(|state| state.presenter_only_audio).unwrap_or(false)
+    }
+
+    pub fn set_recording(&mut self, room: RoomId, enabled: bool) {
+        self.rooms.entry(room.clone()).or_default().recording = enabled;
+        self.tidy_room(&room);
+    }
+
+    pub fn is_recording(&self, room: &RoomId) -> bool {
+        self.get_room_state(room).map(|state| state.recording).unwrap_or(false)
+    }
+
+    pub fn get_room_state(&self, room: &RoomId) -> Option<&RoomState> {
+        self.rooms.get(room)
+    }
+
+    pub fn is_expected(&self, room: &RoomId, user: &UserId) -> bool {
+        match self.get_room_state(room) {
+            Some(state) => state.is_expected(user),
+            None => true,
+        }
+    }
+
+    /// Returns the users on the given room's roster who haven't joined it yet.
+    pub fn get_absent_users(&self, room: &RoomId) -> impl Iterator<Item = &UserId> {
+        let present = self.get_room_users(room).collect::<HashSet<_>>();
+        let roster = self.get_room_state(room).and_then(|state| state.roster.as_ref());
+        roster.into_iter().flatten().filter(move |user| !present.contains(*user))
+    }
+
+    /// Adds the given publisher to the given room, returning whether this brought the room into existence, i.e.
+    /// whether nobody else was publishing there.
+    pub fn join_publisher(&mut self, session: Arc<Session>, user: UserId, room: RoomId) -> bool {
         self.publishers_by_user.entry(user).or_insert(session.clone());
-        self.publishers_by_room.entry(room).or_insert_with(Vec::new).push(session);
+        let publishers = self.publishers_by_room.entry(room.clone()).or_insert_with(Vec::new);
+        publishers.push(session);
+        let created = publishers.len() == 1;
+        self.refresh_routes(&room);
+        created
     }
 
-    pub fn join_subscriber(&mut self, session: Arc<Session>, user: UserId, _room: RoomId) {
-        self.subscribers_by_user.entry(user).or_insert_with(Vec::new).push(session);
+    pub fn join_subscriber(&mut self, session: Arc<Session>, user: UserId, room: RoomId) {
+        self.subscribers_by_user.entry(user).or_insert_with(Vec::new).push(Arc::clone(&session));
+        self.subscribers_by_room.entry(room).or_insert_with(Vec::new).push(session);
     }
 
-    pub fn leave_publisher(&mut self, session: &Session) {
+    /// Removes the given publisher from the switchboard, returning any subscribers who were receiving its media, and
+    /// whether this destroyed its room, i.e. whether it was the last one publishing there.
+    pub fn leave_publisher(&mut self, session: &Session) -> (Vec<Arc<Session>>, bool) {
+        let subscribers = self.subscribers_to(session).to_vec();
         self.publisher_to_subscribers.remove_key(session);
+        self.pending_subscribers.remove_key(session);
+        let mut destroyed = false;
         if let Some(joined) = session.join_state.get() {
             self.publishers_by_user.remove(&joined.user_id);
             if let Entry::Occupied(mut others) = self.publishers_by_room.entry(joined.room_id.clone()) {
                 others.get_mut().retain(|x| x.as_ref() != session);
                 if others.get().is_empty() {
                     others.remove_entry();
+                    destroyed = true;
                 }
             }
+            self.refresh_routes(&joined.room_id);
         }
+        (subscribers, destroyed)
     }
 
     pub fn leave_subscriber(&mut self, session: &Session) {
+        let publishers = self.publishers_to(session).to_vec();
         self.publisher_to_subscribers.remove_value(session);
+        self.pending_subscribers.remove_value(session);
+        self.refresh_routes_for(&publishers);
         if let Some(joined) = session.join_state.get() {
             if let Entry::Occupied(mut others) = self.subscribers_by_user.entry(joined.user_id.clone()) {
                 others.get_mut().retain(|x| x.as_ref() != session);
@@ -182,11 +461,124 @@ impl Switchboard {
                     others.remove_entry();
                 }
             }
+            if let Entry::Occupied(mut others) = self.subscribers_by_room.entry(joined.room_id.clone()) {
+                others.get_mut().retain(|x| x.as_ref() != session);
+                if others.get().is_empty() {
+                    others.remove_entry();
+                }
+            }
+        }
+    }
+
+    /// Keeps the given user's subscriber offer around until the given deadline, so that it can be reused if they
+    /// publish again before then.
+    pub fn retain_offer(&mut self, user: UserId, offer: Sdp, expires_at: Instant, now: Instant) {
+        self.retained_offers.insert(user, offer, expires_at, now);
+    }
+
+    /// Removes and returns the given user's retained subscriber offer, if it hasn't expired.
+    pub fn take_retained_offer(&mut self, user: &UserId, now: Instant) -> Option<Sdp> {
+        self.retained_offers.take(user, now)
+    }
+
+    /// Moves the given session, which must already be in a room, to another room as the same user, with the given
+    /// moderator status. Since media never crosses rooms, any media subscriptions to or from the session are dropped;
+    /// returns the sessions which were receiving its media.
+    pub fn change_room(&mut self, session: &Arc<Session>, room: RoomId, is_moderator: bool) -> Vec<Arc<Session>> {
+        let joined = match session.join_state.get() {
+            Some(joined) => joined,
+            None => return Vec::new(),
+        };
+        let orphans = match joined.kind {
+            JoinKind::Publisher => self.leave_publisher(session).0,
+            JoinKind::Subscriber => Vec::new(),
+        };
+        self.leave_subscriber(session);
+        session.join_state.take();
+        let state = JoinState::new(joined.kind, room.clone(), joined.user_id.clone(), is_moderator).with_declared_kind(joined.declared_kind);
+        session.join_state.set(state).expect("Join state was set while changing rooms.");
+        match joined.kind {
+            JoinKind::Publisher => {
+                self.join_publisher(Arc::clone(session), joined.user_id.clone(), room);
+            }
+            JoinKind::Subscriber => self.join_subscriber(Arc::clone(session), joined.user_id.clone(), room),
         }
+        orphans
     }
 
     pub fn subscribe_to_user(&mut self, subscriber: Arc<Session>, publisher: Arc<Session>) {
-        self.publisher_to_subscribers.associate(publisher, subscriber);
+        self.publisher_to_subscribers.associate(Arc::clone(&publisher), subscriber);
+        self.refresh_routes_for(iter::once(&publisher));
+    }
+
+    /// Remembers that the given subscriber wants media from the given publisher, which hasn't negotiated any yet, so
+    /// that it can be subscribed once the publisher does.
+    pub fn add_pending_subscriber(&mut self, subscriber: Arc<Session>, publisher: Arc<Session>) {
+        if !self.pending_subscribers.get_values(&publisher).contains(&subscriber) {
+            self.pending_subscribers.associate(publisher, subscriber);
+        }
+    }
+
+    /// Subscribes everyone who was waiting for the given publisher's media to it, returning them.
+    pub fn admit_pending_subscribers(&mut self, publisher: &Arc<Session>) -> Vec<Arc<Session>> {
+        let subscribers = self.pending_subscribers.get_values(publisher).to_vec();
+        self.pending_subscribers.remove_key(publisher);
+        for subscriber in &subscribers {
+            self.publisher_to_subscribers.associate(Arc::clone(publisher), Arc::clone(subscriber));
+        }
+        if !subscribers.is_empty() {
+            self.refresh_routes_for(iter::once(publisher));
+        }
+        subscribers
+    }
+
+    /// Replaces whatever media the given subscriber is receiving with media from the given publisher, all at once, so
+    /// that there's no moment where it's receiving neither or both.
+    pub fn switch_publisher(&mut self, subscriber: Arc<Session>, publisher: Arc<Session>) {
+        if !matches!(self.publishers_to(&subscriber), [current] if current == &publisher) {
+            let mut affected = self.publishers_to(&subscriber).to_vec();
+            affected.push(Arc::clone(&publisher));
+            self.publisher_to_subscribers.remove_value(subscriber.as_ref());
+            self.publisher_to_subscribers.associate(publisher, subscriber);
+            self.refresh_routes_for(&affected);
+        }
+    }
+
+    /// Stops each of the given users' subscribers from receiving media from the other, e.g. because one blocked the
+    /// other, returning each subscriber which had been receiving it along with the publisher it came from.
+    pub fn drop_mutual_subscriptions(&mut self, a: &UserId, b: &UserId) -> Vec<(Arc<Session>, Arc<Session>)> {
+        let mut dropped = Vec::new();
+        let mut affected = Vec::new();
+        for &(subscriber_user, publisher_user) in &[(a, b), (b, a)] {
+            let publisher = match self.get_publisher(publisher_user) {
+                Some(publisher) => Arc::clone(publisher),
+                None => continue,
+            };
+            let belongs_to_subscriber = |s: &&Arc<Session>| matches!(s.join_state.get(), Some(joined) if &joined.user_id == subscriber_user);
+            let subscribers = self
+                .subscribers_to(&publisher)
+                .iter()
+                .filter(belongs_to_subscriber)
+                .cloned()
+                .collect::<Vec<_>>();
+            let pending = self
+                .pending_subscribers
+                .get_values(&publisher)
+                .iter()
+                .filter(belongs_to_subscriber)
+                .cloned()
+                .collect::<Vec<_>>();
+            for subscriber in pending {
+                self.pending_subscribers.disassociate(publisher.as_ref(), subscriber.as_ref());
+            }
+            for subscriber in subscribers {
+                self.publisher_to_subscribers.disassociate(publisher.as_ref(), subscriber.as_ref());
+                dropped.push((subscriber, Arc::clone(&publisher)));
+            }
+            affected.push(publisher);
+        }
+        self.refresh_routes_for(&affected);
+        dropped
     }
 
     pub fn subscribers_to(&self, publisher: &Session) -> &[Arc<Session>] {
@@ -201,15 +593,50 @@ impl Switchboard {
         self.publishers_by_room.get(room).map(Vec::as_slice).unwrap_or(&[])
     }
 
-    pub fn media_recipients_for(&self, sender: &Session) -> impl Iterator<Item = &Arc<Session>> {
-        let (forward_blocks, reverse_blocks) = match sender.join_state.get() {
-            None => (&[] as &[_], &[] as &[_]),
+    pub fn subscribers_occupying(&self, room: &RoomId) -> &[Arc<Session>] {
+        self.subscribers_by_room.get(room).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Returns whether the given user is already in the given room with a different kind of connection than the given
+    /// one, e.g. whether a subscriber joining as them would share a user ID with their publisher.
+    pub fn has_conflicting_join(&self, room: &RoomId, user: &UserId, kind: JoinKind) -> bool {
+        self.get_user_sessions_in(room, user)
+            .any(|s| matches!(s.join_state.get(), Some(joined) if joined.kind != kind))
+    }
+
+    /// Returns every publisher and subscriber joined to the given room.
+    pub fn occupants_of<'s>(&'s self, room: &RoomId) -> impl Iterator<Item = &'s Arc<Session>> {
+        self.publishers_occupying(room).iter().chain(self.subscribers_occupying(room))
+    }
+
+    /// Gives or takes away the given session's moderator permissions for its room.
+    pub fn set_moderator(&mut self, session: &Session, is_moderator: bool) {
+        if let Some(joined) = session.join_state.get() {
+            joined.set_moderator(is_moderator);
+            // moderators are heard even when only presenters are, so their audio may need rerouting
+            self.refresh_routes(&joined.room_id);
+        }
+    }
+
+    /// Returns the publishers in the given room who have moderator permissions.
+    pub fn moderators_occupying<'s>(&'s self, room: &RoomId) -> impl Iterator<Item = &'s Arc<Session>> {
+        self.publishers_occupying(room)
+            .iter()
+            .filter(|s| matches!(s.join_state.get(), Some(joined) if joined.is_moderator()))
+    }
+
+    /// Returns the sessions which should receive the given sender's audio or video, depending on `video`.
+    pub fn media_recipients_for(&self, sender: &Session, video: bool) -> impl Iterator<Item = &Arc<Session>> {
+        let (forward_blocks, reverse_blocks, silenced) = match sender.join_state.get() {
+            None => (&[] as &[_], &[] as &[_], false),
             Some(joined) => (
                 self.blockers_to_miscreants.get_keys(&joined.user_id),
                 self.blockers_to_miscreants.get_values(&joined.user_id),
+                !video && !joined.is_moderator() && self.is_presenter_only_audio(&joined.room_id),
             ),
         };
         self.subscribers_to(sender).iter().filter(move |subscriber| match subscriber.join_state.get() {
+            _ if silenced => false,
             None => true,
             Some(other) => {
                 let blocks = forward_blocks.contains(&other.user_id);
@@ -237,16 +664,25 @@ impl Switchboard {
         })
     }
 
+    /// Returns the sessions which should receive the given sender's data: the other publishers in its room, and any
+    /// subscribers there belonging to other users who asked for data.
     pub fn data_recipients_for<'s>(&'s self, session: &'s Session) -> impl Iterator<Item = &'s Arc<Session>> {
-        let (forward_blocks, reverse_blocks, cohabitators) = match session.join_state.get() {
-            None => (&[] as &[_], &[] as &[_], &[] as &[_]),
+        let (sender, forward_blocks, reverse_blocks, publishers, subscribers) = match session.join_state.get() {
+            None => (None, &[] as &[_], &[] as &[_], &[] as &[_], &[] as &[_]),
+            Some(_) if self.is_waiting(session) => (None, &[] as &[_], &[] as &[_], &[] as &[_], &[] as &[_]),
             Some(joined) => (
+                Some(joined.user_id.clone()),
                 self.blockers_to_miscreants.get_keys(&joined.user_id),
                 self.blockers_to_miscreants.get_values(&joined.user_id),
                 self.publishers_occupying(&joined.room_id),
+                self.subscribers_occupying(&joined.room_id),
             ),
         };
-        cohabitators.iter().filter(move |cohabitator| {
+        let listeners = subscribers.iter().filter(move |subscriber| {
+            let wants_data = subscriber.subscription.lock().unwrap().as_ref().map_or(false, |s| s.data);
+            wants_data && subscriber.join_state.get().map_or(true, |joined| Some(&joined.user_id) != sender.as_ref())
+        });
+        publishers.iter().chain(listeners).filter(move |cohabitator| {
             cohabitator.handle != session.handle
                 && match cohabitator.join_state.get() {
                     None => true,
@@ -259,8 +695,35 @@ impl Switchboard {
         })
     }
 
-    pub fn get_room_users(&self, room: &RoomId) -> impl Iterator<Item = &UserId> {
-        self.publishers_occupying(room).iter().filter_map(|s| s.join_state.get().map(|j| &j.user_id))
+    /// Returns every room which anyone is publishing in, along with how many publishers it has.
+    pub fn rooms(&self) -> impl Iterator<Item = (&RoomId, usize)> {
+        self.publishers_by_room.iter().map(|(room, publishers)| (room, publishers.len()))
+    }
+
+    /// Returns how many rooms anyone is publishing in.
+    pub fn room_count(&self) -> usize {
+        self.publishers_by_room.len()
+    }
+
+    pub fn get_room_users(&self, room: &RoomId) -> impl Iterator<Item = UserId> + '_ {
+        self.publishers_occupying(room)
+            .iter()
+            .filter_map(|s| s.join_state.get().map(|j| j.user_id.clone()))
+    }
+
+    /// Returns how many subscriber connections are joined to the given room.
+    pub fn count_subscribers_in(&self, room: &RoomId) -> usize {
+        self.subscribers_occupying(room).len()
+    }
+
+    /// Returns how many publishers and subscribers are joined to the given room.
+    pub fn room_counts(&self, room: &RoomId) -> (usize, usize) {
+        (self.publishers_occupying(room).len(), self.subscribers_occupying(room).len())
+    }
+
+    /// Returns how many publisher and subscriber connections are joined to any room.
+    pub fn total_connections(&self) -> usize {
+        self.publishers_by_user.len() + self.subscribers_by_user.values().map(Vec::len).sum::<usize>()
     }
 
     pub fn get_all_users(&self) -> impl Iterator<Item = &UserId> {
@@ -274,4 +737,679 @@ impl Switchboard {
     pub fn get_subscribers(&self, user: &UserId) -> Option<&Vec<Arc<Session>>> {
         self.subscribers_by_user.get(user)
     }
+
+    /// Returns all of the given user's sessions, publisher or subscriber, which are joined to the given room.
+    pub fn get_user_sessions_in<'s>(&'s self, room: &'s RoomId, user: &UserId) -> impl Iterator<Item = &'s Arc<Session>> {
+        let publisher = self.get_publisher(user).into_iter();
+        let subscribers = self.get_subscribers(user).into_iter().flatten();
+        publisher
+            .chain(subscribers)
+            .filter(move |s| matches!(s.join_state.get(), Some(joined) if &joined.room_id == room))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::messages::Subscription;
+    use crate::sessions::tests::{make_moderator_session, make_session};
+    use std::sync::{mpsc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    fn join_publisher(switchboard: &mut Switchboard, room: &str, user: &str) -> Arc<Session> {
+        let session = make_session(JoinKind::Publisher, room, user);
+        switchboard.join_publisher(Arc::clone(&session), user.into(), room.into());
+        session
+    }
+
+    fn join_subscriber(switchboard: &mut Switchboard, room: &str, user: &str) -> Arc<Session> {
+        let session = make_session(JoinKind::Subscriber, room, user);
+        switchboard.join_subscriber(Arc::clone(&session), user.into(), room.into());
+        session
+    }
+
+    mod multimap {
+
+        use super::*;
+        use std::sync::atomic::Ordering;
+
+        fn inconsistencies() -> u64 {
+            COUNTERS.map_inconsistencies.load(Ordering::Relaxed)
+        }
+
+        #[test]
+        fn removing_keys_heals_a_desynced_map() {
+            let mut map = BidirectionalMultimap::new();
+            map.associate(1, "a");
+            map.associate(1, "b");
+            map.associate(2, "b");
+            // the inverse side loses one of the key's associations
+            map.inverse_mapping.remove("a");
+            let before = inconsistencies();
+            map.remove_key(&1);
+            assert!(inconsistencies() > before);
+            assert_eq!(map.get_values(&1), &[] as &[&str]);
+            assert_eq!(map.get_keys(&"b"), &[2]);
+        }
+
+        #[test]
+        fn removing_values_heals_a_desynced_map() {
+            let mut map = BidirectionalMultimap::new();
+            map.associate(1, "a");
+            map.associate(2, "a");
+            map.associate(2, "b");
+            map.forward_mapping.remove(&1);
+            let before = inconsistencies();
+            map.remove_value(&"a");
+            assert!(inconsistencies() > before);
+            assert_eq!(map.get_keys(&"a"), &[] as &[i32]);
+            assert_eq!(map.get_values(&2), &["b"]);
+        }
+
+        #[test]
+        fn consistent_removals_empty_the_map() {
+            let mut map = BidirectionalMultimap::new();
+            map.associate(1, "a");
+            map.associate(2, "a");
+            map.remove_key(&1);
+            map.remove_value(&"a");
+            assert_eq!(map.get_values(&2), &[] as &[&str]);
+            assert!(map.forward_mapping.is_empty() && map.inverse_mapping.is_empty());
+        }
+    }
+
+    #[test]
+    fn user_sessions_include_publishers_and_subscribers() {
+        let mut switchboard = Switchboard::new();
+        let publisher = join_publisher(&mut switchboard, "alpha", "10");
+        let subscriber = join_subscriber(&mut switchboard, "alpha", "10");
+        join_subscriber(&mut switchboard, "beta", "10");
+        join_publisher(&mut switchboard, "alpha", "11");
+        let room = "alpha".into();
+        let sessions = switchboard.get_user_sessions_in(&room, &"10".into()).collect::<Vec<_>>();
+        assert_eq!(sessions, vec![&publisher, &subscriber]);
+        assert_eq!(switchboard.get_user_sessions_in(&"alpha".into(), &"12".into()).count(), 0);
+    }
+
+    #[test]
+    fn rooms_are_listed_with_publisher_counts() {
+        let mut switchboard = Switchboard::new();
+        join_publisher(&mut switchboard, "alpha", "10");
+        join_publisher(&mut switchboard, "alpha", "11");
+        let carol = join_publisher(&mut switchboard, "beta", "12");
+        join_subscriber(&mut switchboard, "gamma", "13");
+        let mut rooms = switchboard.rooms().collect::<Vec<_>>();
+        rooms.sort();
+        assert_eq!(rooms, vec![(&"alpha".into(), 2), (&"beta".into(), 1)]);
+        switchboard.leave_publisher(&carol);
+        assert_eq!(switchboard.rooms().collect::<Vec<_>>(), vec![(&"alpha".into(), 2)]);
+    }
+
+    #[test]
+    fn subscribers_are_tracked_by_room() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_subscriber(&mut switchboard, "alpha", "10");
+        let bob = join_subscriber(&mut switchboard, "alpha", "11");
+        assert_eq!(switchboard.subscribers_occupying(&"alpha".into()), &[Arc::clone(&alice), Arc::clone(&bob)]);
+        switchboard.change_room(&alice, "beta".into(), false);
+        assert_eq!(switchboard.subscribers_occupying(&"alpha".into()), &[Arc::clone(&bob)]);
+        assert_eq!(switchboard.subscribers_occupying(&"beta".into()), &[alice]);
+        switchboard.leave_subscriber(&bob);
+        assert!(switchboard.subscribers_occupying(&"alpha".into()).is_empty());
+    }
+
+    #[test]
+    fn data_reaches_subscribers_who_asked_for_it() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_publisher(&mut switchboard, "alpha", "11");
+        let listener = join_subscriber(&mut switchboard, "alpha", "12");
+        join_subscriber(&mut switchboard, "alpha", "13");
+        let alice_subscriber = join_subscriber(&mut switchboard, "alpha", "10");
+        let elsewhere = join_subscriber(&mut switchboard, "beta", "14");
+        for subscriber in &[&listener, &alice_subscriber, &elsewhere] {
+            *subscriber.subscription.lock().unwrap() = Some(Subscription {
+                notifications: false,
+                data: true,
+                media: None,
+            });
+        }
+        let recipients = switchboard.data_recipients_for(&alice).collect::<Vec<_>>();
+        assert_eq!(recipients, vec![&bob, &listener]);
+    }
+
+    #[test]
+    fn joins_conflict_with_other_kinds_of_connection_for_the_same_user() {
+        let mut switchboard = Switchboard::new();
+        let room = "alpha".into();
+        join_publisher(&mut switchboard, "alpha", "10");
+        join_subscriber(&mut switchboard, "alpha", "11");
+        join_subscriber(&mut switchboard, "beta", "12");
+        assert!(switchboard.has_conflicting_join(&room, &"10".into(), JoinKind::Subscriber));
+        assert!(!switchboard.has_conflicting_join(&room, &"10".into(), JoinKind::Publisher));
+        assert!(switchboard.has_conflicting_join(&room, &"11".into(), JoinKind::Publisher));
+        assert!(!switchboard.has_conflicting_join(&room, &"11".into(), JoinKind::Subscriber));
+        // other rooms and other users don't matter
+        assert!(!switchboard.has_conflicting_join(&room, &"12".into(), JoinKind::Publisher));
+        assert!(!switchboard.has_conflicting_join(&room, &"13".into(), JoinKind::Publisher));
+        assert!(!switchboard.has_conflicting_join(&room, &"13".into(), JoinKind::Subscriber));
+    }
+
+    #[test]
+    fn subscribers_are_counted_per_room() {
+        let mut switchboard = Switchboard::new();
+        join_publisher(&mut switchboard, "alpha", "10");
+        join_subscriber(&mut switchboard, "alpha", "10");
+        join_subscriber(&mut switchboard, "alpha", "11");
+        join_subscriber(&mut switchboard, "alpha", "11");
+        join_subscriber(&mut switchboard, "beta", "12");
+        assert_eq!(switchboard.count_subscribers_in(&"alpha".into()), 3);
+        assert_eq!(switchboard.count_subscribers_in(&"beta".into()), 1);
+        assert_eq!(switchboard.count_subscribers_in(&"gamma".into()), 0);
+    }
+
+    #[test]
+    fn connections_are_counted() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        join_subscriber(&mut switchboard, "alpha", "10");
+        join_subscriber(&mut switchboard, "alpha", "11");
+        join_subscriber(&mut switchboard, "beta", "11");
+        assert_eq!(switchboard.get_all_users().count(), 1);
+        assert_eq!(switchboard.total_connections(), 4);
+        switchboard.leave_publisher(&alice);
+        assert_eq!(switchboard.total_connections(), 3);
+    }
+
+    #[test]
+    fn rooms_are_counted() {
+        let mut switchboard = Switchboard::new();
+        join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_publisher(&mut switchboard, "alpha", "11");
+        join_subscriber(&mut switchboard, "alpha", "10");
+        join_subscriber(&mut switchboard, "alpha", "11");
+        join_subscriber(&mut switchboard, "alpha", "11");
+        join_publisher(&mut switchboard, "beta", "12");
+        join_subscriber(&mut switchboard, "beta", "12");
+        assert_eq!(switchboard.room_counts(&"alpha".into()), (2, 3));
+        assert_eq!(switchboard.room_counts(&"beta".into()), (1, 1));
+        assert_eq!(switchboard.room_counts(&"gamma".into()), (0, 0));
+        assert_eq!(switchboard.room_count(), 2);
+        // bob's subscribers are still in the room even once he stops publishing
+        switchboard.leave_publisher(&bob);
+        assert_eq!(switchboard.room_counts(&"alpha".into()), (1, 3));
+        assert_eq!(switchboard.room_count(), 2);
+    }
+
+    #[test]
+    fn changing_rooms_moves_publishers() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        join_publisher(&mut switchboard, "alpha", "11");
+        let orphans = switchboard.change_room(&alice, "beta".into(), false);
+        assert!(orphans.is_empty());
+        assert_eq!(alice.join_state.get().unwrap().room_id, "beta");
+        assert_eq!(switchboard.get_room_users(&"alpha".into()).collect::<Vec<_>>(), vec!["11"]);
+        assert_eq!(switchboard.get_room_users(&"beta".into()).collect::<Vec<_>>(), vec!["10"]);
+        assert_eq!(switchboard.get_publisher(&"10".into()), Some(&alice));
+    }
+
+    #[test]
+    fn changing_rooms_drops_media_subscriptions() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_publisher(&mut switchboard, "alpha", "11");
+        let bob_subscriber = join_subscriber(&mut switchboard, "alpha", "11");
+        let carol_subscriber = join_subscriber(&mut switchboard, "alpha", "12");
+        switchboard.subscribe_to_user(Arc::clone(&bob_subscriber), Arc::clone(&alice));
+        switchboard.subscribe_to_user(Arc::clone(&carol_subscriber), Arc::clone(&alice));
+        switchboard.subscribe_to_user(Arc::clone(&carol_subscriber), Arc::clone(&bob));
+
+        let orphans = switchboard.change_room(&alice, "beta".into(), false);
+        assert_eq!(orphans, vec![Arc::clone(&bob_subscriber), Arc::clone(&carol_subscriber)]);
+        assert!(switchboard.subscribers_to(&alice).is_empty());
+        assert!(switchboard.publishers_to(&bob_subscriber).is_empty());
+        assert_eq!(switchboard.publishers_to(&carol_subscriber), &[Arc::clone(&bob)]);
+
+        switchboard.change_room(&carol_subscriber, "beta".into(), false);
+        assert!(switchboard.subscribers_to(&bob).is_empty());
+        assert!(switchboard.publishers_to(&carol_subscriber).is_empty());
+        assert_eq!(switchboard.room_counts(&"alpha".into()), (1, 1));
+        assert_eq!(switchboard.room_counts(&"beta".into()), (1, 1));
+    }
+
+    #[test]
+    fn subscribers_to_publisher_are_tracked() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_subscriber(&mut switchboard, "alpha", "11");
+        let carol = join_subscriber(&mut switchboard, "alpha", "12");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice));
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&alice));
+        assert_eq!(switchboard.subscribers_to(&alice).len(), 2);
+        switchboard.leave_subscriber(&bob);
+        assert_eq!(switchboard.subscribers_to(&alice), &[Arc::clone(&carol)]);
+        switchboard.leave_subscriber(&carol);
+        assert!(switchboard.subscribers_to(&alice).is_empty());
+    }
+
+    #[test]
+    fn first_and_last_publishers_create_and_destroy_rooms() {
+        let mut switchboard = Switchboard::new();
+        let alice = make_session(JoinKind::Publisher, "alpha", "10");
+        let bob = make_session(JoinKind::Publisher, "alpha", "11");
+        assert!(switchboard.join_publisher(Arc::clone(&alice), "10".into(), "alpha".into()));
+        assert!(!switchboard.join_publisher(Arc::clone(&bob), "11".into(), "alpha".into()));
+        assert!(!switchboard.leave_publisher(&alice).1);
+        assert!(switchboard.leave_publisher(&bob).1);
+        assert!(switchboard.join_publisher(alice, "10".into(), "alpha".into()));
+    }
+
+    #[test]
+    fn leaving_publisher_returns_subscribers() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_publisher(&mut switchboard, "alpha", "11");
+        let subscriber = join_subscriber(&mut switchboard, "alpha", "12");
+        switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&alice));
+        assert!(switchboard.leave_publisher(&bob).0.is_empty());
+        assert_eq!(switchboard.leave_publisher(&alice).0, vec![Arc::clone(&subscriber)]);
+        assert!(switchboard.publishers_to(&subscriber).is_empty());
+    }
+
+    #[test]
+    fn pending_subscribers_are_subscribed_once_publishers_negotiate() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_subscriber(&mut switchboard, "alpha", "11");
+        let carol = join_subscriber(&mut switchboard, "alpha", "12");
+        switchboard.add_pending_subscriber(Arc::clone(&bob), Arc::clone(&alice));
+        switchboard.add_pending_subscriber(Arc::clone(&bob), Arc::clone(&alice));
+        switchboard.add_pending_subscriber(Arc::clone(&carol), Arc::clone(&alice));
+        assert!(switchboard.subscribers_to(&alice).is_empty());
+        assert_eq!(switchboard.admit_pending_subscribers(&alice), vec![Arc::clone(&bob), Arc::clone(&carol)]);
+        assert_eq!(switchboard.subscribers_to(&alice), &[Arc::clone(&bob), Arc::clone(&carol)]);
+        assert!(switchboard.admit_pending_subscribers(&alice).is_empty());
+    }
+
+    #[test]
+    fn pending_subscribers_are_forgotten_when_either_side_leaves() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_subscriber(&mut switchboard, "alpha", "11");
+        let carol = join_subscriber(&mut switchboard, "alpha", "12");
+        switchboard.add_pending_subscriber(Arc::clone(&bob), Arc::clone(&alice));
+        switchboard.add_pending_subscriber(Arc::clone(&carol), Arc::clone(&alice));
+        switchboard.leave_subscriber(&bob);
+        assert_eq!(switchboard.admit_pending_subscribers(&alice), vec![Arc::clone(&carol)]);
+
+        let dave = join_publisher(&mut switchboard, "alpha", "13");
+        switchboard.add_pending_subscriber(Arc::clone(&carol), Arc::clone(&dave));
+        switchboard.leave_publisher(&dave);
+        assert!(switchboard.admit_pending_subscribers(&dave).is_empty());
+        assert!(switchboard.subscribers_to(&dave).is_empty());
+    }
+
+    #[test]
+    fn switching_publishers_replaces_subscription() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_publisher(&mut switchboard, "alpha", "11");
+        let subscriber = join_subscriber(&mut switchboard, "alpha", "12");
+        switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&alice));
+        switchboard.switch_publisher(Arc::clone(&subscriber), Arc::clone(&bob));
+        assert_eq!(switchboard.publishers_to(&subscriber), &[Arc::clone(&bob)]);
+        assert!(switchboard.subscribers_to(&alice).is_empty());
+        switchboard.switch_publisher(Arc::clone(&subscriber), Arc::clone(&bob));
+        assert_eq!(switchboard.publishers_to(&subscriber), &[Arc::clone(&bob)]);
+        assert_eq!(switchboard.subscribers_to(&bob), &[Arc::clone(&subscriber)]);
+    }
+
+    #[test]
+    fn switching_publishers_never_double_delivers() {
+        let switchboard = Arc::new(RwLock::new(Switchboard::new()));
+        let (alice, bob, subscriber) = {
+            let mut switchboard = switchboard.write().unwrap();
+            let alice = join_publisher(&mut switchboard, "alpha", "10");
+            let bob = join_publisher(&mut switchboard, "alpha", "11");
+            let subscriber = join_subscriber(&mut switchboard, "alpha", "12");
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&alice));
+            (alice, bob, subscriber)
+        };
+        let switcher = {
+            let switchboard = Arc::clone(&switchboard);
+            let (alice, bob, subscriber) = (Arc::clone(&alice), Arc::clone(&bob), Arc::clone(&subscriber));
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    let publisher = if i % 2 == 0 { &bob } else { &alice };
+                    switchboard.write().unwrap().switch_publisher(Arc::clone(&subscriber), Arc::clone(publisher));
+                }
+            })
+        };
+        for _ in 0..1000 {
+            let switchboard = switchboard.read().unwrap();
+            let from_alice = switchboard.media_recipients_for(&alice, true).filter(|s| **s == subscriber).count();
+            let from_bob = switchboard.media_recipients_for(&bob, true).filter(|s| **s == subscriber).count();
+            assert_eq!(from_alice + from_bob, 1);
+        }
+        switcher.join().unwrap();
+    }
+
+    mod rosters {
+
+        use super::*;
+
+        #[test]
+        fn unrostered_rooms_admit_anyone() {
+            let switchboard = Switchboard::new();
+            assert!(switchboard.is_expected(&"alpha".into(), &"10".into()));
+            assert_eq!(switchboard.get_absent_users(&"alpha".into()).count(), 0);
+        }
+
+        #[test]
+        fn rostered_rooms_admit_expected_users() {
+            let mut switchboard = Switchboard::new();
+            let roster = vec!["10".to_owned(), "11".to_owned()].into_iter().collect();
+            switchboard.set_roster("alpha".into(), Some(roster));
+            assert!(switchboard.is_expected(&"alpha".into(), &"10".into()));
+            assert!(!switchboard.is_expected(&"alpha".into(), &"12".into()));
+            assert!(switchboard.is_expected(&"beta".into(), &"12".into()));
+        }
+
+        #[test]
+        fn absent_users_exclude_joined_users() {
+            let mut switchboard = Switchboard::new();
+            let roster = vec!["10".to_owned(), "11".to_owned()].into_iter().collect();
+            switchboard.set_roster("alpha".into(), Some(roster));
+            join_publisher(&mut switchboard, "alpha", "10");
+            let absent = switchboard.get_absent_users(&"alpha".into()).collect::<Vec<_>>();
+            assert_eq!(absent, vec!["11"]);
+        }
+
+        #[test]
+        fn clearing_roster_forgets_room() {
+            let mut switchboard = Switchboard::new();
+            let roster = vec!["10".to_owned()].into_iter().collect();
+            switchboard.set_roster("alpha".into(), Some(roster));
+            switchboard.set_roster("alpha".into(), None);
+            assert!(switchboard.get_room_state(&"alpha".into()).is_none());
+            assert!(switchboard.is_expected(&"alpha".into(), &"12".into()));
+        }
+    }
+
+    mod locks {
+
+        use super::*;
+
+        #[test]
+        fn rooms_are_unlocked_by_default() {
+            let switchboard = Switchboard::new();
+            assert!(!switchboard.is_locked(&"alpha".into()));
+        }
+
+        #[test]
+        fn unlocking_forgets_room() {
+            let mut switchboard = Switchboard::new();
+            switchboard.set_locked("alpha".into(), true);
+            assert!(switchboard.is_locked(&"alpha".into()));
+            assert!(!switchboard.is_locked(&"beta".into()));
+            switchboard.set_locked("alpha".into(), false);
+            assert!(!switchboard.is_locked(&"alpha".into()));
+            assert!(switchboard.get_room_state(&"alpha".into()).is_none());
+        }
+    }
+
+    mod waiting_rooms {
+
+        use super::*;
+
+        fn park(switchboard: &mut Switchboard, kind: JoinKind, room: &str, user: &str) -> Arc<Session> {
+            let session = make_session(kind, room, user);
+            switchboard.join_waiting_room(Arc::clone(&session), room.into());
+            session
+        }
+
+        #[test]
+        fn waiting_sessions_are_not_occupants() {
+            let mut switchboard = Switchboard::new();
+            switchboard.set_waiting_room("alpha".into(), true);
+            join_publisher(&mut switchboard, "alpha", "10");
+            let waiting = park(&mut switchboard, JoinKind::Publisher, "alpha", "11");
+            assert!(switchboard.is_waiting(&waiting));
+            assert_eq!(switchboard.get_room_users(&"alpha".into()).collect::<Vec<_>>(), vec!["10"]);
+            assert_eq!(switchboard.data_recipients_for(&waiting).count(), 0);
+        }
+
+        #[test]
+        fn admitting_takes_all_user_sessions() {
+            let mut switchboard = Switchboard::new();
+            switchboard.set_waiting_room("alpha".into(), true);
+            let publisher = park(&mut switchboard, JoinKind::Publisher, "alpha", "11");
+            let subscriber = park(&mut switchboard, JoinKind::Subscriber, "alpha", "11");
+            let other = park(&mut switchboard, JoinKind::Publisher, "alpha", "12");
+            let admitted = switchboard.take_waiting_sessions(&"alpha".into(), &"11".into());
+            assert_eq!(admitted, vec![publisher, subscriber]);
+            assert!(switchboard.is_waiting(&other));
+            assert!(switchboard.take_waiting_sessions(&"alpha".into(), &"11".into()).is_empty());
+        }
+
+        #[test]
+        fn leaving_waiting_room_forgets_session() {
+            let mut switchboard = Switchboard::new();
+            let waiting = park(&mut switchboard, JoinKind::Publisher, "alpha", "11");
+            assert!(switchboard.leave_waiting_room(&waiting));
+            assert!(!switchboard.leave_waiting_room(&waiting));
+            assert!(!switchboard.is_waiting(&waiting));
+            assert!(switchboard.get_room_state(&"alpha".into()).is_none());
+        }
+
+        #[test]
+        fn disabling_waiting_room_releases_everyone() {
+            let mut switchboard = Switchboard::new();
+            switchboard.set_waiting_room("alpha".into(), true);
+            let waiting = park(&mut switchboard, JoinKind::Publisher, "alpha", "11");
+            assert_eq!(switchboard.set_waiting_room("alpha".into(), false), vec![waiting]);
+            assert!(!switchboard.is_waiting_room(&"alpha".into()));
+            assert!(switchboard.get_room_state(&"alpha".into()).is_none());
+        }
+    }
+
+    mod presenter_only_audio {
+
+        use super::*;
+
+        #[test]
+        fn only_moderator_audio_is_relayed() {
+            let mut switchboard = Switchboard::new();
+            let presenter = make_moderator_session(JoinKind::Publisher, "alpha", "10");
+            switchboard.join_publisher(Arc::clone(&presenter), "10".into(), "alpha".into());
+            let listener = join_publisher(&mut switchboard, "alpha", "11");
+            let subscriber = join_subscriber(&mut switchboard, "alpha", "12");
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&presenter));
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&listener));
+            switchboard.set_presenter_only_audio("alpha".into(), true);
+            assert_eq!(switchboard.media_recipients_for(&presenter, false).count(), 1);
+            assert_eq!(switchboard.media_recipients_for(&listener, false).count(), 0);
+            assert_eq!(switchboard.media_recipients_for(&listener, true).count(), 1);
+        }
+
+        #[test]
+        fn disabling_restores_audio() {
+            let mut switchboard = Switchboard::new();
+            let listener = join_publisher(&mut switchboard, "alpha", "11");
+            let subscriber = join_subscriber(&mut switchboard, "alpha", "12");
+            switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&listener));
+            switchboard.set_presenter_only_audio("alpha".into(), true);
+            switchboard.set_presenter_only_audio("alpha".into(), false);
+            assert_eq!(switchboard.media_recipients_for(&listener, false).count(), 1);
+            assert!(switchboard.get_room_state(&"alpha".into()).is_none());
+        }
+    }
+
+    fn routed(switchboard: &Switchboard, sender: &Arc<Session>, video: bool) -> Vec<Arc<Session>> {
+        let room = sender.join_state.get().unwrap().room_id.clone();
+        let mut recipients = Vec::new();
+        switchboard
+            .routes()
+            .for_each_recipient(&room, sender, video, |s| recipients.push(Arc::clone(s)));
+        recipients
+    }
+
+    #[test]
+    fn routes_follow_subscriptions() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_subscriber(&mut switchboard, "alpha", "11");
+        let dave = join_publisher(&mut switchboard, "beta", "13");
+        let erin = join_subscriber(&mut switchboard, "beta", "14");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice));
+        switchboard.subscribe_to_user(Arc::clone(&erin), Arc::clone(&dave));
+        assert_eq!(routed(&switchboard, &alice, true), vec![Arc::clone(&bob)]);
+        assert_eq!(routed(&switchboard, &alice, false), vec![Arc::clone(&bob)]);
+
+        switchboard.leave_subscriber(&bob);
+        assert!(routed(&switchboard, &alice, true).is_empty());
+        assert_eq!(routed(&switchboard, &dave, true), vec![Arc::clone(&erin)]);
+
+        switchboard.leave_publisher(&alice);
+        assert!(routed(&switchboard, &alice, true).is_empty());
+        assert_eq!(routed(&switchboard, &dave, true), vec![erin]);
+    }
+
+    #[test]
+    fn routes_follow_blocks_and_presenter_only_audio() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        join_publisher(&mut switchboard, "alpha", "11");
+        let subscriber = join_subscriber(&mut switchboard, "alpha", "11");
+        switchboard.subscribe_to_user(Arc::clone(&subscriber), Arc::clone(&alice));
+
+        switchboard.establish_block("11".into(), "10".into());
+        assert!(routed(&switchboard, &alice, true).is_empty());
+        switchboard.lift_block(&"11".into(), &"10".into());
+        assert_eq!(routed(&switchboard, &alice, true), vec![Arc::clone(&subscriber)]);
+
+        switchboard.set_presenter_only_audio("alpha".into(), true);
+        assert!(routed(&switchboard, &alice, false).is_empty());
+        assert_eq!(routed(&switchboard, &alice, true), vec![subscriber]);
+    }
+
+    #[test]
+    fn blocks_are_listed_in_both_directions() {
+        let mut switchboard = Switchboard::new();
+        switchboard.establish_block("10".into(), "11".into());
+        switchboard.establish_block("10".into(), "12".into());
+        switchboard.establish_block("12".into(), "10".into());
+        assert_eq!(switchboard.blocked_by(&"10".into()), &["11".to_string(), "12".to_string()]);
+        assert_eq!(switchboard.blockers_of(&"10".into()), &["12".to_string()]);
+        assert_eq!(switchboard.blockers_of(&"11".into()), &["10".to_string()]);
+        assert!(switchboard.blocked_by(&"11".into()).is_empty());
+
+        switchboard.lift_block(&"10".into(), &"12".into());
+        assert_eq!(switchboard.blocked_by(&"10".into()), &["11".to_string()]);
+        assert!(switchboard.blockers_of(&"12".into()).is_empty());
+        assert_eq!(switchboard.blocked_by(&"12".into()), &["10".to_string()]);
+    }
+
+    #[test]
+    fn blocks_are_forgotten_when_their_blocker_leaves() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_publisher(&mut switchboard, "alpha", "11");
+        let bob_subscriber = join_subscriber(&mut switchboard, "alpha", "11");
+        switchboard.subscribe_to_user(Arc::clone(&bob_subscriber), Arc::clone(&alice));
+        switchboard.establish_block("10".into(), "11".into());
+        switchboard.establish_block("11".into(), "12".into());
+        assert!(routed(&switchboard, &alice, true).is_empty());
+
+        let now = Instant::now();
+        let expires_at = now + Duration::from_secs(30);
+        switchboard.leave_publisher(&alice);
+        assert!(!switchboard.is_user_present(&"10".into()));
+        switchboard.remove_user_blocks(&"10".into(), expires_at, now);
+        assert!(switchboard.blockers_to_miscreants.get_values(&UserId::from("10")).is_empty());
+        assert!(switchboard.blockers_to_miscreants.get_keys(&UserId::from("11")).is_empty());
+        assert!(!switchboard.blockers_to_miscreants.forward_mapping.contains_key("10"));
+        assert!(!switchboard.blockers_to_miscreants.inverse_mapping.contains_key("11"));
+
+        // bob's own block stands until bob is gone entirely
+        switchboard.leave_publisher(&bob);
+        assert!(switchboard.is_user_present(&"11".into()));
+        switchboard.leave_subscriber(&bob_subscriber);
+        assert!(!switchboard.is_user_present(&"11".into()));
+        assert_eq!(switchboard.blockers_to_miscreants.get_values(&UserId::from("11")), &["12".to_string()]);
+        switchboard.remove_user_blocks(&"11".into(), now, now);
+        assert!(switchboard.blockers_to_miscreants.forward_mapping.is_empty());
+        assert!(switchboard.blockers_to_miscreants.inverse_mapping.is_empty());
+
+        // bob's blocks expired as soon as he left, so they're gone for good
+        switchboard.restore_user_blocks(&"11".into(), now);
+        assert!(switchboard.blockers_to_miscreants.forward_mapping.is_empty());
+        assert_eq!(switchboard.retained_blocks.len(), 1);
+        // whereas alice's come back if she rejoins in time, but only once
+        switchboard.restore_user_blocks(&"10".into(), now + Duration::from_secs(10));
+        assert_eq!(switchboard.blockers_to_miscreants.get_values(&UserId::from("10")), &["11".to_string()]);
+        assert_eq!(switchboard.retained_blocks.len(), 0);
+    }
+
+    #[test]
+    fn blocks_expire_if_their_blocker_stays_away() {
+        let mut switchboard = Switchboard::new();
+        join_publisher(&mut switchboard, "alpha", "11");
+        switchboard.establish_block("10".into(), "11".into());
+        let now = Instant::now();
+        switchboard.remove_user_blocks(&"10".into(), now + Duration::from_secs(30), now);
+        switchboard.restore_user_blocks(&"10".into(), now + Duration::from_secs(31));
+        assert!(switchboard.blockers_to_miscreants.forward_mapping.is_empty());
+        assert_eq!(switchboard.retained_blocks.len(), 0);
+    }
+
+    #[test]
+    fn mutual_subscriptions_are_dropped() {
+        let mut switchboard = Switchboard::new();
+        let alice = join_publisher(&mut switchboard, "alpha", "10");
+        let bob = join_publisher(&mut switchboard, "alpha", "11");
+        let carol = join_publisher(&mut switchboard, "alpha", "12");
+        let alice_watching_bob = join_subscriber(&mut switchboard, "alpha", "10");
+        let bob_watching_alice = join_subscriber(&mut switchboard, "alpha", "11");
+        let bob_waiting_for_alice = join_subscriber(&mut switchboard, "alpha", "11");
+        let carol_watching_alice = join_subscriber(&mut switchboard, "alpha", "12");
+        switchboard.subscribe_to_user(Arc::clone(&alice_watching_bob), Arc::clone(&bob));
+        switchboard.subscribe_to_user(Arc::clone(&bob_watching_alice), Arc::clone(&alice));
+        switchboard.add_pending_subscriber(Arc::clone(&bob_waiting_for_alice), Arc::clone(&alice));
+        switchboard.subscribe_to_user(Arc::clone(&carol_watching_alice), Arc::clone(&alice));
+
+        let dropped = switchboard.drop_mutual_subscriptions(&"10".into(), &"11".into());
+        assert_eq!(dropped, vec![(alice_watching_bob, Arc::clone(&bob)), (bob_watching_alice, Arc::clone(&alice))]);
+        assert!(switchboard.subscribers_to(&bob).is_empty());
+        assert_eq!(switchboard.subscribers_to(&alice), &[Arc::clone(&carol_watching_alice)]);
+        assert!(switchboard.admit_pending_subscribers(&alice).is_empty());
+        assert_eq!(routed(&switchboard, &alice, true), vec![carol_watching_alice]);
+        assert!(routed(&switchboard, &carol, true).is_empty());
+    }
+
+    #[test]
+    fn relaying_media_does_not_wait_for_the_switchboard() {
+        let switchboard = RwLock::new(Switchboard::new());
+        let (alice, routes) = {
+            let mut switchboard = switchboard.write().unwrap();
+            let alice = join_publisher(&mut switchboard, "alpha", "10");
+            let bob = join_subscriber(&mut switchboard, "alpha", "11");
+            switchboard.subscribe_to_user(bob, Arc::clone(&alice));
+            (alice, Arc::clone(switchboard.routes()))
+        };
+        // e.g. someone joining another room is holding the switchboard
+        let _writer = switchboard.write().unwrap();
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            let mut relayed = 0;
+            for _ in 0..1000 {
+                routes.for_each_recipient(&"alpha".into(), &alice, true, |_| relayed += 1);
+            }
+            done.send(relayed).unwrap();
+        });
+        assert_eq!(finished.recv_timeout(Duration::from_secs(10)).unwrap(), 1000);
+    }
 }
@@ -0,0 +1,252 @@
+/// Tools for rewriting RTP headers, so that a subscriber whose media switches from one publisher to another still sees
+/// a single continuous stream.
+use std::sync::Mutex;
+
+/// How far to advance timestamps across a switch: one 20ms Opus frame at 48kHz.
+const AUDIO_TIMESTAMP_STEP: u32 = 960;
+
+/// How far to advance timestamps across a switch: one frame at 30fps on the 90kHz video clock.
+const VIDEO_TIMESTAMP_STEP: u32 = 3000;
+
+/// The fields of an RTP header which we rewrite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpHeader {
+    pub payload_type: u8,
+    pub seq: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    /// Reads the header of the given RTP packet, if it's long enough to have one.
+    pub fn read(packet: &[u8]) -> Option<Self> {
+        if packet.len() < 12 {
+            return None;
+        }
+        Some(Self {
+            payload_type: packet[1] & 0x7F,
+            seq: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        })
+    }
+
+    /// Writes this header into the given RTP packet, which must be long enough to have one.
+    pub fn write(&self, packet: &mut [u8]) {
+        packet[1] = (packet[1] & 0x80) | (self.payload_type & 0x7F);
+        packet[2..4].copy_from_slice(&self.seq.to_be_bytes());
+        packet[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        packet[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+    }
+}
+
+/// The state needed to rewrite a single stream of audio or video for one subscriber.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamContext {
+    /// The header of the last packet we sent, as rewritten, if we've sent any.
+    last: Option<RtpHeader>,
+    /// The SSRC of the source whose packets we're currently rewriting.
+    source_ssrc: u32,
+    seq_offset: u16,
+    timestamp_offset: u32,
+    /// The payload type which the subscriber negotiated for this stream, if it wasn't negotiated from the first source's
+    /// offer, e.g. because the subscriber is a publisher who negotiated its own.
+    payload_type: Option<u8>,
+}
+
+impl StreamContext {
+    /// Returns the header which the subscriber should see in place of the given one. The first source's payload type
+    /// and SSRC are kept for good, and when the source changes, its sequence numbers and timestamps are offset so that
+    /// they carry on from the last packet of the previous source.
+    pub fn rewrite(&mut self, header: RtpHeader, timestamp_step: u32) -> RtpHeader {
+        let last = match self.last {
+            None => {
+                let first = RtpHeader {
+                    payload_type: self.payload_type.unwrap_or(header.payload_type),
+                    ..header
+                };
+                self.source_ssrc = header.ssrc;
+                self.last = Some(first);
+                return first;
+            }
+            Some(last) => last,
+        };
+        if header.ssrc != self.source_ssrc {
+            self.source_ssrc = header.ssrc;
+            self.seq_offset = last.seq.wrapping_add(1).wrapping_sub(header.seq);
+            self.timestamp_offset = last.timestamp.wrapping_add(timestamp_step).wrapping_sub(header.timestamp);
+        }
+        let rewritten = RtpHeader {
+            payload_type: last.payload_type,
+            seq: header.seq.wrapping_add(self.seq_offset),
+            timestamp: header.timestamp.wrapping_add(self.timestamp_offset),
+            ssrc: last.ssrc,
+        };
+        // packets which arrive out of order shouldn't hold back where the next source carries on from
+        if (rewritten.seq.wrapping_sub(last.seq) as i16) > 0 {
+            self.last = Some(rewritten);
+        }
+        rewritten
+    }
+
+    /// Returns the SSRC and sequence number which the packet the subscriber saw with the given sequence number had
+    /// when it came from the current source, if we've sent any packets.
+    pub fn source_of(&self, seq: u16) -> Option<(u32, u16)> {
+        self.last.map(|_| (self.source_ssrc, seq.wrapping_sub(self.seq_offset)))
+    }
+}
+
+/// The rewriting state for everything one subscriber receives.
+#[derive(Debug, Default)]
+pub struct SwitchingContext {
+    audio: Mutex<StreamContext>,
+    video: Mutex<StreamContext>,
+}
+
+impl SwitchingContext {
+    /// Rewrites the header of the given RTP packet for this subscriber, calls the given function to relay it, and then
+    /// restores the original header, so that the packet can be relayed to others.
+    pub fn relay<F: FnOnce()>(&self, packet: &mut [u8], video: bool, relay: F) {
+        let original = match RtpHeader::read(packet) {
+            Some(header) => header,
+            None => {
+                relay();
+                return;
+            }
+        };
+        let rewritten = if video {
+            self.video.lock().unwrap().rewrite(original, VIDEO_TIMESTAMP_STEP)
+        } else {
+            self.audio.lock().unwrap().rewrite(original, AUDIO_TIMESTAMP_STEP)
+        };
+        rewritten.write(packet);
+        relay();
+        original.write(packet);
+    }
+
+    /// Returns the SSRC and sequence number which the packet of the given kind that this subscriber saw with the given
+    /// sequence number had when it came from its current source, e.g. so that it can be sent again.
+    pub fn source_of(&self, seq: u16, video: bool) -> Option<(u32, u16)> {
+        if video {
+            self.video.lock().unwrap().source_of(seq)
+        } else {
+            self.audio.lock().unwrap().source_of(seq)
+        }
+    }
+
+    /// Forgets everything about the streams so far, e.g. because the subscriber renegotiated and expects the next
+    /// source's headers as they are. Payload types set with `set_payload_types` are kept.
+    pub fn reset(&self) {
+        for stream in &[&self.audio, &self.video] {
+            let mut stream = stream.lock().unwrap();
+            *stream = StreamContext {
+                payload_type: stream.payload_type,
+                ..StreamContext::default()
+            };
+        }
+    }
+
+    /// Makes every packet relayed to this subscriber use the given payload types, rather than those of the first
+    /// source, because it negotiated these itself.
+    pub fn set_payload_types(&self, audio: Option<u8>, video: Option<u8>) {
+        self.audio.lock().unwrap().payload_type = audio;
+        self.video.lock().unwrap().payload_type = video;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn header(payload_type: u8, seq: u16, timestamp: u32, ssrc: u32) -> RtpHeader {
+        RtpHeader {
+            payload_type,
+            seq,
+            timestamp,
+            ssrc,
+        }
+    }
+
+    #[test]
+    fn headers_are_read_and_written() {
+        let mut packet = vec![0x80, 0x80 | 111, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0xAA];
+        assert_eq!(RtpHeader::read(&packet), Some(header(111, 1, 2, 3)));
+        header(96, 0xABCD, 0x01020304, 0x05060708).write(&mut packet);
+        assert_eq!(packet, vec![0x80, 0x80 | 96, 0xAB, 0xCD, 1, 2, 3, 4, 5, 6, 7, 8, 0xAA]);
+        assert_eq!(RtpHeader::read(&packet[..11]), None);
+    }
+
+    #[test]
+    fn first_source_passes_through() {
+        let mut context = StreamContext::default();
+        assert_eq!(context.rewrite(header(111, 100, 5000, 1), 960), header(111, 100, 5000, 1));
+        assert_eq!(context.rewrite(header(111, 101, 5960, 1), 960), header(111, 101, 5960, 1));
+    }
+
+    #[test]
+    fn switching_sources_continues_the_stream() {
+        let mut context = StreamContext::default();
+        context.rewrite(header(111, 100, 5000, 1), 960);
+        context.rewrite(header(111, 101, 5960, 1), 960);
+        assert_eq!(context.rewrite(header(109, 40000, 123456, 2), 960), header(111, 102, 6920, 1));
+        assert_eq!(context.rewrite(header(109, 40001, 124416, 2), 960), header(111, 103, 7880, 1));
+        assert_eq!(context.rewrite(header(111, 102, 6920, 1), 960), header(111, 104, 8840, 1));
+    }
+
+    #[test]
+    fn negotiated_payload_types_are_used() {
+        let context = SwitchingContext::default();
+        context.set_payload_types(Some(109), None);
+        context.reset();
+        let mut audio = context.audio.lock().unwrap();
+        assert_eq!(audio.rewrite(header(111, 100, 5000, 1), 960), header(109, 100, 5000, 1));
+        assert_eq!(audio.rewrite(header(111, 101, 5960, 1), 960), header(109, 101, 5960, 1));
+        assert_eq!(audio.rewrite(header(96, 7, 0, 2), 960), header(109, 102, 6920, 1));
+        let mut video = context.video.lock().unwrap();
+        assert_eq!(video.rewrite(header(96, 100, 0, 1), 3000), header(96, 100, 0, 1));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut context = StreamContext::default();
+        context.rewrite(header(96, 65535, u32::MAX, 1), 3000);
+        assert_eq!(context.rewrite(header(96, 7, 0, 2), 3000), header(96, 0, 2999, 1));
+        assert_eq!(context.rewrite(header(96, 8, 3000, 2), 3000), header(96, 1, 5999, 1));
+    }
+
+    #[test]
+    fn sent_packets_are_traced_to_their_source() {
+        let mut context = StreamContext::default();
+        assert_eq!(context.source_of(100), None);
+        context.rewrite(header(96, 100, 0, 1), 3000);
+        assert_eq!(context.source_of(100), Some((1, 100)));
+        context.rewrite(header(96, 65535, 0, 2), 3000);
+        assert_eq!(context.source_of(101), Some((2, 65535)));
+        assert_eq!(context.source_of(102), Some((2, 0)));
+    }
+
+    #[test]
+    fn late_packets_do_not_move_the_stream_back() {
+        let mut context = StreamContext::default();
+        context.rewrite(header(96, 10, 0, 1), 3000);
+        context.rewrite(header(96, 12, 6000, 1), 3000);
+        assert_eq!(context.rewrite(header(96, 11, 3000, 1), 3000), header(96, 11, 3000, 1));
+        assert_eq!(context.rewrite(header(96, 500, 0, 2), 3000), header(96, 13, 9000, 1));
+    }
+
+    #[test]
+    fn relayed_packets_are_restored() {
+        let context = SwitchingContext::default();
+        let mut first = vec![0x80, 111, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        context.relay(&mut first, false, || ());
+        let mut second = vec![0x80, 111, 0, 9, 0, 0, 0, 9, 0, 0, 0, 4];
+        let mut relayed = None;
+        let original = second.clone();
+        context.relay(&mut second, false, || relayed = Some(()));
+        assert!(relayed.is_some());
+        assert_eq!(second, original);
+        context.reset();
+        assert_eq!(context.audio.lock().unwrap().last, None);
+    }
+}
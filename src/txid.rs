@@ -1,6 +1,20 @@
 use std::ffi::CStr;
 use std::fmt;
 use std::os::raw::c_char;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many bytes of a transaction ID we show by default before cutting it short.
+pub const DEFAULT_MAX_DISPLAY_LENGTH: usize = 64;
+
+/// How many bytes of a transaction ID we show before cutting it short, or zero to show all of it. Transaction IDs come
+/// from clients, so this stops one from filling our logs with a huge one.
+static MAX_DISPLAY_LENGTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_DISPLAY_LENGTH);
+
+/// Sets how many bytes of a transaction ID are shown when it's displayed, where zero means all of them.
+pub fn set_max_display_length(max_len: usize) {
+    MAX_DISPLAY_LENGTH.store(max_len, Ordering::Relaxed);
+}
 
 /// A Janus transaction ID. Used to correlate signalling requests and responses.
 #[derive(Debug)]
@@ -8,15 +22,70 @@ pub struct TransactionId(pub *mut c_char);
 
 unsafe impl Send for TransactionId {}
 
+impl TransactionId {
+    /// Returns this transaction ID as text, cut short with an ellipsis if it's longer than the given number of bytes,
+    /// unless that's zero. Only as much of the ID as is shown is read.
+    pub fn abbreviated(&self, max_len: usize) -> String {
+        if self.0.is_null() {
+            return "<null>".into();
+        }
+        if max_len == 0 {
+            return unsafe { CStr::from_ptr(self.0) }.to_string_lossy().into_owned();
+        }
+        let mut len = 0;
+        while len <= max_len && unsafe { *self.0.add(len) } != 0 {
+            len += 1;
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(self.0 as *const u8, len.min(max_len)) };
+        if len <= max_len {
+            return String::from_utf8_lossy(bytes).into_owned();
+        }
+        // don't leave half of a character dangling where we cut it off
+        let end = match str::from_utf8(bytes) {
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            _ => bytes.len(),
+        };
+        format!("{}…", String::from_utf8_lossy(&bytes[..end]))
+    }
+}
+
 impl fmt::Display for TransactionId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        unsafe {
-            if self.0.is_null() {
-                f.write_str("<null>")
-            } else {
-                let contents = CStr::from_ptr(self.0);
-                f.write_str(&contents.to_string_lossy())
-            }
-        }
+        f.write_str(&self.abbreviated(MAX_DISPLAY_LENGTH.load(Ordering::Relaxed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    fn abbreviate(txid: &str, max_len: usize) -> String {
+        let text = CString::new(txid).unwrap();
+        TransactionId(text.as_ptr() as *mut c_char).abbreviated(max_len)
+    }
+
+    #[test]
+    fn short_ids_are_shown_in_full() {
+        assert_eq!(abbreviate("abc123", 8), "abc123");
+        assert_eq!(abbreviate("abcdefgh", 8), "abcdefgh");
+        assert_eq!(abbreviate("", 8), "");
+        assert_eq!(TransactionId(ptr::null_mut()).abbreviated(8), "<null>");
+    }
+
+    #[test]
+    fn long_ids_are_cut_short() {
+        assert_eq!(abbreviate("abcdefghi", 8), "abcdefgh…");
+        assert_eq!(abbreviate(&"x".repeat(100_000), 4), "xxxx…");
+        // "é" is two bytes, so there's only room for one of them
+        assert_eq!(abbreviate("éé", 3), "é…");
+    }
+
+    #[test]
+    fn zero_means_no_limit() {
+        let long = "y".repeat(1000);
+        assert_eq!(abbreviate(&long, 0), long);
     }
 }
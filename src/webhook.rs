@@ -0,0 +1,212 @@
+/// A queue of events about rooms which are posted to an external HTTP endpoint, e.g. for analytics.
+use crate::messages::{RoomId, UserId};
+use janus_plugin::{janus_verb, janus_warn};
+use serde::Serialize;
+use std::error::Error;
+use std::io;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The most events we'll queue up for delivery before dropping new ones.
+const QUEUE_SIZE: usize = 1024;
+
+/// How long to wait for the endpoint to respond to a post.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How we retry posts which fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// How long to wait before the first retry. Each retry after that waits twice as long as the last.
+    pub initial_delay: Duration,
+    /// How many times to try posting an event before giving up on it.
+    pub max_attempts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Something significant which happened in a room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WebhookEvent {
+    /// What happened, e.g. "join" or "room_created".
+    pub event: &'static str,
+    /// The user it happened to, if it happened to a particular user.
+    pub user_id: Option<UserId>,
+    pub room_id: RoomId,
+    /// When it happened, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl WebhookEvent {
+    /// An event which happened just now.
+    pub fn now(event: &'static str, user_id: Option<&UserId>, room_id: &RoomId) -> Self {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            event,
+            user_id: user_id.cloned(),
+            room_id: room_id.clone(),
+            timestamp: since_epoch.as_millis() as u64,
+        }
+    }
+}
+
+/// A running webhook. Events are posted in order by a background thread, so that nobody sending them has to wait on
+/// the endpoint. The thread exits once the webhook is dropped and it's finished with the events already queued.
+#[derive(Debug)]
+pub struct Webhook {
+    events: SyncSender<WebhookEvent>,
+}
+
+impl Webhook {
+    /// Spawns the thread which posts events to the given URL as JSON.
+    pub fn start(url: &str, backoff: Backoff) -> io::Result<Self> {
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+        let url = url.to_owned();
+        Self::start_with(backoff, move |body| {
+            agent.post(&url).set("Content-Type", "application/json").send_bytes(body)?;
+            Ok(())
+        })
+    }
+
+    /// Spawns the thread which delivers events using the given function, which is passed each event's JSON.
+    fn start_with<F>(backoff: Backoff, post: F) -> io::Result<Self>
+    where
+        F: Fn(&[u8]) -> Result<(), Box<dyn Error>> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("sfu webhook".into())
+            .spawn(move || service_events(receiver, backoff, post))?;
+        Ok(Self { events: sender })
+    }
+
+    /// Queues an event to be posted. If the queue is full, the event is dropped.
+    pub fn send(&self, event: WebhookEvent) {
+        match self.events.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(e)) => janus_warn!("Webhook queue is full; dropping {:?}.", e),
+            Err(TrySendError::Disconnected(e)) => janus_warn!("Webhook is closed; dropping {:?}.", e),
+        }
+    }
+}
+
+fn service_events<F>(events: Receiver<WebhookEvent>, backoff: Backoff, post: F)
+where
+    F: Fn(&[u8]) -> Result<(), Box<dyn Error>>,
+{
+    for event in events.iter() {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                janus_warn!("Failed to serialize webhook event {:?}: {}", event, e);
+                continue;
+            }
+        };
+        let mut delay = backoff.initial_delay;
+        for attempt in 1..=backoff.max_attempts {
+            match post(&body) {
+                Ok(()) => break,
+                Err(e) if attempt < backoff.max_attempts => {
+                    janus_verb!("Failed to post {:?} to webhook (attempt {}): {}", event, attempt, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(e) => janus_warn!("Giving up on posting {:?} to webhook: {}", event, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::{json, Value as JsonValue};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    fn quick_backoff(max_attempts: u32) -> Backoff {
+        Backoff {
+            initial_delay: Duration::from_millis(1),
+            max_attempts,
+        }
+    }
+
+    fn event(name: &'static str, user_id: Option<&str>) -> WebhookEvent {
+        WebhookEvent {
+            event: name,
+            user_id: user_id.map(UserId::from),
+            room_id: "alpha".into(),
+            timestamp: 1234,
+        }
+    }
+
+    #[test]
+    fn events_serialize_as_json() {
+        assert_eq!(
+            serde_json::to_value(&event("join", Some("10"))).unwrap(),
+            json!({ "event": "join", "user_id": "10", "room_id": "alpha", "timestamp": 1234 })
+        );
+        assert_eq!(
+            serde_json::to_value(&event("room_created", None)).unwrap(),
+            json!({ "event": "room_created", "user_id": null, "room_id": "alpha", "timestamp": 1234 })
+        );
+    }
+
+    #[test]
+    fn queued_events_are_posted_in_order() {
+        let (sender, receiver) = mpsc::channel();
+        let webhook = Webhook::start_with(quick_backoff(1), move |body| {
+            sender.send(serde_json::from_slice::<JsonValue>(body).unwrap())?;
+            Ok(())
+        })
+        .unwrap();
+        for name in &["join", "kick", "leave"] {
+            webhook.send(event(*name, Some("10")));
+        }
+        drop(webhook);
+        let posted = receiver.iter().map(|body| body["event"].clone()).collect::<Vec<_>>();
+        assert_eq!(posted, vec!["join", "kick", "leave"]);
+    }
+
+    #[test]
+    fn failed_posts_are_retried() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let (sender, receiver) = mpsc::channel();
+        let counter = Arc::clone(&attempts);
+        let webhook = Webhook::start_with(quick_backoff(3), move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                return Err(From::from("unavailable"));
+            }
+            sender.send(())?;
+            Ok(())
+        })
+        .unwrap();
+        webhook.send(event("join", Some("10")));
+        drop(webhook);
+        assert_eq!(receiver.iter().count(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn undeliverable_events_are_given_up_on() {
+        let (sender, receiver) = mpsc::channel();
+        let webhook = Webhook::start_with(quick_backoff(2), move |body| {
+            let body = serde_json::from_slice::<JsonValue>(body).unwrap();
+            sender.send(body["event"].clone())?;
+            Err(From::from("unavailable"))
+        })
+        .unwrap();
+        webhook.send(event("join", Some("10")));
+        webhook.send(event("leave", Some("10")));
+        drop(webhook);
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), vec!["join", "join", "leave", "leave"]);
+    }
+}
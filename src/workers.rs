@@ -0,0 +1,99 @@
+/// Pools of threads which each work through a queue of their own, and which can be shut down without losing work.
+use std::io;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+/// A fixed number of threads, each of which does the same work on whatever is put on its queue.
+#[derive(Debug)]
+pub struct WorkerPool<T> {
+    senders: RwLock<Vec<SyncSender<T>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// Starts the given number of threads, named after the given prefix, each with a queue holding up to the given
+    /// number of items, which it works through with the given function.
+    pub fn start<F>(name: &str, count: usize, queue_depth: usize, work: F) -> io::Result<Self>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let work = Arc::new(work);
+        let mut senders = Vec::with_capacity(count);
+        let mut threads = Vec::with_capacity(count);
+        for i in 0..count {
+            let (tx, rx) = mpsc::sync_channel(queue_depth);
+            let work = Arc::clone(&work);
+            let thread = thread::Builder::new().name(format!("{} {}", name, i)).spawn(move || {
+                for item in rx.iter() {
+                    work(item);
+                }
+            })?;
+            senders.push(tx);
+            threads.push(thread);
+        }
+        Ok(Self {
+            senders: RwLock::new(senders),
+            threads: Mutex::new(threads),
+        })
+    }
+
+    /// Returns the queue of the thread which the given function chooses, given how many threads there are, or none if
+    /// the pool has been drained.
+    pub fn sender<F>(&self, choose: F) -> Option<SyncSender<T>>
+    where
+        F: FnOnce(usize) -> usize,
+    {
+        let senders = self.senders.read().unwrap();
+        match senders.len() {
+            0 => None,
+            n => senders.get(choose(n)).cloned(),
+        }
+    }
+
+    /// Closes every thread's queue, and waits for the threads to finish whatever was already on it. Nothing can be
+    /// queued afterwards. Queues which were handed out beforehand stay open until they're dropped, so don't hold onto
+    /// them.
+    pub fn drain(&self) {
+        drop(std::mem::take(&mut *self.senders.write().unwrap()));
+        for thread in std::mem::take(&mut *self.threads.lock().unwrap()) {
+            // a thread which panicked has nothing left to finish
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn draining_finishes_queued_work() {
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let finished = Arc::clone(&done);
+        let pool = WorkerPool::start("test worker", 2, 16, move |n: usize| {
+            thread::sleep(Duration::from_millis(5));
+            finished.lock().unwrap().push(n);
+        })
+        .unwrap();
+        for n in 0..20 {
+            pool.sender(|count| n % count).unwrap().send(n).unwrap();
+        }
+        pool.drain();
+        let mut done = done.lock().unwrap().clone();
+        done.sort();
+        assert_eq!(done, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn nothing_is_queued_after_draining() {
+        let pool = WorkerPool::start("test worker", 2, 1, |_: ()| {}).unwrap();
+        assert!(pool.sender(|_| 1).is_some());
+        pool.drain();
+        assert!(pool.sender(|_| 0).is_none());
+        // draining twice is harmless
+        pool.drain();
+    }
+}